@@ -0,0 +1,497 @@
+//! Smart albums: saved searches whose files are kept up to date.
+//!
+//! An album stores a query for [`Catalog::search`] together with the offset from UTC
+//! it was saved with, so its dates keep matching the same files. Its files are kept
+//! in `album_media`, and [`Catalog::refresh_albums`] evaluates the queries again for
+//! the files a rescan or the watcher touched, reporting what each album gained or lost.
+
+use super::search::parse;
+use super::Catalog;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Version of the format written by [`Catalog::export_albums`].
+const EXPORT_VERSION: u32 = 1;
+
+/// A saved album with its number of files.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Album {
+    pub id: i64,
+    pub name: String,
+    pub query: String,
+    /// Offset from UTC in minutes that dates in the query are read in.
+    pub utc_offset: i32,
+    pub count: u64,
+}
+
+/// What defines an album, as saved by the frontend and in exports.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumDefinition {
+    pub name: String,
+    pub query: String,
+    #[serde(default)]
+    pub utc_offset: i32,
+}
+
+/// Files an album gained or lost, see [`Catalog::refresh_albums`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumChange {
+    pub id: i64,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AlbumExport {
+    version: u32,
+    albums: Vec<AlbumDefinition>,
+}
+
+fn album_error(e: rusqlite::Error) -> String {
+    format!("Failed to update albums: {}", e)
+}
+
+/// Checks a definition, returns its trimmed name.
+fn validate(definition: &AlbumDefinition) -> Result<&str, String> {
+    let name = definition.name.trim();
+    if name.is_empty() {
+        return Err("Album name is empty".to_string());
+    }
+    parse(&definition.query, definition.utc_offset)?;
+    Ok(name)
+}
+
+/// Creates an album, or replaces album `id`, returns its id.
+fn write_album(
+    conn: &Connection,
+    id: Option<i64>,
+    definition: &AlbumDefinition,
+) -> Result<i64, String> {
+    let name = validate(definition)?;
+    match id {
+        Some(id) => {
+            let updated = conn
+                .execute(
+                    "UPDATE albums SET name = ?2, query = ?3, utc_offset = ?4 WHERE id = ?1",
+                    params![id, name, definition.query, definition.utc_offset],
+                )
+                .map_err(album_error)?;
+            if updated == 0 {
+                return Err(format!("Album {} does not exist", id));
+            }
+            Ok(id)
+        }
+        None => {
+            conn.execute(
+                "INSERT INTO albums (name, query, utc_offset) VALUES (?1, ?2, ?3)",
+                params![name, definition.query, definition.utc_offset],
+            )
+            .map_err(album_error)?;
+            Ok(conn.last_insert_rowid())
+        }
+    }
+}
+
+impl Catalog {
+    /// Lists the albums by name.
+    pub fn albums(&self) -> Result<Vec<Album>, String> {
+        let mut stmt = self
+            .conn
+            .prepare_cached(
+                "SELECT id, name, query, utc_offset,
+                    (SELECT COUNT(*) FROM album_media WHERE album_id = albums.id)
+                 FROM albums ORDER BY name COLLATE NOCASE, id",
+            )
+            .map_err(album_error)?;
+        let albums = stmt
+            .query_map([], |row| {
+                Ok(Album {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    query: row.get(2)?,
+                    utc_offset: row.get(3)?,
+                    count: row.get(4)?,
+                })
+            })
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(album_error)?;
+        Ok(albums)
+    }
+
+    fn album(&self, id: i64) -> Result<Album, String> {
+        self.albums()?
+            .into_iter()
+            .find(|a| a.id == id)
+            .ok_or_else(|| format!("Album {} does not exist", id))
+    }
+
+    /// Creates an album, or replaces album `id`, and collects its files.
+    /// Fails if the name is empty or the query is invalid.
+    pub fn save_album(
+        &mut self,
+        id: Option<i64>,
+        definition: &AlbumDefinition,
+    ) -> Result<Album, String> {
+        let tx = self.conn.unchecked_transaction().map_err(album_error)?;
+        let id = write_album(&tx, id, definition)?;
+        self.refresh_album(&tx, id, &definition.query, definition.utc_offset, None)?;
+        tx.commit().map_err(album_error)?;
+        self.album(id)
+    }
+
+    /// Deletes an album. The files themselves are not touched.
+    pub fn delete_album(&mut self, id: i64) -> Result<(), String> {
+        let tx = self.conn.transaction().map_err(album_error)?;
+        tx.execute("DELETE FROM album_media WHERE album_id = ?1", params![id])
+            .map_err(album_error)?;
+        tx.execute("DELETE FROM albums WHERE id = ?1", params![id])
+            .map_err(album_error)?;
+        tx.commit().map_err(album_error)
+    }
+
+    /// Writes the definitions of all albums as JSON.
+    pub fn export_albums(&self) -> Result<String, String> {
+        let export = AlbumExport {
+            version: EXPORT_VERSION,
+            albums: self
+                .albums()?
+                .into_iter()
+                .map(|a| AlbumDefinition {
+                    name: a.name,
+                    query: a.query,
+                    utc_offset: a.utc_offset,
+                })
+                .collect(),
+        };
+        serde_json::to_string_pretty(&export).map_err(|e| format!("Failed to export albums: {}", e))
+    }
+
+    /// Adds the albums of an export, skipping those that exist with the same name and
+    /// query. Nothing is imported if any album is invalid or fails to save.
+    /// Returns the new albums.
+    pub fn import_albums(&mut self, json: &str) -> Result<Vec<Album>, String> {
+        let export: AlbumExport =
+            serde_json::from_str(json).map_err(|e| format!("Invalid album export: {}", e))?;
+        if export.version > EXPORT_VERSION {
+            return Err(format!(
+                "Album export version {} is not supported",
+                export.version
+            ));
+        }
+        for definition in &export.albums {
+            validate(definition)
+                .map_err(|e| format!("Invalid album \"{}\": {}", definition.name, e))?;
+        }
+
+        let mut existing: HashSet<(String, String)> = self
+            .albums()?
+            .into_iter()
+            .map(|a| (a.name, a.query))
+            .collect();
+        let tx = self.conn.unchecked_transaction().map_err(album_error)?;
+        let mut ids = Vec::new();
+        for definition in &export.albums {
+            let key = (definition.name.trim().to_string(), definition.query.clone());
+            if existing.insert(key) {
+                let id = write_album(&tx, None, definition)?;
+                self.refresh_album(&tx, id, &definition.query, definition.utc_offset, None)?;
+                ids.push(id);
+            }
+        }
+        tx.commit().map_err(album_error)?;
+        ids.into_iter().map(|id| self.album(id)).collect()
+    }
+
+    /// Evaluates the albums again for the files at `scope`, or for all files if None,
+    /// and for files no longer in the catalog. Returns the albums that changed.
+    pub fn refresh_albums(&mut self, scope: Option<&[String]>) -> Result<Vec<AlbumChange>, String> {
+        let tx = self.conn.unchecked_transaction().map_err(album_error)?;
+        let mut changes = Vec::new();
+        for album in self.albums()? {
+            let change =
+                self.refresh_album(&tx, album.id, &album.query, album.utc_offset, scope)?;
+            if !change.added.is_empty() || !change.removed.is_empty() {
+                changes.push(change);
+            }
+        }
+        tx.commit().map_err(album_error)?;
+        Ok(changes)
+    }
+
+    /// Evaluates an album again, writing its changes with `tx`, a transaction on the
+    /// catalog connection.
+    fn refresh_album(
+        &self,
+        tx: &Connection,
+        id: i64,
+        query: &str,
+        utc_offset: i32,
+        scope: Option<&[String]>,
+    ) -> Result<AlbumChange, String> {
+        let matching = self.matching_paths(query, utc_offset, scope)?;
+
+        let scope_json = scope
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|e| e.to_string())?;
+        let mut stmt = self
+            .conn
+            .prepare_cached(
+                "SELECT path FROM album_media WHERE album_id = ?1 AND (?2 IS NULL
+                    OR path IN (SELECT value FROM json_each(?2))
+                    OR path NOT IN (SELECT path FROM media))",
+            )
+            .map_err(album_error)?;
+        let members = stmt
+            .query_map(params![id, scope_json], |row| row.get(0))
+            .and_then(|rows| rows.collect::<rusqlite::Result<HashSet<String>>>())
+            .map_err(album_error)?;
+
+        let mut added: Vec<String> = matching.difference(&members).cloned().collect();
+        let mut removed: Vec<String> = members.difference(&matching).cloned().collect();
+        added.sort();
+        removed.sort();
+
+        for path in &added {
+            tx.prepare_cached("INSERT INTO album_media (album_id, path) VALUES (?1, ?2)")
+                .and_then(|mut stmt| stmt.execute(params![id, path]))
+                .map_err(album_error)?;
+        }
+        for path in &removed {
+            tx.prepare_cached("DELETE FROM album_media WHERE album_id = ?1 AND path = ?2")
+                .and_then(|mut stmt| stmt.execute(params![id, path]))
+                .map_err(album_error)?;
+        }
+
+        let count = tx
+            .query_row(
+                "SELECT COUNT(*) FROM album_media WHERE album_id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .map_err(album_error)?;
+        Ok(AlbumChange {
+            id,
+            added,
+            removed,
+            count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_png;
+    use crate::thumbnail::normalize_path;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    struct Env {
+        temp: TempDir,
+        catalog: Catalog,
+    }
+
+    impl Env {
+        fn new() -> Self {
+            let temp = TempDir::new().unwrap();
+            let catalog = Catalog::open(&temp.path().join("catalog.sqlite")).unwrap();
+            Self { temp, catalog }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.temp.path().join("photos").join(name)
+        }
+
+        fn key(&self, name: &str) -> String {
+            normalize_path(&self.path(name).to_string_lossy())
+        }
+
+        fn roots(&self) -> Vec<String> {
+            vec![normalize_path(
+                &self.temp.path().join("photos").to_string_lossy(),
+            )]
+        }
+
+        fn rescan(&mut self) {
+            let roots = self.roots();
+            let cache = self.temp.path().join("cache");
+            self.catalog.rescan(&roots, &cache).unwrap();
+        }
+
+        fn update(&mut self, changed: &[&str], removed: &[&str]) -> Vec<AlbumChange> {
+            let roots = self.roots();
+            let changed: Vec<PathBuf> = changed.iter().map(|n| self.path(n)).collect();
+            let removed: Vec<PathBuf> = removed.iter().map(|n| self.path(n)).collect();
+            let cache = self.temp.path().join("cache");
+            self.catalog
+                .update(&roots, &changed, &removed, &cache)
+                .unwrap();
+            let scope: Vec<String> = changed
+                .iter()
+                .map(|p| normalize_path(&p.to_string_lossy()))
+                .collect();
+            self.catalog.refresh_albums(Some(&scope)).unwrap()
+        }
+    }
+
+    fn definition(name: &str, query: &str) -> AlbumDefinition {
+        AlbumDefinition {
+            name: name.to_string(),
+            query: query.to_string(),
+            utc_offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_save_and_delete_albums() {
+        let mut env = Env::new();
+        write_png(&env.path("IMG_20230601_100000.png"), 4, 4);
+        write_png(&env.path("IMG_20240601_100000.png"), 4, 4);
+        env.rescan();
+
+        let album = env
+            .catalog
+            .save_album(None, &definition(" 2023 ", "date:2023"))
+            .unwrap();
+        assert_eq!(album.name, "2023");
+        assert_eq!(album.count, 1);
+
+        let album = env
+            .catalog
+            .save_album(Some(album.id), &definition("All", "type:image"))
+            .unwrap();
+        assert_eq!(album.count, 2);
+        assert_eq!(env.catalog.albums().unwrap(), std::slice::from_ref(&album));
+
+        assert!(env.catalog.save_album(None, &definition("", "x")).is_err());
+        assert!(env
+            .catalog
+            .save_album(None, &definition("Bad", "colour:red"))
+            .is_err());
+        assert!(env
+            .catalog
+            .save_album(Some(album.id + 1), &definition("Gone", "x"))
+            .is_err());
+
+        env.catalog.delete_album(album.id).unwrap();
+        assert!(env.catalog.albums().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_refresh_albums_incrementally() {
+        let mut env = Env::new();
+        write_png(&env.path("IMG_20230601_100000.png"), 4, 4);
+        env.rescan();
+        let album = env
+            .catalog
+            .save_album(None, &definition("Rated", "rating>=4"))
+            .unwrap();
+        let summer = env
+            .catalog
+            .save_album(None, &definition("Summer", "date:2023-06"))
+            .unwrap();
+        assert_eq!(album.count, 0);
+        assert_eq!(summer.count, 1);
+
+        // Rating the file in a sidecar adds it
+        fs::write(
+            env.path("IMG_20230601_100000.xmp"),
+            "<x:xmpmeta><xmp:Rating>5</xmp:Rating></x:xmpmeta>",
+        )
+        .unwrap();
+        let changes = env.update(&["IMG_20230601_100000.png"], &[]);
+        assert_eq!(
+            changes,
+            [AlbumChange {
+                id: album.id,
+                added: vec![env.key("IMG_20230601_100000.png")],
+                removed: Vec::new(),
+                count: 1,
+            }]
+        );
+
+        // Unrelated files leave the albums alone
+        write_png(&env.path("IMG_20240101_100000.png"), 4, 4);
+        assert!(env.update(&["IMG_20240101_100000.png"], &[]).is_empty());
+
+        // Deleting the file removes it from both
+        fs::remove_file(env.path("IMG_20230601_100000.png")).unwrap();
+        let changes = env.update(&[], &["IMG_20230601_100000.png"]);
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().all(|c| c.count == 0 && c.removed.len() == 1));
+    }
+
+    #[test]
+    fn test_export_and_import_albums() {
+        let mut env = Env::new();
+        env.catalog
+            .save_album(None, &definition("Beach", "tag:beach"))
+            .unwrap();
+        let json = env.catalog.export_albums().unwrap();
+        assert!(json.contains("\"version\": 1"));
+
+        // Albums that exist already are skipped
+        let mut other = Env::new();
+        other
+            .catalog
+            .save_album(None, &definition("Videos", "type:video"))
+            .unwrap();
+        let imported = other.catalog.import_albums(&json).unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].query, "tag:beach");
+        assert!(other.catalog.import_albums(&json).unwrap().is_empty());
+        assert_eq!(other.catalog.albums().unwrap().len(), 2);
+
+        assert!(other.catalog.import_albums("[]").is_err());
+        assert!(other
+            .catalog
+            .import_albums(r#"{"version": 2, "albums": []}"#)
+            .is_err());
+        assert!(other
+            .catalog
+            .import_albums(r#"{"version": 1, "albums": [{"name": "x", "query": "colour:red"}]}"#)
+            .is_err());
+
+        // Invalid names fail the whole import, as do invalid queries
+        let invalid = r#"{"version": 1, "albums": [
+            {"name": "Cats", "query": "tag:cat"},
+            {"name": " ", "query": "tag:dog"}
+        ]}"#;
+        assert!(other.catalog.import_albums(invalid).is_err());
+        assert_eq!(other.catalog.albums().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_import_albums_is_atomic() {
+        let mut env = Env::new();
+        let json = r#"{"version": 1, "albums": [
+            {"name": "Cats", "query": "tag:cat"},
+            {"name": "Dogs", "query": "tag:dog"}
+        ]}"#;
+        // Saving the second album fails after the first was written
+        env.catalog
+            .conn
+            .execute_batch(
+                "CREATE TRIGGER no_dogs BEFORE INSERT ON albums WHEN NEW.name = 'Dogs'
+                BEGIN SELECT RAISE(ABORT, 'no dogs'); END;",
+            )
+            .unwrap();
+        assert!(env.catalog.import_albums(json).is_err());
+        assert!(env.catalog.albums().unwrap().is_empty());
+
+        env.catalog
+            .conn
+            .execute_batch("DROP TRIGGER no_dogs")
+            .unwrap();
+        let imported = env.catalog.import_albums(json).unwrap();
+        let names: Vec<&str> = imported.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["Cats", "Dogs"]);
+    }
+}
@@ -0,0 +1,295 @@
+//! Persistent catalog of the media files below the root folders.
+//!
+//! The catalog is a SQLite database in the app data dir. [`Catalog::rescan`] brings
+//! it up to date with the filesystem, reading only files whose size or modification
+//! time changed since the last scan, and the watcher updates it while the app runs.
+//! The shared catalog is rescanned with [`rescan`], which only holds it for writes.
+//! Queries, such as the [`Catalog::timeline`] across all roots or [`Catalog::search`],
+//! go through the shared instance opened at startup, see [`with`]. Saved searches are
+//! kept as smart albums, see [`Catalog::refresh_albums`].
+
+mod albums;
+mod scan;
+mod search;
+mod stats;
+mod timeline;
+
+pub use albums::{Album, AlbumChange, AlbumDefinition};
+pub use scan::{rescan, RescanSummary};
+pub use search::SearchResults;
+pub use stats::FolderStats;
+pub use timeline::{Timeline, TimelineCursor};
+
+use crate::media::{CaptureDate, DateSource, MediaKind};
+use crate::thumbnail::normalize_path;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Version of the schema created by [`migrate`], stored as `user_version`.
+const SCHEMA_VERSION: i32 = 5;
+
+/// The catalog of the running app, opened by [`init`].
+static CATALOG: Mutex<Option<Catalog>> = Mutex::new(None);
+
+/// A media file in the catalog.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CatalogEntry {
+    /// Normalized path of the file.
+    pub path: String,
+    /// Root folder the file was found below.
+    pub root: String,
+    pub name: String,
+    pub kind: MediaKind,
+    /// Size in bytes.
+    pub size: u64,
+    /// Modification time in milliseconds since the Unix epoch.
+    pub mtime: u64,
+    /// Creation time in milliseconds since the Unix epoch, where the filesystem has one.
+    pub created: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// See [`crate::media::capture_date`].
+    pub capture: Option<CaptureDate>,
+    /// Hash the thumbnails of the file are stored under, see [`crate::thumbnail::thumbnail_key`].
+    pub thumbnail_key: Option<String>,
+    /// See [`crate::media::read_metadata`].
+    pub camera: Option<String>,
+    pub rating: Option<i32>,
+    pub tags: Vec<String>,
+    /// Modification time of the newest XMP sidecar, see [`crate::media::sidecar_modified`].
+    pub sidecar_mtime: Option<u64>,
+}
+
+/// Columns read by [`entry_from_row`], in order.
+const ENTRY_COLUMNS: &str = "path, root, name, kind, size, mtime, created, width, height, \
+    captured_at, capture_offset, capture_source, thumbnail_key, camera, rating, tags, sidecar_mtime";
+
+/// Local time of a file in milliseconds read as UTC, given the viewer's offset
+/// from UTC in minutes as `?1`. Mirrors [`crate::media::CaptureDate::local_millis`]:
+/// `local_base` is the local time of files that know their time zone, and the UTC
+/// time of the `floating` ones, which are shifted by the viewer's offset.
+const LOCAL_TIME: &str = "(local_base + floating * ?1 * 60000)";
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<CatalogEntry> {
+    let kind: String = row.get(3)?;
+    let captured_at: Option<i64> = row.get(9)?;
+    let capture_source: Option<String> = row.get(11)?;
+    let tags: Option<String> = row.get(15)?;
+    let capture = captured_at
+        .zip(capture_source.as_deref().and_then(DateSource::parse))
+        .map(|(millis, source)| -> rusqlite::Result<CaptureDate> {
+            Ok(CaptureDate {
+                millis,
+                offset: row.get(10)?,
+                source,
+            })
+        })
+        .transpose()?;
+    Ok(CatalogEntry {
+        path: row.get(0)?,
+        root: row.get(1)?,
+        name: row.get(2)?,
+        kind: MediaKind::parse(&kind).unwrap_or(MediaKind::Image),
+        size: row.get(4)?,
+        mtime: row.get(5)?,
+        created: row.get(6)?,
+        width: row.get(7)?,
+        height: row.get(8)?,
+        capture,
+        thumbnail_key: row.get(12)?,
+        camera: row.get(13)?,
+        rating: row.get(14)?,
+        tags: tags
+            .and_then(|t| serde_json::from_str(&t).ok())
+            .unwrap_or_default(),
+        sidecar_mtime: row.get(16)?,
+    })
+}
+
+/// Creates or upgrades the tables.
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version < 1 {
+        conn.execute_batch(
+            "CREATE TABLE media (
+                path TEXT PRIMARY KEY NOT NULL,
+                root TEXT NOT NULL,
+                dir TEXT NOT NULL,
+                name TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                mtime INTEGER NOT NULL,
+                created INTEGER,
+                width INTEGER,
+                height INTEGER,
+                captured_at INTEGER,
+                thumbnail_key TEXT
+            );
+            CREATE INDEX media_root ON media (root);
+            CREATE INDEX media_dir ON media (dir);",
+        )?;
+    }
+    if version < 2 {
+        // Clearing the modification times makes the next rescan read all dates again
+        conn.execute_batch(
+            "ALTER TABLE media ADD COLUMN capture_offset INTEGER;
+            ALTER TABLE media ADD COLUMN capture_source TEXT;
+            UPDATE media SET mtime = 0;",
+        )?;
+    }
+    if version < 3 {
+        // Tags are a JSON array, queried with json_each
+        conn.execute_batch(
+            "ALTER TABLE media ADD COLUMN camera TEXT;
+            ALTER TABLE media ADD COLUMN rating INTEGER;
+            ALTER TABLE media ADD COLUMN tags TEXT;
+            ALTER TABLE media ADD COLUMN sidecar_mtime INTEGER;
+            UPDATE media SET mtime = 0;",
+        )?;
+    }
+    if version < 4 {
+        conn.execute_batch(
+            "CREATE TABLE albums (
+                id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                query TEXT NOT NULL,
+                utc_offset INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE album_media (
+                album_id INTEGER NOT NULL,
+                path TEXT NOT NULL,
+                PRIMARY KEY (album_id, path)
+            );",
+        )?;
+    }
+    if version < 5 {
+        // Files of either kind keep their order for any viewer offset, so the
+        // timeline pages through each kind on the index and merges them
+        conn.execute_batch(
+            "ALTER TABLE media ADD COLUMN local_base INTEGER GENERATED ALWAYS AS (CASE
+                WHEN captured_at IS NULL OR capture_source IS NULL THEN mtime
+                WHEN capture_offset IS NOT NULL THEN captured_at + capture_offset * 60000
+                ELSE captured_at
+            END) VIRTUAL;
+            ALTER TABLE media ADD COLUMN floating INTEGER GENERATED ALWAYS AS (
+                captured_at IS NULL OR capture_source IS NULL
+                OR (capture_offset IS NULL AND capture_source = 'quickTime')
+            ) VIRTUAL;
+            CREATE INDEX media_local_time ON media (floating, local_base, path);",
+        )?;
+    }
+    conn.pragma_update(None, "user_version", SCHEMA_VERSION)
+}
+
+/// Connection to a catalog database.
+pub struct Catalog {
+    conn: Connection,
+    /// Folder → stats, see [`Catalog::folder_stats`].
+    folder_stats: HashMap<String, FolderStats>,
+}
+
+impl Catalog {
+    /// Opens the catalog at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(|e| {
+            format!(
+                "Failed to open catalog {}: {}",
+                normalize_path(&path.to_string_lossy()),
+                e
+            )
+        })?;
+        // WAL keeps the many small commits of a rescan cheap
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(|e| format!("Failed to configure catalog: {}", e))?;
+        migrate(&conn).map_err(|e| format!("Failed to migrate catalog: {}", e))?;
+        Ok(Self {
+            conn,
+            folder_stats: HashMap::new(),
+        })
+    }
+
+    /// Returns the entry of a file, if it is in the catalog.
+    pub fn get(&self, path: &str) -> Result<Option<CatalogEntry>, String> {
+        self.conn
+            .query_row(
+                &format!("SELECT {} FROM media WHERE path = ?1", ENTRY_COLUMNS),
+                params![path],
+                entry_from_row,
+            )
+            .optional()
+            .map_err(|e| format!("Failed to query catalog: {}", e))
+    }
+
+    /// Lists the files directly inside `dir`, sorted by name.
+    pub fn list_dir(&self, dir: &str) -> Result<Vec<CatalogEntry>, String> {
+        let dir = normalize_path(dir);
+        let mut stmt = self
+            .conn
+            .prepare_cached(&format!(
+                "SELECT {} FROM media WHERE dir = ?1 ORDER BY name",
+                ENTRY_COLUMNS
+            ))
+            .map_err(|e| format!("Failed to query catalog: {}", e))?;
+        let entries = stmt
+            .query_map(params![dir.trim_end_matches('/')], entry_from_row)
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(|e| format!("Failed to query catalog: {}", e))?;
+        Ok(entries)
+    }
+
+    /// Number of files in the catalog.
+    pub fn count(&self) -> Result<u64, String> {
+        self.conn
+            .query_row("SELECT COUNT(*) FROM media", [], |row| row.get(0))
+            .map_err(|e| format!("Failed to query catalog: {}", e))
+    }
+}
+
+/// Opens the catalog shared by the commands and the watcher.
+pub fn init(path: &Path) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create catalog directory: {}", e))?;
+    }
+    let catalog = Catalog::open(path)?;
+    *CATALOG.lock().unwrap_or_else(|e| e.into_inner()) = Some(catalog);
+    Ok(())
+}
+
+/// Runs `f` with the shared catalog, fails if [`init`] was not called or failed.
+pub fn with<T>(f: impl FnOnce(&mut Catalog) -> Result<T, String>) -> Result<T, String> {
+    let mut catalog = CATALOG.lock().unwrap_or_else(|e| e.into_inner());
+    match catalog.as_mut() {
+        Some(catalog) => f(catalog),
+        None => Err("Catalog is not open".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_open_creates_and_reopens() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("catalog.sqlite");
+
+        let catalog = Catalog::open(&path).unwrap();
+        assert_eq!(catalog.count().unwrap(), 0);
+        let version: i32 = catalog
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        drop(catalog);
+
+        // Migrations only run once
+        let catalog = Catalog::open(&path).unwrap();
+        assert_eq!(catalog.get("/missing.jpg").unwrap(), None);
+    }
+}
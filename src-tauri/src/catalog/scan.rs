@@ -0,0 +1,658 @@
+//! Bringing the catalog up to date with the filesystem.
+
+use super::{Catalog, CatalogEntry};
+use crate::media::{self, classify, IgnoreRules};
+use crate::thumbnail::{self, normalize_path};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// What a rescan changed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RescanSummary {
+    pub added: u32,
+    pub updated: u32,
+    pub removed: u32,
+    pub unchanged: u32,
+}
+
+fn db_error(e: rusqlite::Error) -> String {
+    format!("Failed to update catalog: {}", e)
+}
+
+/// Normalizes a root folder for prefix matching.
+fn normalize_root(root: &str) -> String {
+    normalize_path(root).trim_end_matches('/').to_string()
+}
+
+/// Returns the innermost root containing `path`.
+fn root_of<'a>(path: &str, roots: &'a [String]) -> Option<&'a String> {
+    roots
+        .iter()
+        .filter(|root| {
+            path.strip_prefix(root.as_str())
+                .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .max_by_key(|root| root.len())
+}
+
+/// Reads what the catalog stores about a media file.
+fn read_entry(
+    path: &Path,
+    root: &str,
+    meta: &Metadata,
+    cache_base_dir: &Path,
+) -> Option<CatalogEntry> {
+    let kind = classify(path)?;
+    let (width, height) = match kind {
+        media::MediaKind::Image => media::image_dimensions(path).unzip(),
+        media::MediaKind::Video => (None, None),
+    };
+    let metadata = media::read_metadata(path);
+    Some(CatalogEntry {
+        path: normalize_path(&path.to_string_lossy()),
+        root: root.to_string(),
+        name: path.file_name()?.to_string_lossy().to_string(),
+        kind,
+        size: meta.len(),
+        mtime: media::modified_millis(meta),
+        created: meta
+            .created()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64),
+        width,
+        height,
+        capture: media::capture_date(path),
+        thumbnail_key: Some(thumbnail::thumbnail_key(path, cache_base_dir)),
+        camera: metadata.camera,
+        rating: metadata.rating,
+        tags: metadata.tags,
+        sidecar_mtime: media::sidecar_modified(path),
+    })
+}
+
+fn upsert(conn: &Connection, entry: &CatalogEntry) -> rusqlite::Result<()> {
+    let dir = entry.path.rsplit_once('/').map_or("", |(dir, _)| dir);
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO media
+            (path, root, dir, name, kind, size, mtime, created, width, height,
+             captured_at, capture_offset, capture_source, thumbnail_key,
+             camera, rating, tags, sidecar_mtime)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14,
+                 ?15, ?16, ?17, ?18)",
+    )?
+    .execute(params![
+        entry.path,
+        entry.root,
+        dir,
+        entry.name,
+        entry.kind.as_str(),
+        entry.size as i64,
+        entry.mtime as i64,
+        entry.created.map(|c| c as i64),
+        entry.width,
+        entry.height,
+        entry.capture.map(|c| c.millis),
+        entry.capture.and_then(|c| c.offset),
+        entry.capture.map(|c| c.source.as_str()),
+        entry.thumbnail_key,
+        entry.camera,
+        entry.rating,
+        (!entry.tags.is_empty()).then(|| serde_json::to_string(&entry.tags).unwrap_or_default()),
+        entry.sidecar_mtime.map(|m| m as i64),
+    ])?;
+    Ok(())
+}
+
+/// Deletes a file, or everything below it if it was a directory.
+fn remove_prefix(conn: &Connection, path: &str) -> rusqlite::Result<usize> {
+    conn.prepare_cached(
+        "DELETE FROM media WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'",
+    )?
+    .execute(params![path])
+}
+
+/// Collects the media files below `root` with their metadata.
+/// Caches, ignored files and folders and the directories in `skip`, i.e. nested
+/// roots, are left out.
+fn walk(root: &Path, skip: &[PathBuf]) -> Vec<(PathBuf, Metadata)> {
+    let mut files = Vec::new();
+    let mut dirs = vec![(root.to_path_buf(), IgnoreRules::for_dir(root))];
+    while let Some((dir, rules)) = dirs.pop() {
+        if rules.is_hidden() {
+            continue;
+        }
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            // Symlinked directories are not followed, they could form cycles
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if rules.is_ignored(&path, file_type.is_dir()) {
+                continue;
+            }
+            if file_type.is_dir() {
+                if entry.file_name() != thumbnail::PORTABLE_CACHE_DIR && !skip.contains(&path) {
+                    let child = rules.child(&path);
+                    dirs.push((path, child));
+                }
+            } else if classify(&path).is_some() {
+                if let Some(meta) = fs::metadata(&path).ok().filter(|m| m.is_file()) {
+                    files.push((path, meta));
+                }
+            }
+        }
+    }
+    files
+}
+
+/// Files read between two writes of a rescan, queries wait for one batch at most.
+const RESCAN_BATCH: usize = 500;
+
+/// Rescans the shared catalog, see [`Catalog::rescan`]. Files are walked and read
+/// without holding the catalog, which is only taken to write each batch of changes,
+/// so queries are answered while a first scan runs.
+pub fn rescan(roots: &[String], cache_base_dir: &Path) -> Result<RescanSummary, String> {
+    rescan_by(|write| super::with(write), roots, cache_base_dir)
+}
+
+/// Runs a rescan, calling `write` whenever the catalog needs to be read or written.
+fn rescan_by(
+    mut write: impl FnMut(&mut dyn FnMut(&mut Catalog) -> Result<(), String>) -> Result<(), String>,
+    roots: &[String],
+    cache_base_dir: &Path,
+) -> Result<RescanSummary, String> {
+    let roots: Vec<String> = roots.iter().map(|r| normalize_root(r)).collect();
+    let mut summary = RescanSummary::default();
+
+    write(&mut |catalog| {
+        summary.removed += catalog.drop_other_roots(&roots)?;
+        Ok(())
+    })?;
+
+    for root in &roots {
+        let root_path = Path::new(root);
+        if !root_path.is_dir() {
+            continue;
+        }
+
+        let mut known = HashMap::new();
+        write(&mut |catalog| {
+            known = catalog.stamps(root)?;
+            Ok(())
+        })?;
+
+        let mut skip: Vec<PathBuf> = roots
+            .iter()
+            .filter(|other| *other != root && Path::new(other).starts_with(root_path))
+            .map(PathBuf::from)
+            .collect();
+        skip.push(cache_base_dir.to_path_buf());
+
+        let mut batch = Vec::new();
+        for (path, meta) in walk(root_path, &skip) {
+            let key = normalize_path(&path.to_string_lossy());
+            let stamp = (
+                meta.len(),
+                media::modified_millis(&meta),
+                media::sidecar_modified(&path),
+            );
+            let previous = known.remove(&key);
+            if previous == Some(stamp) {
+                summary.unchanged += 1;
+                continue;
+            }
+            let Some(entry) = read_entry(&path, root, &meta, cache_base_dir) else {
+                continue;
+            };
+            batch.push((entry, previous.is_some()));
+            if batch.len() == RESCAN_BATCH {
+                write(&mut |catalog| catalog.write_batch(&batch, &[], &mut summary))?;
+                batch.clear();
+            }
+        }
+
+        let removed: Vec<String> = known.into_keys().collect();
+        write(&mut |catalog| catalog.write_batch(&batch, &removed, &mut summary))?;
+    }
+    Ok(summary)
+}
+
+/// Size, modification time and sidecar modification time of a cataloged file.
+type Stamp = (u64, u64, Option<u64>);
+
+impl Catalog {
+    /// Brings the catalog up to date with the files below `roots`.
+    /// Files whose size and modification time are unchanged are not read again.
+    /// Files of roots no longer in `roots` are dropped, while roots that are missing,
+    /// e.g. on a disconnected drive, keep their files until they are back.
+    pub fn rescan(
+        &mut self,
+        roots: &[String],
+        cache_base_dir: &Path,
+    ) -> Result<RescanSummary, String> {
+        rescan_by(|write| write(self), roots, cache_base_dir)
+    }
+
+    /// Drops the files of roots not in `roots`, returns how many there were.
+    fn drop_other_roots(&mut self, roots: &[String]) -> Result<u32, String> {
+        let known_roots: Vec<String> = self
+            .conn
+            .prepare("SELECT DISTINCT root FROM media")
+            .and_then(|mut stmt| {
+                let roots = stmt.query_map([], |row| row.get(0))?.collect();
+                roots
+            })
+            .map_err(db_error)?;
+        let mut removed = 0;
+        for root in known_roots.iter().filter(|root| !roots.contains(root)) {
+            removed += self
+                .conn
+                .execute("DELETE FROM media WHERE root = ?1", params![root])
+                .map_err(db_error)? as u32;
+        }
+        if removed > 0 {
+            self.folder_stats.clear();
+        }
+        Ok(removed)
+    }
+
+    /// Path → stamp of the files of a root.
+    fn stamps(&self, root: &str) -> Result<HashMap<String, Stamp>, String> {
+        self.conn
+            .prepare("SELECT path, size, mtime, sidecar_mtime FROM media WHERE root = ?1")
+            .and_then(|mut stmt| {
+                let known = stmt
+                    .query_map(params![root], |row| {
+                        Ok((row.get(0)?, (row.get(1)?, row.get(2)?, row.get(3)?)))
+                    })?
+                    .collect();
+                known
+            })
+            .map_err(db_error)
+    }
+
+    /// Writes rescanned entries, each with whether it was cataloged before, and
+    /// drops the `removed` paths in one transaction.
+    fn write_batch(
+        &mut self,
+        entries: &[(CatalogEntry, bool)],
+        removed: &[String],
+        summary: &mut RescanSummary,
+    ) -> Result<(), String> {
+        if entries.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+        let tx = self.conn.transaction().map_err(db_error)?;
+        for (entry, known) in entries {
+            upsert(&tx, entry).map_err(db_error)?;
+            if *known {
+                summary.updated += 1;
+            } else {
+                summary.added += 1;
+            }
+        }
+        for path in removed {
+            summary.removed += remove_prefix(&tx, path).map_err(db_error)? as u32;
+        }
+        tx.commit().map_err(db_error)?;
+        self.folder_stats.clear();
+        Ok(())
+    }
+
+    /// Applies changes reported by the watcher: `changed` files are read again and
+    /// `removed` paths, which may be directories, are dropped with everything below.
+    /// Paths outside of `roots` are not cataloged.
+    pub fn update(
+        &mut self,
+        roots: &[String],
+        changed: &[PathBuf],
+        removed: &[PathBuf],
+        cache_base_dir: &Path,
+    ) -> Result<(), String> {
+        let roots: Vec<String> = roots.iter().map(|r| normalize_root(r)).collect();
+        let tx = self.conn.transaction().map_err(db_error)?;
+
+        for path in removed {
+            remove_prefix(&tx, &normalize_path(&path.to_string_lossy())).map_err(db_error)?;
+        }
+        for path in changed {
+            let key = normalize_path(&path.to_string_lossy());
+            let Some(root) = root_of(&key, &roots) else {
+                continue;
+            };
+            let entry = fs::metadata(path)
+                .ok()
+                .filter(|m| m.is_file() && !media::is_ignored(path, false))
+                .and_then(|meta| read_entry(path, root, &meta, cache_base_dir));
+            match entry {
+                Some(entry) => upsert(&tx, &entry).map_err(db_error)?,
+                None => {
+                    remove_prefix(&tx, &key).map_err(db_error)?;
+                }
+            }
+        }
+
+        tx.commit().map_err(db_error)?;
+        for path in changed.iter().chain(removed) {
+            self.forget_folder_stats(&normalize_path(&path.to_string_lossy()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_png;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    struct Env {
+        temp: TempDir,
+        catalog: Catalog,
+    }
+
+    impl Env {
+        fn new() -> Self {
+            let temp = TempDir::new().unwrap();
+            let catalog = Catalog::open(&temp.path().join("catalog.sqlite")).unwrap();
+            fs::create_dir(temp.path().join("photos")).unwrap();
+            Self { temp, catalog }
+        }
+
+        fn path(&self, relative: &str) -> PathBuf {
+            self.temp.path().join(relative)
+        }
+
+        fn root(&self, relative: &str) -> String {
+            normalize_path(&self.path(relative).to_string_lossy())
+        }
+
+        fn rescan(&mut self, roots: &[&str]) -> RescanSummary {
+            let roots: Vec<String> = roots.iter().map(|r| self.root(r)).collect();
+            let cache = self.path("cache");
+            self.catalog.rescan(&roots, &cache).unwrap()
+        }
+
+        fn names(&self, dir: &str) -> Vec<String> {
+            self.catalog
+                .list_dir(&self.root(dir))
+                .unwrap()
+                .into_iter()
+                .map(|e| e.name)
+                .collect()
+        }
+    }
+
+    fn summary(added: u32, updated: u32, removed: u32, unchanged: u32) -> RescanSummary {
+        RescanSummary {
+            added,
+            updated,
+            removed,
+            unchanged,
+        }
+    }
+
+    // -----------------------------------------------------------------------
+    // rescan
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_rescan_catalogs_media_files() {
+        let mut env = Env::new();
+        write_png(&env.path("photos/a.png"), 40, 30);
+        write_png(&env.path("photos/2020/b.png"), 10, 20);
+        fs::write(env.path("photos/clip.mp4"), b"video").unwrap();
+        fs::write(env.path("photos/notes.txt"), b"text").unwrap();
+        // Thumbnails in the cache and portable caches are not media of the collection
+        write_png(&env.path("photos/.mv/ab/thumb.png"), 8, 8);
+
+        assert_eq!(env.rescan(&["photos"]), summary(3, 0, 0, 0));
+        assert_eq!(env.catalog.count().unwrap(), 3);
+        assert_eq!(env.names("photos"), ["a.png", "clip.mp4"]);
+        assert_eq!(env.names("photos/2020"), ["b.png"]);
+
+        let a = env.catalog.get(&env.root("photos/a.png")).unwrap().unwrap();
+        assert_eq!(a.root, env.root("photos"));
+        assert_eq!(a.kind, media::MediaKind::Image);
+        assert_eq!((a.width, a.height), (Some(40), Some(30)));
+        assert_eq!(
+            a.thumbnail_key,
+            Some(thumbnail::thumbnail_key(
+                &env.path("photos/a.png"),
+                &env.path("cache")
+            ))
+        );
+        assert!(a.mtime > 0);
+    }
+
+    #[test]
+    fn test_rescan_is_incremental() {
+        let mut env = Env::new();
+        write_png(&env.path("photos/a.png"), 40, 30);
+        write_png(&env.path("photos/b.png"), 40, 30);
+        write_png(&env.path("photos/old/c.png"), 40, 30);
+        env.rescan(&["photos"]);
+
+        assert_eq!(env.rescan(&["photos"]), summary(0, 0, 0, 3));
+
+        thread::sleep(Duration::from_millis(20));
+        write_png(&env.path("photos/a.png"), 80, 60);
+        write_png(&env.path("photos/d.png"), 5, 5);
+        fs::remove_dir_all(env.path("photos/old")).unwrap();
+
+        assert_eq!(env.rescan(&["photos"]), summary(1, 1, 1, 1));
+        let a = env.catalog.get(&env.root("photos/a.png")).unwrap().unwrap();
+        assert_eq!(a.width, Some(80));
+        assert_eq!(env.names("photos/old"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_rescan_stores_capture_dates() {
+        let mut env = Env::new();
+        write_png(&env.path("photos/IMG_20240131_123456.png"), 4, 4);
+        write_png(&env.path("photos/b.png"), 4, 4);
+        env.rescan(&["photos"]);
+
+        let dated = env
+            .catalog
+            .get(&env.root("photos/IMG_20240131_123456.png"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            dated.capture,
+            media::capture_date(&env.path("photos/IMG_20240131_123456.png"))
+        );
+        assert_eq!(
+            dated.capture.map(|c| c.source),
+            Some(media::DateSource::FileName)
+        );
+        let undated = env.catalog.get(&env.root("photos/b.png")).unwrap().unwrap();
+        assert_eq!(undated.capture, None);
+    }
+
+    #[test]
+    fn test_rescan_reads_sidecars() {
+        let mut env = Env::new();
+        write_png(&env.path("photos/a.png"), 4, 4);
+        env.rescan(&["photos"]);
+
+        // Editing only the sidecar still reads the file again
+        fs::write(
+            env.path("photos/a.xmp"),
+            "<x:xmpmeta><xmp:Rating>4</xmp:Rating>\
+             <dc:subject><rdf:Bag><rdf:li>beach</rdf:li></rdf:Bag></dc:subject></x:xmpmeta>",
+        )
+        .unwrap();
+        assert_eq!(env.rescan(&["photos"]), summary(0, 1, 0, 0));
+        let a = env.catalog.get(&env.root("photos/a.png")).unwrap().unwrap();
+        assert_eq!(a.rating, Some(4));
+        assert_eq!(a.tags, ["beach"]);
+        assert!(a.sidecar_mtime.is_some());
+        assert_eq!(env.rescan(&["photos"]), summary(0, 0, 0, 1));
+    }
+
+    #[test]
+    fn test_schema_upgrade_reads_files_again() {
+        let mut env = Env::new();
+        write_png(&env.path("photos/IMG_20240131_123456.png"), 4, 4);
+        env.rescan(&["photos"]);
+
+        // Turn the catalog back into a version 1 catalog
+        env.catalog
+            .conn
+            .execute_batch(
+                "DROP INDEX media_local_time;
+                ALTER TABLE media DROP COLUMN floating;
+                ALTER TABLE media DROP COLUMN local_base;
+                ALTER TABLE media DROP COLUMN capture_offset;
+                ALTER TABLE media DROP COLUMN capture_source;
+                ALTER TABLE media DROP COLUMN camera;
+                ALTER TABLE media DROP COLUMN rating;
+                ALTER TABLE media DROP COLUMN tags;
+                ALTER TABLE media DROP COLUMN sidecar_mtime;
+                DROP TABLE albums;
+                DROP TABLE album_media;
+                PRAGMA user_version = 1;",
+            )
+            .unwrap();
+        env.catalog = Catalog::open(&env.path("catalog.sqlite")).unwrap();
+
+        assert_eq!(env.rescan(&["photos"]), summary(0, 1, 0, 0));
+        let entry = env
+            .catalog
+            .get(&env.root("photos/IMG_20240131_123456.png"))
+            .unwrap()
+            .unwrap();
+        assert!(entry.capture.is_some());
+    }
+
+    #[test]
+    fn test_rescan_respects_ignore_rules() {
+        let mut env = Env::new();
+        write_png(&env.path("photos/a.png"), 8, 8);
+        write_png(&env.path("photos/@eaDir/a.png"), 8, 8);
+        write_png(&env.path("photos/private/b.png"), 8, 8);
+        fs::write(env.path("photos").join(media::IGNORE_FILE), "@eaDir/\n").unwrap();
+        assert_eq!(env.rescan(&["photos"]), summary(2, 0, 0, 0));
+
+        // Files hidden later are dropped by the next rescan
+        fs::write(env.path("photos/private").join(media::NOMEDIA_FILE), b"").unwrap();
+        assert_eq!(env.rescan(&["photos"]), summary(0, 0, 1, 1));
+        assert_eq!(env.names("photos"), ["a.png"]);
+    }
+
+    #[test]
+    fn test_rescan_roots() {
+        let mut env = Env::new();
+        write_png(&env.path("photos/a.png"), 4, 4);
+        write_png(&env.path("photos/trip/b.png"), 4, 4);
+        write_png(&env.path("videos/c.png"), 4, 4);
+        assert_eq!(env.rescan(&["photos", "photos/trip"]), summary(2, 0, 0, 0));
+
+        // Files belong to the innermost root, so nested roots don't fight over them
+        let b = env.root("photos/trip/b.png");
+        assert_eq!(
+            env.catalog.get(&b).unwrap().unwrap().root,
+            env.root("photos/trip")
+        );
+        assert_eq!(env.rescan(&["photos", "photos/trip"]), summary(0, 0, 0, 2));
+
+        // Missing roots keep their files, removed roots lose them
+        assert_eq!(
+            env.rescan(&["photos", "photos/trip", "videos"]),
+            summary(1, 0, 0, 2)
+        );
+        fs::rename(env.path("videos"), env.path("unmounted")).unwrap();
+        assert_eq!(
+            env.rescan(&["photos", "photos/trip", "videos"]),
+            summary(0, 0, 0, 2)
+        );
+        assert_eq!(env.catalog.count().unwrap(), 3);
+        assert_eq!(env.rescan(&["photos"]), summary(1, 0, 2, 1));
+        assert_eq!(
+            env.catalog.get(&b).unwrap().unwrap().root,
+            env.root("photos")
+        );
+    }
+
+    #[test]
+    fn test_rescan_writes_in_batches() {
+        let mut env = Env::new();
+        for i in 0..=RESCAN_BATCH {
+            fs::write(env.path(&format!("photos/{}.mp4", i)), b"").unwrap();
+        }
+        let roots = vec![env.root("photos")];
+        let cache = env.path("cache");
+
+        // Dropping other roots, reading the known files, a full batch and the rest
+        let mut counts = Vec::new();
+        let summary = rescan_by(
+            |write| {
+                write(&mut env.catalog)?;
+                counts.push(env.catalog.count()?);
+                Ok(())
+            },
+            &roots,
+            &cache,
+        )
+        .unwrap();
+        assert_eq!(summary.added as usize, RESCAN_BATCH + 1);
+        let batch = RESCAN_BATCH as u64;
+        assert_eq!(counts, [0, 0, batch, batch + 1]);
+    }
+
+    // -----------------------------------------------------------------------
+    // update
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_update_applies_watcher_changes() {
+        let mut env = Env::new();
+        write_png(&env.path("photos/a.png"), 4, 4);
+        write_png(&env.path("photos/trip/b.png"), 4, 4);
+        write_png(&env.path("elsewhere/c.png"), 4, 4);
+        env.rescan(&["photos"]);
+
+        write_png(&env.path("photos/a.png"), 8, 8);
+        write_png(&env.path("photos/d.png"), 4, 4);
+        let roots = vec![env.root("photos")];
+        env.catalog
+            .update(
+                &roots,
+                &[
+                    env.path("photos/a.png"),
+                    env.path("photos/d.png"),
+                    env.path("elsewhere/c.png"),
+                ],
+                &[env.path("photos/trip")],
+                &env.path("cache"),
+            )
+            .unwrap();
+
+        assert_eq!(env.names("photos"), ["a.png", "d.png"]);
+        assert_eq!(env.names("photos/trip"), Vec::<String>::new());
+        assert_eq!(env.names("elsewhere"), Vec::<String>::new());
+        let a = env.catalog.get(&env.root("photos/a.png")).unwrap().unwrap();
+        assert_eq!(a.width, Some(8));
+    }
+
+    #[test]
+    fn test_root_of() {
+        let roots = vec!["/photos".to_string(), "/photos/trip".to_string()];
+        assert_eq!(root_of("/photos/a.jpg", &roots), Some(&roots[0]));
+        assert_eq!(root_of("/photos/trip/b.jpg", &roots), Some(&roots[1]));
+        assert_eq!(root_of("/photos-old/c.jpg", &roots), None);
+        assert_eq!(root_of("/photos", &roots), None);
+    }
+}
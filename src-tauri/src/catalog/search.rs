@@ -0,0 +1,577 @@
+//! Searching the catalog with a small query language.
+//!
+//! A query is a list of terms that all have to match. Terms are filters such as
+//! `camera:"iPhone 15"`, `date:2023-06..2023-08`, `type:video`, `ext:cr2`,
+//! `rating>=4`, `tag:beach` or `width>4000`, or free text matched against file
+//! names. Values with spaces are quoted. Results are newest first, by the same
+//! local time as the timeline.
+
+use super::{entry_from_row, Catalog, CatalogEntry, ENTRY_COLUMNS, LOCAL_TIME};
+use crate::media::{civil_millis, MediaEntry, MediaKind};
+use crate::thumbnail::{self, normalize_path};
+use rusqlite::params_from_iter;
+use rusqlite::types::Value;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Files per page of [`Catalog::search`].
+pub const SEARCH_PAGE_SIZE: u32 = 200;
+
+const DAY_MILLIS: i64 = 86_400_000;
+
+/// A page of search results, shaped like a directory listing.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResults {
+    pub entries: Vec<MediaEntry>,
+    /// Files matching the query.
+    pub total: u64,
+    pub page: u32,
+    pub has_more: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    /// `:` or `=`
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn sql(self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        }
+    }
+}
+
+/// Splits a query at whitespace outside of double quotes, dropping the quotes.
+/// The flag tells whether a term started with a quote, making it free text.
+fn tokenize(query: &str) -> Result<Vec<(String, bool)>, String> {
+    let mut terms = Vec::new();
+    let mut current: Option<(String, bool)> = None;
+    let mut quoted = false;
+    for c in query.chars() {
+        match c {
+            '"' => {
+                current.get_or_insert_with(|| (String::new(), true));
+                quoted = !quoted;
+            }
+            c if c.is_whitespace() && !quoted => terms.extend(current.take()),
+            c => current
+                .get_or_insert_with(|| (String::new(), false))
+                .0
+                .push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quote in search".to_string());
+    }
+    terms.extend(current);
+    Ok(terms)
+}
+
+/// Splits a term into its key, operator and value, if it is a filter.
+fn split_filter(term: &str) -> Option<(&str, Op, &str)> {
+    let key_len = term
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .filter(|&len| len > 0)?;
+    let (key, rest) = term.split_at(key_len);
+    let (op, value) = [
+        (":", Op::Eq),
+        (">=", Op::Ge),
+        ("<=", Op::Le),
+        (">", Op::Gt),
+        ("<", Op::Lt),
+        ("=", Op::Eq),
+    ]
+    .into_iter()
+    .find_map(|(prefix, op)| rest.strip_prefix(prefix).map(|value| (op, value)))?;
+    Some((key, op, value))
+}
+
+/// Local time range `start..end` of a year, month or day: `2023`, `2023-06` or `2023-06-15`.
+fn period(value: &str) -> Option<(i64, i64)> {
+    let parts: Vec<&str> = value.split('-').collect();
+    let numbers: Vec<u32> = parts
+        .iter()
+        .map(|p| p.parse().ok().filter(|_| !p.is_empty() && p.len() <= 4))
+        .collect::<Option<_>>()?;
+    if parts[0].len() != 4 {
+        return None;
+    }
+    let year = i64::from(numbers[0]);
+    match numbers[..] {
+        [_] => Some((
+            civil_millis(year, 1, 1, 0, 0, 0)?,
+            civil_millis(year + 1, 1, 1, 0, 0, 0)?,
+        )),
+        [_, month] => {
+            let (next_year, next_month) = if month == 12 {
+                (year + 1, 1)
+            } else {
+                (year, month + 1)
+            };
+            Some((
+                civil_millis(year, month, 1, 0, 0, 0)?,
+                civil_millis(next_year, next_month, 1, 0, 0, 0)?,
+            ))
+        }
+        [_, month, day] => {
+            let start = civil_millis(year, month, day, 0, 0, 0)?;
+            Some((start, start + DAY_MILLIS))
+        }
+        _ => None,
+    }
+}
+
+/// Query conditions with their parameters. `?1` is reserved for the viewer's offset
+/// from UTC, which [`LOCAL_TIME`] needs.
+pub(super) struct Conditions {
+    sql: Vec<String>,
+    params: Vec<Value>,
+}
+
+impl Conditions {
+    /// Adds a parameter and returns its placeholder.
+    fn param(&mut self, value: impl Into<Value>) -> String {
+        self.params.push(value.into());
+        format!("?{}", self.params.len())
+    }
+
+    fn date(&mut self, op: Op, value: &str) -> Result<(), String> {
+        let invalid = || {
+            format!(
+                "Invalid date \"{}\", expected e.g. 2023, 2023-06 or 2023-06-15",
+                value
+            )
+        };
+        let bound = |v: &str| {
+            if v.is_empty() {
+                Ok(None)
+            } else {
+                period(v).map(Some).ok_or_else(invalid)
+            }
+        };
+        let (from, to) = match (op, value.split_once("..")) {
+            (Op::Eq, Some((from, to))) => {
+                let (from, to) = (bound(from)?, bound(to)?);
+                if from.is_none() && to.is_none() {
+                    return Err(invalid());
+                }
+                (from.map(|p| p.0), to.map(|p| p.1))
+            }
+            (_, Some(_)) => return Err(invalid()),
+            (op, None) => {
+                let (start, end) = period(value).ok_or_else(invalid)?;
+                match op {
+                    Op::Eq => (Some(start), Some(end)),
+                    Op::Lt => (None, Some(start)),
+                    Op::Le => (None, Some(end)),
+                    Op::Gt => (Some(end), None),
+                    Op::Ge => (Some(start), None),
+                }
+            }
+        };
+        if let Some(from) = from {
+            let placeholder = self.param(from);
+            self.sql.push(format!("local_time >= {}", placeholder));
+        }
+        if let Some(to) = to {
+            let placeholder = self.param(to);
+            self.sql.push(format!("local_time < {}", placeholder));
+        }
+        Ok(())
+    }
+
+    fn number(&mut self, column: &str, op: Op, value: &str) -> Result<(), String> {
+        let number: i64 = value
+            .parse()
+            .map_err(|_| format!("Invalid number \"{}\" for {}", value, column))?;
+        let placeholder = self.param(number);
+        self.sql
+            .push(format!("{} {} {}", column, op.sql(), placeholder));
+        Ok(())
+    }
+
+    fn filter(&mut self, key: &str, op: Op, value: &str) -> Result<(), String> {
+        let text_only = |key: &str| {
+            if op == Op::Eq {
+                Ok(())
+            } else {
+                Err(format!("{} only supports \"{}:value\"", key, key))
+            }
+        };
+        match key.to_lowercase().as_str() {
+            "camera" => {
+                text_only("camera")?;
+                let placeholder = self.param(value.to_lowercase());
+                self.sql
+                    .push(format!("instr(lower(camera), {}) > 0", placeholder));
+            }
+            "date" => self.date(op, value)?,
+            "type" => {
+                text_only("type")?;
+                let kind = match value.to_lowercase().as_str() {
+                    "image" | "photo" => MediaKind::Image,
+                    "video" => MediaKind::Video,
+                    _ => {
+                        return Err(format!(
+                            "Invalid type \"{}\", expected image or video",
+                            value
+                        ))
+                    }
+                };
+                let placeholder = self.param(kind.as_str().to_string());
+                self.sql.push(format!("kind = {}", placeholder));
+            }
+            "ext" => {
+                text_only("ext")?;
+                let suffix = format!(".{}", value.trim_start_matches('.').to_lowercase());
+                let placeholder = self.param(suffix);
+                self.sql.push(format!(
+                    "substr(lower(name), -length({0})) = {0}",
+                    placeholder
+                ));
+            }
+            "tag" => {
+                text_only("tag")?;
+                let placeholder = self.param(value.to_lowercase());
+                self.sql.push(format!(
+                    "EXISTS (SELECT 1 FROM json_each(tags) WHERE lower(value) = {})",
+                    placeholder
+                ));
+            }
+            "rating" => self.number("rating", op, value)?,
+            "width" => self.number("width", op, value)?,
+            "height" => self.number("height", op, value)?,
+            _ => return Err(format!("Unknown search filter \"{}\"", key)),
+        }
+        Ok(())
+    }
+
+    /// Files matching all conditions, with their local time, for use after `FROM`.
+    fn matching(&self) -> String {
+        let filter = if self.sql.is_empty() {
+            "1".to_string()
+        } else {
+            self.sql.join(" AND ")
+        };
+        format!(
+            "(SELECT *, {} AS local_time FROM media) WHERE {}",
+            LOCAL_TIME, filter
+        )
+    }
+
+    fn text(&mut self, text: &str) {
+        let placeholder = self.param(text.to_lowercase());
+        self.sql
+            .push(format!("instr(lower(name), {}) > 0", placeholder));
+    }
+}
+
+/// Parses a query into conditions, with `utc_offset` as the first parameter.
+pub(super) fn parse(query: &str, utc_offset: i32) -> Result<Conditions, String> {
+    let mut conditions = Conditions {
+        sql: Vec::new(),
+        params: vec![Value::from(utc_offset)],
+    };
+    for (term, quoted) in tokenize(query)? {
+        match split_filter(&term).filter(|_| !quoted) {
+            Some((key, _, "")) => return Err(format!("Missing value for {}", key)),
+            Some((key, op, value)) => conditions.filter(key, op, value)?,
+            None if term.is_empty() => {}
+            None => conditions.text(&term),
+        }
+    }
+    Ok(conditions)
+}
+
+/// A search result in the shape of a directory listing.
+fn media_entry(entry: CatalogEntry, utc_offset: i32, cache_base_dir: Option<&Path>) -> MediaEntry {
+    let thumbnail_path = cache_base_dir
+        .and_then(|base| thumbnail::fresh_thumbnail(Path::new(&entry.path), base))
+        .map(|thumb| normalize_path(&thumb.to_string_lossy()));
+    MediaEntry {
+        name: entry.name,
+        path: entry.path,
+        kind: entry.kind,
+        size: entry.size,
+        mtime: entry.mtime,
+        width: entry.width,
+        height: entry.height,
+        thumbnail_path,
+        captured_at: entry.capture.map(|c| c.instant(utc_offset)),
+        group: None,
+    }
+}
+
+fn query_error(e: rusqlite::Error) -> String {
+    format!("Failed to search catalog: {}", e)
+}
+
+impl Catalog {
+    /// Returns page `page` of the files matching `query`, newest first. `utc_offset`
+    /// is the viewer's offset from UTC in minutes, for dates recorded without a time
+    /// zone, and thumbnails are looked up in `cache_base_dir` if given.
+    pub fn search(
+        &self,
+        query: &str,
+        page: u32,
+        utc_offset: i32,
+        cache_base_dir: Option<&Path>,
+    ) -> Result<SearchResults, String> {
+        let mut conditions = parse(query, utc_offset)?;
+        let matching = conditions.matching();
+
+        let total: u64 = self
+            .conn
+            .prepare_cached(&format!("SELECT COUNT(*) FROM {}", matching))
+            .and_then(|mut stmt| {
+                stmt.query_row(params_from_iter(&conditions.params), |row| row.get(0))
+            })
+            .map_err(query_error)?;
+
+        let offset = u64::from(page) * u64::from(SEARCH_PAGE_SIZE);
+        let limit = conditions.param(SEARCH_PAGE_SIZE);
+        let skip = conditions.param(offset as i64);
+        let mut stmt = self
+            .conn
+            .prepare_cached(&format!(
+                "SELECT {} FROM {} ORDER BY local_time DESC, path LIMIT {} OFFSET {}",
+                ENTRY_COLUMNS, matching, limit, skip
+            ))
+            .map_err(query_error)?;
+        let entries = stmt
+            .query_map(params_from_iter(&conditions.params), entry_from_row)
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(query_error)?;
+
+        Ok(SearchResults {
+            entries: entries
+                .into_iter()
+                .map(|e| media_entry(e, utc_offset, cache_base_dir))
+                .collect(),
+            total,
+            page,
+            has_more: offset + u64::from(SEARCH_PAGE_SIZE) < total,
+        })
+    }
+
+    /// Paths of the files matching `query`, among `scope` if given.
+    pub(super) fn matching_paths(
+        &self,
+        query: &str,
+        utc_offset: i32,
+        scope: Option<&[String]>,
+    ) -> Result<HashSet<String>, String> {
+        let mut conditions = parse(query, utc_offset)?;
+        if let Some(scope) = scope {
+            let paths = serde_json::to_string(scope).map_err(|e| e.to_string())?;
+            let placeholder = conditions.param(paths);
+            conditions.sql.push(format!(
+                "path IN (SELECT value FROM json_each({}))",
+                placeholder
+            ));
+        }
+        let mut stmt = self
+            .conn
+            .prepare_cached(&format!("SELECT path FROM {}", conditions.matching()))
+            .map_err(query_error)?;
+        let paths = stmt
+            .query_map(params_from_iter(&conditions.params), |row| row.get(0))
+            .and_then(|rows| rows.collect::<rusqlite::Result<HashSet<_>>>())
+            .map_err(query_error)?;
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_png;
+    use std::fs;
+    use tempfile::TempDir;
+
+    const XMP: &str = "<x:xmpmeta><xmp:Rating>4</xmp:Rating>\
+        <dc:subject><rdf:Bag><rdf:li>Beach</rdf:li></rdf:Bag></dc:subject></x:xmpmeta>";
+
+    /// A catalog of a few photos and a video in one root.
+    fn catalog() -> (TempDir, Catalog) {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("photos");
+        write_png(&root.join("IMG_20230601_100000.png"), 4000, 3000);
+        write_png(&root.join("IMG_20230715_100000.png"), 20, 10);
+        fs::write(root.join("IMG_20230715_100000.xmp"), XMP).unwrap();
+        write_png(&root.join("beach/IMG_20230901_100000.png"), 5000, 10);
+        fs::write(root.join("VID_20230801_100000.mp4"), b"video").unwrap();
+
+        let mut catalog = Catalog::open(&temp.path().join("catalog.sqlite")).unwrap();
+        let roots = vec![normalize_path(&root.to_string_lossy())];
+        catalog.rescan(&roots, &temp.path().join("cache")).unwrap();
+        (temp, catalog)
+    }
+
+    fn names(catalog: &Catalog, query: &str) -> Vec<String> {
+        catalog
+            .search(query, 0, 0, None)
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|e| e.name)
+            .collect()
+    }
+
+    // -----------------------------------------------------------------------
+    // parse
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize(r#"camera:"iPhone 15"  beach "a:b""#).unwrap(),
+            [
+                ("camera:iPhone 15".to_string(), false),
+                ("beach".to_string(), false),
+                ("a:b".to_string(), true),
+            ]
+        );
+        assert!(tokenize(r#"camera:"iPhone"#).is_err());
+    }
+
+    #[test]
+    fn test_split_filter() {
+        assert_eq!(split_filter("rating>=4"), Some(("rating", Op::Ge, "4")));
+        assert_eq!(split_filter("width>4000"), Some(("width", Op::Gt, "4000")));
+        assert_eq!(split_filter("type:video"), Some(("type", Op::Eq, "video")));
+        assert_eq!(split_filter("10:30"), None);
+        assert_eq!(split_filter("beach"), None);
+    }
+
+    #[test]
+    fn test_period() {
+        assert_eq!(period("2023"), Some((1_672_531_200_000, 1_704_067_200_000)));
+        assert_eq!(
+            period("2023-12"),
+            Some((1_701_388_800_000, 1_704_067_200_000))
+        );
+        assert_eq!(
+            period("2023-06-15"),
+            Some((1_686_787_200_000, 1_686_873_600_000))
+        );
+        assert_eq!(period("2023-13"), None);
+        assert_eq!(period("23-06"), None);
+        assert_eq!(period("june"), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for query in [
+            "colour:red",
+            "rating>=high",
+            "type:song",
+            "date:last-week",
+            "tag>a",
+            "ext:",
+        ] {
+            assert!(parse(query, 0).is_err(), "{}", query);
+        }
+    }
+
+    // -----------------------------------------------------------------------
+    // search
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_search_filters() {
+        let (_temp, catalog) = catalog();
+
+        assert_eq!(
+            names(&catalog, ""),
+            [
+                "IMG_20230901_100000.png",
+                "VID_20230801_100000.mp4",
+                "IMG_20230715_100000.png",
+                "IMG_20230601_100000.png",
+            ]
+        );
+        assert_eq!(
+            names(&catalog, "date:2023-06..2023-08"),
+            [
+                "VID_20230801_100000.mp4",
+                "IMG_20230715_100000.png",
+                "IMG_20230601_100000.png",
+            ]
+        );
+        assert_eq!(names(&catalog, "date>2023-08"), ["IMG_20230901_100000.png"]);
+        assert_eq!(
+            names(&catalog, "date:..2023-06"),
+            ["IMG_20230601_100000.png"]
+        );
+        assert_eq!(names(&catalog, "type:video"), ["VID_20230801_100000.mp4"]);
+        assert_eq!(names(&catalog, "ext:MP4"), ["VID_20230801_100000.mp4"]);
+        assert_eq!(names(&catalog, "rating>=4"), ["IMG_20230715_100000.png"]);
+        assert_eq!(names(&catalog, "tag:beach"), ["IMG_20230715_100000.png"]);
+        assert_eq!(
+            names(&catalog, "width>=4000"),
+            ["IMG_20230901_100000.png", "IMG_20230601_100000.png"]
+        );
+        assert_eq!(
+            names(&catalog, "width>=4000 height<100"),
+            ["IMG_20230901_100000.png"]
+        );
+        assert_eq!(names(&catalog, "img 0601"), ["IMG_20230601_100000.png"]);
+        assert!(names(&catalog, "camera:\"iPhone 15\"").is_empty());
+    }
+
+    #[test]
+    fn test_search_camera() {
+        let (temp, catalog) = catalog();
+        let photo = normalize_path(
+            &temp
+                .path()
+                .join("photos/IMG_20230601_100000.png")
+                .to_string_lossy(),
+        );
+        catalog
+            .conn
+            .execute(
+                "UPDATE media SET camera = 'Apple iPhone 15 Pro' WHERE path = ?1",
+                [&photo],
+            )
+            .unwrap();
+
+        assert_eq!(
+            names(&catalog, "camera:\"iphone 15\""),
+            ["IMG_20230601_100000.png"]
+        );
+        assert!(names(&catalog, "camera:canon").is_empty());
+    }
+
+    #[test]
+    fn test_search_results() {
+        let (temp, catalog) = catalog();
+        let results = catalog
+            .search("type:image", 0, 0, Some(&temp.path().join("cache")))
+            .unwrap();
+        assert_eq!(results.total, 3);
+        assert!(!results.has_more);
+        let first = &results.entries[0];
+        assert_eq!(first.kind, MediaKind::Image);
+        assert_eq!(first.width, Some(5000));
+        assert_eq!(first.captured_at, Some(1_693_562_400_000));
+        assert_eq!(first.thumbnail_path, None);
+
+        let beyond = catalog.search("type:image", 1, 0, None).unwrap();
+        assert!(beyond.entries.is_empty());
+        assert_eq!(beyond.total, 3);
+    }
+}
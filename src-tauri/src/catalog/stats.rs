@@ -0,0 +1,130 @@
+//! Media counts and sizes of folders, including their subfolders.
+//!
+//! Stats are summed over the catalog and cached per folder. Updates to the catalog
+//! drop the cached stats of the folders they touch, so only those are summed again.
+
+use super::Catalog;
+use crate::media::MediaKind;
+use crate::thumbnail::normalize_path;
+use rusqlite::params;
+use serde::Serialize;
+
+/// Media below a folder.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderStats {
+    pub photos: u64,
+    pub videos: u64,
+    /// Total size in bytes.
+    pub bytes: u64,
+}
+
+impl Catalog {
+    /// Stats of the media below `dir`, at any depth.
+    pub fn folder_stats(&mut self, dir: &str) -> Result<FolderStats, String> {
+        let dir = normalize_path(dir).trim_end_matches('/').to_string();
+        if let Some(stats) = self.folder_stats.get(&dir) {
+            return Ok(*stats);
+        }
+        // Paths below the folder sort between "dir/" and "dir0", which keeps the
+        // range on the primary key index
+        let mut stmt = self
+            .conn
+            .prepare_cached(
+                "SELECT kind, COUNT(*), SUM(size) FROM media
+                WHERE path >= ?1 AND path < ?2 GROUP BY kind",
+            )
+            .map_err(|e| format!("Failed to query catalog: {}", e))?;
+        let rows = stmt
+            .query_map(params![format!("{}/", dir), format!("{}0", dir)], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, u64>(1)?,
+                    row.get::<_, u64>(2)?,
+                ))
+            })
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(|e| format!("Failed to query catalog: {}", e))?;
+        let mut stats = FolderStats::default();
+        for (kind, count, bytes) in rows {
+            match MediaKind::parse(&kind) {
+                Some(MediaKind::Video) => stats.videos += count,
+                _ => stats.photos += count,
+            }
+            stats.bytes += bytes;
+        }
+        self.folder_stats.insert(dir, stats);
+        Ok(stats)
+    }
+
+    /// Drops the cached stats of the folders containing `path`, and of the folders
+    /// below it in case it was a folder.
+    pub(super) fn forget_folder_stats(&mut self, path: &str) {
+        let path = path.trim_end_matches('/');
+        self.folder_stats.retain(|dir, _| {
+            let contains = path
+                .strip_prefix(dir.as_str())
+                .is_some_and(|r| r.starts_with('/'));
+            let inside = dir.strip_prefix(path).is_some_and(|r| r.starts_with('/'));
+            !(contains || inside || dir == path)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_png;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_folder_stats_follow_updates() {
+        let temp = TempDir::new().unwrap();
+        let mut catalog = Catalog::open(&temp.path().join("catalog.sqlite")).unwrap();
+        let photos = temp.path().join("photos");
+        write_png(&photos.join("a.png"), 4, 4);
+        write_png(&photos.join("2020/b.png"), 4, 4);
+        fs::create_dir_all(photos.join("2020/trip")).unwrap();
+        fs::write(photos.join("2020/trip/clip.mp4"), b"video").unwrap();
+        // A sibling sharing the prefix is not below the folder
+        write_png(&temp.path().join("photos2/c.png"), 4, 4);
+
+        let root = normalize_path(&photos.to_string_lossy());
+        let roots = [
+            root.clone(),
+            normalize_path(&temp.path().join("photos2").to_string_lossy()),
+        ];
+        let cache = temp.path().join("cache");
+        catalog.rescan(&roots, &cache).unwrap();
+
+        let png_size = fs::metadata(photos.join("a.png")).unwrap().len();
+        let stats = catalog.folder_stats(&root).unwrap();
+        assert_eq!(
+            stats,
+            FolderStats {
+                photos: 2,
+                videos: 1,
+                bytes: 2 * png_size + 5,
+            }
+        );
+        let year = format!("{}/2020", root);
+        assert_eq!(catalog.folder_stats(&year).unwrap().photos, 1);
+        assert_eq!(
+            catalog.folder_stats(&format!("{}/missing", root)).unwrap(),
+            FolderStats::default()
+        );
+
+        // Removing a file drops the cached stats of the folders above it only
+        fs::remove_file(photos.join("2020/trip/clip.mp4")).unwrap();
+        catalog
+            .update(&roots, &[], &[photos.join("2020/trip/clip.mp4")], &cache)
+            .unwrap();
+        assert!(!catalog.folder_stats.contains_key(&root));
+        assert!(catalog
+            .folder_stats
+            .contains_key(&format!("{}/missing", root)));
+        assert_eq!(catalog.folder_stats(&root).unwrap().videos, 0);
+        assert_eq!(catalog.folder_stats(&year).unwrap().videos, 0);
+    }
+}
@@ -0,0 +1,449 @@
+//! Timeline of every cataloged file across all roots, newest first.
+//!
+//! Files are placed by their local capture time, see [`CaptureDate::local_millis`],
+//! or by their modification time if they have no capture date. Pages hold a fixed
+//! number of files bucketed by day and continue after the cursor of the previous
+//! page; a day cut by a page boundary continues as the first bucket of the next page.
+//!
+//! Files that know their time zone and files shifted by the viewer's offset each
+//! keep their order on the `media_local_time` index, so a page reads at most two
+//! pages of rows from it whatever its position.
+//!
+//! [`CaptureDate::local_millis`]: crate::media::CaptureDate::local_millis
+
+use super::{entry_from_row, Catalog, CatalogEntry, ENTRY_COLUMNS, LOCAL_TIME};
+use crate::media::civil_date;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+/// Files per page of [`Catalog::timeline`].
+pub const TIMELINE_PAGE_SIZE: u32 = 500;
+
+/// The files of one day, newest first.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineDay {
+    /// `yyyy-mm-dd`
+    pub date: String,
+    pub items: Vec<CatalogEntry>,
+}
+
+/// Number of files in a month (`yyyy-mm`) or year (`yyyy`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeriodCount {
+    pub period: String,
+    pub count: u64,
+}
+
+/// Counts over the whole queried range, for scrubbing.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineCounts {
+    /// Newest first.
+    pub months: Vec<PeriodCount>,
+    /// Newest first.
+    pub years: Vec<PeriodCount>,
+    /// Files in the queried range.
+    pub total: u64,
+}
+
+/// The last file of a page, the next page starts after it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineCursor {
+    /// Local time in milliseconds read as UTC.
+    pub local_time: i64,
+    pub path: String,
+}
+
+/// A page of the timeline.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timeline {
+    pub days: Vec<TimelineDay>,
+    /// Only on the first page of a range, later pages keep the counts of the first.
+    pub counts: Option<TimelineCounts>,
+    /// Cursor of the next page, None on the last page.
+    pub next: Option<TimelineCursor>,
+}
+
+fn query_error(e: rusqlite::Error) -> String {
+    format!("Failed to query timeline: {}", e)
+}
+
+/// A page of the files of one kind, `floating` or not, newest first. The bounds
+/// in local time are moved onto `local_base`, by the viewer's offset in
+/// milliseconds `?1` for floating files, so the index can be used.
+fn page_of_kind(floating: bool) -> String {
+    let shift = if floating { "?1" } else { "0" };
+    format!(
+        "SELECT * FROM (SELECT {columns}, local_base + {shift} AS local_time FROM media
+         WHERE floating = {floating} AND local_base >= ?2 - {shift}
+             AND (local_base, path) < (?3 - {shift}, ?4)
+         ORDER BY local_base DESC, path DESC LIMIT ?5)",
+        columns = ENTRY_COLUMNS,
+        shift = shift,
+        floating = u8::from(floating),
+    )
+}
+
+/// A page of files, merged from the pages of both kinds.
+fn page_sql() -> String {
+    format!(
+        "SELECT * FROM ({} UNION ALL {}) ORDER BY local_time DESC, path DESC LIMIT ?5",
+        page_of_kind(false),
+        page_of_kind(true)
+    )
+}
+
+impl Catalog {
+    /// Returns the page after `cursor`, or the first page, of the files whose local
+    /// time lies in `from..to`, both in milliseconds read as UTC and open-ended if
+    /// None. `utc_offset` is the viewer's offset from UTC in minutes, for files
+    /// recorded without a time zone. Files of the same time are ordered by path,
+    /// descending.
+    pub fn timeline(
+        &self,
+        from: Option<i64>,
+        to: Option<i64>,
+        cursor: Option<&TimelineCursor>,
+        utc_offset: i32,
+    ) -> Result<Timeline, String> {
+        // Open ends are kept far from the limits, the kinds shift them by the offset
+        let from = from.unwrap_or(i64::MIN / 2);
+        let to = to.unwrap_or(i64::MAX / 2);
+        // Every path sorts after "", so the range end bounds all files of that time
+        let (before_time, before_path) = match cursor {
+            Some(c) if c.local_time < to => (c.local_time, c.path.as_str()),
+            _ => (to, ""),
+        };
+        let shift = i64::from(utc_offset) * 60_000;
+
+        let counts = match cursor {
+            Some(_) => None,
+            None => Some(self.timeline_counts(from, to, utc_offset)?),
+        };
+
+        let mut items_stmt = self.conn.prepare_cached(&page_sql()).map_err(query_error)?;
+        // One more than a page tells whether there is another
+        let limit = TIMELINE_PAGE_SIZE + 1;
+        let mut items = items_stmt
+            .query_map(
+                params![shift, from, before_time, before_path, limit],
+                |row| Ok((row.get::<_, i64>(17)?, entry_from_row(row)?)),
+            )
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(query_error)?;
+
+        let has_more = items.len() > TIMELINE_PAGE_SIZE as usize;
+        items.truncate(TIMELINE_PAGE_SIZE as usize);
+        let next = items
+            .last()
+            .filter(|_| has_more)
+            .map(|(local_time, entry)| TimelineCursor {
+                local_time: *local_time,
+                path: entry.path.clone(),
+            });
+
+        let mut days: Vec<TimelineDay> = Vec::new();
+        for (local_time, entry) in items {
+            let (year, month, day) = civil_date(local_time);
+            let date = format!("{:04}-{:02}-{:02}", year, month, day);
+            match days.last_mut() {
+                Some(last) if last.date == date => last.items.push(entry),
+                _ => days.push(TimelineDay {
+                    date,
+                    items: vec![entry],
+                }),
+            }
+        }
+
+        Ok(Timeline { days, counts, next })
+    }
+
+    /// Counts the files per month and year in `from..to`.
+    fn timeline_counts(
+        &self,
+        from: i64,
+        to: i64,
+        utc_offset: i32,
+    ) -> Result<TimelineCounts, String> {
+        let mut months_stmt = self
+            .conn
+            .prepare_cached(&format!(
+                "SELECT strftime('%Y-%m', local_time / 1000.0, 'unixepoch') AS month, COUNT(*)
+                 FROM (SELECT {} AS local_time FROM media)
+                 WHERE local_time >= ?2 AND local_time < ?3
+                 GROUP BY month ORDER BY month DESC",
+                LOCAL_TIME
+            ))
+            .map_err(query_error)?;
+        let months = months_stmt
+            .query_map(params![utc_offset, from, to], |row| {
+                Ok(PeriodCount {
+                    period: row.get(0)?,
+                    count: row.get(1)?,
+                })
+            })
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(query_error)?;
+
+        let mut years: Vec<PeriodCount> = Vec::new();
+        for month in &months {
+            let year = &month.period[..month.period.len().min(4)];
+            match years.last_mut() {
+                Some(last) if last.period == year => last.count += month.count,
+                _ => years.push(PeriodCount {
+                    period: year.to_string(),
+                    count: month.count,
+                }),
+            }
+        }
+        let total = months.iter().map(|m| m.count).sum();
+        Ok(TimelineCounts {
+            months,
+            years,
+            total,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::write_png;
+    use crate::thumbnail::normalize_path;
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// Catalogs files named after their capture dates in two roots.
+    fn catalog_with(names: &[&str]) -> (TempDir, Catalog) {
+        let temp = TempDir::new().unwrap();
+        for (i, name) in names.iter().enumerate() {
+            let root = if i % 2 == 0 { "photos" } else { "phone" };
+            write_png(&temp.path().join(root).join(name), 4, 4);
+        }
+        let mut catalog = Catalog::open(&temp.path().join("catalog.sqlite")).unwrap();
+        let roots: Vec<String> = ["photos", "phone"]
+            .iter()
+            .map(|r| normalize_path(&temp.path().join(r).to_string_lossy()))
+            .collect();
+        catalog.rescan(&roots, &temp.path().join("cache")).unwrap();
+        (temp, catalog)
+    }
+
+    fn count(period: &str, count: u64) -> PeriodCount {
+        PeriodCount {
+            period: period.to_string(),
+            count,
+        }
+    }
+
+    fn day_names(timeline: &Timeline) -> Vec<(String, Vec<String>)> {
+        timeline
+            .days
+            .iter()
+            .map(|d| {
+                let names = d.items.iter().map(|e| e.name.clone()).collect();
+                (d.date.clone(), names)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_timeline_buckets_across_roots() {
+        let (_temp, catalog) = catalog_with(&[
+            "IMG_20231231_235959.png",
+            "IMG_20240101_080000.png",
+            "IMG_20240101_200000.png",
+            "IMG_20240215_120000.png",
+        ]);
+
+        let timeline = catalog.timeline(None, None, None, 0).unwrap();
+        assert_eq!(
+            day_names(&timeline),
+            [
+                (
+                    "2024-02-15".to_string(),
+                    vec!["IMG_20240215_120000.png".to_string()]
+                ),
+                (
+                    "2024-01-01".to_string(),
+                    vec![
+                        "IMG_20240101_200000.png".to_string(),
+                        "IMG_20240101_080000.png".to_string()
+                    ]
+                ),
+                (
+                    "2023-12-31".to_string(),
+                    vec!["IMG_20231231_235959.png".to_string()]
+                ),
+            ]
+        );
+        let counts = timeline.counts.unwrap();
+        assert_eq!(
+            counts.months,
+            [
+                count("2024-02", 1),
+                count("2024-01", 2),
+                count("2023-12", 1)
+            ]
+        );
+        assert_eq!(counts.years, [count("2024", 3), count("2023", 1)]);
+        assert_eq!(counts.total, 4);
+        assert_eq!(timeline.next, None);
+    }
+
+    #[test]
+    fn test_timeline_range() {
+        let (_temp, catalog) = catalog_with(&[
+            "IMG_20231231_235959.png",
+            "IMG_20240101_080000.png",
+            "IMG_20240215_120000.png",
+        ]);
+
+        // 2024-01-01T00:00:00 up to 2024-02-01T00:00:00
+        let january = catalog
+            .timeline(Some(1_704_067_200_000), Some(1_706_745_600_000), None, 0)
+            .unwrap();
+        let counts = january.counts.unwrap();
+        assert_eq!(counts.total, 1);
+        assert_eq!(january.days[0].date, "2024-01-01");
+        assert_eq!(counts.months, [count("2024-01", 1)]);
+
+        let since = catalog
+            .timeline(Some(1_704_067_200_000), None, None, 0)
+            .unwrap();
+        assert_eq!(since.counts.unwrap().total, 2);
+    }
+
+    #[test]
+    fn test_timeline_pages() {
+        let names: Vec<String> = (0..TIMELINE_PAGE_SIZE + 10)
+            .map(|i| format!("IMG_20240101_{:02}{:02}00.png", i / 60 % 24, i % 60))
+            .collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let (_temp, catalog) = catalog_with(&names);
+
+        let first = catalog.timeline(None, None, None, 0).unwrap();
+        assert!(first.next.is_some());
+        assert_eq!(first.days.len(), 1);
+        assert_eq!(first.days[0].items.len(), TIMELINE_PAGE_SIZE as usize);
+        assert_eq!(
+            first.counts.unwrap().total,
+            u64::from(TIMELINE_PAGE_SIZE) + 10
+        );
+
+        // The day continues on the next page, which leaves the counts to the first
+        let second = catalog
+            .timeline(None, None, first.next.as_ref(), 0)
+            .unwrap();
+        assert_eq!(second.next, None);
+        assert_eq!(second.days[0].date, "2024-01-01");
+        assert_eq!(second.days[0].items.len(), 10);
+        assert_eq!(second.counts, None);
+    }
+
+    #[test]
+    fn test_timeline_cursor_merges_both_kinds() {
+        // Files named after their capture time keep it, the others are placed by
+        // their modification time in the viewer's time zone
+        let mut names: Vec<String> = (0..300)
+            .map(|i| format!("IMG_20240101_{:02}{:02}00.png", 10 + i / 60, i % 60))
+            .collect();
+        names.extend((0..300).map(|i| format!("plain_{:03}.png", i)));
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let temp = TempDir::new().unwrap();
+        // 2024-01-01T11:00:00Z, all at the same time, in the middle of the dated files
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_704_106_800);
+        for (i, name) in names.iter().enumerate() {
+            let path = temp
+                .path()
+                .join(if i % 2 == 0 { "photos" } else { "phone" });
+            write_png(&path.join(name), 4, 4);
+            if name.starts_with("plain") {
+                fs::File::options()
+                    .write(true)
+                    .open(path.join(name))
+                    .unwrap()
+                    .set_modified(mtime)
+                    .unwrap();
+            }
+        }
+        let mut catalog = Catalog::open(&temp.path().join("catalog.sqlite")).unwrap();
+        let roots: Vec<String> = ["photos", "phone"]
+            .iter()
+            .map(|r| normalize_path(&temp.path().join(r).to_string_lossy()))
+            .collect();
+        catalog.rescan(&roots, &temp.path().join("cache")).unwrap();
+
+        let utc_offset = 60;
+        let local_time = |entry: &CatalogEntry| match entry.capture {
+            Some(capture) => capture.local_millis(utc_offset),
+            None => entry.mtime as i64 + i64::from(utc_offset) * 60_000,
+        };
+        let mut seen: Vec<(i64, String)> = Vec::new();
+        let mut cursor = None;
+        let mut pages = 0;
+        loop {
+            let page = catalog
+                .timeline(None, None, cursor.as_ref(), utc_offset)
+                .unwrap();
+            pages += 1;
+            for entry in page.days.iter().flat_map(|d| &d.items) {
+                seen.push((local_time(entry), entry.path.clone()));
+            }
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        assert_eq!(pages, 2);
+        assert_eq!(seen.len(), 600);
+        // Newest first, the files of the same time by path, none twice
+        assert!(seen.windows(2).all(|w| w[0] > w[1]));
+        // The shifted files sit at 12:00 local time, between the dated ones and
+        // on the same time as one of them
+        let noon = 1_704_110_400_000;
+        assert_eq!(seen.iter().filter(|(time, _)| *time == noon).count(), 301);
+        assert!(seen[0].1.contains("IMG_") && seen[599].1.contains("IMG_"));
+    }
+
+    #[test]
+    fn test_timeline_pages_on_the_index() {
+        let (_temp, catalog) = catalog_with(&["IMG_20240101_080000.png"]);
+        let mut stmt = catalog
+            .conn
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", page_sql()))
+            .unwrap();
+        let plan: Vec<String> = stmt
+            .query_map(params![0, 0, 0, "", 1], |row| row.get(3))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+
+        // Both kinds seek on the index, only their pages are sorted for merging
+        let reads: Vec<&String> = plan.iter().filter(|d| d.contains(" media")).collect();
+        assert_eq!(reads.len(), 2, "{:?}", plan);
+        assert!(
+            reads
+                .iter()
+                .all(|d| d.starts_with("SEARCH media USING INDEX media_local_time")),
+            "{:?}",
+            plan
+        );
+    }
+
+    #[test]
+    fn test_timeline_offsets() {
+        let (_temp, catalog) = catalog_with(&["IMG_20240101_233000.png"]);
+
+        // Wall clock times stay on their day wherever the viewer is
+        let timeline = catalog.timeline(None, None, None, 120).unwrap();
+        assert_eq!(timeline.days[0].date, "2024-01-01");
+        let timeline = catalog.timeline(None, None, None, -600).unwrap();
+        assert_eq!(timeline.days[0].date, "2024-01-01");
+    }
+}
@@ -1,12 +1,187 @@
+mod catalog;
+mod media;
+mod protocol;
+#[cfg(test)]
+mod test_util;
 mod thumbnail;
 
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use tauri::{
     menu::{MenuBuilder, MenuItemBuilder, SubmenuBuilder},
-    Emitter,
+    Emitter, Manager,
 };
 use tauri_plugin_updater::UpdaterExt;
 use thumbnail::ThumbnailService;
 
+#[tauri::command]
+async fn list_media(
+    dir: String,
+    options: media::ListOptions,
+) -> Result<Vec<media::MediaEntry>, String> {
+    tokio::task::spawn_blocking(move || media::list_media(Path::new(&dir), &options))
+        .await
+        .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn list_folders(dir: String) -> Result<Vec<media::FolderEntry>, String> {
+    tokio::task::spawn_blocking(move || media::list_folders(Path::new(&dir)))
+        .await
+        .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+fn set_ignore_patterns(patterns: Vec<String>) {
+    media::set_global_patterns(&patterns);
+}
+
+/// Registering recursive watches walks the whole tree on some platforms, so this
+/// runs off the main thread.
+#[tauri::command]
+async fn watch_media(
+    dir: Option<String>,
+    session_id: u64,
+    cache_base_dir: String,
+    roots: Vec<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    tokio::task::spawn_blocking(move || {
+        media::watch(
+            dir.as_deref().map(Path::new),
+            &roots,
+            Path::new(&cache_base_dir),
+            session_id,
+            app_handle,
+        )
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn rescan_catalog(
+    roots: Vec<String>,
+    cache_base_dir: String,
+    app_handle: tauri::AppHandle,
+) -> Result<catalog::RescanSummary, String> {
+    let roots_changed = roots.clone();
+    let (summary, album_changes) = tokio::task::spawn_blocking(move || {
+        let summary = catalog::rescan(&roots, Path::new(&cache_base_dir))?;
+        Ok::<_, String>((summary, catalog::with(|c| c.refresh_albums(None))?))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))??;
+    if !album_changes.is_empty() {
+        let _ = app_handle.emit("albums-changed", album_changes);
+    }
+    if summary.added + summary.updated + summary.removed > 0 {
+        let _ = app_handle.emit("folder-stats-changed", roots_changed);
+    }
+    Ok(summary)
+}
+
+#[tauri::command]
+async fn folder_stats(dirs: Vec<String>) -> Result<HashMap<String, catalog::FolderStats>, String> {
+    tokio::task::spawn_blocking(move || {
+        catalog::with(|c| {
+            dirs.into_iter()
+                .map(|dir| Ok((dir.clone(), c.folder_stats(&dir)?)))
+                .collect()
+        })
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn catalog_entries(dir: String) -> Result<Vec<catalog::CatalogEntry>, String> {
+    tokio::task::spawn_blocking(move || catalog::with(|c| c.list_dir(&dir)))
+        .await
+        .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn timeline_query(
+    from: Option<i64>,
+    to: Option<i64>,
+    cursor: Option<catalog::TimelineCursor>,
+    utc_offset: Option<i32>,
+) -> Result<catalog::Timeline, String> {
+    tokio::task::spawn_blocking(move || {
+        catalog::with(|c| c.timeline(from, to, cursor.as_ref(), utc_offset.unwrap_or(0)))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn search_media(
+    query: String,
+    page: Option<u32>,
+    cache_base_dir: Option<String>,
+    utc_offset: Option<i32>,
+) -> Result<catalog::SearchResults, String> {
+    tokio::task::spawn_blocking(move || {
+        let cache_base_dir = cache_base_dir.as_deref().map(Path::new);
+        catalog::with(|c| {
+            c.search(
+                &query,
+                page.unwrap_or(0),
+                utc_offset.unwrap_or(0),
+                cache_base_dir,
+            )
+        })
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn list_albums() -> Result<Vec<catalog::Album>, String> {
+    tokio::task::spawn_blocking(|| catalog::with(|c| c.albums()))
+        .await
+        .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn save_album(
+    id: Option<i64>,
+    definition: catalog::AlbumDefinition,
+) -> Result<catalog::Album, String> {
+    tokio::task::spawn_blocking(move || catalog::with(|c| c.save_album(id, &definition)))
+        .await
+        .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn delete_album(id: i64) -> Result<(), String> {
+    tokio::task::spawn_blocking(move || catalog::with(|c| c.delete_album(id)))
+        .await
+        .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn export_albums(path: String) -> Result<(), String> {
+    tokio::task::spawn_blocking(move || {
+        let json = catalog::with(|c| c.export_albums())?;
+        std::fs::write(&path, json).map_err(|e| format!("Failed to write {}: {}", path, e))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn import_albums(path: String) -> Result<Vec<catalog::Album>, String> {
+    tokio::task::spawn_blocking(move || {
+        let json = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        catalog::with(|c| c.import_albums(&json))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
 #[tauri::command]
 async fn generate_thumbnails(
     dir: String,
@@ -17,6 +192,108 @@ async fn generate_thumbnails(
     ThumbnailService::generate_for_dir(dir, session_id, cache_base_dir, app_handle).await
 }
 
+/// Generates thumbnails for files from any number of directories, e.g. search results.
+#[tauri::command]
+async fn generate_thumbnails_for_files(
+    paths: Vec<String>,
+    session_id: u64,
+    cache_base_dir: String,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    // Each directory may have its own portable cache
+    let mut by_dir: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for path in paths.into_iter().map(PathBuf::from) {
+        if let Some(dir) = path.parent() {
+            by_dir.entry(dir.to_path_buf()).or_default().push(path);
+        }
+    }
+    for (dir, files) in by_dir {
+        ThumbnailService::generate_for_files(
+            dir,
+            files,
+            session_id,
+            cache_base_dir.clone(),
+            app_handle.clone(),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn generate_preview(path: String, cache_base_dir: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || {
+        ThumbnailService::generate_preview(Path::new(&path), Path::new(&cache_base_dir))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn transcode_video(
+    path: String,
+    cache_base_dir: String,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || {
+        let source = path.clone();
+        thumbnail::transcode(
+            Path::new(&source),
+            Path::new(&cache_base_dir),
+            move |progress| {
+                let payload = thumbnail::TranscodeProgress {
+                    path: path.clone(),
+                    progress,
+                };
+                let _ = app_handle.emit("transcode-progress", payload);
+            },
+        )
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn generate_proxies(
+    dir: String,
+    session_id: u64,
+    cache_base_dir: String,
+    options: thumbnail::ProxyOptions,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    tokio::task::spawn_blocking(move || {
+        thumbnail::generate_proxies(
+            Path::new(&dir),
+            session_id,
+            Path::new(&cache_base_dir),
+            &options,
+            |update| {
+                let _ = app_handle.emit("proxy-update", update);
+            },
+        )
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+fn set_media_scope(cache_base_dir: String, roots: Vec<String>) {
+    protocol::set_scope(&cache_base_dir, &roots);
+    thumbnail::set_roots(&roots);
+}
+
+#[tauri::command]
+async fn tile_info(
+    path: String,
+    cache_base_dir: String,
+) -> Result<Option<thumbnail::TileInfo>, String> {
+    tokio::task::spawn_blocking(move || {
+        thumbnail::tile_info(Path::new(&path), Path::new(&cache_base_dir))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
 #[tauri::command]
 async fn cleanup_thumbnails_for_dir(dir: String, cache_base_dir: String) -> Result<u32, String> {
     tokio::task::spawn_blocking(move || thumbnail::cleanup_for_prefix(&dir, &cache_base_dir))
@@ -31,6 +308,71 @@ async fn cleanup_orphan_thumbnails(cache_base_dir: String) -> Result<u32, String
         .map_err(|e| format!("Task join error: {}", e))?
 }
 
+#[tauri::command]
+async fn preview_cleanup_thumbnails_for_dir(
+    dir: String,
+    cache_base_dir: String,
+) -> Result<thumbnail::CleanupPreview, String> {
+    tokio::task::spawn_blocking(move || {
+        thumbnail::preview_cleanup_for_prefix(&dir, &cache_base_dir)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn preview_cleanup_orphan_thumbnails(
+    cache_base_dir: String,
+) -> Result<thumbnail::CleanupPreview, String> {
+    tokio::task::spawn_blocking(move || thumbnail::preview_cleanup_orphans(&cache_base_dir))
+        .await
+        .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn verify_cache(
+    cache_base_dir: String,
+    roots: Vec<String>,
+    repair: bool,
+) -> Result<thumbnail::VerifyReport, String> {
+    tokio::task::spawn_blocking(move || thumbnail::verify(&cache_base_dir, &roots, repair))
+        .await
+        .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn enable_portable_cache(root: String, cache_base_dir: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || thumbnail::enable_portable_cache(&root, &cache_base_dir))
+        .await
+        .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn move_cache(
+    from: String,
+    to: String,
+    roots: Vec<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<thumbnail::CacheMoveProgress, String> {
+    tokio::task::spawn_blocking(move || {
+        thumbnail::move_cache(&from, &to, &roots, |progress| {
+            let _ = app_handle.emit("cache-move-progress", progress);
+        })
+    })
+    .await
+    .map_err(|e| format!("Task join error: {}", e))?
+}
+
+#[tauri::command]
+async fn retry_failed_thumbnails(
+    cache_base_dir: String,
+    roots: Vec<String>,
+) -> Result<u32, String> {
+    tokio::task::spawn_blocking(move || thumbnail::clear_failures(&cache_base_dir, &roots))
+        .await
+        .map_err(|e| format!("Task join error: {}", e))?
+}
+
 #[tauri::command]
 async fn delete_all_thumbnails(cache_base_dir: String) -> Result<(), String> {
     tokio::task::spawn_blocking(move || thumbnail::delete_all(&cache_base_dir))
@@ -112,6 +454,24 @@ pub fn run() {
             let menu = menu_builder.build()?;
             app.set_menu(menu)?;
 
+            // The app still works without a catalog, it is only needed for queries
+            match app.path().app_data_dir() {
+                Ok(dir) => {
+                    if let Err(e) = catalog::init(&dir.join("catalog.sqlite")) {
+                        eprintln!("Failed to open catalog: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Failed to resolve app data dir: {}", e),
+            }
+
+            // Builder::setup replaces earlier hooks, so all setup happens here
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                if let Err(err) = update(handle).await {
+                    eprintln!("Failed to check for updates: {}", err);
+                }
+            });
+
             Ok(())
         })
         .on_menu_event(|app, event| {
@@ -126,22 +486,40 @@ pub fn run() {
         .plugin(tauri_plugin_store::Builder::default().build())
         .plugin(tauri_plugin_persisted_scope::init())
         .plugin(tauri_plugin_window_state::Builder::new().build())
+        .register_asynchronous_uri_scheme_protocol(protocol::SCHEME, protocol::handle)
         .invoke_handler(tauri::generate_handler![
+            list_media,
+            list_folders,
+            set_ignore_patterns,
+            watch_media,
+            rescan_catalog,
+            catalog_entries,
+            folder_stats,
+            timeline_query,
+            search_media,
+            list_albums,
+            save_album,
+            delete_album,
+            export_albums,
+            import_albums,
             generate_thumbnails,
+            generate_thumbnails_for_files,
+            generate_preview,
+            tile_info,
+            transcode_video,
+            generate_proxies,
+            set_media_scope,
             cleanup_thumbnails_for_dir,
             cleanup_orphan_thumbnails,
+            preview_cleanup_thumbnails_for_dir,
+            preview_cleanup_orphan_thumbnails,
+            verify_cache,
+            retry_failed_thumbnails,
+            move_cache,
+            enable_portable_cache,
             delete_all_thumbnails,
             save_video_thumbnail
         ])
-        .setup(|app| {
-            let handle = app.handle().clone();
-            tauri::async_runtime::spawn(async move {
-                if let Err(err) = update(handle).await {
-                    eprintln!("Failed to check for updates: {}", err);
-                }
-            });
-            Ok(())
-        })
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,482 @@
+//! Capture dates of media files.
+//!
+//! Dates are read from EXIF for images and from the QuickTime movie header for
+//! videos, falling back to dates in file names such as `IMG_20240101_123456.jpg`.
+
+use super::{classify, MediaKind};
+use exif::{In, Reader, Tag, Value};
+use serde::Serialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Seconds from the QuickTime epoch, 1904-01-01, to the Unix epoch.
+const QUICKTIME_EPOCH_OFFSET: u64 = 2_082_844_800;
+
+/// Where a capture date was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DateSource {
+    Exif,
+    QuickTime,
+    FileName,
+}
+
+impl DateSource {
+    /// Name of the source, as serialized.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DateSource::Exif => "exif",
+            DateSource::QuickTime => "quickTime",
+            DateSource::FileName => "fileName",
+        }
+    }
+
+    /// Parses a name returned by [`DateSource::as_str`].
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "exif" => Some(DateSource::Exif),
+            "quickTime" => Some(DateSource::QuickTime),
+            "fileName" => Some(DateSource::FileName),
+            _ => None,
+        }
+    }
+}
+
+/// When a photo or video was taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptureDate {
+    /// Milliseconds since the Unix epoch. An instant for QuickTime dates and EXIF
+    /// dates with an offset, otherwise the wall clock time of the camera read as UTC.
+    pub millis: i64,
+    /// Offset from UTC in minutes, where the file records one.
+    pub offset: Option<i32>,
+    pub source: DateSource,
+}
+
+impl CaptureDate {
+    /// True if [`CaptureDate::millis`] is a wall clock time in an unknown time zone.
+    fn is_wall_clock(&self) -> bool {
+        self.offset.is_none() && self.source != DateSource::QuickTime
+    }
+
+    /// The instant the file was captured. Wall clock times are taken to be in the
+    /// viewer's time zone, `utc_offset` minutes from UTC.
+    pub fn instant(&self, utc_offset: i32) -> i64 {
+        if self.is_wall_clock() {
+            self.millis - i64::from(utc_offset) * 60_000
+        } else {
+            self.millis
+        }
+    }
+
+    /// The wall clock time where the file was captured, read as UTC. Instants without
+    /// a recorded offset are shown in the viewer's time zone, `utc_offset` minutes from UTC.
+    pub fn local_millis(&self, utc_offset: i32) -> i64 {
+        match self.offset {
+            Some(offset) => self.millis + i64::from(offset) * 60_000,
+            None if self.source == DateSource::QuickTime => {
+                self.millis + i64::from(utc_offset) * 60_000
+            }
+            None => self.millis,
+        }
+    }
+}
+
+/// Days since the Unix epoch of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Year, month and day of a time in milliseconds since the Unix epoch.
+pub fn civil_date(millis: i64) -> (i64, u32, u32) {
+    let days = millis.div_euclid(86_400_000) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Milliseconds since the Unix epoch of a date and time read as UTC,
+/// None if any part is out of range.
+pub fn civil_millis(
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+) -> Option<i64> {
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+    let seconds = days_from_civil(year, month, day) * 86_400
+        + i64::from(hour) * 3_600
+        + i64::from(minute) * 60
+        + i64::from(second);
+    Some(seconds * 1_000)
+}
+
+/// Reads `DateTimeOriginal` with `OffsetTimeOriginal`, or `OffsetTime`, from EXIF.
+fn exif_date(path: &Path) -> Option<CaptureDate> {
+    let file = File::open(path).ok()?;
+    let exif = Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+    let ascii = |tag: Tag| match exif.get_field(tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(values)) => values.first().cloned(),
+        _ => None,
+    };
+
+    let mut date = exif::DateTime::from_ascii(&ascii(Tag::DateTimeOriginal)?).ok()?;
+    let wall_clock = civil_millis(
+        i64::from(date.year),
+        date.month.into(),
+        date.day.into(),
+        date.hour.into(),
+        date.minute.into(),
+        date.second.into(),
+    )?;
+    let offset = [Tag::OffsetTimeOriginal, Tag::OffsetTime]
+        .into_iter()
+        .find_map(|tag| {
+            date.parse_offset(&ascii(tag)?).ok()?;
+            date.offset.map(i32::from)
+        });
+
+    Some(CaptureDate {
+        millis: wall_clock - i64::from(offset.unwrap_or(0)) * 60_000,
+        offset,
+        source: DateSource::Exif,
+    })
+}
+
+/// Reads the creation time of the movie header of MP4 and QuickTime files.
+fn quicktime_date(path: &Path) -> Option<CaptureDate> {
+    let file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    let mp4 = mp4::Mp4Reader::read_header(BufReader::new(file), size).ok()?;
+    // Zero, or anything before 1970, means the recorder did not set it
+    let seconds = mp4
+        .moov
+        .mvhd
+        .creation_time
+        .checked_sub(QUICKTIME_EPOCH_OFFSET)
+        .filter(|&s| s > 0)?;
+    Some(CaptureDate {
+        millis: i64::try_from(seconds).ok()?.checked_mul(1_000)?,
+        offset: None,
+        source: DateSource::QuickTime,
+    })
+}
+
+/// Reads a number of exactly `digits` ASCII digits at `pos`.
+fn number(s: &[u8], pos: &mut usize, digits: usize) -> Option<u32> {
+    let part = s.get(*pos..*pos + digits)?;
+    if !part.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    *pos += digits;
+    std::str::from_utf8(part).ok()?.parse().ok()
+}
+
+/// Skips one of `separators` at `pos`, returning it.
+fn separator(s: &[u8], pos: &mut usize, separators: &[u8]) -> Option<u8> {
+    let c = *s.get(*pos).filter(|c| separators.contains(c))?;
+    *pos += 1;
+    Some(c)
+}
+
+/// Reads `hhmmss`, `hh-mm-ss` or `hh.mm.ss` at the start of `s`.
+fn name_time(s: &[u8]) -> Option<(u32, u32, u32)> {
+    let mut pos = 0;
+    let hour = number(s, &mut pos, 2)?;
+    let sep = separator(s, &mut pos, b"-.:");
+    let minute = number(s, &mut pos, 2)?;
+    if sep.is_some() && separator(s, &mut pos, b"-.:") != sep {
+        return None;
+    }
+    let second = number(s, &mut pos, 2)?;
+    Some((hour, minute, second))
+}
+
+/// Reads `yyyymmdd` or `yyyy-mm-dd`, optionally followed by a time, at the start of `s`.
+fn name_date(s: &[u8]) -> Option<i64> {
+    let mut pos = 0;
+    let year = number(s, &mut pos, 4)?;
+    let sep = separator(s, &mut pos, b"-_.");
+    let month = number(s, &mut pos, 2)?;
+    if sep.is_some() && separator(s, &mut pos, b"-_.") != sep {
+        return None;
+    }
+    let day = number(s, &mut pos, 2)?;
+    // More digits mean this is part of a longer number, not a date
+    if !(1970..=2099).contains(&year) || s.get(pos).is_some_and(u8::is_ascii_digit) {
+        return None;
+    }
+
+    let mut rest = &s[pos..];
+    rest = rest
+        .strip_prefix(b" at ")
+        .or_else(|| rest.get(1..).filter(|_| b"_- T".contains(&rest[0])))
+        .unwrap_or(rest);
+    let (hour, minute, second) = name_time(rest)
+        .filter(|&(h, m, s)| h < 24 && m < 60 && s < 60)
+        .unwrap_or((0, 0, 0));
+    civil_millis(year.into(), month, day, hour, minute, second)
+}
+
+/// Finds a date in a file name, as written by phones, cameras and messengers, e.g.
+/// `IMG_20240101_123456.jpg`, `Screenshot_2024-01-01-12-34-56.png` or
+/// `WhatsApp Image 2024-01-01 at 12.34.56.jpeg`. Dates without a time are at midnight.
+fn filename_date(name: &str) -> Option<CaptureDate> {
+    let bytes = name.as_bytes();
+    let millis = (0..bytes.len())
+        .filter(|&start| start == 0 || !bytes[start - 1].is_ascii_digit())
+        .find_map(|start| name_date(&bytes[start..]))?;
+    Some(CaptureDate {
+        millis,
+        offset: None,
+        source: DateSource::FileName,
+    })
+}
+
+/// Reads the capture date of a media file from its metadata, or from its name.
+pub fn capture_date(path: &Path) -> Option<CaptureDate> {
+    let metadata = match classify(path)? {
+        MediaKind::Image => exif_date(path),
+        MediaKind::Video => quicktime_date(path),
+    };
+    metadata.or_else(|| filename_date(&path.file_name()?.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::experimental::Writer;
+    use exif::Field;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    /// Writes a TIFF file that carries nothing but the given EXIF fields.
+    fn write_tiff(path: &Path, fields: &[(Tag, &str)]) {
+        let fields: Vec<Field> = fields
+            .iter()
+            .map(|(tag, value)| Field {
+                tag: *tag,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+            })
+            .collect();
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut buf = Cursor::new(Vec::new());
+        writer.write(&mut buf, false).unwrap();
+        std::fs::write(path, buf.into_inner()).unwrap();
+    }
+
+    /// Writes an MP4 without tracks whose movie header has the given creation time.
+    fn write_mp4(path: &Path, creation_time: u32) {
+        let config = mp4::Mp4Config {
+            major_brand: str::parse("isom").unwrap(),
+            minor_version: 512,
+            compatible_brands: vec![str::parse("isom").unwrap()],
+            timescale: 1000,
+        };
+        let mut buf = Cursor::new(Vec::new());
+        let mut writer = mp4::Mp4Writer::write_start(&mut buf, &config).unwrap();
+        writer.write_end().unwrap();
+        let mut bytes = buf.into_inner();
+
+        // Version 0 headers store the creation time right after version and flags
+        let mvhd = bytes.windows(4).position(|w| w == b"mvhd").unwrap();
+        assert_eq!(bytes[mvhd + 4], 0);
+        bytes[mvhd + 8..mvhd + 12].copy_from_slice(&creation_time.to_be_bytes());
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    fn file_name_millis(name: &str) -> Option<i64> {
+        filename_date(name).map(|d| d.millis)
+    }
+
+    // -----------------------------------------------------------------------
+    // Calendar
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_days_from_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+    }
+
+    #[test]
+    fn test_civil_date_round_trip() {
+        for (year, month, day) in [(1970, 1, 1), (1969, 12, 31), (2000, 2, 29), (2024, 12, 31)] {
+            let millis = days_from_civil(year, month, day) * 86_400_000 + 3_600_000;
+            assert_eq!(civil_date(millis), (year, month, day));
+        }
+    }
+
+    #[test]
+    fn test_civil_millis_validates() {
+        assert_eq!(civil_millis(2024, 2, 29, 0, 0, 0), Some(1_709_164_800_000));
+        assert_eq!(civil_millis(2023, 2, 29, 0, 0, 0), None);
+        assert_eq!(civil_millis(2024, 13, 1, 0, 0, 0), None);
+        assert_eq!(civil_millis(2024, 1, 1, 24, 0, 0), None);
+    }
+
+    // -----------------------------------------------------------------------
+    // Sources
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_capture_date_from_exif() {
+        let temp = TempDir::new().unwrap();
+        let photo = temp.path().join("photo.tif");
+        write_tiff(&photo, &[(Tag::DateTimeOriginal, "2021:07:04 10:20:30")]);
+        // 2021-07-04T10:20:30 in an unknown time zone
+        let date = capture_date(&photo).unwrap();
+        assert_eq!(date.millis, 1_625_394_030_000);
+        assert_eq!((date.offset, date.source), (None, DateSource::Exif));
+
+        let blank = temp.path().join("IMG_20200101_000000.tif");
+        write_tiff(&blank, &[(Tag::DateTimeOriginal, "    :  :     :  :  ")]);
+        assert_eq!(capture_date(&blank).unwrap().source, DateSource::FileName);
+
+        let text = temp.path().join("notes.txt");
+        std::fs::write(&text, "2021:07:04 10:20:30").unwrap();
+        assert_eq!(capture_date(&text), None);
+    }
+
+    #[test]
+    fn test_capture_date_exif_offset() {
+        let temp = TempDir::new().unwrap();
+        let photo = temp.path().join("photo.tif");
+        write_tiff(
+            &photo,
+            &[
+                (Tag::DateTimeOriginal, "2021:07:04 10:20:30"),
+                (Tag::OffsetTimeOriginal, "+02:00"),
+            ],
+        );
+        let date = capture_date(&photo).unwrap();
+        assert_eq!(date.offset, Some(120));
+        // 08:20:30 UTC, shown at 10:20:30 wherever the viewer is
+        assert_eq!(date.instant(-300), 1_625_386_830_000);
+        assert_eq!(date.local_millis(-300), 1_625_394_030_000);
+
+        // OffsetTime is used when the original offset is missing
+        write_tiff(
+            &photo,
+            &[
+                (Tag::DateTimeOriginal, "2021:07:04 10:20:30"),
+                (Tag::OffsetTime, "-05:30"),
+            ],
+        );
+        assert_eq!(capture_date(&photo).unwrap().offset, Some(-330));
+    }
+
+    #[test]
+    fn test_capture_date_from_quicktime() {
+        let temp = TempDir::new().unwrap();
+        let video = temp.path().join("clip.mov");
+        // 2021-07-04T10:20:30Z
+        write_mp4(&video, (1_625_394_030 + QUICKTIME_EPOCH_OFFSET) as u32);
+        let date = capture_date(&video).unwrap();
+        assert_eq!(date.source, DateSource::QuickTime);
+        assert_eq!(date.instant(120), 1_625_394_030_000);
+        assert_eq!(date.local_millis(120), 1_625_401_230_000);
+
+        // Unset creation times fall back to the file name
+        let unset = temp.path().join("VID_20240101_120000.mp4");
+        write_mp4(&unset, 0);
+        assert_eq!(capture_date(&unset).unwrap().source, DateSource::FileName);
+    }
+
+    #[test]
+    fn test_wall_clock_dates_use_viewer_offset() {
+        let date = CaptureDate {
+            millis: 1_625_394_030_000,
+            offset: None,
+            source: DateSource::FileName,
+        };
+        assert_eq!(date.local_millis(120), 1_625_394_030_000);
+        assert_eq!(date.instant(120), 1_625_386_830_000);
+    }
+
+    // -----------------------------------------------------------------------
+    // File names
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_filename_date_patterns() {
+        // 2024-01-31T12:34:56
+        let expected = Some(1_706_704_496_000);
+        assert_eq!(file_name_millis("IMG_20240131_123456.jpg"), expected);
+        assert_eq!(file_name_millis("VID_20240131_123456.mp4"), expected);
+        assert_eq!(file_name_millis("PXL_20240131_123456789.jpg"), expected);
+        assert_eq!(file_name_millis("20240131_123456.jpg"), expected);
+        assert_eq!(
+            file_name_millis("Screenshot_2024-01-31-12-34-56.png"),
+            expected
+        );
+        assert_eq!(
+            file_name_millis("WhatsApp Image 2024-01-31 at 12.34.56.jpeg"),
+            expected
+        );
+        assert_eq!(
+            file_name_millis("Screen Recording 2024-01-31 at 12.34.56.mov"),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_filename_date_without_time() {
+        // 2024-01-31T00:00:00
+        let midnight = Some(1_706_659_200_000);
+        assert_eq!(file_name_millis("IMG-20240131-WA0001.jpg"), midnight);
+        assert_eq!(file_name_millis("holiday 2024-01-31.jpg"), midnight);
+        assert_eq!(file_name_millis("IMG_20240131_993456.jpg"), midnight);
+    }
+
+    #[test]
+    fn test_filename_date_rejects_other_numbers() {
+        assert_eq!(file_name_millis("DSC01234.jpg"), None);
+        assert_eq!(file_name_millis("IMG_1234.jpg"), None);
+        assert_eq!(file_name_millis("1234567890123.jpg"), None);
+        assert_eq!(file_name_millis("IMG_20241301_123456.jpg"), None);
+        assert_eq!(file_name_millis("IMG_2024-01_31.jpg"), None);
+        assert_eq!(file_name_millis("photo.jpg"), None);
+    }
+}
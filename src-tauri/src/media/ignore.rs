@@ -0,0 +1,348 @@
+//! Ignore rules: folders and files that are not part of the collection.
+//!
+//! Rules come from the global patterns in the settings and from `.mvignore` files,
+//! which use gitignore syntax and apply to their folder and everything below it. A
+//! folder containing a `.nomedia` file is hidden with all of its contents. Global
+//! patterns match at any depth, as if they started with `**/`.
+
+use crate::thumbnail::normalize_path;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// Per-folder ignore file, in gitignore syntax.
+pub const IGNORE_FILE: &str = ".mvignore";
+/// Marker file hiding its folder.
+pub const NOMEDIA_FILE: &str = ".nomedia";
+
+/// Global patterns from the settings, see [`set_global_patterns`].
+static GLOBAL_PATTERNS: RwLock<Vec<Pattern>> = RwLock::new(Vec::new());
+
+/// A compiled gitignore pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Pattern {
+    glob: Vec<char>,
+    negated: bool,
+    dir_only: bool,
+}
+
+impl Pattern {
+    /// Parses a line of an ignore file, None for blank lines and comments.
+    /// `global` patterns match at any depth even if they contain a slash.
+    fn parse(line: &str, global: bool) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        if line.is_empty() {
+            return None;
+        }
+        // Patterns with a slash are relative to the ignore file, others match at any depth
+        let anchored = line.contains('/') && !global;
+        let line = line.strip_prefix('/').unwrap_or(line);
+        let glob = if anchored || line.starts_with("**/") {
+            line.chars().collect()
+        } else {
+            format!("**/{}", line).chars().collect()
+        };
+        Some(Self {
+            glob,
+            negated,
+            dir_only,
+        })
+    }
+
+    fn matches(&self, relative: &[char], is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && glob_match(&self.glob, relative)
+    }
+}
+
+fn parse_patterns<'a>(lines: impl IntoIterator<Item = &'a str>, global: bool) -> Vec<Pattern> {
+    lines
+        .into_iter()
+        .filter_map(|line| Pattern::parse(line, global))
+        .collect()
+}
+
+/// Matches a path relative to the pattern's folder, with `/` separators.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        // `**/` matches any number of whole folders, including none
+        ['*', '*', '/', rest @ ..] => {
+            glob_match(rest, text)
+                || (0..text.len()).any(|i| text[i] == '/' && glob_match(rest, &text[i + 1..]))
+        }
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        ['*', rest @ ..] => {
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        ['?', rest @ ..] => {
+            matches!(text.first(), Some(c) if *c != '/') && glob_match(rest, &text[1..])
+        }
+        ['[', rest @ ..] => match (class_match(rest, text.first()), text.first()) {
+            (Some((true, len)), Some(_)) => glob_match(&rest[len..], &text[1..]),
+            (Some(_), _) => false,
+            // An unterminated class is a literal `[`
+            (None, Some('[')) => glob_match(rest, &text[1..]),
+            (None, _) => false,
+        },
+        ['\\', escaped, rest @ ..] => text.first() == Some(escaped) && glob_match(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// Matches a character class such as `[a-z]` or `[!0-9]` after its `[`. Returns
+/// whether `c` is in it and the length of the class, None if it is unterminated.
+fn class_match(class: &[char], c: Option<&char>) -> Option<(bool, usize)> {
+    let (negated, start) = match class.first() {
+        Some('!') | Some('^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut i = start;
+    let mut found = false;
+    loop {
+        let first = *class.get(i)?;
+        if first == ']' && i > start {
+            break;
+        }
+        match (class.get(i + 1), class.get(i + 2)) {
+            (Some('-'), Some(&last)) if last != ']' => {
+                found |= c.is_some_and(|c| (first..=last).contains(c));
+                i += 3;
+            }
+            _ => {
+                found |= c == Some(&first);
+                i += 1;
+            }
+        }
+    }
+    let matched = c.is_some_and(|c| *c != '/') && found != negated;
+    Some((matched, i + 1))
+}
+
+/// Replaces the global patterns, one pattern per entry.
+pub fn set_global_patterns(patterns: &[String]) {
+    let patterns = parse_patterns(patterns.iter().map(String::as_str), true);
+    *GLOBAL_PATTERNS.write().unwrap_or_else(|e| e.into_inner()) = patterns;
+}
+
+/// The patterns of one `.mvignore` file.
+#[derive(Clone, Debug)]
+struct IgnoreFile {
+    dir: PathBuf,
+    patterns: Vec<Pattern>,
+}
+
+/// The rules that apply inside a folder. Walks start with [`IgnoreRules::for_dir`]
+/// at the top and descend with [`IgnoreRules::child`].
+#[derive(Clone, Debug, Default)]
+pub struct IgnoreRules {
+    global: Vec<Pattern>,
+    /// Outermost first, so later files take precedence like in git.
+    files: Vec<IgnoreFile>,
+    hidden: bool,
+}
+
+impl IgnoreRules {
+    /// The rules inside `dir`, from the global patterns and the ignore files of `dir`
+    /// and all of its ancestors. `dir` is hidden if it or an ancestor is ignored.
+    pub fn for_dir(dir: &Path) -> Self {
+        let mut rules = Self {
+            global: GLOBAL_PATTERNS
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+            ..Self::default()
+        };
+        let mut ancestors: Vec<&Path> = dir.ancestors().collect();
+        ancestors.reverse();
+        for (i, ancestor) in ancestors.into_iter().enumerate() {
+            if i > 0 && rules.is_ignored(ancestor, true) {
+                rules.hidden = true;
+            }
+            rules = rules.child(ancestor);
+            if rules.hidden {
+                break;
+            }
+        }
+        rules
+    }
+
+    /// The rules inside `dir`, a folder directly inside the folder of these rules.
+    pub fn child(&self, dir: &Path) -> Self {
+        let mut rules = self.clone();
+        if rules.hidden {
+            return rules;
+        }
+        if dir.join(NOMEDIA_FILE).exists() {
+            rules.hidden = true;
+        }
+        if let Ok(text) = fs::read_to_string(dir.join(IGNORE_FILE)) {
+            let patterns = parse_patterns(text.lines(), false);
+            if !patterns.is_empty() {
+                rules.files.push(IgnoreFile {
+                    dir: dir.to_path_buf(),
+                    patterns,
+                });
+            }
+        }
+        rules
+    }
+
+    /// Whether the folder of these rules is hidden, so nothing inside it is media.
+    pub fn is_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    /// Whether `path`, a file or folder inside the folder of these rules, is ignored.
+    /// The last matching pattern decides, and `!` patterns include paths again.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if self.hidden {
+            return true;
+        }
+        let normalized = normalize_path(&path.to_string_lossy());
+        let full: Vec<char> = normalized.trim_start_matches('/').chars().collect();
+        let mut ignored = false;
+        for pattern in &self.global {
+            if pattern.matches(&full, is_dir) {
+                ignored = !pattern.negated;
+            }
+        }
+        for file in &self.files {
+            let Ok(relative) = path.strip_prefix(&file.dir) else {
+                continue;
+            };
+            let relative: Vec<char> = normalize_path(&relative.to_string_lossy())
+                .chars()
+                .collect();
+            for pattern in &file.patterns {
+                if pattern.matches(&relative, is_dir) {
+                    ignored = !pattern.negated;
+                }
+            }
+        }
+        ignored
+    }
+}
+
+/// Whether a file or folder is ignored, looking up the rules of its folder.
+pub fn is_ignored(path: &Path, is_dir: bool) -> bool {
+    match path.parent() {
+        Some(parent) => IgnoreRules::for_dir(parent).is_ignored(path, is_dir),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn matches(pattern: &str, path: &str, is_dir: bool) -> bool {
+        let path: Vec<char> = path.chars().collect();
+        Pattern::parse(pattern, false)
+            .unwrap()
+            .matches(&path, is_dir)
+    }
+
+    // -----------------------------------------------------------------------
+    // patterns
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_parse_pattern() {
+        assert_eq!(Pattern::parse("# comment", false), None);
+        assert_eq!(Pattern::parse("   ", false), None);
+        let pattern = Pattern::parse("!/raw/", false).unwrap();
+        assert!(pattern.negated && pattern.dir_only);
+        assert_eq!(pattern.glob.iter().collect::<String>(), "raw");
+        let pattern = Pattern::parse("Thumbs.db", false).unwrap();
+        assert_eq!(pattern.glob.iter().collect::<String>(), "**/Thumbs.db");
+        let pattern = Pattern::parse("Lightroom/*.lrdata", true).unwrap();
+        assert_eq!(
+            pattern.glob.iter().collect::<String>(),
+            "**/Lightroom/*.lrdata"
+        );
+    }
+
+    #[test]
+    fn test_pattern_matching() {
+        assert!(matches("Thumbs.db", "Thumbs.db", false));
+        assert!(matches("Thumbs.db", "a/b/Thumbs.db", false));
+        assert!(!matches("Thumbs.db", "a/Thumbs.db.jpg", false));
+        assert!(matches("*.lrdata/", "cat/Previews.lrdata", true));
+        assert!(!matches("*.lrdata/", "cat/Previews.lrdata", false));
+        assert!(matches("/raw", "raw", true));
+        assert!(!matches("/raw", "2020/raw", true));
+        assert!(matches("2020/*.cr2", "2020/a.cr2", false));
+        assert!(!matches("2020/*.cr2", "2020/b/a.cr2", false));
+        assert!(matches("2020/**/*.cr2", "2020/b/c/a.cr2", false));
+        assert!(matches("2020/**/*.cr2", "2020/a.cr2", false));
+        assert!(matches("tmp/**", "tmp/a/b", false));
+        assert!(matches("IMG_00?.jpg", "IMG_001.jpg", false));
+        assert!(matches("IMG_[0-4]*.jpg", "IMG_3.jpg", false));
+        assert!(!matches("IMG_[!0-4]*.jpg", "IMG_3.jpg", false));
+        assert!(matches("\\#1.jpg", "#1.jpg", false));
+        assert!(matches("a[b", "a[b", false));
+    }
+
+    // -----------------------------------------------------------------------
+    // rules
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_ignore_files_nest() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("trip/raw")).unwrap();
+        fs::write(root.join(IGNORE_FILE), "*.cr2\n@eaDir/\n").unwrap();
+        fs::write(root.join("trip").join(IGNORE_FILE), "!keep.cr2\n/raw/\n").unwrap();
+
+        let top = IgnoreRules::for_dir(root);
+        assert!(top.is_ignored(&root.join("a.cr2"), false));
+        assert!(top.is_ignored(&root.join("@eaDir"), true));
+        assert!(!top.is_ignored(&root.join("a.jpg"), false));
+
+        let trip = top.child(&root.join("trip"));
+        assert!(trip.is_ignored(&root.join("trip/b.cr2"), false));
+        assert!(!trip.is_ignored(&root.join("trip/keep.cr2"), false));
+        assert!(trip.is_ignored(&root.join("trip/raw"), true));
+
+        // Looking up a folder directly sees the rules of its ancestors
+        assert!(IgnoreRules::for_dir(&root.join("trip/raw")).is_hidden());
+        assert!(is_ignored(&root.join("trip/c.cr2"), false));
+        assert!(!is_ignored(&root.join("trip/keep.cr2"), false));
+    }
+
+    #[test]
+    fn test_nomedia_hides_folder() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("private/inner")).unwrap();
+        fs::write(root.join("private").join(NOMEDIA_FILE), "").unwrap();
+
+        let top = IgnoreRules::for_dir(root);
+        assert!(!top.is_hidden());
+        let private = top.child(&root.join("private"));
+        assert!(private.is_hidden());
+        assert!(private.is_ignored(&root.join("private/a.jpg"), false));
+        assert!(IgnoreRules::for_dir(&root.join("private/inner")).is_hidden());
+    }
+}
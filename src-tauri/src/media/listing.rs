@@ -0,0 +1,406 @@
+//! Listing the media files of a directory.
+
+use super::{capture_date, civil_date, classify, CaptureDate, IgnoreRules, MediaKind};
+use crate::catalog;
+use crate::thumbnail::{self, normalize_path};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Order of a listing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    #[default]
+    Name,
+    /// Capture date, or the modification time of files without one.
+    Date,
+}
+
+/// Period a listing is grouped by, see [`MediaEntry::group`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    Day,
+    Month,
+}
+
+/// What [`list_media`] looks up besides the file metadata, and how it orders files.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ListOptions {
+    /// Read image dimensions from the file headers, which is cheap for most formats.
+    pub dimensions: bool,
+    /// Cache to look up existing thumbnails in, none are reported without it.
+    pub cache_base_dir: Option<String>,
+    /// Read capture dates, implied by sorting or grouping by date.
+    pub capture_dates: bool,
+    pub sort: SortBy,
+    pub group_by: Option<GroupBy>,
+    /// Offset of the viewer's time zone from UTC in minutes, for dates recorded
+    /// without a time zone and for grouping modification times.
+    pub utc_offset: i32,
+}
+
+impl ListOptions {
+    fn reads_dates(&self) -> bool {
+        self.capture_dates || self.sort == SortBy::Date || self.group_by.is_some()
+    }
+}
+
+/// A media file in a directory listing.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaEntry {
+    pub name: String,
+    pub path: String,
+    pub kind: MediaKind,
+    /// Size in bytes.
+    pub size: u64,
+    /// Modification time in milliseconds since the Unix epoch.
+    pub mtime: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Up-to-date cached thumbnail, if there is one.
+    pub thumbnail_path: Option<String>,
+    /// Capture date in milliseconds since the Unix epoch, if it was read and is known.
+    pub captured_at: Option<i64>,
+    /// Day (`2024-01-31`) or month (`2024-01`) the file was captured, or last
+    /// modified, if the listing is grouped.
+    pub group: Option<String>,
+}
+
+/// Reads the dimensions of an image from its header without decoding it.
+pub fn image_dimensions(path: &Path) -> Option<(u32, u32)> {
+    image::ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Modification time of a file in milliseconds since the Unix epoch, 0 if unknown.
+pub fn modified_millis(meta: &Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Describes a single media file, or returns None if it is not a readable media file.
+pub fn media_entry(path: &Path, options: &ListOptions) -> Option<MediaEntry> {
+    let kind = classify(path)?;
+    // Follows symlinks, unlike the metadata of a directory entry
+    let meta = fs::metadata(path).ok().filter(|m| m.is_file())?;
+    let mtime = modified_millis(&meta);
+    let (width, height) = match kind {
+        MediaKind::Image if options.dimensions => image_dimensions(path).unzip(),
+        _ => (None, None),
+    };
+    let thumbnail_path = options
+        .cache_base_dir
+        .as_ref()
+        .and_then(|base| thumbnail::fresh_thumbnail(path, Path::new(base)))
+        .map(|thumb| normalize_path(&thumb.to_string_lossy()));
+
+    Some(MediaEntry {
+        name: path.file_name()?.to_string_lossy().to_string(),
+        path: normalize_path(&path.to_string_lossy()),
+        kind,
+        size: meta.len(),
+        mtime,
+        width,
+        height,
+        thumbnail_path,
+        captured_at: None,
+        group: None,
+    })
+}
+
+/// Group label of a wall clock time in milliseconds read as UTC.
+fn group_label(local_millis: i64, group_by: GroupBy) -> String {
+    let (year, month, day) = civil_date(local_millis);
+    match group_by {
+        GroupBy::Day => format!("{:04}-{:02}-{:02}", year, month, day),
+        GroupBy::Month => format!("{:04}-{:02}", year, month),
+    }
+}
+
+/// Fills in capture dates and groups, and sorts the files as requested.
+/// Dates of files the catalog knows unchanged are taken from it.
+fn order(media: &mut Vec<MediaEntry>, dir: &Path, options: &ListOptions) {
+    if options.reads_dates() {
+        let known: HashMap<String, catalog::CatalogEntry> =
+            catalog::with(|c| c.list_dir(&dir.to_string_lossy()))
+                .unwrap_or_default()
+                .into_iter()
+                .map(|entry| (entry.path.clone(), entry))
+                .collect();
+        let offset = options.utc_offset;
+
+        let mut dated: Vec<(i64, MediaEntry)> = media
+            .drain(..)
+            .map(|mut entry| {
+                let capture: Option<CaptureDate> = match known.get(&entry.path) {
+                    Some(k) if k.size == entry.size && k.mtime == entry.mtime => k.capture,
+                    _ => capture_date(Path::new(&entry.path)),
+                };
+                entry.captured_at = capture.map(|c| c.instant(offset));
+                let modified = entry.mtime as i64;
+                if let Some(group_by) = options.group_by {
+                    let local = capture.map_or(modified + i64::from(offset) * 60_000, |c| {
+                        c.local_millis(offset)
+                    });
+                    entry.group = Some(group_label(local, group_by));
+                }
+                (entry.captured_at.unwrap_or(modified), entry)
+            })
+            .collect();
+
+        if options.sort == SortBy::Date {
+            dated.sort_by(|(a_date, a), (b_date, b)| {
+                a.group
+                    .cmp(&b.group)
+                    .then(a_date.cmp(b_date))
+                    .then(a.name.cmp(&b.name))
+            });
+        }
+        media.extend(dated.into_iter().map(|(_, entry)| entry));
+    }
+
+    // Sorting by group first keeps the files of a group together
+    if options.sort == SortBy::Name {
+        media.sort_by(|a, b| a.group.cmp(&b.group).then(a.name.cmp(&b.name)));
+    }
+}
+
+/// Lists the media files directly inside `dir`, ordered as set in `options`.
+/// Files that cannot be read or are ignored are skipped.
+pub fn list_media(dir: &Path, options: &ListOptions) -> Result<Vec<MediaEntry>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| {
+        format!(
+            "Failed to read directory {}: {}",
+            normalize_path(&dir.to_string_lossy()),
+            e
+        )
+    })?;
+
+    let rules = IgnoreRules::for_dir(dir);
+    if rules.is_hidden() {
+        return Ok(Vec::new());
+    }
+    let mut media: Vec<MediaEntry> = entries
+        .filter_map(|e| e.ok())
+        .map(|entry| entry.path())
+        .filter(|path| !rules.is_ignored(path, false))
+        .filter_map(|path| media_entry(&path, options))
+        .collect();
+    order(&mut media, dir, options);
+    Ok(media)
+}
+
+/// A folder in the folder tree.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderEntry {
+    pub name: String,
+    pub path: String,
+    pub has_subfolders: bool,
+}
+
+/// Folders directly inside `dir` that are part of the collection, ordered by name.
+/// Caches and ignored folders are left out.
+pub fn list_folders(dir: &Path) -> Result<Vec<FolderEntry>, String> {
+    let rules = IgnoreRules::for_dir(dir);
+    let mut folders: Vec<FolderEntry> = subfolders(dir, &rules)?
+        .into_iter()
+        .filter_map(|path| {
+            // Folders with a `.nomedia` file are hidden too
+            let child_rules = rules.child(&path);
+            if child_rules.is_hidden() {
+                return None;
+            }
+            let has_subfolders = subfolders(&path, &child_rules)
+                .is_ok_and(|s| s.iter().any(|sub| !child_rules.child(sub).is_hidden()));
+            Some(FolderEntry {
+                name: path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                path: normalize_path(&path.to_string_lossy()),
+                has_subfolders,
+            })
+        })
+        .collect();
+    folders.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(folders)
+}
+
+/// Visible folders directly inside `dir`, whose rules are `rules`.
+fn subfolders(dir: &Path, rules: &IgnoreRules) -> Result<Vec<PathBuf>, String> {
+    if rules.is_hidden() {
+        return Ok(Vec::new());
+    }
+    let entries = fs::read_dir(dir).map_err(|e| {
+        format!(
+            "Failed to read directory {}: {}",
+            normalize_path(&dir.to_string_lossy()),
+            e
+        )
+    })?;
+    Ok(entries
+        .filter_map(|e| e.ok())
+        .filter(|entry| entry.file_name() != thumbnail::PORTABLE_CACHE_DIR)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir() && !rules.is_ignored(path, true))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::{IGNORE_FILE, NOMEDIA_FILE};
+    use crate::test_util::write_png;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_list_media_classifies_and_skips_other_files() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        write_png(&dir.join("b.png"), 40, 30);
+        fs::write(dir.join("a.mp4"), b"not really a video").unwrap();
+        fs::write(dir.join("notes.txt"), b"text").unwrap();
+        fs::create_dir(dir.join("folder.jpg")).unwrap();
+
+        let media = list_media(dir, &ListOptions::default()).unwrap();
+
+        let names: Vec<&str> = media.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["a.mp4", "b.png"]);
+        assert_eq!(media[0].kind, MediaKind::Video);
+        assert_eq!(media[0].size, 18);
+        assert_eq!(
+            media[0].path,
+            normalize_path(&dir.join("a.mp4").to_string_lossy())
+        );
+        assert_eq!(media[1].kind, MediaKind::Image);
+        assert!(media[1].mtime > 0);
+        // Dimensions are only read on request
+        assert_eq!(media[1].width, None);
+        assert_eq!(media[1].thumbnail_path, None);
+    }
+
+    #[test]
+    fn test_list_media_reads_dimensions() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        write_png(&dir.join("a.png"), 40, 30);
+        write_png(&dir.join("b.png"), 10, 20);
+        fs::write(dir.join("broken.jpg"), b"not an image").unwrap();
+
+        let options = ListOptions {
+            dimensions: true,
+            ..Default::default()
+        };
+        let media = list_media(dir, &options).unwrap();
+
+        assert_eq!((media[0].width, media[0].height), (Some(40), Some(30)));
+        assert_eq!((media[1].width, media[1].height), (Some(10), Some(20)));
+        assert_eq!(media[2].name, "broken.jpg");
+        assert_eq!(media[2].width, None);
+    }
+
+    #[test]
+    fn test_list_media_sorts_and_groups_by_date() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        write_png(&dir.join("IMG_20240102_100000.png"), 4, 4);
+        write_png(&dir.join("IMG_20240101_233000.png"), 4, 4);
+        write_png(&dir.join("a.png"), 4, 4);
+        let names = |media: &[MediaEntry]| -> Vec<String> {
+            media.iter().map(|m| m.name.clone()).collect()
+        };
+
+        let options = ListOptions {
+            sort: SortBy::Date,
+            group_by: Some(GroupBy::Day),
+            utc_offset: 60,
+            ..Default::default()
+        };
+        let media = list_media(dir, &options).unwrap();
+        // Files without a capture date are sorted by their modification time
+        assert_eq!(
+            names(&media),
+            [
+                "IMG_20240101_233000.png",
+                "IMG_20240102_100000.png",
+                "a.png"
+            ]
+        );
+        assert_eq!(media[0].group.as_deref(), Some("2024-01-01"));
+        // 2024-01-01T23:30:00+01:00
+        assert_eq!(media[0].captured_at, Some(1_704_148_200_000));
+        assert_eq!(media[1].group.as_deref(), Some("2024-01-02"));
+        assert_eq!(media[2].captured_at, None);
+        assert!(media[2].group.is_some());
+
+        let options = ListOptions {
+            group_by: Some(GroupBy::Month),
+            ..Default::default()
+        };
+        let media = list_media(dir, &options).unwrap();
+        assert_eq!(media[0].group.as_deref(), Some("2024-01"));
+        assert_eq!(media[1].group.as_deref(), Some("2024-01"));
+        assert_eq!(media[2].name, "a.png");
+
+        // Dates are only read on request
+        let media = list_media(dir, &ListOptions::default()).unwrap();
+        assert_eq!(names(&media)[0], "IMG_20240101_233000.png");
+        assert!(media
+            .iter()
+            .all(|m| m.captured_at.is_none() && m.group.is_none()));
+    }
+
+    #[test]
+    fn test_list_media_missing_dir() {
+        let temp = TempDir::new().unwrap();
+        assert!(list_media(&temp.path().join("missing"), &ListOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_list_media_skips_ignored() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        write_png(&dir.join("a.png"), 4, 4);
+        write_png(&dir.join("b.png"), 4, 4);
+        fs::write(dir.join(IGNORE_FILE), "b.*\n").unwrap();
+        let media = list_media(dir, &ListOptions::default()).unwrap();
+        let names: Vec<&str> = media.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["a.png"]);
+
+        fs::write(dir.join(NOMEDIA_FILE), "").unwrap();
+        assert!(list_media(dir, &ListOptions::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_list_folders() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("b/inner")).unwrap();
+        fs::create_dir_all(dir.join("a/@eaDir")).unwrap();
+        fs::create_dir_all(dir.join("hidden")).unwrap();
+        fs::create_dir_all(dir.join(thumbnail::PORTABLE_CACHE_DIR)).unwrap();
+        fs::write(dir.join("c.png"), b"").unwrap();
+        fs::write(dir.join("hidden").join(NOMEDIA_FILE), "").unwrap();
+        fs::write(dir.join(IGNORE_FILE), "@eaDir/\n").unwrap();
+
+        let folders = list_folders(dir).unwrap();
+        let names: Vec<(&str, bool)> = folders
+            .iter()
+            .map(|f| (f.name.as_str(), f.has_subfolders))
+            .collect();
+        assert_eq!(names, [("a", false), ("b", true)]);
+    }
+}
@@ -0,0 +1,338 @@
+//! Descriptive metadata of media files: camera, rating and tags.
+//!
+//! The camera comes from EXIF. Ratings and tags are read from XMP, embedded in the
+//! file or in a sidecar next to it (`photo.xmp` or `photo.jpg.xmp`), which takes
+//! precedence as editors write it when they cannot modify the original. The EXIF
+//! rating written by Windows is used when there is no XMP rating.
+
+use super::{classify, modified_millis, MediaKind};
+use exif::{Context, In, Reader, Tag, Value};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// Largest part of a file searched for an embedded XMP packet, which sits near the start.
+const XMP_SEARCH_LEN: u64 = 1024 * 1024;
+
+/// EXIF rating tag written by Windows Explorer.
+const EXIF_RATING: Tag = Tag(Context::Tiff, 0x4746);
+
+/// What [`read_metadata`] found, all parts are optional.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MediaMetadata {
+    /// Make and model, e.g. `Apple iPhone 15 Pro`.
+    pub camera: Option<String>,
+    /// Star rating from 0 to 5, -1 for rejected files.
+    pub rating: Option<i32>,
+    pub tags: Vec<String>,
+}
+
+/// Camera and rating from EXIF.
+fn exif_metadata(path: &Path) -> (Option<String>, Option<i32>) {
+    let Ok(file) = File::open(path) else {
+        return (None, None);
+    };
+    let Ok(exif) = Reader::new().read_from_container(&mut BufReader::new(file)) else {
+        return (None, None);
+    };
+    let text = |tag: Tag| match exif.get_field(tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(values)) => values
+            .first()
+            .map(|v| {
+                String::from_utf8_lossy(v)
+                    .trim_matches(['\0', ' '])
+                    .to_string()
+            })
+            .filter(|v| !v.is_empty()),
+        _ => None,
+    };
+
+    let camera = match (text(Tag::Make), text(Tag::Model)) {
+        // Models usually repeat the make, as in `Canon` + `Canon EOS R5`
+        (Some(make), Some(model)) if model.to_lowercase().starts_with(&make.to_lowercase()) => {
+            Some(model)
+        }
+        (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+        (make, model) => make.or(model),
+    };
+    let rating = exif
+        .get_field(EXIF_RATING, In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0))
+        .map(|r| r.min(5) as i32);
+    (camera, rating)
+}
+
+/// Finds an XMP packet in the first [`XMP_SEARCH_LEN`] bytes of a file.
+fn embedded_xmp(path: &Path) -> Option<String> {
+    let mut data = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(XMP_SEARCH_LEN)
+        .read_to_end(&mut data)
+        .ok()?;
+    let start = find(&data, b"<x:xmpmeta")?;
+    let end = find(&data[start..], b"</x:xmpmeta>")? + start;
+    Some(String::from_utf8_lossy(&data[start..end]).into_owned())
+}
+
+/// Sidecar files an editor may have written for `path`.
+fn sidecar_paths(path: &Path) -> [PathBuf; 2] {
+    let mut with_extension = path.as_os_str().to_owned();
+    with_extension.push(".xmp");
+    [path.with_extension("xmp"), PathBuf::from(with_extension)]
+}
+
+/// Modification time of the newest sidecar of `path` in milliseconds since the Unix
+/// epoch, so that rescans notice edits that leave the media file untouched.
+pub fn sidecar_modified(path: &Path) -> Option<u64> {
+    sidecar_paths(path)
+        .iter()
+        .filter_map(|p| fs::metadata(p).ok())
+        .map(|meta| modified_millis(&meta))
+        .max()
+}
+
+/// Media files in the directory of `sidecar` that it holds metadata for.
+pub fn sidecar_owners(sidecar: &Path) -> Vec<PathBuf> {
+    let is_sidecar = sidecar
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("xmp"));
+    let Some(dir) = sidecar.parent().filter(|_| is_sidecar) else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut owners: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| {
+            classify(path).is_some() && sidecar_paths(path).contains(&sidecar.to_path_buf())
+        })
+        .collect();
+    owners.sort();
+    owners
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Reads `xmp:Rating`, written as an attribute or as an element.
+fn xmp_rating(xmp: &str) -> Option<i32> {
+    let rest = &xmp[xmp.find("xmp:Rating")? + "xmp:Rating".len()..];
+    let value = rest
+        .trim_start()
+        .strip_prefix('=')
+        .map(|v| v.trim_start().trim_start_matches(['"', '\'']))
+        .or_else(|| rest.strip_prefix('>'))?;
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '-' || c == '.'))
+        .unwrap_or(value.len());
+    let rating: f32 = value[..end].parse().ok()?;
+    Some((rating.round() as i32).clamp(-1, 5))
+}
+
+/// Reads the keywords of `dc:subject`.
+fn xmp_tags(xmp: &str) -> Vec<String> {
+    let Some(start) = xmp.find("<dc:subject") else {
+        return Vec::new();
+    };
+    let subject = &xmp[start..];
+    let subject = &subject[..subject.find("</dc:subject>").unwrap_or(subject.len())];
+
+    let mut tags = Vec::new();
+    let mut rest = subject;
+    while let Some(open) = rest.find("<rdf:li") {
+        rest = &rest[open..];
+        let Some(content) = rest.find('>') else {
+            break;
+        };
+        rest = &rest[content + 1..];
+        let Some(close) = rest.find("</rdf:li>") else {
+            break;
+        };
+        let tag = decode_entities(rest[..close].trim());
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+        rest = &rest[close..];
+    }
+    tags
+}
+
+/// Reads the camera, rating and tags of a media file. Missing or unreadable
+/// metadata leaves the respective part empty.
+pub fn read_metadata(path: &Path) -> MediaMetadata {
+    let Some(kind) = classify(path) else {
+        return MediaMetadata::default();
+    };
+    let (camera, exif_rating) = match kind {
+        MediaKind::Image => exif_metadata(path),
+        MediaKind::Video => (None, None),
+    };
+
+    let sidecar = sidecar_paths(path)
+        .iter()
+        .find_map(|p| fs::read_to_string(p).ok());
+    let embedded = match kind {
+        MediaKind::Image => embedded_xmp(path),
+        MediaKind::Video => None,
+    };
+    let packets: Vec<&String> = sidecar.iter().chain(embedded.iter()).collect();
+
+    let mut tags: Vec<String> = Vec::new();
+    for tag in packets.iter().flat_map(|xmp| xmp_tags(xmp)) {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    MediaMetadata {
+        camera,
+        rating: packets
+            .iter()
+            .find_map(|xmp| xmp_rating(xmp))
+            .or(exif_rating),
+        tags,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::experimental::Writer;
+    use exif::Field;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    const XMP: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="4">
+   <dc:subject>
+    <rdf:Bag>
+     <rdf:li>beach</rdf:li>
+     <rdf:li xml:lang="x-default">Tom &amp; Jerry</rdf:li>
+    </rdf:Bag>
+   </dc:subject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+    fn write_tiff(path: &Path, fields: &[Field]) {
+        let mut writer = Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut buf = Cursor::new(Vec::new());
+        writer.write(&mut buf, false).unwrap();
+        fs::write(path, buf.into_inner()).unwrap();
+    }
+
+    fn ascii(tag: Tag, value: &str) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        }
+    }
+
+    #[test]
+    fn test_xmp_rating() {
+        assert_eq!(xmp_rating(XMP), Some(4));
+        assert_eq!(xmp_rating("<xmp:Rating>3</xmp:Rating>"), Some(3));
+        assert_eq!(xmp_rating("xmp:Rating = '-1'"), Some(-1));
+        assert_eq!(xmp_rating("xmp:Rating=\"2.0\""), Some(2));
+        assert_eq!(xmp_rating("<xmp:CreatorTool>x</xmp:CreatorTool>"), None);
+    }
+
+    #[test]
+    fn test_xmp_tags() {
+        assert_eq!(xmp_tags(XMP), ["beach", "Tom & Jerry"]);
+        assert_eq!(xmp_tags("<x:xmpmeta></x:xmpmeta>"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_read_metadata_from_exif() {
+        let temp = TempDir::new().unwrap();
+        let photo = temp.path().join("photo.tif");
+        write_tiff(
+            &photo,
+            &[
+                ascii(Tag::Make, "Canon"),
+                ascii(Tag::Model, "Canon EOS R5"),
+                Field {
+                    tag: EXIF_RATING,
+                    ifd_num: In::PRIMARY,
+                    value: Value::Short(vec![3]),
+                },
+            ],
+        );
+        let metadata = read_metadata(&photo);
+        assert_eq!(metadata.camera.as_deref(), Some("Canon EOS R5"));
+        assert_eq!(metadata.rating, Some(3));
+        assert!(metadata.tags.is_empty());
+
+        write_tiff(
+            &photo,
+            &[
+                ascii(Tag::Make, "Apple"),
+                ascii(Tag::Model, "iPhone 15 Pro"),
+            ],
+        );
+        assert_eq!(
+            read_metadata(&photo).camera.as_deref(),
+            Some("Apple iPhone 15 Pro")
+        );
+    }
+
+    #[test]
+    fn test_read_metadata_from_xmp() {
+        let temp = TempDir::new().unwrap();
+
+        // Embedded packet
+        let embedded = temp.path().join("embedded.jpg");
+        let mut data = b"\xFF\xD8\xFF\xE1 not a real jpeg ".to_vec();
+        data.extend_from_slice(XMP.as_bytes());
+        fs::write(&embedded, data).unwrap();
+        let metadata = read_metadata(&embedded);
+        assert_eq!(metadata.rating, Some(4));
+        assert_eq!(metadata.tags, ["beach", "Tom & Jerry"]);
+
+        // Sidecars win over embedded packets, tags are merged
+        fs::write(
+            temp.path().join("embedded.jpg.xmp"),
+            "<x:xmpmeta><xmp:Rating>5</xmp:Rating>\
+             <dc:subject><rdf:Bag><rdf:li>sunset</rdf:li></rdf:Bag></dc:subject></x:xmpmeta>",
+        )
+        .unwrap();
+        let metadata = read_metadata(&embedded);
+        assert_eq!(metadata.rating, Some(5));
+        assert_eq!(metadata.tags, ["sunset", "beach", "Tom & Jerry"]);
+
+        // Videos only use sidecars
+        let video = temp.path().join("clip.mp4");
+        fs::write(&video, b"video").unwrap();
+        fs::write(temp.path().join("clip.xmp"), XMP).unwrap();
+        assert_eq!(read_metadata(&video).rating, Some(4));
+        assert!(sidecar_modified(&video).is_some());
+        assert_eq!(sidecar_modified(&temp.path().join("other.mp4")), None);
+        assert_eq!(sidecar_owners(&temp.path().join("clip.xmp")), [video]);
+        assert_eq!(
+            sidecar_owners(&temp.path().join("embedded.jpg.xmp")),
+            [embedded]
+        );
+
+        assert_eq!(
+            read_metadata(&temp.path().join("notes.txt")),
+            MediaMetadata::default()
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Media files of the collection: what counts as media, and directory listings.
+//!
+//! [`classify`] is the only place deciding whether a file is media, going by its
+//! contents before its extension. The frontend lists directories through
+//! [`list_media`] and thumbnail generation skips anything it does not classify, so
+//! both sides always agree. Folders and files excluded by
+//! the [`IgnoreRules`] are not part of the collection anywhere.
+
+mod dates;
+mod ignore;
+mod listing;
+mod metadata;
+mod sniff;
+mod watcher;
+
+pub use dates::{capture_date, civil_date, civil_millis, CaptureDate, DateSource};
+pub use ignore::{is_ignored, set_global_patterns, IgnoreRules, IGNORE_FILE, NOMEDIA_FILE};
+pub use listing::{
+    image_dimensions, list_folders, list_media, media_entry, modified_millis, FolderEntry, GroupBy,
+    ListOptions, MediaEntry, SortBy,
+};
+pub use metadata::{read_metadata, sidecar_modified, sidecar_owners, MediaMetadata};
+pub use sniff::{sniff, Content};
+pub use watcher::watch;
+
+use serde::Serialize;
+use std::path::Path;
+
+const IMAGE_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "bmp", "tif", "tiff", "svg", "ico", "avif", "cr2",
+];
+
+const HEIC_EXTENSIONS: &[&str] = &["heic", "heif"];
+
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "webm", "mkv", "avi", "mov", "wmv", "flv", "m4v"];
+
+/// Kind of a media file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Image,
+    Video,
+}
+
+impl MediaKind {
+    /// Name of the kind, as serialized.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MediaKind::Image => "image",
+            MediaKind::Video => "video",
+        }
+    }
+
+    /// Parses a name returned by [`MediaKind::as_str`].
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "image" => Some(MediaKind::Image),
+            "video" => Some(MediaKind::Video),
+            _ => None,
+        }
+    }
+}
+
+/// Detects what a file contains from its signature, see [`sniff`], or else from its
+/// extension for files without a known signature or that cannot be read.
+pub fn detect(path: &Path) -> Option<Content> {
+    if let Some(content) = sniff(path) {
+        return Some(content);
+    }
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    if HEIC_EXTENSIONS.contains(&ext.as_str()) {
+        Some(Content::Heic)
+    } else if IMAGE_EXTENSIONS.contains(&ext.as_str()) {
+        Some(Content::Image)
+    } else if VIDEO_EXTENSIONS.contains(&ext.as_str()) {
+        Some(Content::Video)
+    } else {
+        None
+    }
+}
+
+/// Classifies a file by what it contains, see [`detect`], returns None for files that
+/// are not media.
+pub fn classify(path: &Path) -> Option<MediaKind> {
+    match detect(path)? {
+        Content::Image | Content::Heic => Some(MediaKind::Image),
+        Content::Video => Some(MediaKind::Video),
+        Content::Other => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(Path::new("/a/photo.JPG")), Some(MediaKind::Image));
+        assert_eq!(classify(Path::new("/a/photo.heic")), Some(MediaKind::Image));
+        assert_eq!(classify(Path::new("/a/clip.MOV")), Some(MediaKind::Video));
+        assert_eq!(classify(Path::new("/a/clip.mkv")), Some(MediaKind::Video));
+        assert_eq!(classify(Path::new("/a/notes.txt")), None);
+        assert_eq!(classify(Path::new("/a/jpg")), None);
+    }
+
+    #[test]
+    fn test_classify_by_contents() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/problematic-files");
+        let examples = [
+            ("mp4-without-extension", Some(MediaKind::Video)),
+            ("mkv-without-extension", Some(MediaKind::Video)),
+            ("avi-with-jpg-extension.jpg", Some(MediaKind::Video)),
+            ("wmv-with-png-extension.png", Some(MediaKind::Video)),
+            ("heic-with-jpg-extension.jpg", Some(MediaKind::Image)),
+            ("jpg-with-png-extension.png", Some(MediaKind::Image)),
+        ];
+        for (name, kind) in examples {
+            assert_eq!(classify(&fixtures.join(name)), kind, "{}", name);
+        }
+        assert_eq!(
+            detect(&fixtures.join("heic-with-jpg-extension.jpg")),
+            Some(Content::Heic)
+        );
+
+        // Audio in a video container is not media
+        let temp = tempfile::TempDir::new().unwrap();
+        let audio = temp.path().join("song.mp4");
+        std::fs::write(&audio, b"\0\0\0\x18ftypM4A \0\0\0\0M4A isom").unwrap();
+        assert_eq!(classify(&audio), None);
+
+        // Weak image signatures count only with a matching extension
+        let notes = temp.path().join("notes.txt");
+        std::fs::write(&notes, b"BMW service history").unwrap();
+        assert_eq!(classify(&notes), None);
+        let bitmap = temp.path().join("scan.bmp");
+        std::fs::write(&bitmap, b"BM\0\0\0\0").unwrap();
+        assert_eq!(classify(&bitmap), Some(MediaKind::Image));
+    }
+
+    #[test]
+    fn test_kind_names() {
+        for kind in [MediaKind::Image, MediaKind::Video] {
+            assert_eq!(MediaKind::parse(kind.as_str()), Some(kind));
+        }
+        assert_eq!(MediaKind::parse("audio"), None);
+    }
+}
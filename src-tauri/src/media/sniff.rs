@@ -0,0 +1,240 @@
+//! Detecting media types from file contents, for mislabeled and extensionless files.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Bytes read from the start of a file, enough for the signatures below.
+const HEADER_LEN: u64 = 64;
+
+/// ISO base media brands of still images, checked before the HEIF brands since
+/// AVIF files list `mif1` too, and Canon raw files list `isom`.
+const IMAGE_BRANDS: &[&[u8; 4]] = &[b"avif", b"avis", b"crx "];
+const HEIF_BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1",
+];
+const VIDEO_BRANDS: &[&[u8; 4]] = &[
+    b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"qt  ", b"M4V ",
+    b"M4VH", b"M4VP", b"3gp4", b"3gp5", b"3gp6", b"3gg6", b"3g2a", b"dash", b"mmp4", b"f4v ",
+    b"MSNV", b"XAVC",
+];
+/// Audio files use the same container, often listing video brands as compatible.
+const AUDIO_BRANDS: &[&[u8; 4]] = &[b"M4A ", b"M4B ", b"M4P "];
+
+/// Header of ASF containers, i.e. WMV files.
+const ASF_GUID: [u8; 16] = [
+    0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+const EBML_MAGIC: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+
+/// Image formats whose signatures are long enough to trust over the extension.
+const STRONG_IMAGE_FORMATS: &[image::ImageFormat] = &[
+    image::ImageFormat::Jpeg,
+    image::ImageFormat::Png,
+    image::ImageFormat::Gif,
+    image::ImageFormat::WebP,
+    image::ImageFormat::Tiff,
+];
+
+/// What the contents of a file are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Content {
+    /// An image the image crate or the viewer reads directly.
+    Image,
+    /// HEIC or another HEIF image, which needs its embedded thumbnail or ffmpeg.
+    Heic,
+    Video,
+    /// A known format that is not media, e.g. audio.
+    Other,
+}
+
+/// Detects the type of a file from its first bytes. Returns None if the file cannot
+/// be read or has no known signature, or only a weak one such as BMP's, so callers
+/// fall back to the extension.
+pub fn sniff(path: &Path) -> Option<Content> {
+    let mut header = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(HEADER_LEN)
+        .read_to_end(&mut header)
+        .ok()?;
+    sniff_bytes(&header)
+}
+
+fn sniff_bytes(header: &[u8]) -> Option<Content> {
+    if header.get(4..8) == Some(b"ftyp") {
+        return ftyp_content(header);
+    }
+    if header.starts_with(&EBML_MAGIC) || header.starts_with(&ASF_GUID) {
+        // Matroska and WebM, or WMV
+        return Some(Content::Video);
+    }
+    if header.starts_with(b"RIFF") {
+        return match header.get(8..12) {
+            Some(b"AVI ") => Some(Content::Video),
+            Some(b"WEBP") => Some(Content::Image),
+            _ => Some(Content::Other),
+        };
+    }
+    if header.starts_with(b"FLV\x01") {
+        return Some(Content::Video);
+    }
+    // Old QuickTime files start with their atoms instead of a file type box
+    if matches!(header.get(4..8), Some(b"moov" | b"mdat" | b"wide")) {
+        return Some(Content::Video);
+    }
+    // Short magics like `BM` or `P1` also start plain text files, files with them are
+    // left to their extension
+    let format = image::guess_format(header).ok()?;
+    STRONG_IMAGE_FORMATS
+        .contains(&format)
+        .then_some(Content::Image)
+}
+
+/// Reads the brands of a file type box, the major brand taking precedence over
+/// the compatible ones.
+fn ftyp_content(header: &[u8]) -> Option<Content> {
+    let size = u32::from_be_bytes(header[0..4].try_into().ok()?) as usize;
+    // Truncated files may end within the box
+    let end = size.max(16).min(header.len());
+    let major = header.get(8..12)?;
+    // The minor version at 12..16 is not a brand
+    let compatible = header.get(16..end).unwrap_or_default();
+
+    let brand_content = |brand: &[u8]| {
+        let is = |brands: &[&[u8; 4]]| brands.iter().any(|b| &b[..] == brand);
+        if is(IMAGE_BRANDS) {
+            Some(Content::Image)
+        } else if is(HEIF_BRANDS) {
+            Some(Content::Heic)
+        } else if is(VIDEO_BRANDS) {
+            Some(Content::Video)
+        } else if is(AUDIO_BRANDS) {
+            Some(Content::Other)
+        } else {
+            None
+        }
+    };
+    brand_content(major).or_else(|| {
+        let brands: Vec<&[u8]> = compatible.chunks_exact(4).collect();
+        [Content::Image, Content::Heic, Content::Video]
+            .into_iter()
+            .find(|content| brands.iter().any(|b| brand_content(b) == Some(*content)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name)
+    }
+
+    fn ftyp(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+        let size = 16 + 4 * compatible.len() as u32;
+        let mut header = size.to_be_bytes().to_vec();
+        header.extend_from_slice(b"ftyp");
+        header.extend_from_slice(major);
+        header.extend_from_slice(&[0; 4]);
+        for brand in compatible {
+            header.extend_from_slice(*brand);
+        }
+        header
+    }
+
+    // -----------------------------------------------------------------------
+    // signatures
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_sniff_ftyp_brands() {
+        assert_eq!(
+            sniff_bytes(&ftyp(b"heic", &[b"mif1", b"heic"])),
+            Some(Content::Heic)
+        );
+        assert_eq!(sniff_bytes(&ftyp(b"mif1", &[b"heic"])), Some(Content::Heic));
+        assert_eq!(
+            sniff_bytes(&ftyp(b"avif", &[b"avif", b"mif1"])),
+            Some(Content::Image)
+        );
+        assert_eq!(
+            sniff_bytes(&ftyp(b"crx ", &[b"crx ", b"isom"])),
+            Some(Content::Image)
+        );
+        assert_eq!(
+            sniff_bytes(&ftyp(b"M4A ", &[b"mp42", b"isom"])),
+            Some(Content::Other)
+        );
+        assert_eq!(
+            sniff_bytes(&ftyp(b"qt  ", &[b"qt  "])),
+            Some(Content::Video)
+        );
+        // Unknown major brands are decided by the compatible ones
+        assert_eq!(
+            sniff_bytes(&ftyp(b"abcd", &[b"mif1", b"isom"])),
+            Some(Content::Heic)
+        );
+        assert_eq!(sniff_bytes(&ftyp(b"abcd", &[b"abcd"])), None);
+    }
+
+    #[test]
+    fn test_sniff_without_signature() {
+        assert_eq!(sniff_bytes(b"plain text"), None);
+        assert_eq!(sniff_bytes(b""), None);
+        assert_eq!(sniff(&fixture("missing.mp4")), None);
+    }
+
+    #[test]
+    fn test_sniff_ignores_weak_image_signatures() {
+        assert_eq!(sniff_bytes(b"BMW service history"), None);
+        assert_eq!(sniff_bytes(b"P1 is the first priority"), None);
+        assert_eq!(sniff_bytes(&[0, 0, 1, 0, 1, 0]), None);
+        assert_eq!(sniff_bytes(b"\x89PNG\r\n\x1a\n"), Some(Content::Image));
+        assert_eq!(sniff_bytes(b"II*\0"), Some(Content::Image));
+    }
+
+    #[test]
+    fn test_sniff_truncated_ftyp() {
+        assert_eq!(sniff_bytes(b"\0\0\0\x20ftyp"), None);
+        assert_eq!(sniff_bytes(b"\0\0\0\x20ftypqt  "), Some(Content::Video));
+        assert_eq!(sniff_bytes(b"\0\0\0\x20ftypabcd\0\0"), None);
+    }
+
+    // -----------------------------------------------------------------------
+    // fixtures
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_sniff_real_files() {
+        let examples = [
+            ("file_example_MP4_480_1_5MG.mp4", Content::Video),
+            ("file_example_MOV_1280_1_4MB.mov", Content::Video),
+            ("file_example_JPG_100kB.jpg", Content::Image),
+            ("file_example_WEBP_250kB.webp", Content::Image),
+        ];
+        for (name, content) in examples {
+            let path = fixture("file-examples.com").join(name);
+            assert_eq!(sniff(&path), Some(content), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_sniff_mislabeled_files() {
+        let examples = [
+            ("jpg-with-png-extension.png", Content::Image),
+            ("heic-with-jpg-extension.jpg", Content::Heic),
+            ("mp4-without-extension", Content::Video),
+            ("mkv-without-extension", Content::Video),
+            ("avi-with-jpg-extension.jpg", Content::Video),
+            ("wmv-with-png-extension.png", Content::Video),
+        ];
+        for (name, content) in examples {
+            let path = fixture("problematic-files").join(name);
+            assert_eq!(sniff(&path), Some(content), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,589 @@
+//! Watches the open directory and the root folders, so the grid and the thumbnail
+//! cache follow files added, removed or edited by other applications.
+//!
+//! Raw filesystem events are debounced, since copying or saving a single file often
+//! produces a burst of them, and then resolved against what exists on disk.
+
+use super::{classify, media_entry, sidecar_owners, IgnoreRules, ListOptions, MediaEntry};
+use crate::catalog;
+use crate::thumbnail::{self, normalize_path, ThumbnailService};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter};
+
+/// Quiet period after the last event before changes are reported.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+/// Longest a change waits while events keep coming, e.g. during a large copy.
+const MAX_DELAY: Duration = Duration::from_secs(2);
+/// How long to wait for events while nothing is pending.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// The active watcher. Replacing or dropping it stops its event thread.
+static WATCHER: Mutex<Option<RecommendedWatcher>> = Mutex::new(None);
+
+/// Paths touched since the last flush, see [`Debouncer::record`].
+#[derive(Default)]
+struct Debouncer {
+    /// Path → whether the first event seen for it created the path.
+    pending: HashMap<PathBuf, bool>,
+    first: Option<Instant>,
+    last: Option<Instant>,
+}
+
+impl Debouncer {
+    /// Records the paths of an event. Only the first event of a path matters:
+    /// a path created and then modified is new, one modified and then recreated
+    /// (as editors do when saving) has changed.
+    fn record(&mut self, event: &Event, now: Instant) {
+        match event.kind {
+            EventKind::Access(_) | EventKind::Other => return,
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let [from, to] = &event.paths[..] {
+                    self.pending.entry(from.clone()).or_insert(false);
+                    self.pending.entry(to.clone()).or_insert(true);
+                }
+            }
+            _ => {
+                let created = matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                );
+                for path in &event.paths {
+                    self.pending.entry(path.clone()).or_insert(created);
+                }
+            }
+        }
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+    }
+
+    /// Time left until [`Debouncer::is_due`].
+    fn timeout(&self, now: Instant) -> Duration {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => (last + DEBOUNCE)
+                .min(first + MAX_DELAY)
+                .saturating_duration_since(now),
+            _ => IDLE_TIMEOUT,
+        }
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        !self.pending.is_empty() && self.timeout(now).is_zero()
+    }
+
+    fn take(&mut self) -> HashMap<PathBuf, bool> {
+        self.first = None;
+        self.last = None;
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Media changes resolved from a batch of events.
+#[derive(Debug, Default, PartialEq)]
+struct Changes {
+    added: Vec<PathBuf>,
+    changed: Vec<PathBuf>,
+    /// Removed media files, and paths that may have been directories.
+    removed: Vec<PathBuf>,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Returns true for paths inside the cache or a portable cache, which hold images too.
+fn is_ignored(path: &Path, ignored: &[PathBuf]) -> bool {
+    path.components()
+        .any(|c| c.as_os_str() == thumbnail::PORTABLE_CACHE_DIR)
+        || ignored.iter().any(|dir| path.starts_with(dir))
+}
+
+/// Collects the media files below a directory that appeared at once, e.g. by moving it in.
+fn media_below(dir: &Path, ignored: &[PathBuf], files: &mut Vec<PathBuf>) {
+    let mut dirs = vec![(dir.to_path_buf(), IgnoreRules::for_dir(dir))];
+    while let Some((dir, rules)) = dirs.pop() {
+        if rules.is_hidden() {
+            continue;
+        }
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let is_dir = path.is_dir();
+            if is_ignored(&path, ignored) || rules.is_ignored(&path, is_dir) {
+                continue;
+            }
+            if is_dir {
+                let child = rules.child(&path);
+                dirs.push((path, child));
+            } else if classify(&path).is_some() {
+                files.push(path);
+            }
+        }
+    }
+}
+
+/// Sorts pending paths into changes by looking at what exists on disk now.
+fn resolve(pending: HashMap<PathBuf, bool>, ignored: &[PathBuf]) -> Changes {
+    let mut changes = Changes::default();
+    // Events often come in bursts within one directory
+    let mut rules: HashMap<PathBuf, IgnoreRules> = HashMap::new();
+    for (path, created) in pending {
+        if is_ignored(&path, ignored) {
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            let rules = rules
+                .entry(parent.to_path_buf())
+                .or_insert_with(|| IgnoreRules::for_dir(parent));
+            if rules.is_ignored(&path, path.is_dir()) {
+                continue;
+            }
+        }
+        let owners = sidecar_owners(&path);
+        if !owners.is_empty() {
+            // Edited sidecars change the rating and tags of their media files
+            changes.changed.extend(owners);
+        } else if path.is_dir() {
+            if created {
+                media_below(&path, ignored, &mut changes.added);
+            }
+        } else if path.exists() {
+            if classify(&path).is_some() {
+                if created {
+                    changes.added.push(path);
+                } else {
+                    changes.changed.push(path);
+                }
+            }
+        } else if !created {
+            // Gone without a trace of what it was, it may have been a directory
+            changes.removed.push(path);
+        }
+    }
+
+    changes.added.sort();
+    changes.added.dedup();
+    changes
+        .changed
+        .retain(|path| changes.added.binary_search(path).is_err());
+    changes.changed.sort();
+    changes.changed.dedup();
+    changes.removed.sort();
+    changes
+}
+
+/// Debounces the events arriving on `rx` and reports the resolved changes,
+/// until the watcher sending them is dropped.
+fn run(
+    rx: Receiver<notify::Result<Event>>,
+    ignored: Vec<PathBuf>,
+    mut on_changes: impl FnMut(Changes),
+) {
+    let mut debouncer = Debouncer::default();
+    loop {
+        match rx.recv_timeout(debouncer.timeout(Instant::now())) {
+            Ok(Ok(event)) => debouncer.record(&event, Instant::now()),
+            Ok(Err(e)) => eprintln!("[watcher] {}", e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if debouncer.is_due(Instant::now()) {
+            let changes = resolve(debouncer.take(), &ignored);
+            if !changes.is_empty() {
+                on_changes(changes);
+            }
+        }
+    }
+}
+
+/// Starts watching `targets` on a background thread that reports changes to `on_changes`.
+/// Watching stops when the returned watcher is dropped.
+fn spawn(
+    targets: &[(PathBuf, RecursiveMode)],
+    ignored: Vec<PathBuf>,
+    on_changes: impl FnMut(Changes) + Send + 'static,
+) -> Result<RecommendedWatcher, String> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })
+    .map_err(|e| format!("Failed to create watcher: {}", e))?;
+
+    for (path, mode) in targets {
+        watcher.watch(path, *mode).map_err(|e| {
+            format!(
+                "Failed to watch {}: {}",
+                normalize_path(&path.to_string_lossy()),
+                e
+            )
+        })?;
+    }
+
+    std::thread::spawn(move || run(rx, ignored, on_changes));
+    Ok(watcher)
+}
+
+/// Updates the catalog and the cache and tells the frontend about a batch of changes.
+fn apply(
+    changes: Changes,
+    roots: &[String],
+    cache_base_dir: &Path,
+    session_id: u64,
+    app: &AppHandle,
+) {
+    let updated: Vec<PathBuf> = changes
+        .added
+        .iter()
+        .chain(&changes.changed)
+        .cloned()
+        .collect();
+    // Albums are evaluated again for the touched files only
+    let album_changes = catalog::with(|c| {
+        c.update(roots, &updated, &changes.removed, cache_base_dir)?;
+        let scope: Vec<String> = updated
+            .iter()
+            .map(|p| normalize_path(&p.to_string_lossy()))
+            .collect();
+        c.refresh_albums(Some(&scope))
+    });
+    if album_changes.is_ok() && !(updated.is_empty() && changes.removed.is_empty()) {
+        // Stats of the folders above these paths are summed again when asked for
+        let touched: Vec<String> = updated
+            .iter()
+            .chain(&changes.removed)
+            .map(|p| normalize_path(&p.to_string_lossy()))
+            .collect();
+        let _ = app.emit("folder-stats-changed", touched);
+    }
+    match album_changes {
+        Ok(album_changes) if !album_changes.is_empty() => {
+            let _ = app.emit("albums-changed", album_changes);
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("[watcher] Failed to update catalog: {}", e),
+    }
+
+    let paths = |paths: &[PathBuf]| -> Vec<String> {
+        paths
+            .iter()
+            .map(|p| normalize_path(&p.to_string_lossy()))
+            .collect()
+    };
+
+    if !changes.removed.is_empty() {
+        // One pass over the manifest of each cache, deleting a folder removes many paths
+        let mut by_cache: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+        for path in &changes.removed {
+            by_cache
+                .entry(thumbnail::cache_dir_for(path, cache_base_dir))
+                .or_default()
+                .push(normalize_path(&path.to_string_lossy()));
+        }
+        for (cache, prefixes) in by_cache {
+            if let Err(e) = thumbnail::cleanup_for_prefixes(&prefixes, &cache.to_string_lossy()) {
+                eprintln!("[watcher] Failed to clean up thumbnails: {}", e);
+            }
+        }
+        let _ = app.emit("media-removed", paths(&changes.removed));
+    }
+
+    let options = ListOptions {
+        cache_base_dir: Some(cache_base_dir.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    let entries = |paths: &[PathBuf]| -> Vec<MediaEntry> {
+        paths
+            .iter()
+            .filter_map(|p| media_entry(p, &options))
+            .collect()
+    };
+    if !changes.added.is_empty() {
+        let _ = app.emit("media-added", entries(&changes.added));
+    }
+    if !changes.changed.is_empty() {
+        let _ = app.emit("media-changed", entries(&changes.changed));
+    }
+
+    // Thumbnails are generated per directory, which may have its own portable cache
+    let mut by_dir: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for path in changes.added.into_iter().chain(changes.changed) {
+        if let Some(dir) = path.parent() {
+            by_dir.entry(dir.to_path_buf()).or_default().push(path);
+        }
+    }
+    for (dir, files) in by_dir {
+        let cache_base_dir = cache_base_dir.to_string_lossy().to_string();
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) =
+                ThumbnailService::generate_for_files(dir, files, session_id, cache_base_dir, app)
+                    .await
+            {
+                eprintln!("[watcher] Failed to generate thumbnails: {}", e);
+            }
+        });
+    }
+}
+
+/// Watches the root folders recursively, and `open_dir` if it lies outside of them,
+/// replacing the previous watch. Changes are emitted as `media-added`, `media-changed`
+/// and `media-removed` events. Thumbnails of new and changed files are generated,
+/// reported with `session_id` like those of [`ThumbnailService::generate_for_dir`],
+/// thumbnails of removed files are deleted and the catalog follows all changes.
+pub fn watch(
+    open_dir: Option<&Path>,
+    roots: &[String],
+    cache_base_dir: &Path,
+    session_id: u64,
+    app: AppHandle,
+) -> Result<(), String> {
+    let mut targets: Vec<(PathBuf, RecursiveMode)> = roots
+        .iter()
+        .map(PathBuf::from)
+        .filter(|root| root.is_dir())
+        .map(|root| (root, RecursiveMode::Recursive))
+        .collect();
+    if let Some(dir) = open_dir {
+        if dir.is_dir() && !targets.iter().any(|(root, _)| dir.starts_with(root)) {
+            targets.push((dir.to_path_buf(), RecursiveMode::NonRecursive));
+        }
+    }
+
+    let cache = cache_base_dir.to_path_buf();
+    let roots = roots.to_vec();
+    let watcher = spawn(&targets, vec![cache.clone()], move |changes| {
+        apply(changes, &roots, &cache, session_id, &app)
+    })?;
+    *WATCHER.lock().unwrap_or_else(|e| e.into_inner()) = Some(watcher);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::{IGNORE_FILE, NOMEDIA_FILE};
+    use notify::event::{CreateKind, DataChange, RemoveKind};
+    use tempfile::TempDir;
+
+    fn event(kind: EventKind, paths: &[&str]) -> Event {
+        let mut event = Event::new(kind);
+        for path in paths {
+            event = event.add_path(PathBuf::from(path));
+        }
+        event
+    }
+
+    // ---------------------------------------------------------------
+    // Debouncer
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_debouncer_first_event_decides() {
+        let now = Instant::now();
+        let mut debouncer = Debouncer::default();
+        debouncer.record(
+            &event(EventKind::Create(CreateKind::File), &["/a.jpg"]),
+            now,
+        );
+        debouncer.record(
+            &event(
+                EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+                &["/a.jpg"],
+            ),
+            now,
+        );
+        debouncer.record(
+            &event(EventKind::Remove(RemoveKind::File), &["/b.jpg"]),
+            now,
+        );
+        debouncer.record(
+            &event(EventKind::Create(CreateKind::File), &["/b.jpg"]),
+            now,
+        );
+        debouncer.record(
+            &event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &["/c.jpg", "/d.jpg"],
+            ),
+            now,
+        );
+
+        let pending = debouncer.take();
+        assert!(pending[Path::new("/a.jpg")]);
+        assert!(!pending[Path::new("/b.jpg")]);
+        assert!(!pending[Path::new("/c.jpg")]);
+        assert!(pending[Path::new("/d.jpg")]);
+        assert!(debouncer.take().is_empty());
+    }
+
+    #[test]
+    fn test_debouncer_waits_for_quiet_period() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::default();
+        assert!(!debouncer.is_due(start));
+        assert_eq!(debouncer.timeout(start), IDLE_TIMEOUT);
+
+        debouncer.record(
+            &event(EventKind::Create(CreateKind::File), &["/a.jpg"]),
+            start,
+        );
+        assert!(!debouncer.is_due(start + DEBOUNCE / 2));
+        assert!(debouncer.is_due(start + DEBOUNCE));
+
+        // Access events do not postpone the flush
+        debouncer.record(
+            &event(
+                EventKind::Access(notify::event::AccessKind::Any),
+                &["/a.jpg"],
+            ),
+            start + DEBOUNCE,
+        );
+        assert!(debouncer.is_due(start + DEBOUNCE));
+    }
+
+    #[test]
+    fn test_debouncer_flushes_continuous_events() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::default();
+        let mut now = start;
+        while now < start + MAX_DELAY {
+            debouncer.record(
+                &event(EventKind::Create(CreateKind::File), &["/a.jpg"]),
+                now,
+            );
+            now += DEBOUNCE / 2;
+        }
+        assert!(debouncer.is_due(start + MAX_DELAY));
+    }
+
+    // ---------------------------------------------------------------
+    // resolve
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_resolve_against_disk() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        fs::write(dir.join("new.jpg"), b"jpg").unwrap();
+        fs::write(dir.join("edited.mp4"), b"mp4").unwrap();
+        fs::write(dir.join("notes.txt"), b"txt").unwrap();
+        fs::write(dir.join("rated.jpg"), b"jpg").unwrap();
+        fs::write(dir.join("rated.xmp"), b"xmp").unwrap();
+        fs::create_dir_all(dir.join("moved").join("nested")).unwrap();
+        fs::write(dir.join("moved").join("b.png"), b"png").unwrap();
+        fs::write(dir.join("moved").join("nested").join("c.png"), b"png").unwrap();
+        fs::create_dir_all(dir.join(".mv").join("ab")).unwrap();
+        fs::write(dir.join(".mv").join("ab").join("ab12.jpg"), b"jpg").unwrap();
+
+        let pending: HashMap<PathBuf, bool> = [
+            (dir.join("new.jpg"), true),
+            (dir.join("edited.mp4"), false),
+            (dir.join("notes.txt"), true),
+            (dir.join("rated.xmp"), false),
+            (dir.join("deleted.jpg"), false),
+            (dir.join("temporary.jpg"), true),
+            (dir.join("moved"), true),
+            (dir.join("moved").join("b.png"), true),
+            (dir.join(".mv").join("ab").join("ab12.jpg"), true),
+        ]
+        .into_iter()
+        .collect();
+
+        let changes = resolve(pending, &[]);
+        assert_eq!(
+            changes,
+            Changes {
+                added: vec![
+                    dir.join("moved").join("b.png"),
+                    dir.join("moved").join("nested").join("c.png"),
+                    dir.join("new.jpg"),
+                ],
+                changed: vec![dir.join("edited.mp4"), dir.join("rated.jpg")],
+                removed: vec![dir.join("deleted.jpg")],
+            }
+        );
+    }
+
+    #[test]
+    fn test_resolve_ignores_cache_dir() {
+        let temp = TempDir::new().unwrap();
+        let cache = temp.path().join("thumbnails");
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(cache.join("a.jpg"), b"jpg").unwrap();
+
+        let pending = [(cache.join("a.jpg"), true)].into_iter().collect();
+        assert!(resolve(pending, &[cache]).is_empty());
+    }
+
+    #[test]
+    fn test_resolve_respects_ignore_rules() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("@eaDir")).unwrap();
+        fs::create_dir_all(dir.join("moved/private")).unwrap();
+        fs::write(dir.join(IGNORE_FILE), "@eaDir/\n*.tmp.jpg\n").unwrap();
+        fs::write(dir.join("@eaDir/a.jpg"), b"jpg").unwrap();
+        fs::write(dir.join("b.tmp.jpg"), b"jpg").unwrap();
+        fs::write(dir.join("moved/c.jpg"), b"jpg").unwrap();
+        fs::write(dir.join("moved/private/d.jpg"), b"jpg").unwrap();
+        fs::write(dir.join("moved/private").join(NOMEDIA_FILE), b"").unwrap();
+
+        let pending = [
+            (dir.join("@eaDir/a.jpg"), true),
+            (dir.join("b.tmp.jpg"), true),
+            (dir.join("moved"), true),
+        ]
+        .into_iter()
+        .collect();
+        let changes = resolve(pending, &[]);
+        assert_eq!(changes.added, [dir.join("moved/c.jpg")]);
+    }
+
+    // ---------------------------------------------------------------
+    // spawn
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_spawn_reports_added_and_removed_files() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("photos");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("old.jpg"), b"jpg").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let watcher = spawn(
+            &[(dir.clone(), RecursiveMode::Recursive)],
+            Vec::new(),
+            move |changes| {
+                let _ = tx.send(changes);
+            },
+        )
+        .unwrap();
+
+        fs::write(dir.join("new.jpg"), b"jpg").unwrap();
+        fs::remove_file(dir.join("old.jpg")).unwrap();
+
+        // Events may be split across batches depending on timing
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        while added.is_empty() || removed.is_empty() {
+            let changes = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            added.extend(changes.added);
+            removed.extend(changes.removed);
+        }
+        let name = |p: &PathBuf| p.file_name().unwrap().to_owned();
+        assert_eq!(added.iter().map(name).collect::<Vec<_>>(), ["new.jpg"]);
+        assert_eq!(removed.iter().map(name).collect::<Vec<_>>(), ["old.jpg"]);
+
+        drop(watcher);
+    }
+}
@@ -0,0 +1,625 @@
+//! The `mv` URI scheme, serving thumbnails, previews, tiles and media files to the webview.
+//!
+//! Routes are resolved by the modules owning the files, so only files the backend
+//! handed out or files inside the configured roots can be requested:
+//! - `thumb/<name>`: cached thumbnails and variants, see [`thumbnail::find_thumbnail`].
+//! - `preview/<name>`: full-screen previews, see [`thumbnail::find_preview`].
+//! - `tiles/<id>/<level>/<col>_<row>.jpg`: deep-zoom tiles, see [`thumbnail::tile_info`].
+//! - `transcode/<name>`: browser-playable transcodes, served while they are written,
+//!   see [`thumbnail::transcode`].
+//! - `proxy/<name>`: low-resolution proxies, see [`thumbnail::generate_proxies`].
+//! - `media/<path>`: original files below one of the roots set with [`set_scope`].
+
+use crate::thumbnail;
+use percent_encoding::percent_decode_str;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tauri::http::{header, Method, Request, Response, StatusCode};
+use tauri::{Runtime, UriSchemeContext, UriSchemeResponder};
+
+/// Name of the URI scheme, URLs are built with `convertFileSrc(route, "mv")`.
+pub const SCHEME: &str = "mv";
+
+/// Largest body returned for a range request, players ask for the rest later.
+const MAX_RANGE_LEN: u64 = 4 * 1024 * 1024;
+/// Largest file returned whole to a request without a range, bodies are held in memory.
+const MAX_BODY_LEN: u64 = 64 * 1024 * 1024;
+
+/// How long a request for bytes of a growing transcode waits for them to be written.
+const GROWING_WAIT: Duration = Duration::from_secs(30);
+const GROWING_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Directories the protocol may serve files from.
+struct Scope {
+    cache_base_dir: Option<PathBuf>,
+    /// Canonicalized collection roots.
+    roots: Vec<PathBuf>,
+}
+
+impl Scope {
+    /// The cache and the portable caches of the roots, which hold thumbnails and proxies.
+    fn cache_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = self.cache_base_dir.iter().cloned().collect();
+        dirs.extend(self.roots.iter().map(|r| thumbnail::portable_cache_dir(r)));
+        dirs
+    }
+}
+
+static SCOPE: RwLock<Scope> = RwLock::new(Scope {
+    cache_base_dir: None,
+    roots: Vec::new(),
+});
+
+/// Sets the cache and the collection roots the protocol serves files from.
+/// Called by the frontend whenever the settings change.
+pub fn set_scope(cache_base_dir: &str, roots: &[String]) {
+    let mut scope = SCOPE.write().unwrap_or_else(|e| e.into_inner());
+    scope.cache_base_dir = Some(PathBuf::from(cache_base_dir));
+    scope.roots = roots
+        .iter()
+        .map(|root| fs::canonicalize(root).unwrap_or_else(|_| PathBuf::from(root)))
+        .collect();
+}
+
+/// Handles a request on the `mv` scheme. Files are read on the bounded pool of
+/// blocking threads, so slow disks don't block the webview and a grid firing
+/// hundreds of requests does not start as many threads.
+pub fn handle<R: Runtime>(
+    _ctx: UriSchemeContext<'_, R>,
+    request: Request<Vec<u8>>,
+    responder: UriSchemeResponder,
+) {
+    tauri::async_runtime::spawn_blocking(move || responder.respond(respond(&request)));
+}
+
+/// Resolves the route of a request to a file and serves it, or 404 for unknown routes.
+fn respond(request: &Request<Vec<u8>>) -> Response<Vec<u8>> {
+    // `convertFileSrc` percent-encodes the whole route, including slashes
+    let route = percent_decode_str(request.uri().path()).decode_utf8_lossy();
+    let route = route.trim_start_matches('/');
+
+    if let Some(name) = route.strip_prefix("transcode/") {
+        let cache_dirs = SCOPE.read().unwrap_or_else(|e| e.into_inner()).cache_dirs();
+        if let Some(partial) = thumbnail::growing_transcode(name, &cache_dirs) {
+            return serve_growing_file(&partial, request);
+        }
+    }
+
+    let file = {
+        let scope = SCOPE.read().unwrap_or_else(|e| e.into_inner());
+        match route.split_once('/') {
+            Some(("thumb", name)) => thumbnail::find_thumbnail(name, &scope.cache_dirs()),
+            Some(("preview", name)) => thumbnail::find_preview(name, &scope.cache_dirs()),
+            Some(("tiles", tile)) => thumbnail::resolve_tile(tile),
+            Some(("transcode", name)) => thumbnail::find_transcode(name, &scope.cache_dirs()),
+            Some(("proxy", name)) => thumbnail::find_proxy(name, &scope.cache_dirs()),
+            Some(("media", path)) => resolve_media(path, &scope.roots),
+            _ => None,
+        }
+    };
+
+    match file {
+        Some(path) => serve_file(&path, request),
+        None => status(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Resolves a media route to the file, if it lies inside one of the roots.
+/// The path is canonicalized first, so neither `..` nor symlinks can leave the roots.
+fn resolve_media(path: &str, roots: &[PathBuf]) -> Option<PathBuf> {
+    // Accept Unix paths with or without their leading slash
+    let path = if cfg!(windows) {
+        PathBuf::from(path)
+    } else {
+        Path::new("/").join(path)
+    };
+    let canonical = fs::canonicalize(path).ok()?;
+    (canonical.is_file() && roots.iter().any(|root| canonical.starts_with(root)))
+        .then_some(canonical)
+}
+
+/// Returns an empty response with the given status.
+fn status(status: StatusCode) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .body(Vec::new())
+        .unwrap_or_default()
+}
+
+/// Returns the content type for a file extension.
+fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "ico" => "image/x-icon",
+        "svg" => "image/svg+xml",
+        "avif" => "image/avif",
+        "tif" | "tiff" => "image/tiff",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "mp4" | "m4v" => "video/mp4",
+        "mov" => "video/quicktime",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        "avi" => "video/x-msvideo",
+        "wmv" => "video/x-ms-wmv",
+        "flv" => "video/x-flv",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Parses a `Range` header for a file of `len` bytes into an inclusive byte range.
+/// Only the first range of a multi-range request is honored, and at most
+/// [`MAX_RANGE_LEN`] bytes of it. Returns `None` if the range cannot be satisfied.
+fn parse_range(value: &str, len: u64) -> Option<(u64, u64)> {
+    let spec = value
+        .trim()
+        .strip_prefix("bytes=")?
+        .split(',')
+        .next()?
+        .trim();
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (len.checked_sub(suffix.min(len))?, len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(len.checked_sub(1)?),
+        ),
+    };
+    let end = end.min(start.saturating_add(MAX_RANGE_LEN - 1));
+    (start <= end && start < len).then_some((start, end))
+}
+
+/// Serves a file with validation and range support, reading at most
+/// [`MAX_BODY_LEN`] bytes into memory.
+/// Files are revalidated on every use through their ETag, since thumbnails and
+/// previews are rewritten in place when their source changes.
+fn serve_file(path: &Path, request: &Request<Vec<u8>>) -> Response<Vec<u8>> {
+    let Ok(meta) = fs::metadata(path) else {
+        return status(StatusCode::NOT_FOUND);
+    };
+    let len = meta.len();
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", modified, len);
+
+    let builder = Response::builder()
+        .header(header::CONTENT_TYPE, mime_type(path))
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header(header::ETAG, &etag)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+
+    let header_value =
+        |name: header::HeaderName| request.headers().get(name).and_then(|v| v.to_str().ok());
+    if header_value(header::IF_NONE_MATCH).is_some_and(|tags| {
+        tags.split(',')
+            .any(|tag| tag.trim() == etag || tag.trim() == "*")
+    }) {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Vec::new())
+            .unwrap_or_default();
+    }
+
+    let range = match header_value(header::RANGE) {
+        Some(value) => match parse_range(value, len) {
+            Some(range) => Some(range),
+            None => {
+                return builder
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                    .body(Vec::new())
+                    .unwrap_or_default();
+            }
+        },
+        // Larger files are answered with their start, like an open-ended range
+        None if len > MAX_BODY_LEN => Some((0, MAX_RANGE_LEN - 1)),
+        None => None,
+    };
+    let (start, end) = range.unwrap_or((0, len.saturating_sub(1)));
+    let body_len = if len == 0 { 0 } else { end - start + 1 };
+
+    let builder = match range {
+        Some(_) => builder.status(StatusCode::PARTIAL_CONTENT).header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end, len),
+        ),
+        None => builder.status(StatusCode::OK),
+    }
+    .header(header::CONTENT_LENGTH, body_len);
+
+    if request.method() == Method::HEAD {
+        return builder.body(Vec::new()).unwrap_or_default();
+    }
+
+    let body = fs::File::open(path).and_then(|mut file| {
+        file.seek(SeekFrom::Start(start))?;
+        let mut body = Vec::with_capacity(body_len as usize);
+        file.take(body_len).read_to_end(&mut body)?;
+        Ok(body)
+    });
+    match body {
+        Ok(body) => builder.body(body).unwrap_or_default(),
+        Err(e) => {
+            eprintln!("[protocol] Failed to read {}: {}", path.display(), e);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Serves a transcode that is still being written to `partial`. Ranges are answered
+/// with the bytes written so far and an unknown total length, waiting up to
+/// [`GROWING_WAIT`] for the start of the range, so players keep asking for more.
+/// Once finished, the partial file is renamed and served like any other file.
+fn serve_growing_file(partial: &Path, request: &Request<Vec<u8>>) -> Response<Vec<u8>> {
+    // `<hash>.mp4.part` becomes `<hash>.mp4`
+    let finished = partial.with_extension("");
+    if !partial.exists() {
+        return serve_file(&finished, request);
+    }
+    let start = match request.headers().get(header::RANGE) {
+        Some(value) => {
+            let start = value
+                .to_str()
+                .ok()
+                .and_then(|v| v.trim().strip_prefix("bytes="))
+                .and_then(|spec| spec.split_once('-'))
+                .and_then(|(start, _)| start.trim().parse::<u64>().ok());
+            match start {
+                Some(start) => start,
+                // Suffix ranges need the total length, which is not known yet
+                None => {
+                    return Response::builder()
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header(header::CONTENT_RANGE, "bytes */*")
+                        .body(Vec::new())
+                        .unwrap_or_default()
+                }
+            }
+        }
+        None => 0,
+    };
+
+    let deadline = Instant::now() + GROWING_WAIT;
+    let len = loop {
+        let Ok(meta) = fs::metadata(partial) else {
+            return serve_file(&finished, request);
+        };
+        if meta.len() > start {
+            break meta.len();
+        }
+        if Instant::now() >= deadline {
+            return status(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        thread::sleep(GROWING_POLL_INTERVAL);
+    };
+    let end = (len - 1).min(start.saturating_add(MAX_RANGE_LEN - 1));
+
+    let builder = Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(header::CONTENT_TYPE, mime_type(&finished))
+        .header(header::CACHE_CONTROL, "no-store")
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(header::CONTENT_RANGE, format!("bytes {}-{}/*", start, end))
+        .header(header::CONTENT_LENGTH, end - start + 1);
+    if request.method() == Method::HEAD {
+        return builder.body(Vec::new()).unwrap_or_default();
+    }
+
+    let body = fs::File::open(partial).and_then(|mut file| {
+        file.seek(SeekFrom::Start(start))?;
+        let mut body = Vec::with_capacity((end - start + 1) as usize);
+        file.take(end - start + 1).read_to_end(&mut body)?;
+        Ok(body)
+    });
+    match body {
+        Ok(body) => builder.body(body).unwrap_or_default(),
+        // Renamed since it was looked at
+        Err(_) => serve_file(&finished, request),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn request(uri: &str, headers: &[(header::HeaderName, &str)]) -> Request<Vec<u8>> {
+        let mut builder = Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(name, *value);
+        }
+        builder.body(Vec::new()).unwrap()
+    }
+
+    fn media_uri(path: &Path) -> String {
+        let encoded: String = percent_encoding::utf8_percent_encode(
+            &path.to_string_lossy(),
+            percent_encoding::NON_ALPHANUMERIC,
+        )
+        .to_string();
+        format!("mv://localhost/media%2F{}", encoded)
+    }
+
+    // ---------------------------------------------------------------
+    // parse_range
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some((0, 999)));
+        assert_eq!(parse_range("bytes=500-2000", 1000), Some((500, 999)));
+        assert_eq!(parse_range("bytes=0-9, 20-29", 1000), Some((0, 9)));
+    }
+
+    #[test]
+    fn test_parse_range_limits_range_length() {
+        let len = 3 * MAX_RANGE_LEN;
+        assert_eq!(parse_range("bytes=0-", len), Some((0, MAX_RANGE_LEN - 1)));
+        assert_eq!(
+            parse_range(&format!("bytes=10-{}", len - 1), len),
+            Some((10, 10 + MAX_RANGE_LEN - 1))
+        );
+        assert_eq!(
+            parse_range(&format!("bytes=-{}", len), len),
+            Some((0, MAX_RANGE_LEN - 1))
+        );
+        assert_eq!(parse_range("bytes=-100", len), Some((len - 100, len - 1)));
+    }
+
+    #[test]
+    fn test_parse_range_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=50-10", 1000), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("items=0-10", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+    }
+
+    // ---------------------------------------------------------------
+    // serve_file
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_serve_file_whole_and_range() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("clip.mp4");
+        let data: Vec<u8> = (0..=255).collect();
+        fs::write(&path, &data).unwrap();
+
+        let whole = serve_file(&path, &request("mv://localhost/x", &[]));
+        assert_eq!(whole.status(), StatusCode::OK);
+        assert_eq!(whole.headers()[header::CONTENT_TYPE], "video/mp4");
+        assert_eq!(whole.headers()[header::CONTENT_LENGTH], "256");
+        assert_eq!(whole.body(), &data);
+
+        let partial = serve_file(
+            &path,
+            &request("mv://localhost/x", &[(header::RANGE, "bytes=16-31")]),
+        );
+        assert_eq!(partial.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(partial.headers()[header::CONTENT_RANGE], "bytes 16-31/256");
+        assert_eq!(partial.body(), &data[16..32]);
+
+        let invalid = serve_file(
+            &path,
+            &request("mv://localhost/x", &[(header::RANGE, "bytes=300-")]),
+        );
+        assert_eq!(invalid.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(invalid.headers()[header::CONTENT_RANGE], "bytes */256");
+    }
+
+    #[test]
+    fn test_serve_file_limits_large_bodies() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("large.mp4");
+        let file = fs::File::create(&path).unwrap();
+        file.set_len(MAX_BODY_LEN + 1).unwrap();
+
+        let response = serve_file(&path, &request("mv://localhost/x", &[]));
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()[header::CONTENT_RANGE],
+            format!("bytes 0-{}/{}", MAX_RANGE_LEN - 1, MAX_BODY_LEN + 1).as_str()
+        );
+        assert_eq!(response.body().len() as u64, MAX_RANGE_LEN);
+    }
+
+    #[test]
+    fn test_serve_file_not_modified() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("thumb.jpg");
+        fs::write(&path, b"jpeg").unwrap();
+
+        let first = serve_file(&path, &request("mv://localhost/x", &[]));
+        let etag = first.headers()[header::ETAG].to_str().unwrap().to_string();
+        assert_eq!(first.headers()[header::CACHE_CONTROL], "no-cache");
+
+        let second = serve_file(
+            &path,
+            &request("mv://localhost/x", &[(header::IF_NONE_MATCH, &etag)]),
+        );
+        assert_eq!(second.status(), StatusCode::NOT_MODIFIED);
+        assert!(second.body().is_empty());
+
+        fs::write(&path, b"changed jpeg").unwrap();
+        let third = serve_file(
+            &path,
+            &request("mv://localhost/x", &[(header::IF_NONE_MATCH, &etag)]),
+        );
+        assert_eq!(third.status(), StatusCode::OK);
+    }
+
+    // ---------------------------------------------------------------
+    // serve_growing_file
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_serve_growing_file_waits_for_bytes() {
+        let temp = TempDir::new().unwrap();
+        let partial = temp.path().join("0123456789abcdef.mp4.part");
+        fs::write(&partial, [1u8; 100]).unwrap();
+
+        let response = serve_growing_file(&partial, &request("mv://localhost/x", &[]));
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-99/*");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "video/mp4");
+        assert_eq!(response.body().len(), 100);
+
+        // Bytes past the end are served once written
+        let writer = {
+            let partial = partial.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                let mut file = fs::OpenOptions::new().append(true).open(partial).unwrap();
+                std::io::Write::write_all(&mut file, &[2u8; 50]).unwrap();
+            })
+        };
+        let response = serve_growing_file(
+            &partial,
+            &request("mv://localhost/x", &[(header::RANGE, "bytes=100-")]),
+        );
+        writer.join().unwrap();
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 100-149/*");
+        assert_eq!(response.body(), &[2u8; 50]);
+    }
+
+    #[test]
+    fn test_serve_growing_file_after_it_finished() {
+        let temp = TempDir::new().unwrap();
+        let partial = temp.path().join("0123456789abcdef.mp4.part");
+        let data: Vec<u8> = (0..=255).collect();
+        fs::write(temp.path().join("0123456789abcdef.mp4"), &data).unwrap();
+
+        let response = serve_growing_file(
+            &partial,
+            &request("mv://localhost/x", &[(header::RANGE, "bytes=200-")]),
+        );
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()[header::CONTENT_RANGE],
+            "bytes 200-255/256"
+        );
+        assert_eq!(response.body(), &data[200..]);
+
+        let suffix = request("mv://localhost/x", &[(header::RANGE, "bytes=-10")]);
+        assert_eq!(serve_growing_file(&partial, &suffix).body(), &data[246..]);
+        // Suffix ranges of a growing file have no known end
+        fs::write(&partial, [1u8; 100]).unwrap();
+        assert_eq!(
+            serve_growing_file(&partial, &suffix).status(),
+            StatusCode::RANGE_NOT_SATISFIABLE
+        );
+    }
+
+    // ---------------------------------------------------------------
+    // resolve_media
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_resolve_media_inside_roots_only() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("photos");
+        fs::create_dir_all(root.join("2024")).unwrap();
+        fs::write(root.join("2024").join("a.jpg"), b"a").unwrap();
+        fs::write(temp.path().join("secret.txt"), b"s").unwrap();
+        let roots = vec![fs::canonicalize(&root).unwrap()];
+        let route = |path: PathBuf| path.to_string_lossy().trim_start_matches('/').to_string();
+
+        assert!(resolve_media(&route(root.join("2024").join("a.jpg")), &roots).is_some());
+        assert_eq!(
+            resolve_media(&route(temp.path().join("secret.txt")), &roots),
+            None
+        );
+        assert_eq!(
+            resolve_media(&route(root.join("..").join("secret.txt")), &roots),
+            None
+        );
+        assert_eq!(
+            resolve_media(&route(root.join("missing.jpg")), &roots),
+            None
+        );
+        assert_eq!(resolve_media(&route(root.clone()), &roots), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_media_rejects_symlinks_leaving_roots() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("photos");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(temp.path().join("secret.txt"), b"s").unwrap();
+        std::os::unix::fs::symlink(temp.path().join("secret.txt"), root.join("link.jpg")).unwrap();
+        let roots = vec![fs::canonicalize(&root).unwrap()];
+        let route = root.join("link.jpg");
+
+        assert_eq!(
+            resolve_media(route.to_string_lossy().trim_start_matches('/'), &roots),
+            None
+        );
+    }
+
+    // ---------------------------------------------------------------
+    // respond
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_respond_routes() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("photos");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.jpg"), b"original").unwrap();
+        fs::write(temp.path().join("secret.txt"), b"s").unwrap();
+        set_scope(
+            &temp.path().join("cache").to_string_lossy(),
+            &[root.to_string_lossy().to_string()],
+        );
+
+        let media = respond(&request(&media_uri(&root.join("a.jpg")), &[]));
+        assert_eq!(media.status(), StatusCode::OK);
+        assert_eq!(media.body(), b"original");
+
+        let outside = respond(&request(&media_uri(&temp.path().join("secret.txt")), &[]));
+        assert_eq!(outside.status(), StatusCode::NOT_FOUND);
+
+        for uri in [
+            "mv://localhost/thumb%2F0123456789abcdef.jpg",
+            "mv://localhost/preview%2F..%2Fmanifest.json",
+            "mv://localhost/proxy%2F0123456789abcdef_proxy.mp4",
+            "mv://localhost/unknown%2Fa.jpg",
+            "mv://localhost/",
+        ] {
+            assert_eq!(
+                respond(&request(uri, &[])).status(),
+                StatusCode::NOT_FOUND,
+                "{}",
+                uri
+            );
+        }
+    }
+}
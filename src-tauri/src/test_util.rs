@@ -0,0 +1,10 @@
+//! Helpers shared by the tests of several modules.
+
+use std::fs;
+use std::path::Path;
+
+/// Writes a black PNG of the given size, creating its parent directories.
+pub fn write_png(path: &Path, width: u32, height: u32) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    image::RgbImage::new(width, height).save(path).unwrap();
+}
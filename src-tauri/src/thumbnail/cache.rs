@@ -1,14 +1,172 @@
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 #[cfg(test)]
 use std::cell::RefCell;
 
-static MANIFEST_LOCK: Mutex<()> = Mutex::new(());
+/// Cache directories locked by this process, see [`CacheLock`].
+static LOCKED_DIRS: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Notified whenever a directory is removed from [`LOCKED_DIRS`].
+static LOCKED_DIRS_RELEASED: Condvar = Condvar::new();
+
+/// How long to wait for another process to release the cache lock.
+const CACHE_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval between attempts to acquire the cache lock.
+const CACHE_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Number of bytes read from the start and the end of a file to fingerprint it.
+const FINGERPRINT_CHUNK: u64 = 16 * 1024;
+
+/// Extension of temporary files created by [`write_atomic`].
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Minimum age before a temporary file is considered abandoned, see [`is_abandoned_temp`].
+const STALE_TEMP_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// Name of the directory holding a portable cache at the root of a collection.
+pub const PORTABLE_CACHE_DIR: &str = ".mv";
+
+/// Number of leading hash characters naming the shard directory of a thumbnail.
+const SHARD_PREFIX_LEN: usize = 2;
+
+/// Edge lengths of the additional thumbnails stored next to the default one,
+/// for small grid sizes and HiDPI screens.
+pub const VARIANT_SIZES: &[u32] = &[256, 1024];
+
+/// Directory inside the cache holding full-screen previews, see [`preview_path`].
+const PREVIEW_DIR: &str = "previews";
+
+/// Directory inside the cache holding transcoded videos, see [`transcode_path`].
+const TRANSCODE_DIR: &str = "transcodes";
+
+/// Directory inside the cache holding deep-zoom tile pyramids, see [`tile_pyramid_dir`].
+const TILE_DIR: &str = "tiles";
+
+/// Cache directories already migrated to the sharded layout by this process.
+static MIGRATED_DIRS: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Fingerprint index of the manifest of each cache directory, see [`FingerprintIndex`].
+static FINGERPRINT_INDEXES: Mutex<Option<HashMap<PathBuf, FingerprintIndex>>> = Mutex::new(None);
+
+/// Normalized collection roots whose portable caches are used, see [`set_roots`].
+static ROOTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Counter making temporary file names unique within this process.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A manifest record describing a cached thumbnail.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RawManifestEntry")]
+pub(crate) struct ManifestEntry {
+    /// Normalized path of the source file, relative to the collection root
+    /// for portable caches, see [`source_key`].
+    pub source: String,
+    /// Content fingerprint of the source at generation time, see [`fingerprint`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Set if no thumbnail could be generated, in which case there is no thumbnail file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<Failure>,
+    /// Set once a low-resolution proxy was generated, see [`proxy_path`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub proxy: bool,
+    /// Set once a video was probed for a proxy, see [`recorded_probe`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe: Option<VideoProbe>,
+}
+
+/// Properties deciding whether a video gets a proxy, recorded so unchanged videos are
+/// not probed with ffprobe again.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VideoProbe {
+    /// Content fingerprint of the video when it was probed, see [`fingerprint`].
+    pub fingerprint: String,
+    /// Dimensions of the video stream, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<(u32, u32)>,
+    /// Overall bitrate in bits per second, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bit_rate: Option<u64>,
+}
+
+/// A failed thumbnail generation, recorded so the file is not retried until it changes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Failure {
+    /// Status reported to the frontend, e.g. "error" or "unsupported".
+    pub status: String,
+    /// Why generation failed.
+    pub reason: String,
+    /// Modification time of the source in milliseconds since the Unix epoch.
+    pub mtime: u64,
+    /// Size of the source in bytes.
+    pub size: u64,
+}
+
+/// On-disk representation of a manifest entry.
+/// Older manifests map the hash directly to the source path.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawManifestEntry {
+    Legacy(String),
+    Entry {
+        source: String,
+        #[serde(default)]
+        fingerprint: Option<String>,
+        #[serde(default)]
+        failure: Option<Failure>,
+        #[serde(default)]
+        proxy: bool,
+        #[serde(default)]
+        probe: Option<VideoProbe>,
+    },
+}
+
+impl From<RawManifestEntry> for ManifestEntry {
+    fn from(raw: RawManifestEntry) -> Self {
+        match raw {
+            RawManifestEntry::Legacy(source) => ManifestEntry {
+                source,
+                fingerprint: None,
+                failure: None,
+                proxy: false,
+                probe: None,
+            },
+            RawManifestEntry::Entry {
+                source,
+                fingerprint,
+                failure,
+                proxy,
+                probe,
+            } => ManifestEntry {
+                source,
+                fingerprint,
+                failure,
+                proxy,
+                probe,
+            },
+        }
+    }
+}
+
+type Manifest = HashMap<String, ManifestEntry>;
+
+/// Fingerprint → hashes of the thumbnails generated from that content, built when a
+/// manifest is loaded or saved. Valid while the manifest file keeps its stamp, so a
+/// manifest written by another process is read again.
+struct FingerprintIndex {
+    stamp: Option<(u64, u64)>,
+    hashes: HashMap<String, Vec<String>>,
+}
 
 #[cfg(test)]
 thread_local! {
@@ -28,11 +186,286 @@ fn hash_for_path(source: &Path) -> String {
     format!("{:016x}", hasher.finish())
 }
 
+/// Returns the collection root of a portable cache, or None for a global cache.
+fn portable_root(cache_base_dir: &Path) -> Option<&Path> {
+    if cache_base_dir.file_name()? == PORTABLE_CACHE_DIR {
+        cache_base_dir.parent()
+    } else {
+        None
+    }
+}
+
+/// Returns the manifest key of a source file.
+/// Portable caches key files relative to their collection root so the cache stays
+/// valid when the drive is mounted elsewhere. Global caches use the full path.
+fn source_key(source: &Path, cache_base_dir: &Path) -> String {
+    let source = super::normalize_path(&source.to_string_lossy());
+    if let Some(root) = portable_root(cache_base_dir) {
+        let root = super::normalize_path(&root.to_string_lossy());
+        if let Some(relative) = source
+            .strip_prefix(root.trim_end_matches('/'))
+            .and_then(|rest| rest.strip_prefix('/'))
+            .filter(|rest| !rest.is_empty())
+        {
+            return relative.to_string();
+        }
+    }
+    source
+}
+
+/// Resolves a manifest key back to the path of the source file, see [`source_key`].
+fn source_path(key: &str, cache_base_dir: &Path) -> PathBuf {
+    match portable_root(cache_base_dir) {
+        Some(root) if Path::new(key).is_relative() => root.join(key),
+        _ => PathBuf::from(key),
+    }
+}
+
+/// Like [`source_path`], normalized for display and prefix matching.
+fn source_display(key: &str, cache_base_dir: &Path) -> String {
+    super::normalize_path(&source_path(key, cache_base_dir).to_string_lossy())
+}
+
+/// Computes the hash a source file is stored under in the given cache.
+fn hash_for_source(source: &Path, cache_base_dir: &Path) -> String {
+    hash_for_path(Path::new(&source_key(source, cache_base_dir)))
+}
+
+/// Sets the collection roots whose portable caches are used.
+/// Called by the frontend whenever the settings change.
+pub fn set_roots(roots: &[String]) {
+    *ROOTS.lock().unwrap_or_else(|e| e.into_inner()) = roots
+        .iter()
+        .map(|root| super::normalize_path(root))
+        .collect();
+}
+
+fn roots() -> Vec<String> {
+    ROOTS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Returns the cache directory responsible for `path`: the portable cache of the
+/// closest enclosing collection root that has one, otherwise `cache_base_dir`.
+/// Only the roots set with [`set_roots`] are considered, so a `.mv` directory in
+/// e.g. the home directory is not mistaken for a cache.
+pub fn cache_dir_for(path: &Path, cache_base_dir: &Path) -> PathBuf {
+    let path = super::normalize_path(&path.to_string_lossy());
+    roots()
+        .iter()
+        .filter(|root| is_under_prefix(&path, root))
+        .map(|root| portable_cache_dir(Path::new(root)))
+        .filter(|dir| dir.is_dir())
+        .max_by_key(|dir| dir.as_os_str().len())
+        .unwrap_or_else(|| cache_base_dir.to_path_buf())
+}
+
+/// Returns `cache_base_dir` followed by the existing portable caches of `roots`.
+fn cache_dirs(cache_base_dir: &Path, roots: &[String]) -> Vec<PathBuf> {
+    let mut dirs = vec![cache_base_dir.to_path_buf()];
+    dirs.extend(
+        roots
+            .iter()
+            .map(|root| portable_cache_dir(Path::new(root)))
+            .filter(|dir| dir.is_dir()),
+    );
+    dirs
+}
+
+/// Returns the hash the thumbnails of `source` are stored under, in the cache
+/// responsible for it.
+pub fn thumbnail_key(source: &Path, cache_base_dir: &Path) -> String {
+    hash_for_source(source, &cache_dir_for(source, cache_base_dir))
+}
+
+/// Creates a portable cache at the root of a collection.
+/// Thumbnails of files below `root` are stored there from now on, the ones already
+/// in `cache_base_dir` are moved there. Returns the portable cache directory.
+pub fn enable_portable_cache(root: &str, cache_base_dir: &str) -> Result<String, String> {
+    let dir = portable_cache_dir(Path::new(root));
+    ensure_cache_dir(&dir)?;
+    let base = Path::new(cache_base_dir);
+    if base.is_dir() {
+        migrate_to_portable(root, base, &dir)?;
+    }
+    Ok(super::normalize_path(&dir.to_string_lossy()))
+}
+
+/// Moves the thumbnails and proxies of files below `root` from `cache_base_dir`
+/// into the portable cache `portable`, rekeyed relative to the root.
+/// Entries whose files cannot be moved stay where they are.
+/// Returns the number of entries moved.
+fn migrate_to_portable(root: &str, cache_base_dir: &Path, portable: &Path) -> Result<u32, String> {
+    let mut moved = Manifest::new();
+    {
+        let _lock = CacheLock::acquire(cache_base_dir)?;
+        let mut manifest = load_manifest(cache_base_dir)?;
+        for hash in select_for_prefixes(&manifest, &[root.to_string()], cache_base_dir) {
+            let Some(entry) = manifest.remove(&hash) else {
+                continue;
+            };
+            let source = source_path(&entry.source, cache_base_dir);
+            let new_hash = hash_for_source(&source, portable);
+            let moved_all = thumbnail_files_for_hash(&hash, cache_base_dir)
+                .iter()
+                .zip(thumbnail_files_for_hash(&new_hash, portable))
+                .all(|(from, to)| !from.is_file() || move_file(from, &to).is_ok());
+            if moved_all {
+                let source = source_key(&source, portable);
+                moved.insert(new_hash, ManifestEntry { source, ..entry });
+            } else {
+                manifest.insert(hash, entry);
+            }
+        }
+        if moved.is_empty() {
+            return Ok(0);
+        }
+        save_manifest(&manifest, cache_base_dir)?;
+    }
+
+    // The caches are locked one at a time, a crash in between leaves thumbnails
+    // that `verify` attributes again
+    let _lock = CacheLock::acquire(portable)?;
+    let mut manifest = load_manifest(portable)?;
+    let count = moved.len() as u32;
+    for (hash, entry) in moved {
+        manifest.entry(hash).or_insert(entry);
+    }
+    save_manifest(&manifest, portable)?;
+    Ok(count)
+}
+
+/// Moves a file, copying it if the target lies on another volume.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+/// Returns the path to the lock file guarding the cache directory.
+fn lock_path(cache_base_dir: &Path) -> PathBuf {
+    cache_base_dir.join(".lock")
+}
+
+/// Returns the path the corrupt manifest is moved to before it is rebuilt.
+fn corrupt_manifest_path(cache_base_dir: &Path) -> PathBuf {
+    cache_base_dir.join("manifest.json.corrupt")
+}
+
+/// Returns the path to the thumbnail stored under the given hash.
+/// Thumbnails are sharded into subdirectories named after the first characters
+/// of the hash, which keeps directories small for large collections.
+fn thumbnail_path_for_hash(hash: &str, cache_base_dir: &Path) -> PathBuf {
+    let shard = hash.get(..SHARD_PREFIX_LEN).unwrap_or(hash);
+    cache_base_dir.join(shard).join(format!("{}.jpg", hash))
+}
+
+/// Returns the path to the variant of the given edge length stored under the given hash.
+/// Format: <cache_base_dir>/<hash prefix>/<hash>_<size>.jpg
+fn variant_path_for_hash(hash: &str, size: u32, cache_base_dir: &Path) -> PathBuf {
+    thumbnail_path_for_hash(hash, cache_base_dir).with_file_name(format!("{}_{}.jpg", hash, size))
+}
+
+/// Returns the path to the low-resolution proxy stored under the given hash.
+/// Format: <cache_base_dir>/<hash prefix>/<hash>_proxy.mp4
+fn proxy_path_for_hash(hash: &str, cache_base_dir: &Path) -> PathBuf {
+    thumbnail_path_for_hash(hash, cache_base_dir).with_file_name(format!("{}_proxy.mp4", hash))
+}
+
+/// Returns the default thumbnail, all variant and the proxy paths for a hash, existing or not.
+fn thumbnail_files_for_hash(hash: &str, cache_base_dir: &Path) -> Vec<PathBuf> {
+    std::iter::once(thumbnail_path_for_hash(hash, cache_base_dir))
+        .chain(
+            VARIANT_SIZES
+                .iter()
+                .map(|size| variant_path_for_hash(hash, *size, cache_base_dir)),
+        )
+        .chain(std::iter::once(proxy_path_for_hash(hash, cache_base_dir)))
+        .collect()
+}
+
+/// Extracts the hash from a thumbnail file name, either `<hash>.jpg` or `<hash>_<size>.jpg`.
+fn hash_of_thumbnail(path: &Path) -> Option<String> {
+    if path.extension().and_then(|e| e.to_str()) != Some("jpg") {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    let hash = match stem.split_once('_') {
+        Some((hash, size)) if size.parse::<u32>().is_ok() => hash,
+        Some(_) => return None,
+        None => stem,
+    };
+    Some(hash.to_string())
+}
+
+/// Returns true if the directory is a thumbnail shard, see [`thumbnail_path_for_hash`].
+fn is_shard_dir(path: &Path) -> bool {
+    path.is_dir()
+        && path.file_name().and_then(|n| n.to_str()).is_some_and(|n| {
+            n.len() == SHARD_PREFIX_LEN && n.chars().all(|c| c.is_ascii_hexdigit())
+        })
+}
+
+/// Lists the files in the cache directory and its shard directories.
+fn cache_files(cache_base_dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    let mut dirs = vec![cache_base_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = fs::read_dir(&dir).map_err(|e| format!("Failed to read cache dir: {}", e))?;
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_file() {
+                files.push(path);
+            } else if dir == cache_base_dir && is_shard_dir(&path) {
+                dirs.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
 /// Returns the path to the thumbnail for a given source file.
-/// Format: <cache_base_dir>/<hash>.jpg
+/// Format: <cache_base_dir>/<hash prefix>/<hash>.jpg
 pub fn thumbnail_path(source: &Path, cache_base_dir: &Path) -> Result<PathBuf, String> {
-    let hash = hash_for_path(source);
-    Ok(cache_base_dir.join(format!("{}.jpg", hash)))
+    let hash = hash_for_source(source, cache_base_dir);
+    Ok(thumbnail_path_for_hash(&hash, cache_base_dir))
+}
+
+/// Returns the path to a size variant of the thumbnail for a given source file,
+/// see [`VARIANT_SIZES`].
+pub fn variant_path(source: &Path, size: u32, cache_base_dir: &Path) -> PathBuf {
+    variant_path_for_hash(
+        &hash_for_source(source, cache_base_dir),
+        size,
+        cache_base_dir,
+    )
+}
+
+/// Computes a content fingerprint for a source file.
+/// Combines the file size with a hash of its first and last few KB, which is
+/// cheap to compute and survives renames and moves.
+/// Returns None if the file cannot be read.
+pub fn fingerprint(source: &Path) -> Option<String> {
+    let mut file = fs::File::open(source).ok()?;
+    let size = file.metadata().ok()?.len();
+    let chunk = size.min(FINGERPRINT_CHUNK);
+
+    let mut hasher = DefaultHasher::new();
+    size.hash(&mut hasher);
+
+    let mut buf = vec![0u8; chunk as usize];
+    file.read_exact(&mut buf).ok()?;
+    buf.hash(&mut hasher);
+
+    file.seek(SeekFrom::Start(size - chunk)).ok()?;
+    file.read_exact(&mut buf).ok()?;
+    buf.hash(&mut hasher);
+
+    Some(format!("{:x}-{:016x}", size, hasher.finish()))
 }
 
 fn get_canonicalized_path(user_provided_path: &Path) -> Result<PathBuf, String> {
@@ -67,6 +500,58 @@ pub fn is_stale(source: &Path, thumbnail: &Path) -> bool {
     source_mtime > thumb_mtime
 }
 
+/// Writes `data` to `path` so that readers never observe a partially written file.
+/// The data goes to a temporary file in the same directory, which is flushed to disk
+/// and then renamed over the target.
+pub fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let temp_path = dir.join(format!(
+        "{}.{}-{}.{}",
+        file_name,
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        TEMP_EXTENSION
+    ));
+
+    let result = (|| {
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        sync_dir(dir);
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Writes a thumbnail with [`write_atomic`], creating its shard directory first.
+pub fn write_thumbnail(thumb_path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(shard) = thumb_path.parent() {
+        fs::create_dir_all(shard)?;
+    }
+    write_atomic(thumb_path, data)
+}
+
+/// Flushes a directory entry to disk so a preceding rename survives a power loss.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) {
+    if let Ok(d) = fs::File::open(dir) {
+        let _ = d.sync_all();
+    }
+}
+
+/// Directories cannot be opened for syncing on this platform.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) {}
+
 /// Creates the base cache directory if it doesn't exist.
 pub fn ensure_cache_dir(cache_base_dir: &Path) -> Result<PathBuf, String> {
     if cache_base_dir.exists() {
@@ -100,20 +585,245 @@ pub fn ensure_cache_dir(cache_base_dir: &Path) -> Result<PathBuf, String> {
     Ok(cache_base_dir.to_path_buf())
 }
 
+// --- Locking ---
+
+/// Exclusive lock on a cache directory, held while the manifest or the cache
+/// contents are mutated.
+/// Threads of this process are serialized per directory, other app instances sharing
+/// the directory through an advisory lock on a file inside it. Waiting for another
+/// process therefore only holds up users of the same directory.
+/// Both are released when the guard is dropped.
+struct CacheLock {
+    dir: PathBuf,
+    file: Option<fs::File>,
+}
+
+impl CacheLock {
+    /// Acquires the lock, waiting up to [`CACHE_LOCK_TIMEOUT`] for other processes.
+    fn acquire(cache_base_dir: &Path) -> Result<Self, String> {
+        Self::acquire_with_timeout(cache_base_dir, CACHE_LOCK_TIMEOUT)
+    }
+
+    /// The directory is not created. Until it exists there is nothing to share
+    /// with other processes, so only this process is locked out; writers create
+    /// the directory before locking it.
+    fn acquire_with_timeout(cache_base_dir: &Path, timeout: Duration) -> Result<Self, String> {
+        let dir = cache_base_dir.to_path_buf();
+        {
+            let mut locked = LOCKED_DIRS
+                .lock()
+                .map_err(|e| format!("Cache lock error: {}", e))?;
+            while locked.as_ref().is_some_and(|dirs| dirs.contains(&dir)) {
+                locked = LOCKED_DIRS_RELEASED
+                    .wait(locked)
+                    .map_err(|e| format!("Cache lock error: {}", e))?;
+            }
+            locked.get_or_insert_with(HashSet::new).insert(dir.clone());
+        }
+        // Dropping the guard on an error releases the directory again
+        let mut lock = CacheLock { dir, file: None };
+
+        let file = match fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path(cache_base_dir))
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(lock),
+            Err(e) => return Err(format!("Failed to open cache lock file: {}", e)),
+        };
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            match file.try_lock() {
+                Ok(()) => {
+                    lock.file = Some(file);
+                    return Ok(lock);
+                }
+                Err(fs::TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    std::thread::sleep(CACHE_LOCK_RETRY_INTERVAL);
+                }
+                Err(fs::TryLockError::WouldBlock) => {
+                    return Err(format!(
+                        "Thumbnail cache {} is in use by another process, try again later",
+                        cache_base_dir.display()
+                    ))
+                }
+                Err(fs::TryLockError::Error(e)) => {
+                    return Err(format!("Failed to lock thumbnail cache: {}", e))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        // Unlock the file first, so the next thread does not see it held
+        self.file.take();
+        let mut locked = LOCKED_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(dirs) = locked.as_mut() {
+            dirs.remove(&self.dir);
+        }
+        LOCKED_DIRS_RELEASED.notify_all();
+    }
+}
+
+/// Creates the cache directory before a writer locks it, see [`CacheLock`].
+fn create_cache_dir(cache_base_dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(cache_base_dir)
+        .map_err(|e| format!("Failed to create cache directory: {}", e))
+}
+
 // --- Manifest management ---
 
-/// Loads the manifest (hash → source_path).
-fn load_manifest(cache_base_dir: &Path) -> Result<HashMap<String, String>, String> {
+/// Moves thumbnails of the old flat `<cache_base_dir>/<hash>.jpg` layout into
+/// their shard directories. The caller must hold the cache lock.
+/// Returns the number of thumbnails moved.
+fn migrate_flat_layout(cache_base_dir: &Path) -> Result<u32, String> {
+    let mut migrated = MIGRATED_DIRS
+        .lock()
+        .map_err(|e| format!("Migration lock error: {}", e))?;
+    let migrated = migrated.get_or_insert_with(HashSet::new);
+    if migrated.contains(cache_base_dir) {
+        return Ok(0);
+    }
+
+    let mut moved = 0u32;
+    if cache_base_dir.is_dir() {
+        let entries =
+            fs::read_dir(cache_base_dir).map_err(|e| format!("Failed to read cache dir: {}", e))?;
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if !path.is_file() || path.extension().and_then(|e| e.to_str()) != Some("jpg") {
+                continue;
+            }
+            // Only files named like a thumbnail hash, see hash_for_path
+            let hash = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(h) if h.len() == 16 && h.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    h.to_string()
+                }
+                _ => continue,
+            };
+            let target = thumbnail_path_for_hash(&hash, cache_base_dir);
+            if let Some(shard) = target.parent() {
+                fs::create_dir_all(shard)
+                    .map_err(|e| format!("Failed to create shard directory: {}", e))?;
+            }
+            fs::rename(&path, &target)
+                .map_err(|e| format!("Failed to migrate thumbnail: {}", e))?;
+            moved += 1;
+        }
+        if moved > 0 {
+            eprintln!(
+                "[cache] Migrated {} thumbnails in {} to the sharded layout",
+                moved,
+                cache_base_dir.display()
+            );
+        }
+    }
+
+    migrated.insert(cache_base_dir.to_path_buf());
+    Ok(moved)
+}
+
+/// Migrates the cache directory to the sharded layout, see [`migrate_flat_layout`].
+/// Cheap once the directory has been migrated by this process.
+pub fn migrate_layout(cache_base_dir: &Path) -> Result<u32, String> {
+    let _lock = CacheLock::acquire(cache_base_dir)?;
+    migrate_flat_layout(cache_base_dir)
+}
+
+/// Loads the manifest (hash → entry).
+/// A corrupt manifest is rebuilt from the cache directory instead of failing.
+fn load_manifest(cache_base_dir: &Path) -> Result<Manifest, String> {
+    migrate_flat_layout(cache_base_dir)?;
     let path = manifest_path(cache_base_dir);
     if !path.exists() {
-        return Ok(HashMap::new());
+        let manifest = Manifest::new();
+        index_fingerprints(&manifest, cache_base_dir);
+        return Ok(manifest);
     }
-    let data = fs::read_to_string(&path).map_err(|e| format!("Failed to read manifest: {}", e))?;
-    serde_json::from_str(&data).map_err(|e| format!("Failed to parse manifest: {}", e))
+    let data = fs::read(&path).map_err(|e| format!("Failed to read manifest: {}", e))?;
+    match serde_json::from_slice(&data) {
+        Ok(manifest) => {
+            index_fingerprints(&manifest, cache_base_dir);
+            Ok(manifest)
+        }
+        Err(e) => {
+            eprintln!(
+                "[cache] Manifest {} is corrupt ({}), rebuilding from cache directory",
+                path.display(),
+                e
+            );
+            rebuild_manifest(&data, cache_base_dir)
+        }
+    }
+}
+
+/// Rebuilds the manifest from the thumbnails present in the cache directory.
+/// Entries are salvaged from the corrupt manifest data where possible. Thumbnails
+/// whose source cannot be recovered are deleted, as are abandoned temporary files.
+/// The corrupt manifest is kept next to the new one for inspection.
+fn rebuild_manifest(corrupt: &[u8], cache_base_dir: &Path) -> Result<Manifest, String> {
+    let corrupt_text = String::from_utf8_lossy(corrupt);
+    let mut manifest = Manifest::new();
+    let mut dropped = 0u32;
+
+    for path in cache_files(cache_base_dir)? {
+        if path.extension().and_then(|e| e.to_str()) == Some(TEMP_EXTENSION) {
+            if is_abandoned_temp(&path) {
+                let _ = fs::remove_file(&path);
+            }
+            continue;
+        }
+
+        let hash = match hash_of_thumbnail(&path) {
+            Some(h) => h,
+            None => continue,
+        };
+        if manifest.contains_key(&hash) {
+            continue;
+        }
+        match salvage_entry(&corrupt_text, &hash) {
+            Some(entry) => {
+                manifest.insert(hash, entry);
+            }
+            None => {
+                let _ = fs::remove_file(&path);
+                dropped += 1;
+            }
+        }
+    }
+
+    let _ = fs::rename(
+        manifest_path(cache_base_dir),
+        corrupt_manifest_path(cache_base_dir),
+    );
+    save_manifest(&manifest, cache_base_dir)?;
+
+    eprintln!(
+        "[cache] Rebuilt manifest with {} entries, dropped {} unrecoverable thumbnails",
+        manifest.len(),
+        dropped
+    );
+    Ok(manifest)
+}
+
+/// Extracts the entry for `hash` from (possibly truncated) manifest JSON.
+fn salvage_entry(corrupt_text: &str, hash: &str) -> Option<ManifestEntry> {
+    let key = format!("\"{}\"", hash);
+    let rest = corrupt_text[corrupt_text.find(&key)? + key.len()..].trim_start();
+    let value = rest.strip_prefix(':')?;
+    serde_json::Deserializer::from_str(value)
+        .into_iter::<ManifestEntry>()
+        .next()?
+        .ok()
 }
 
 /// Saves the manifest to disk.
-fn save_manifest(manifest: &HashMap<String, String>, cache_base_dir: &Path) -> Result<(), String> {
+fn save_manifest(manifest: &Manifest, cache_base_dir: &Path) -> Result<(), String> {
     let path = manifest_path(cache_base_dir);
     // Ensure parent directory exists
     if let Some(parent) = path.parent() {
@@ -122,113 +832,1235 @@ fn save_manifest(manifest: &HashMap<String, String>, cache_base_dir: &Path) -> R
     }
     let data = serde_json::to_string_pretty(manifest)
         .map_err(|e| format!("Failed to serialize manifest: {}", e))?;
-    fs::write(&path, data).map_err(|e| format!("Failed to write manifest: {}", e))
+    write_atomic(&path, data.as_bytes()).map_err(|e| format!("Failed to write manifest: {}", e))?;
+    index_fingerprints(manifest, cache_base_dir);
+    Ok(())
+}
+
+/// Indexes the fingerprints of the manifest just loaded from or saved to disk.
+fn index_fingerprints(manifest: &Manifest, cache_base_dir: &Path) {
+    let mut hashes: HashMap<String, Vec<String>> = HashMap::new();
+    for (hash, entry) in manifest {
+        if let Some(fingerprint) = &entry.fingerprint {
+            hashes
+                .entry(fingerprint.clone())
+                .or_default()
+                .push(hash.clone());
+        }
+    }
+    let index = FingerprintIndex {
+        stamp: file_stamp(&manifest_path(cache_base_dir)),
+        hashes,
+    };
+    FINGERPRINT_INDEXES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(cache_base_dir.to_path_buf(), index);
+}
+
+/// Returns the hashes of the thumbnails generated from content with `fingerprint`,
+/// only loading the manifest if it changed since it was indexed.
+/// The caller must hold the cache lock.
+fn hashes_with_fingerprint(
+    fingerprint: &str,
+    cache_base_dir: &Path,
+) -> Result<Vec<String>, String> {
+    let stamp = file_stamp(&manifest_path(cache_base_dir));
+    if let Some(index) = FINGERPRINT_INDEXES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .and_then(|indexes| indexes.get(cache_base_dir))
+        .filter(|index| index.stamp == stamp)
+    {
+        return Ok(index.hashes.get(fingerprint).cloned().unwrap_or_default());
+    }
+    let manifest = load_manifest(cache_base_dir)?;
+    Ok(manifest
+        .iter()
+        .filter(|(_, entry)| entry.fingerprint.as_deref() == Some(fingerprint))
+        .map(|(hash, _)| hash.clone())
+        .collect())
 }
 
 /// Registers a thumbnail in the manifest after generation.
 pub fn register_thumbnail(source: &Path, cache_base_dir: &Path) -> Result<(), String> {
-    let _lock = MANIFEST_LOCK
-        .lock()
-        .map_err(|e| format!("Manifest lock error: {}", e))?;
-    let hash = hash_for_path(source);
+    create_cache_dir(cache_base_dir)?;
+    let _lock = CacheLock::acquire(cache_base_dir)?;
+    let hash = hash_for_source(source, cache_base_dir);
     let mut manifest = load_manifest(cache_base_dir)?;
-    manifest.insert(hash, super::normalize_path(&source.to_string_lossy()));
+    let previous = manifest.get(&hash);
+    let proxy = previous.is_some_and(|e| e.proxy);
+    let probe = previous.and_then(|e| e.probe.clone());
+    manifest.insert(
+        hash,
+        ManifestEntry {
+            source: source_key(source, cache_base_dir),
+            fingerprint: fingerprint(source),
+            failure: None,
+            proxy,
+            probe,
+        },
+    );
     save_manifest(&manifest, cache_base_dir)
 }
 
-/// Deletes all thumbnails whose source path starts with the given prefix.
-/// Used when a root directory is removed.
-pub fn cleanup_for_prefix(prefix: &str, cache_base_dir: &str) -> Result<u32, String> {
-    let _lock = MANIFEST_LOCK
-        .lock()
-        .map_err(|e| format!("Manifest lock error: {}", e))?;
-    let base = Path::new(cache_base_dir);
-    let mut manifest = load_manifest(base)?;
+/// Looks for an existing thumbnail of a file with the same content fingerprint
+/// and rekeys it to `source`, so moved or renamed files are not decoded again.
+/// If the previous source is gone the thumbnail is moved, otherwise it is copied.
+/// Returns the new thumbnail path, or None if no matching thumbnail exists.
+pub fn reuse_thumbnail_by_fingerprint(
+    source: &Path,
+    cache_base_dir: &Path,
+) -> Result<Option<PathBuf>, String> {
+    let fingerprint = match fingerprint(source) {
+        Some(f) => f,
+        None => return Ok(None),
+    };
 
-    let to_remove: Vec<String> = manifest
-        .iter()
-        .filter(|(_, source)| source.starts_with(prefix))
-        .map(|(hash, _)| hash.clone())
-        .collect();
+    let _lock = CacheLock::acquire(cache_base_dir)?;
+    let source_str = source_key(source, cache_base_dir);
+    let new_hash = hash_for_source(source, cache_base_dir);
 
-    let mut removed = 0u32;
-    for hash in &to_remove {
-        let thumb = base.join(format!("{}.jpg", hash));
-        if thumb.exists() {
-            let _ = fs::remove_file(&thumb);
+    // Most files are new, the index answers them without reading the manifest
+    let hashes = hashes_with_fingerprint(&fingerprint, cache_base_dir)?;
+    if hashes.iter().all(|hash| *hash == new_hash) {
+        return Ok(None);
+    }
+    let mut manifest = load_manifest(cache_base_dir)?;
+
+    let candidate = hashes.iter().find_map(|hash| {
+        let entry = manifest.get(hash)?;
+        (entry.fingerprint.as_deref() == Some(fingerprint.as_str())
+            && *hash != new_hash
+            && thumbnail_path_for_hash(hash, cache_base_dir).exists())
+        .then(|| (hash.clone(), entry.source.clone()))
+    });
+
+    let (old_hash, old_source) = match candidate {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+
+    let new_thumb = thumbnail_path_for_hash(&new_hash, cache_base_dir);
+    if let Some(shard) = new_thumb.parent() {
+        fs::create_dir_all(shard)
+            .map_err(|e| format!("Failed to create shard directory: {}", e))?;
+    }
+    let keep_old = source_path(&old_source, cache_base_dir).exists();
+    let old_files = thumbnail_files_for_hash(&old_hash, cache_base_dir);
+    let new_files = thumbnail_files_for_hash(&new_hash, cache_base_dir);
+    for (old_file, new_file) in old_files.iter().zip(&new_files) {
+        if !old_file.exists() {
+            continue;
         }
-        manifest.remove(hash);
-        removed += 1;
+        if keep_old {
+            fs::copy(old_file, new_file).map_err(|e| format!("Failed to copy thumbnail: {}", e))?;
+        } else {
+            fs::rename(old_file, new_file)
+                .map_err(|e| format!("Failed to move thumbnail: {}", e))?;
+        }
+    }
+    if !keep_old {
+        manifest.remove(&old_hash);
     }
 
-    save_manifest(&manifest, base)?;
-    Ok(removed)
+    let proxy = proxy_path_for_hash(&new_hash, cache_base_dir).exists();
+    let probe = manifest.get(&new_hash).and_then(|e| e.probe.clone());
+    manifest.insert(
+        new_hash,
+        ManifestEntry {
+            source: source_str,
+            fingerprint: Some(fingerprint),
+            failure: None,
+            proxy,
+            probe,
+        },
+    );
+    save_manifest(&manifest, cache_base_dir)?;
+
+    Ok(Some(new_thumb))
 }
 
-/// Scans the manifest and deletes entries whose source file no longer exists.
-pub fn cleanup_orphans(cache_base_dir: &str) -> Result<u32, String> {
-    let _lock = MANIFEST_LOCK
-        .lock()
-        .map_err(|e| format!("Manifest lock error: {}", e))?;
-    let base = Path::new(cache_base_dir);
-    let mut manifest = load_manifest(base)?;
+/// Returns the modification time in milliseconds and the size of a file.
+fn file_stamp(path: &Path) -> Option<(u64, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((mtime.as_millis() as u64, meta.len()))
+}
+
+/// Records that no thumbnail could be generated for `source`, see [`known_failure`].
+pub fn record_failure(
+    source: &Path,
+    cache_base_dir: &Path,
+    status: &str,
+    reason: &str,
+) -> Result<(), String> {
+    let (mtime, size) = file_stamp(source)
+        .ok_or_else(|| format!("Failed to read metadata of {}", source.display()))?;
+
+    create_cache_dir(cache_base_dir)?;
+    let _lock = CacheLock::acquire(cache_base_dir)?;
+    let mut manifest = load_manifest(cache_base_dir)?;
+    let hash = hash_for_source(source, cache_base_dir);
+    let previous = manifest.get(&hash);
+    let proxy = previous.is_some_and(|e| e.proxy);
+    let probe = previous.and_then(|e| e.probe.clone());
+    manifest.insert(
+        hash,
+        ManifestEntry {
+            source: source_key(source, cache_base_dir),
+            fingerprint: None,
+            failure: Some(Failure {
+                status: status.to_string(),
+                reason: reason.to_string(),
+                mtime,
+                size,
+            }),
+            proxy,
+            probe,
+        },
+    );
+    save_manifest(&manifest, cache_base_dir)
+}
+
+/// Loads all recorded failures (hash → failure), to be checked with [`known_failure`].
+pub fn load_failures(cache_base_dir: &Path) -> Result<HashMap<String, Failure>, String> {
+    let _lock = CacheLock::acquire(cache_base_dir)?;
+    let manifest = load_manifest(cache_base_dir)?;
+    Ok(manifest
+        .into_iter()
+        .filter_map(|(hash, entry)| entry.failure.map(|f| (hash, f)))
+        .collect())
+}
+
+/// Returns the recorded failure for `source`, unless the file changed since.
+pub fn known_failure<'a>(
+    failures: &'a HashMap<String, Failure>,
+    source: &Path,
+    cache_base_dir: &Path,
+) -> Option<&'a Failure> {
+    let failure = failures.get(&hash_for_source(source, cache_base_dir))?;
+    (file_stamp(source) == Some((failure.mtime, failure.size))).then_some(failure)
+}
+
+/// Forgets all recorded failures in the cache and the portable caches of `roots`,
+/// so the files are retried the next time their folder is opened.
+/// Returns the number of failures cleared.
+pub fn clear_failures(cache_base_dir: &str, roots: &[String]) -> Result<u32, String> {
+    let mut cleared = 0u32;
+    for dir in cache_dirs(Path::new(cache_base_dir), roots)
+        .iter()
+        .filter(|dir| dir.is_dir())
+    {
+        let _lock = CacheLock::acquire(dir)?;
+        let mut manifest = load_manifest(dir)?;
+        let before = manifest.len();
+        manifest.retain(|_, entry| entry.failure.is_none());
+        if manifest.len() != before {
+            cleared += (before - manifest.len()) as u32;
+            save_manifest(&manifest, dir)?;
+        }
+    }
+    Ok(cleared)
+}
+
+/// Returns true if `source` is `prefix` itself or lies below it.
+/// Whole path components are compared, so `/photos` does not match `/photos-archive`.
+fn is_under_prefix(source: &str, prefix: &str) -> bool {
+    let prefix = super::normalize_path(prefix);
+    match source.strip_prefix(prefix.trim_end_matches('/')) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
 
-    let orphans: Vec<String> = manifest
+/// Returns the hashes of all entries whose source lies below one of the given prefixes.
+fn select_for_prefixes(
+    manifest: &Manifest,
+    prefixes: &[String],
+    cache_base_dir: &Path,
+) -> Vec<String> {
+    manifest
         .iter()
-        .filter(|(_, source)| !Path::new(source).exists())
+        .filter(|(_, entry)| {
+            let source = source_display(&entry.source, cache_base_dir);
+            prefixes
+                .iter()
+                .any(|prefix| is_under_prefix(&source, prefix))
+        })
         .map(|(hash, _)| hash.clone())
-        .collect();
+        .collect()
+}
+
+/// Returns the hashes of all entries whose source file no longer exists.
+fn select_orphans(manifest: &Manifest, cache_base_dir: &Path) -> Vec<String> {
+    manifest
+        .iter()
+        .filter(|(_, entry)| !source_path(&entry.source, cache_base_dir).exists())
+        .map(|(hash, _)| hash.clone())
+        .collect()
+}
 
+/// Deletes the thumbnails of the given entries and removes them from the manifest.
+fn remove_entries(manifest: &mut Manifest, hashes: &[String], cache_base_dir: &Path) -> u32 {
     let mut removed = 0u32;
-    for hash in &orphans {
-        let thumb = base.join(format!("{}.jpg", hash));
-        if thumb.exists() {
-            let _ = fs::remove_file(&thumb);
+    for hash in hashes {
+        for thumb in thumbnail_files_for_hash(hash, cache_base_dir) {
+            if thumb.exists() {
+                let _ = fs::remove_file(&thumb);
+            }
         }
         manifest.remove(hash);
         removed += 1;
     }
+    removed
+}
 
-    save_manifest(&manifest, base)?;
-    Ok(removed)
+/// What a cleanup would remove, returned by the dry-run variants.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CleanupPreview {
+    /// Source paths whose thumbnails would be deleted.
+    pub sources: Vec<String>,
+    /// Disk space that would be reclaimed, in bytes.
+    pub bytes: u64,
 }
 
-/// Deletes the entire thumbnail cache directory.
-pub fn delete_all(cache_base_dir: &str) -> Result<(), String> {
-    let _lock = MANIFEST_LOCK
-        .lock()
-        .map_err(|e| format!("Manifest lock error: {}", e))?;
-    let base = Path::new(cache_base_dir);
-    if base.exists() {
-        fs::remove_dir_all(base).map_err(|e| format!("Failed to delete cache dir: {}", e))?;
+fn preview_entries(
+    manifest: &Manifest,
+    hashes: &[String],
+    cache_base_dir: &Path,
+) -> CleanupPreview {
+    let mut preview = CleanupPreview::default();
+    for hash in hashes {
+        if let Some(entry) = manifest.get(hash) {
+            preview
+                .sources
+                .push(source_display(&entry.source, cache_base_dir));
+        }
+        preview.bytes += thumbnail_files_for_hash(hash, cache_base_dir)
+            .iter()
+            .filter_map(|thumb| fs::metadata(thumb).ok())
+            .map(|m| m.len())
+            .sum::<u64>();
     }
-    Ok(())
+    preview.sources.sort();
+    preview
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs::File;
-    use std::thread;
-    use std::time::Duration;
-    use tempfile::tempdir;
-
-    /// Helper function to create an isolated test environment
-    struct TestEnvGuard {
-        pub temp_dir: tempfile::TempDir,
+/// Returns `cache_base_dir` and, if it is another one, the cache responsible for `prefix`.
+fn caches_for_prefix(prefix: &str, cache_base_dir: &str) -> Vec<PathBuf> {
+    let base = PathBuf::from(cache_base_dir);
+    let portable = cache_dir_for(Path::new(prefix), &base);
+    if portable == base {
+        vec![base]
+    } else {
+        vec![base, portable]
     }
+}
 
-    fn setup_test_env() -> TestEnvGuard {
-        let temp_dir = tempdir().expect("Failed to create temp test directory");
-        TestEnvGuard { temp_dir }
+/// Combines the dry-runs of several caches.
+fn merge_previews(previews: Vec<CleanupPreview>) -> CleanupPreview {
+    let mut merged = CleanupPreview::default();
+    for preview in previews {
+        merged.sources.extend(preview.sources);
+        merged.bytes += preview.bytes;
     }
+    merged.sources.sort();
+    merged
+}
 
-    #[test]
-    fn test_hash_for_path_deterministic() {
-        // Same logical path should yield same hash
-        let hash1 = hash_for_path(&PathBuf::from("/foo/bar/image.jpg"));
-        let hash2 = hash_for_path(&PathBuf::from("/foo/bar/image.jpg"));
-        assert_eq!(hash1, hash2, "Hashes should be deterministic");
+/// Deletes all thumbnails whose source path lies below the given prefix, in the
+/// cache and in the portable cache responsible for the prefix.
+/// Used when a root directory is removed.
+pub fn cleanup_for_prefix(prefix: &str, cache_base_dir: &str) -> Result<u32, String> {
+    let prefixes = [prefix.to_string()];
+    caches_for_prefix(prefix, cache_base_dir)
+        .iter()
+        .map(|dir| cleanup_for_prefixes(&prefixes, &dir.to_string_lossy()))
+        .sum()
+}
+
+/// Deletes all thumbnails whose source path lies below one of the given prefixes,
+/// in a single pass over the manifest. Used for files removed while watching.
+pub fn cleanup_for_prefixes(prefixes: &[String], cache_base_dir: &str) -> Result<u32, String> {
+    let base = Path::new(cache_base_dir);
+    let _lock = CacheLock::acquire(base)?;
+    let mut manifest = load_manifest(base)?;
+
+    let to_remove = select_for_prefixes(&manifest, prefixes, base);
+    let removed = remove_entries(&mut manifest, &to_remove, base);
+
+    if removed > 0 {
+        save_manifest(&manifest, base)?;
+    }
+    Ok(removed)
+}
+
+/// Dry-run of [`cleanup_for_prefix`]: reports what would be deleted without deleting it.
+pub fn preview_cleanup_for_prefix(
+    prefix: &str,
+    cache_base_dir: &str,
+) -> Result<CleanupPreview, String> {
+    let prefixes = [prefix.to_string()];
+    let previews = caches_for_prefix(prefix, cache_base_dir)
+        .iter()
+        .map(|base| {
+            let _lock = CacheLock::acquire(base)?;
+            let manifest = load_manifest(base)?;
+            let to_remove = select_for_prefixes(&manifest, &prefixes, base);
+            Ok(preview_entries(&manifest, &to_remove, base))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(merge_previews(previews))
+}
+
+/// Deletes the entries whose source file no longer exists from the cache and the
+/// portable caches of the roots.
+pub fn cleanup_orphans(cache_base_dir: &str) -> Result<u32, String> {
+    cache_dirs(Path::new(cache_base_dir), &roots())
+        .iter()
+        .map(|base| {
+            let _lock = CacheLock::acquire(base)?;
+            let mut manifest = load_manifest(base)?;
+
+            let orphans = select_orphans(&manifest, base);
+            let removed = remove_entries(&mut manifest, &orphans, base);
+
+            if removed > 0 {
+                save_manifest(&manifest, base)?;
+            }
+            Ok(removed)
+        })
+        .sum()
+}
+
+/// Dry-run of [`cleanup_orphans`]: reports what would be deleted without deleting it.
+pub fn preview_cleanup_orphans(cache_base_dir: &str) -> Result<CleanupPreview, String> {
+    let previews = cache_dirs(Path::new(cache_base_dir), &roots())
+        .iter()
+        .map(|base| {
+            let _lock = CacheLock::acquire(base)?;
+            let manifest = load_manifest(base)?;
+            let orphans = select_orphans(&manifest, base);
+            Ok(preview_entries(&manifest, &orphans, base))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(merge_previews(previews))
+}
+
+/// Inconsistencies found in a cache directory by [`verify`].
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyReport {
+    /// Sources of thumbnails that have no manifest entry but could be attributed.
+    pub recoverable: Vec<String>,
+    /// Thumbnail files that have no manifest entry and an unknown source.
+    pub unregistered: Vec<String>,
+    /// Sources whose manifest entry points to a missing thumbnail.
+    pub missing: Vec<String>,
+    /// Sources whose thumbnail is empty or cannot be decoded.
+    pub corrupt: Vec<String>,
+    /// Leftover files such as `.write_test` or abandoned temporary files.
+    pub stray: Vec<String>,
+    /// Sources that lost their thumbnail during repair. They are regenerated
+    /// the next time their folder is opened.
+    pub regenerate: Vec<String>,
+    /// True if the problems above were repaired.
+    pub repaired: bool,
+}
+
+impl VerifyReport {
+    /// Returns true if no inconsistencies were found.
+    pub fn is_clean(&self) -> bool {
+        self.recoverable.is_empty()
+            && self.unregistered.is_empty()
+            && self.missing.is_empty()
+            && self.corrupt.is_empty()
+            && self.stray.is_empty()
+    }
+}
+
+/// Returns true if the file is a leftover that does not belong in the cache.
+fn is_stray_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    if name == ".write_test" {
+        return true;
+    }
+    is_abandoned_temp(path)
+}
+
+/// Returns true for temporary files old enough to be abandoned. Recent ones may
+/// still be written by another thread or process, see [`write_atomic`].
+fn is_abandoned_temp(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some(TEMP_EXTENSION)
+        && fs::metadata(path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|age| age >= STALE_TEMP_AGE)
+}
+
+/// Returns true if the thumbnail is non-empty and can be decoded.
+/// Only the header is parsed, plus a check for the JPEG end marker to catch
+/// files that were truncated mid-write.
+fn is_valid_thumbnail(path: &Path) -> bool {
+    let data = match fs::read(path) {
+        Ok(d) if !d.is_empty() => d,
+        _ => return false,
+    };
+
+    let reader = match image::ImageReader::new(std::io::Cursor::new(&data)).with_guessed_format() {
+        Ok(r) => r,
+        Err(_) => return false,
+    };
+    if reader.format() == Some(image::ImageFormat::Jpeg) {
+        let tail = &data[data.len().saturating_sub(32)..];
+        if !tail.windows(2).any(|w| w == [0xFF, 0xD9]) {
+            return false;
+        }
+    }
+    reader.into_dimensions().is_ok()
+}
+
+/// Walks the given roots looking for files whose path hashes to one of `hashes`.
+/// Stops as soon as every hash has been attributed.
+fn find_sources_for_hashes(
+    roots: &[String],
+    hashes: &HashSet<String>,
+    cache_base_dir: &Path,
+) -> HashMap<String, PathBuf> {
+    let mut found = HashMap::new();
+    let mut stack: Vec<PathBuf> = roots.iter().map(PathBuf::from).collect();
+
+    while let Some(dir) = stack.pop() {
+        if found.len() == hashes.len() {
+            break;
+        }
+        let entries = match fs::read_dir(&dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => stack.push(path),
+                Ok(t) if t.is_file() => {
+                    let hash = hash_for_source(&path, cache_base_dir);
+                    if hashes.contains(&hash) {
+                        found.insert(hash, path);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    found
+}
+
+/// Checks the cache directory for inconsistencies between the manifest and the
+/// thumbnail files. Thumbnails without a manifest entry are attributed to their
+/// source through the salvaged corrupt manifest or by walking `roots`.
+///
+/// With `repair`, attributed thumbnails are re-registered, unattributed, empty
+/// or undecodable thumbnails and stray files are deleted, and dangling manifest
+/// entries are removed. Sources that still exist are listed in
+/// [`VerifyReport::regenerate`].
+///
+/// The portable caches of `roots` are verified along with the cache.
+pub fn verify(
+    cache_base_dir: &str,
+    roots: &[String],
+    repair: bool,
+) -> Result<VerifyReport, String> {
+    let mut report = VerifyReport::default();
+    for dir in cache_dirs(Path::new(cache_base_dir), roots) {
+        // Thumbnails in a portable cache can only belong to files below its root
+        let walk = match portable_root(&dir) {
+            Some(root) => vec![super::normalize_path(&root.to_string_lossy())],
+            None => roots.to_vec(),
+        };
+        let found = verify_dir(&dir, &walk, repair)?;
+        report.recoverable.extend(found.recoverable);
+        report.unregistered.extend(found.unregistered);
+        report.missing.extend(found.missing);
+        report.corrupt.extend(found.corrupt);
+        report.stray.extend(found.stray);
+        report.regenerate.extend(found.regenerate);
+        report.repaired |= found.repaired;
+    }
+    Ok(report)
+}
+
+/// Verifies a single cache directory, see [`verify`].
+fn verify_dir(base: &Path, roots: &[String], repair: bool) -> Result<VerifyReport, String> {
+    let mut report = VerifyReport::default();
+    if !base.is_dir() {
+        return Ok(report);
+    }
+
+    let mut unregistered: HashMap<String, PathBuf> = HashMap::new();
+    let mut corrupt_hashes: Vec<String> = Vec::new();
+    let mut missing_hashes: Vec<String> = Vec::new();
+    let mut stray_files: Vec<PathBuf> = Vec::new();
+    let mut broken_unregistered: Vec<PathBuf> = Vec::new();
+    {
+        let _lock = CacheLock::acquire(base)?;
+        let manifest = load_manifest(base)?;
+
+        for path in cache_files(base)? {
+            if is_stray_file(&path) {
+                stray_files.push(path);
+                continue;
+            }
+            let hash = match hash_of_thumbnail(&path) {
+                Some(h) => h,
+                None => continue,
+            };
+
+            let valid = is_valid_thumbnail(&path);
+            if manifest.contains_key(&hash) {
+                if !valid && !corrupt_hashes.contains(&hash) {
+                    corrupt_hashes.push(hash);
+                }
+            } else if valid {
+                unregistered.entry(hash).or_insert(path);
+            } else {
+                // Not worth attributing a broken thumbnail, treat it as unknown
+                broken_unregistered.push(path);
+            }
+        }
+
+        for (hash, entry) in &manifest {
+            if entry.failure.is_none() && !thumbnail_path_for_hash(hash, base).exists() {
+                missing_hashes.push(hash.clone());
+            }
+            if corrupt_hashes.contains(hash) {
+                report.corrupt.push(source_display(&entry.source, base));
+            }
+        }
+        report.missing = missing_hashes
+            .iter()
+            .filter_map(|h| manifest.get(h))
+            .map(|e| source_display(&e.source, base))
+            .collect();
+        report.stray = stray_files
+            .iter()
+            .map(|p| super::normalize_path(&p.to_string_lossy()))
+            .collect();
+    }
+
+    // Attribute unregistered thumbnails without holding the lock, walking roots may take a while
+    let mut attributed: HashMap<String, PathBuf> = HashMap::new();
+    if !unregistered.is_empty() {
+        if let Ok(data) = fs::read(corrupt_manifest_path(base)) {
+            let corrupt_text = String::from_utf8_lossy(&data);
+            for hash in unregistered.keys() {
+                if let Some(entry) = salvage_entry(&corrupt_text, hash) {
+                    attributed.insert(hash.clone(), source_path(&entry.source, base));
+                }
+            }
+        }
+        let remaining: HashSet<String> = unregistered
+            .keys()
+            .filter(|h| !attributed.contains_key(*h))
+            .cloned()
+            .collect();
+        if !remaining.is_empty() {
+            attributed.extend(find_sources_for_hashes(roots, &remaining, base));
+        }
+    }
+    report.unregistered.extend(
+        broken_unregistered
+            .iter()
+            .map(|p| super::normalize_path(&p.to_string_lossy())),
+    );
+    for (hash, thumb) in &unregistered {
+        match attributed.get(hash) {
+            Some(source) => report
+                .recoverable
+                .push(super::normalize_path(&source.to_string_lossy())),
+            None => report
+                .unregistered
+                .push(super::normalize_path(&thumb.to_string_lossy())),
+        }
+    }
+
+    if !repair || report.is_clean() {
+        return Ok(report);
+    }
+
+    let _lock = CacheLock::acquire(base)?;
+    let mut manifest = load_manifest(base)?;
+
+    for (hash, source) in &attributed {
+        if hash_for_source(source, base) == *hash && !manifest.contains_key(hash) {
+            manifest.insert(
+                hash.clone(),
+                ManifestEntry {
+                    source: source_key(source, base),
+                    fingerprint: fingerprint(source),
+                    failure: None,
+                    proxy: proxy_path_for_hash(hash, base).exists(),
+                    probe: None,
+                },
+            );
+        }
+    }
+    for hash in unregistered.keys() {
+        if !attributed.contains_key(hash) && !manifest.contains_key(hash) {
+            for thumb in thumbnail_files_for_hash(hash, base) {
+                let _ = fs::remove_file(thumb);
+            }
+        }
+    }
+    for hash in corrupt_hashes.iter().chain(missing_hashes.iter()) {
+        for thumb in thumbnail_files_for_hash(hash, base) {
+            let _ = fs::remove_file(thumb);
+        }
+        if let Some(entry) = manifest.remove(hash) {
+            if source_path(&entry.source, base).exists() {
+                report.regenerate.push(source_display(&entry.source, base));
+            }
+        }
+    }
+    for path in stray_files.iter().chain(broken_unregistered.iter()) {
+        let _ = fs::remove_file(path);
+    }
+
+    save_manifest(&manifest, base)?;
+    report.repaired = true;
+    Ok(report)
+}
+
+// --- Previews ---
+
+/// Returns the path to the full-screen preview of a source file.
+/// Previews are not registered in the manifest, they are disposable and evicted
+/// by [`evict_previews`] instead.
+/// Format: <cache_base_dir>/previews/<hash prefix>/<hash>.jpg
+pub fn preview_path(source: &Path, cache_base_dir: &Path) -> PathBuf {
+    let hash = hash_for_source(source, cache_base_dir);
+    thumbnail_path_for_hash(&hash, &cache_base_dir.join(PREVIEW_DIR))
+}
+
+/// Marks a preview, tile pyramid or transcode as recently used, so eviction removes
+/// it last. The modification time doubles as the last use, access times are often disabled.
+pub fn touch(path: &Path) {
+    if let Ok(file) = fs::OpenOptions::new().write(true).open(path) {
+        let _ = file.set_modified(std::time::SystemTime::now());
+    }
+}
+
+/// Deletes the least recently used previews until they take up at most `max_bytes`.
+/// Returns the number of previews deleted.
+pub fn evict_previews(cache_base_dir: &Path, max_bytes: u64) -> Result<u32, String> {
+    evict_least_recently_used(cache_base_dir, PREVIEW_DIR, max_bytes)
+}
+
+/// Deletes the least recently used files of a sharded cache subdirectory until they
+/// take up at most `max_bytes`. Returns the number of files deleted.
+fn evict_least_recently_used(
+    cache_base_dir: &Path,
+    dir_name: &str,
+    max_bytes: u64,
+) -> Result<u32, String> {
+    let dir = cache_base_dir.join(dir_name);
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+
+    let _lock = CacheLock::acquire(cache_base_dir)?;
+    let mut files: Vec<(std::time::SystemTime, u64, PathBuf)> = cache_files(&dir)?
+        .into_iter()
+        .filter_map(|path| {
+            let meta = fs::metadata(&path).ok()?;
+            Some((meta.modified().ok()?, meta.len(), path))
+        })
+        .collect();
+
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    if total <= max_bytes {
+        return Ok(0);
+    }
+
+    files.sort_by_key(|(modified, _, _)| *modified);
+    let mut evicted = 0u32;
+    for (_, len, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if fs::remove_file(&path).is_ok() {
+            total -= len;
+            evicted += 1;
+        }
+    }
+    Ok(evicted)
+}
+
+// --- Transcodes ---
+
+/// Returns the path to the browser-playable transcode of a video.
+/// Like previews, transcodes are disposable and evicted by [`evict_transcodes`].
+/// Format: <cache_base_dir>/transcodes/<hash prefix>/<hash>.mp4
+pub fn transcode_path(source: &Path, cache_base_dir: &Path) -> PathBuf {
+    let hash = hash_for_source(source, cache_base_dir);
+    thumbnail_path_for_hash(&hash, &cache_base_dir.join(TRANSCODE_DIR)).with_extension("mp4")
+}
+
+/// Deletes the least recently played transcodes until they take up at most `max_bytes`.
+/// Returns the number of transcodes deleted.
+pub fn evict_transcodes(cache_base_dir: &Path, max_bytes: u64) -> Result<u32, String> {
+    evict_least_recently_used(cache_base_dir, TRANSCODE_DIR, max_bytes)
+}
+
+/// Finds a transcode by its file name in the first of `cache_dirs` holding it,
+/// see [`transcode_path`].
+pub fn find_transcode(name: &str, cache_dirs: &[PathBuf]) -> Option<PathBuf> {
+    let hash = name
+        .strip_suffix(".mp4")
+        .filter(|hash| !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit()))?;
+    cache_dirs
+        .iter()
+        .map(|dir| thumbnail_path_for_hash(hash, &dir.join(TRANSCODE_DIR)).with_extension("mp4"))
+        .find(|path| path.is_file())
+}
+
+// --- Proxies ---
+
+/// Returns the path to the low-resolution proxy of a source video.
+/// Proxies are stored next to the thumbnails and registered in the manifest with
+/// [`register_proxy`], so they are cleaned up, moved and verified along with them.
+/// Format: <cache_base_dir>/<hash prefix>/<hash>_proxy.mp4
+pub fn proxy_path(source: &Path, cache_base_dir: &Path) -> PathBuf {
+    proxy_path_for_hash(&hash_for_source(source, cache_base_dir), cache_base_dir)
+}
+
+/// Records in the manifest that a proxy was generated for `source`.
+pub fn register_proxy(source: &Path, cache_base_dir: &Path) -> Result<(), String> {
+    create_cache_dir(cache_base_dir)?;
+    let _lock = CacheLock::acquire(cache_base_dir)?;
+    let mut manifest = load_manifest(cache_base_dir)?;
+    manifest
+        .entry(hash_for_source(source, cache_base_dir))
+        .or_insert_with(|| ManifestEntry {
+            source: source_key(source, cache_base_dir),
+            fingerprint: fingerprint(source),
+            failure: None,
+            proxy: false,
+            probe: None,
+        })
+        .proxy = true;
+    save_manifest(&manifest, cache_base_dir)
+}
+
+/// Records the properties of a video probed for a proxy, see [`recorded_probe`].
+pub fn record_probe(
+    source: &Path,
+    cache_base_dir: &Path,
+    size: Option<(u32, u32)>,
+    bit_rate: Option<u64>,
+) -> Result<(), String> {
+    let fingerprint =
+        fingerprint(source).ok_or_else(|| format!("Failed to read {}", source.display()))?;
+    create_cache_dir(cache_base_dir)?;
+    let _lock = CacheLock::acquire(cache_base_dir)?;
+    let mut manifest = load_manifest(cache_base_dir)?;
+    manifest
+        .entry(hash_for_source(source, cache_base_dir))
+        .or_insert_with(|| ManifestEntry {
+            source: source_key(source, cache_base_dir),
+            fingerprint: None,
+            failure: None,
+            proxy: false,
+            probe: None,
+        })
+        .probe = Some(VideoProbe {
+        fingerprint,
+        size,
+        bit_rate,
+    });
+    save_manifest(&manifest, cache_base_dir)
+}
+
+/// Returns the recorded properties of a video if its content did not change since it
+/// was probed, see [`record_probe`].
+pub fn recorded_probe(source: &Path, cache_base_dir: &Path) -> Result<Option<VideoProbe>, String> {
+    let probe = {
+        let _lock = CacheLock::acquire(cache_base_dir)?;
+        load_manifest(cache_base_dir)?
+            .remove(&hash_for_source(source, cache_base_dir))
+            .and_then(|e| e.probe)
+    };
+    Ok(probe.filter(|probe| fingerprint(source).as_ref() == Some(&probe.fingerprint)))
+}
+
+/// Returns the proxy of `source` if one is registered and the source did not change since.
+pub fn existing_proxy(source: &Path, cache_base_dir: &Path) -> Result<Option<PathBuf>, String> {
+    let hash = hash_for_source(source, cache_base_dir);
+    let registered = {
+        let _lock = CacheLock::acquire(cache_base_dir)?;
+        load_manifest(cache_base_dir)?
+            .get(&hash)
+            .is_some_and(|e| e.proxy)
+    };
+    let path = proxy_path_for_hash(&hash, cache_base_dir);
+    Ok((registered && path.is_file() && !is_stale(source, &path)).then_some(path))
+}
+
+/// Finds a proxy by its file name in the first of `cache_dirs` holding it, see [`proxy_path`].
+pub fn find_proxy(name: &str, cache_dirs: &[PathBuf]) -> Option<PathBuf> {
+    let hash = name
+        .strip_suffix("_proxy.mp4")
+        .filter(|hash| !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit()))?;
+    cache_dirs
+        .iter()
+        .map(|dir| proxy_path_for_hash(hash, dir))
+        .find(|path| path.is_file())
+}
+
+// --- Serving ---
+
+/// Finds a cached thumbnail or variant by its file name in the first of `cache_dirs`
+/// holding it. Names other than thumbnail names are rejected, so callers serving
+/// the result cannot be made to leave the cache.
+pub fn find_thumbnail(name: &str, cache_dirs: &[PathBuf]) -> Option<PathBuf> {
+    if name.contains(['/', '\\']) {
+        return None;
+    }
+    let hash = hash_of_thumbnail(Path::new(name))
+        .filter(|hash| !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit()))?;
+    cache_dirs
+        .iter()
+        .map(|dir| thumbnail_path_for_hash(&hash, dir).with_file_name(name))
+        .find(|path| path.is_file())
+}
+
+/// Returns the cached thumbnail of `source` if it is up to date, without generating
+/// or rekeying anything. Portable caches of enclosing collections are respected.
+pub fn fresh_thumbnail(source: &Path, cache_base_dir: &Path) -> Option<PathBuf> {
+    let cache_base_dir = cache_dir_for(source, cache_base_dir);
+    let thumb = thumbnail_path_for_hash(&hash_for_source(source, &cache_base_dir), &cache_base_dir);
+    (thumb.is_file() && !is_stale(source, &thumb)).then_some(thumb)
+}
+
+/// Finds a full-screen preview by its file name in the first of `cache_dirs` holding
+/// it, see [`preview_path`].
+pub fn find_preview(name: &str, cache_dirs: &[PathBuf]) -> Option<PathBuf> {
+    let dirs: Vec<PathBuf> = cache_dirs.iter().map(|dir| dir.join(PREVIEW_DIR)).collect();
+    find_thumbnail(name, &dirs)
+}
+
+/// Returns the portable cache directory of a collection root, see [`cache_dir_for`].
+pub fn portable_cache_dir(root: &Path) -> PathBuf {
+    root.join(PORTABLE_CACHE_DIR)
+}
+
+// --- Tiles ---
+
+/// Returns the total size of the files below a directory.
+fn dir_size(dir: &Path) -> u64 {
+    collect_cache_files(dir)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|file| fs::metadata(dir.join(file)).ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Returns the directory holding the deep-zoom tile pyramid of a source file.
+/// Like previews, pyramids are not registered in the manifest and are evicted
+/// by [`evict_tiles`] instead.
+/// Format: <cache_base_dir>/tiles/<hash>
+pub fn tile_pyramid_dir(source: &Path, cache_base_dir: &Path) -> PathBuf {
+    let hash = hash_for_source(source, cache_base_dir);
+    cache_base_dir.join(TILE_DIR).join(hash)
+}
+
+/// Deletes the least recently used tile pyramids until they take up at most `max_bytes`.
+/// The last use of a pyramid is the modification time of `marker`, a file inside
+/// each pyramid directory; pyramids without it are incomplete and deleted first.
+/// The pyramid in `keep` is never deleted. Returns the number of pyramids deleted.
+pub fn evict_tiles(
+    cache_base_dir: &Path,
+    max_bytes: u64,
+    marker: &str,
+    keep: &Path,
+) -> Result<u32, String> {
+    let dir = cache_base_dir.join(TILE_DIR);
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+
+    let _lock = CacheLock::acquire(cache_base_dir)?;
+    let entries = fs::read_dir(&dir).map_err(|e| format!("Failed to read tile dir: {}", e))?;
+    let mut pyramids: Vec<(Option<std::time::SystemTime>, u64, PathBuf)> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| path.is_dir() && path != keep)
+        .map(|path| {
+            let used = fs::metadata(path.join(marker))
+                .and_then(|m| m.modified())
+                .ok();
+            (used, dir_size(&path), path)
+        })
+        .collect();
+
+    let mut total: u64 = dir_size(keep) + pyramids.iter().map(|(_, size, _)| size).sum::<u64>();
+
+    pyramids.sort_by_key(|(used, _, _)| *used);
+    let mut evicted = 0u32;
+    for (used, size, path) in pyramids {
+        if total <= max_bytes && used.is_some() {
+            break;
+        }
+        if fs::remove_dir_all(&path).is_ok() {
+            total -= size;
+            evicted += 1;
+        }
+    }
+    Ok(evicted)
+}
+
+/// Progress of [`move_cache`], reported after every copied file.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheMoveProgress {
+    /// Number of files copied so far.
+    pub copied_files: u64,
+    /// Total number of files to copy.
+    pub total_files: u64,
+    /// Number of bytes copied so far.
+    pub copied_bytes: u64,
+    /// Total number of bytes to copy.
+    pub total_bytes: u64,
+}
+
+/// Resolves symlinks and relative components of a path that may not exist yet,
+/// so paths can be compared reliably.
+fn resolve_path(path: &Path) -> String {
+    let resolved = match (path.canonicalize(), path.parent(), path.file_name()) {
+        (Ok(p), _, _) => p,
+        (Err(_), Some(parent), Some(name)) => match parent.canonicalize() {
+            Ok(p) => p.join(name),
+            Err(_) => path.to_path_buf(),
+        },
+        _ => path.to_path_buf(),
+    };
+    super::normalize_path(&resolved.to_string_lossy())
+}
+
+/// Collects all files below `dir` as paths relative to it, except the lock file
+/// and leftovers that are not worth carrying over.
+fn collect_cache_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    let mut stack = vec![dir.to_path_buf()];
+    let lock_file = lock_path(dir);
+
+    while let Some(current) = stack.pop() {
+        let entries =
+            fs::read_dir(&current).map_err(|e| format!("Failed to read cache dir: {}", e))?;
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                stack.push(path);
+            } else if path != lock_file && !is_stray_file(&path) {
+                if let Ok(relative) = path.strip_prefix(dir) {
+                    files.push(relative.to_path_buf());
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Moves the cache from `from` to `to`.
+/// All files are copied first and compared with the originals, only then is the
+/// old location deleted. Refuses targets that overlap the current cache, lie
+/// inside or above one of the media `roots`, are not empty or are not writable.
+pub fn move_cache(
+    from: &str,
+    to: &str,
+    roots: &[String],
+    mut on_progress: impl FnMut(&CacheMoveProgress),
+) -> Result<CacheMoveProgress, String> {
+    let source_dir = Path::new(from);
+    let target_dir = Path::new(to);
+    let source = resolve_path(source_dir);
+    let target = resolve_path(target_dir);
+
+    if is_under_prefix(&target, &source) || is_under_prefix(&source, &target) {
+        return Err(format!(
+            "Cannot move the cache to {}: it overlaps the current cache location",
+            to
+        ));
+    }
+    for root in roots {
+        let root = resolve_path(Path::new(root));
+        if is_under_prefix(&target, &root) || is_under_prefix(&root, &target) {
+            return Err(format!(
+                "Cannot move the cache to {}: it overlaps the media folder {}",
+                to, root
+            ));
+        }
+    }
+    if target_dir.exists()
+        && fs::read_dir(target_dir)
+            .map_err(|e| format!("Failed to read target directory: {}", e))?
+            .next()
+            .is_some()
+    {
+        return Err(format!(
+            "Cannot move the cache to {}: the directory is not empty",
+            to
+        ));
+    }
+    // Fails for read-only volumes
+    ensure_cache_dir(target_dir)?;
+
+    let mut progress = CacheMoveProgress::default();
+    if !source_dir.is_dir() {
+        return Ok(progress);
+    }
+
+    {
+        let _lock = CacheLock::acquire(source_dir)?;
+        let files = collect_cache_files(source_dir)?;
+        progress.total_files = files.len() as u64;
+        progress.total_bytes = files
+            .iter()
+            .filter_map(|f| fs::metadata(source_dir.join(f)).ok())
+            .map(|m| m.len())
+            .sum();
+        on_progress(&progress);
+
+        let mut copy_all = || -> Result<(), String> {
+            for file in &files {
+                let src = source_dir.join(file);
+                let dest = target_dir.join(file);
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)
+                        .map_err(|e| format!("Failed to create directory: {}", e))?;
+                }
+                let copied = fs::copy(&src, &dest)
+                    .map_err(|e| format!("Failed to copy {}: {}", src.display(), e))?;
+                let expected = fs::metadata(&src).map(|m| m.len()).unwrap_or(copied);
+                if copied != expected || fs::metadata(&dest).map(|m| m.len()).ok() != Some(expected)
+                {
+                    return Err(format!("Verification failed for {}", dest.display()));
+                }
+
+                progress.copied_files += 1;
+                progress.copied_bytes += copied;
+                on_progress(&progress);
+            }
+
+            // The manifest is the one file whose contents matter beyond its size
+            let manifest = manifest_path(Path::new(""));
+            if files.contains(&manifest)
+                && fs::read(source_dir.join(&manifest)).ok()
+                    != fs::read(target_dir.join(&manifest)).ok()
+            {
+                return Err("Verification failed for the manifest".to_string());
+            }
+            Ok(())
+        };
+
+        if let Err(e) = copy_all() {
+            // Leave the old cache untouched and clean up the partial copy
+            let _ = fs::remove_dir_all(target_dir);
+            return Err(e);
+        }
+        // Still locked, so nothing is written to the old cache after it was copied
+        delete_contents(source_dir)?;
+    }
+
+    fs::remove_dir_all(source_dir).map_err(|e| format!("Failed to delete cache dir: {}", e))?;
+    Ok(progress)
+}
+
+/// Deletes the entire thumbnail cache directory.
+/// The lock file is removed last, after the lock has been released.
+pub fn delete_all(cache_base_dir: &str) -> Result<(), String> {
+    let base = Path::new(cache_base_dir);
+    if !base.exists() {
+        return Ok(());
+    }
+
+    {
+        let _lock = CacheLock::acquire(base)?;
+        delete_contents(base)?;
+    }
+
+    fs::remove_dir_all(base).map_err(|e| format!("Failed to delete cache dir: {}", e))
+}
+
+/// Deletes everything in a cache directory but its lock file. Callers hold the lock.
+fn delete_contents(base: &Path) -> Result<(), String> {
+    let lock_file = lock_path(base);
+    let entries = fs::read_dir(base).map_err(|e| format!("Failed to read cache dir: {}", e))?;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path == lock_file {
+            continue;
+        }
+        let result = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        result.map_err(|e| format!("Failed to delete cache dir: {}", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::normalize_path;
+    use super::*;
+    use std::fs::File;
+    use std::sync::MutexGuard;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    /// Helper function to create an isolated test environment
+    struct TestEnvGuard {
+        pub temp_dir: tempfile::TempDir,
+    }
+
+    fn setup_test_env() -> TestEnvGuard {
+        let temp_dir = tempdir().expect("Failed to create temp test directory");
+        TestEnvGuard { temp_dir }
+    }
+
+    /// Serializes the tests setting the global roots.
+    static ROOTS_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Sets the roots for the rest of a test.
+    fn use_roots(roots: &[&Path]) -> MutexGuard<'static, ()> {
+        let guard = ROOTS_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let roots: Vec<String> = roots
+            .iter()
+            .map(|root| root.to_string_lossy().to_string())
+            .collect();
+        set_roots(&roots);
+        guard
+    }
+
+    #[test]
+    fn test_hash_for_path_deterministic() {
+        // Same logical path should yield same hash
+        let hash1 = hash_for_path(&PathBuf::from("/foo/bar/image.jpg"));
+        let hash2 = hash_for_path(&PathBuf::from("/foo/bar/image.jpg"));
+        assert_eq!(hash1, hash2, "Hashes should be deterministic");
     }
 
     #[test]
@@ -314,7 +2146,7 @@ mod tests {
 
         let hash = hash_for_path(&test_path);
         assert_eq!(
-            updated_manifest.get(&hash).unwrap(),
+            updated_manifest.get(&hash).unwrap().source,
             "/test/source/image.jpg"
         );
     }
@@ -431,67 +2263,519 @@ mod tests {
         let env = setup_test_env();
         let path_a = thumbnail_path(&PathBuf::from("/photos/a.jpg"), env.temp_dir.path()).unwrap();
         let path_b = thumbnail_path(&PathBuf::from("/photos/b.jpg"), env.temp_dir.path()).unwrap();
-        assert_ne!(path_a, path_b, "different source paths must yield different thumbnail paths");
+        assert_ne!(
+            path_a, path_b,
+            "different source paths must yield different thumbnail paths"
+        );
     }
 
-    // ---------------------------------------------------------------------------
-    // cleanup_for_prefix
-    // ---------------------------------------------------------------------------
-
     #[test]
-    fn test_cleanup_for_prefix_removes_matching_entries_and_files() {
+    fn test_thumbnail_path_is_sharded_by_hash_prefix() {
         let env = setup_test_env();
-        let cache_dir = env.temp_dir.path();
-        let cache_dir_str = cache_dir.to_str().unwrap().to_string();
-
-        let path_a = PathBuf::from("/photos/vacation/img1.jpg");
-        let path_b = PathBuf::from("/photos/vacation/img2.jpg");
-        let path_c = PathBuf::from("/documents/scan1.jpg");
-
-        register_thumbnail(&path_a, cache_dir).unwrap();
-        register_thumbnail(&path_b, cache_dir).unwrap();
-        register_thumbnail(&path_c, cache_dir).unwrap();
-
-        let thumb_a = thumbnail_path(&path_a, cache_dir).unwrap();
-        let thumb_b = thumbnail_path(&path_b, cache_dir).unwrap();
-        let thumb_c = thumbnail_path(&path_c, cache_dir).unwrap();
-        std::fs::write(&thumb_a, b"fake").unwrap();
-        std::fs::write(&thumb_b, b"fake").unwrap();
-        std::fs::write(&thumb_c, b"fake").unwrap();
-
-        let removed = cleanup_for_prefix("/photos/vacation", &cache_dir_str).unwrap();
+        let source = PathBuf::from("/photos/image.jpg");
+        let hash = hash_for_path(&source);
 
-        assert_eq!(removed, 2, "two matching entries should be removed");
-        assert!(!thumb_a.exists(), "matching thumbnail A should be deleted from disk");
-        assert!(!thumb_b.exists(), "matching thumbnail B should be deleted from disk");
-        assert!(thumb_c.exists(), "non-matching thumbnail C should remain on disk");
+        let path = thumbnail_path(&source, env.temp_dir.path()).unwrap();
 
-        let manifest = load_manifest(cache_dir).unwrap();
-        assert!(
-            !manifest.values().any(|v| v.starts_with("/photos/vacation")),
-            "manifest should not contain the removed prefix"
-        );
-        assert!(
-            manifest.values().any(|v| v.contains("scan1")),
-            "manifest should still contain the non-matching entry"
+        assert_eq!(
+            path,
+            env.temp_dir
+                .path()
+                .join(&hash[..2])
+                .join(format!("{}.jpg", hash))
         );
     }
 
     #[test]
-    fn test_cleanup_for_prefix_no_matches_returns_zero() {
-        let env = setup_test_env();
-        let cache_dir = env.temp_dir.path();
-        let cache_dir_str = cache_dir.to_str().unwrap().to_string();
+    fn test_hash_of_thumbnail_accepts_variants() {
+        assert_eq!(
+            hash_of_thumbnail(Path::new("ab/abcdef0123456789.jpg")),
+            Some("abcdef0123456789".to_string())
+        );
+        assert_eq!(
+            hash_of_thumbnail(Path::new("ab/abcdef0123456789_256.jpg")),
+            Some("abcdef0123456789".to_string())
+        );
+        assert_eq!(
+            hash_of_thumbnail(Path::new("ab/abcdef0123456789_x.jpg")),
+            None
+        );
+        assert_eq!(hash_of_thumbnail(Path::new("manifest.json")), None);
+    }
+
+    #[test]
+    fn test_cleanup_removes_size_variants() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let source = PathBuf::from("/photos/img.jpg");
+        register_thumbnail(&source, cache_dir).unwrap();
+        write_valid_thumbnail(&thumbnail_path(&source, cache_dir).unwrap());
+        for size in VARIANT_SIZES {
+            write_valid_thumbnail(&variant_path(&source, *size, cache_dir));
+        }
+
+        let report = verify(cache_dir.to_str().unwrap(), &[], false).unwrap();
+        assert!(
+            report.is_clean(),
+            "variants are not unregistered: {:?}",
+            report
+        );
+
+        assert_eq!(cleanup_orphans(cache_dir.to_str().unwrap()).unwrap(), 1);
+        for size in VARIANT_SIZES {
+            assert!(!variant_path(&source, *size, cache_dir).exists());
+        }
+    }
+
+    // ---------------------------------------------------------------------------
+    // layout migration
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_load_manifest_migrates_flat_layout() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let source = PathBuf::from("/photos/img.jpg");
+        let hash = hash_for_path(&source);
+        let flat = cache_dir.join(format!("{}.jpg", hash));
+        std::fs::write(&flat, b"old thumb").unwrap();
+        std::fs::write(cache_dir.join("cover.jpg"), b"not a thumbnail").unwrap();
+        std::fs::write(
+            manifest_path(cache_dir),
+            format!(r#"{{"{}": "/photos/img.jpg"}}"#, hash),
+        )
+        .unwrap();
+
+        let manifest = load_manifest(cache_dir).unwrap();
+
+        let sharded = thumbnail_path(&source, cache_dir).unwrap();
+        assert_eq!(manifest.len(), 1);
+        assert!(!flat.exists(), "flat thumbnail should be moved");
+        assert_eq!(std::fs::read(&sharded).unwrap(), b"old thumb");
+        assert!(
+            cache_dir.join("cover.jpg").exists(),
+            "files not named like a hash are left alone"
+        );
+        assert!(verify(cache_dir.to_str().unwrap(), &[], false)
+            .unwrap()
+            .missing
+            .is_empty());
+    }
+
+    // ---------------------------------------------------------------------------
+    // manifest format
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_load_manifest_reads_legacy_format() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        std::fs::write(
+            manifest_path(cache_dir),
+            r#"{ "0123456789abcdef": "/photos/old.jpg" }"#,
+        )
+        .unwrap();
+
+        let manifest = load_manifest(cache_dir).unwrap();
+        let entry = manifest.get("0123456789abcdef").unwrap();
+        assert_eq!(entry.source, "/photos/old.jpg");
+        assert_eq!(entry.fingerprint, None);
+    }
+
+    #[test]
+    fn test_load_manifest_rebuilds_corrupt_manifest() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+
+        let kept = PathBuf::from("/photos/kept.jpg");
+        let lost = PathBuf::from("/photos/lost.jpg");
+        register_thumbnail(&kept, cache_dir).unwrap();
+        register_thumbnail(&lost, cache_dir).unwrap();
+        write_thumbnail(&thumbnail_path(&kept, cache_dir).unwrap(), b"fake").unwrap();
+        write_thumbnail(&thumbnail_path(&lost, cache_dir).unwrap(), b"fake").unwrap();
+
+        // Simulate a manifest truncated right after the entry of `kept`
+        let kept_hash = hash_for_path(&kept);
+        let truncated = format!(
+            "{{\n  \"{}\": {{\n    \"source\": \"/photos/kept.jpg\"\n  }},\n  \"",
+            kept_hash
+        );
+        std::fs::write(manifest_path(cache_dir), truncated).unwrap();
+        std::fs::write(cache_dir.join("abc.jpg.1-0.tmp"), b"partial").unwrap();
+        File::options()
+            .write(true)
+            .open(cache_dir.join("abc.jpg.1-0.tmp"))
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - STALE_TEMP_AGE)
+            .unwrap();
+        // Recent ones may still be renamed into place by another writer
+        std::fs::write(cache_dir.join("def.jpg.1-1.tmp"), b"partial").unwrap();
+
+        let manifest = load_manifest(cache_dir).expect("corrupt manifest should be rebuilt");
+
+        assert_eq!(
+            manifest.len(),
+            1,
+            "only the recoverable entry should remain"
+        );
+        assert_eq!(manifest.get(&kept_hash).unwrap().source, "/photos/kept.jpg");
+        assert!(thumbnail_path(&kept, cache_dir).unwrap().exists());
+        assert!(
+            !thumbnail_path(&lost, cache_dir).unwrap().exists(),
+            "unrecoverable thumbnail should be deleted"
+        );
+        assert!(
+            !cache_dir.join("abc.jpg.1-0.tmp").exists(),
+            "stray temp file should be deleted"
+        );
+        assert!(
+            cache_dir.join("def.jpg.1-1.tmp").exists(),
+            "recent temp file should be kept"
+        );
+        assert!(corrupt_manifest_path(cache_dir).exists());
+        assert_eq!(
+            load_manifest(cache_dir).unwrap(),
+            manifest,
+            "rebuilt manifest should be persisted"
+        );
+    }
+
+    #[test]
+    fn test_cleanup_orphans_succeeds_with_corrupt_manifest() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        std::fs::write(manifest_path(cache_dir), b"\0\0\0garbage").unwrap();
+
+        let result = cleanup_orphans(cache_dir.to_str().unwrap());
+
+        assert_eq!(result, Ok(0));
+    }
+
+    // ---------------------------------------------------------------------------
+    // write_atomic
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_write_atomic_writes_content() {
+        let env = setup_test_env();
+        let path = env.temp_dir.path().join("thumb.jpg");
+
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        let leftovers = std::fs::read_dir(env.temp_dir.path()).unwrap().count();
+        assert_eq!(leftovers, 1, "no temporary files should be left behind");
+    }
+
+    #[test]
+    fn test_write_atomic_fails_for_missing_directory() {
+        let env = setup_test_env();
+        let path = env.temp_dir.path().join("missing").join("thumb.jpg");
+
+        assert!(write_atomic(&path, b"data").is_err());
+    }
+
+    // ---------------------------------------------------------------------------
+    // fingerprint / reuse_thumbnail_by_fingerprint
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_fingerprint_matches_for_identical_content() {
+        let env = setup_test_env();
+        let a = env.temp_dir.path().join("a.jpg");
+        let b = env.temp_dir.path().join("b.jpg");
+        std::fs::write(&a, b"same content").unwrap();
+        std::fs::write(&b, b"same content").unwrap();
+
+        assert!(fingerprint(&a).is_some());
+        assert_eq!(fingerprint(&a), fingerprint(&b));
+    }
+
+    #[test]
+    fn test_fingerprint_differs_for_different_content() {
+        let env = setup_test_env();
+        let a = env.temp_dir.path().join("a.jpg");
+        let b = env.temp_dir.path().join("b.jpg");
+        std::fs::write(&a, b"content a").unwrap();
+        std::fs::write(&b, b"content b").unwrap();
+
+        assert_ne!(fingerprint(&a), fingerprint(&b));
+    }
+
+    #[test]
+    fn test_fingerprint_missing_file() {
+        assert_eq!(fingerprint(Path::new("/nonexistent/photo.jpg")), None);
+    }
+
+    #[test]
+    fn test_reuse_thumbnail_by_fingerprint_moves_thumbnail_of_moved_file() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let old_source = env.temp_dir.path().join("old.jpg");
+        let new_source = env.temp_dir.path().join("new.jpg");
+
+        std::fs::write(&old_source, b"photo content").unwrap();
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        let old_thumb = thumbnail_path(&old_source, &cache_dir).unwrap();
+        write_thumbnail(&old_thumb, b"fake thumb").unwrap();
+        register_thumbnail(&old_source, &cache_dir).unwrap();
+
+        std::fs::rename(&old_source, &new_source).unwrap();
+
+        let reused = reuse_thumbnail_by_fingerprint(&new_source, &cache_dir).unwrap();
+        let new_thumb = thumbnail_path(&new_source, &cache_dir).unwrap();
+
+        assert_eq!(reused, Some(new_thumb.clone()));
+        assert!(
+            new_thumb.exists(),
+            "thumbnail should be rekeyed to the new path"
+        );
+        assert!(
+            !old_thumb.exists(),
+            "old thumbnail should be moved, not copied"
+        );
+
+        let manifest = load_manifest(&cache_dir).unwrap();
+        assert_eq!(manifest.len(), 1, "old entry should be replaced");
+        assert_eq!(
+            manifest.get(&hash_for_path(&new_source)).unwrap().source,
+            normalize_path(&new_source.to_string_lossy())
+        );
+    }
+
+    #[test]
+    fn test_reuse_thumbnail_by_fingerprint_copies_thumbnail_of_copied_file() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let original = env.temp_dir.path().join("original.jpg");
+        let copy = env.temp_dir.path().join("copy.jpg");
+
+        std::fs::write(&original, b"photo content").unwrap();
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        let original_thumb = thumbnail_path(&original, &cache_dir).unwrap();
+        write_thumbnail(&original_thumb, b"fake thumb").unwrap();
+        register_thumbnail(&original, &cache_dir).unwrap();
+
+        std::fs::copy(&original, &copy).unwrap();
+
+        let reused = reuse_thumbnail_by_fingerprint(&copy, &cache_dir).unwrap();
+
+        assert!(reused.is_some());
+        assert!(original_thumb.exists(), "original thumbnail should be kept");
+        assert_eq!(load_manifest(&cache_dir).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_reuse_thumbnail_by_fingerprint_no_match() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let source = env.temp_dir.path().join("photo.jpg");
+        std::fs::write(&source, b"photo content").unwrap();
+
+        let reused = reuse_thumbnail_by_fingerprint(&source, &cache_dir).unwrap();
+
+        assert_eq!(reused, None);
+    }
+
+    #[test]
+    fn test_reuse_thumbnail_by_fingerprint_sees_manifests_of_other_processes() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let original = env.temp_dir.path().join("original.jpg");
+        let copy = env.temp_dir.path().join("copy.jpg");
+        std::fs::write(&original, b"photo content").unwrap();
+        std::fs::write(&copy, b"photo content").unwrap();
+
+        // The index of the empty manifest is outdated once another process writes it
+        assert_eq!(
+            hashes_with_fingerprint(&fingerprint(&original).unwrap(), &cache_dir).unwrap(),
+            Vec::<String>::new()
+        );
+        let hash = hash_for_path(&original);
+        write_thumbnail(
+            &thumbnail_path(&original, &cache_dir).unwrap(),
+            b"fake thumb",
+        )
+        .unwrap();
+        let manifest = serde_json::json!({
+            &hash: {
+                "source": normalize_path(&original.to_string_lossy()),
+                "fingerprint": fingerprint(&original),
+            }
+        });
+        std::fs::write(manifest_path(&cache_dir), manifest.to_string()).unwrap();
+
+        assert_eq!(
+            hashes_with_fingerprint(&fingerprint(&original).unwrap(), &cache_dir).unwrap(),
+            [hash]
+        );
+        assert!(reuse_thumbnail_by_fingerprint(&copy, &cache_dir)
+            .unwrap()
+            .is_some());
+    }
+
+    // ---------------------------------------------------------------------------
+    // cleanup_for_prefix
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_cleanup_for_prefix_removes_matching_entries_and_files() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let cache_dir_str = cache_dir.to_str().unwrap().to_string();
+
+        let path_a = PathBuf::from("/photos/vacation/img1.jpg");
+        let path_b = PathBuf::from("/photos/vacation/img2.jpg");
+        let path_c = PathBuf::from("/documents/scan1.jpg");
+
+        register_thumbnail(&path_a, cache_dir).unwrap();
+        register_thumbnail(&path_b, cache_dir).unwrap();
+        register_thumbnail(&path_c, cache_dir).unwrap();
+
+        let thumb_a = thumbnail_path(&path_a, cache_dir).unwrap();
+        let thumb_b = thumbnail_path(&path_b, cache_dir).unwrap();
+        let thumb_c = thumbnail_path(&path_c, cache_dir).unwrap();
+        write_thumbnail(&thumb_a, b"fake").unwrap();
+        write_thumbnail(&thumb_b, b"fake").unwrap();
+        write_thumbnail(&thumb_c, b"fake").unwrap();
+
+        let removed = cleanup_for_prefix("/photos/vacation", &cache_dir_str).unwrap();
+
+        assert_eq!(removed, 2, "two matching entries should be removed");
+        assert!(
+            !thumb_a.exists(),
+            "matching thumbnail A should be deleted from disk"
+        );
+        assert!(
+            !thumb_b.exists(),
+            "matching thumbnail B should be deleted from disk"
+        );
+        assert!(
+            thumb_c.exists(),
+            "non-matching thumbnail C should remain on disk"
+        );
+
+        let manifest = load_manifest(cache_dir).unwrap();
+        assert!(
+            !manifest
+                .values()
+                .any(|v| v.source.starts_with("/photos/vacation")),
+            "manifest should not contain the removed prefix"
+        );
+        assert!(
+            manifest.values().any(|v| v.source.contains("scan1")),
+            "manifest should still contain the non-matching entry"
+        );
+    }
+
+    #[test]
+    fn test_cleanup_for_prefixes_removes_all_in_one_pass() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let cache_dir_str = cache_dir.to_str().unwrap().to_string();
+
+        for path in [
+            "/photos/a.jpg",
+            "/photos/trip/b.jpg",
+            "/videos/c.mp4",
+            "/docs/d.jpg",
+        ] {
+            register_thumbnail(&PathBuf::from(path), cache_dir).unwrap();
+        }
+
+        let prefixes = ["/photos/trip".to_string(), "/videos/c.mp4".to_string()];
+        assert_eq!(cleanup_for_prefixes(&prefixes, &cache_dir_str).unwrap(), 2);
+
+        let mut sources: Vec<String> = load_manifest(cache_dir)
+            .unwrap()
+            .into_values()
+            .map(|entry| entry.source)
+            .collect();
+        sources.sort();
+        assert_eq!(sources, ["/docs/d.jpg", "/photos/a.jpg"]);
+    }
+
+    #[test]
+    fn test_cleanup_for_prefix_no_matches_returns_zero() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let cache_dir_str = cache_dir.to_str().unwrap().to_string();
 
         register_thumbnail(&PathBuf::from("/photos/img.jpg"), cache_dir).unwrap();
 
         let removed = cleanup_for_prefix("/videos", &cache_dir_str).unwrap();
 
-        assert_eq!(removed, 0, "no entries should be removed when prefix has no match");
+        assert_eq!(
+            removed, 0,
+            "no entries should be removed when prefix has no match"
+        );
         let manifest = load_manifest(cache_dir).unwrap();
         assert_eq!(manifest.len(), 1, "manifest should be unchanged");
     }
 
+    #[test]
+    fn test_is_under_prefix_respects_path_components() {
+        assert!(is_under_prefix("/photos/img.jpg", "/photos"));
+        assert!(is_under_prefix("/photos/img.jpg", "/photos/"));
+        assert!(is_under_prefix("/photos/a/b/img.jpg", "/photos"));
+        assert!(is_under_prefix("/photos", "/photos"));
+        assert!(!is_under_prefix("/photos-archive/img.jpg", "/photos"));
+        assert!(!is_under_prefix("/photo", "/photos"));
+        assert!(is_under_prefix("C:/photos/img.jpg", "C:\\photos"));
+        assert!(is_under_prefix("/photos/img.jpg", "/"));
+    }
+
+    #[test]
+    fn test_cleanup_for_prefix_keeps_sibling_with_same_name_prefix() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let cache_dir_str = cache_dir.to_str().unwrap().to_string();
+
+        let inside = PathBuf::from("/photos/img.jpg");
+        let sibling = PathBuf::from("/photos-archive/img.jpg");
+        register_thumbnail(&inside, cache_dir).unwrap();
+        register_thumbnail(&sibling, cache_dir).unwrap();
+
+        let removed = cleanup_for_prefix("/photos", &cache_dir_str).unwrap();
+
+        assert_eq!(
+            removed, 1,
+            "only the entry inside /photos should be removed"
+        );
+        let manifest = load_manifest(cache_dir).unwrap();
+        assert!(manifest.contains_key(&hash_for_path(&sibling)));
+    }
+
+    #[test]
+    fn test_preview_cleanup_for_prefix_does_not_delete() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let cache_dir_str = cache_dir.to_str().unwrap().to_string();
+
+        let path_a = PathBuf::from("/photos/a.jpg");
+        let path_b = PathBuf::from("/photos/b.jpg");
+        let path_c = PathBuf::from("/videos/c.mp4");
+        for path in [&path_a, &path_b, &path_c] {
+            register_thumbnail(path, cache_dir).unwrap();
+        }
+        let thumb_a = thumbnail_path(&path_a, cache_dir).unwrap();
+        write_thumbnail(&thumb_a, b"12345").unwrap();
+        write_thumbnail(&thumbnail_path(&path_c, cache_dir).unwrap(), b"123").unwrap();
+
+        let preview = preview_cleanup_for_prefix("/photos", &cache_dir_str).unwrap();
+
+        assert_eq!(
+            preview,
+            CleanupPreview {
+                sources: vec!["/photos/a.jpg".to_string(), "/photos/b.jpg".to_string()],
+                bytes: 5,
+            }
+        );
+        assert!(thumb_a.exists(), "dry run must not delete thumbnails");
+        assert_eq!(load_manifest(cache_dir).unwrap().len(), 3);
+    }
+
     // ---------------------------------------------------------------------------
     // cleanup_orphans
     // ---------------------------------------------------------------------------
@@ -507,22 +2791,32 @@ mod tests {
         std::fs::write(&existing_source, b"fake jpg").unwrap();
         register_thumbnail(&existing_source, cache_dir).unwrap();
         let thumb_existing = thumbnail_path(&existing_source, cache_dir).unwrap();
-        std::fs::write(&thumb_existing, b"fake thumb").unwrap();
+        write_thumbnail(&thumb_existing, b"fake thumb").unwrap();
 
         // A source path that does NOT exist on disk
         let ghost_source = PathBuf::from("/ghost/nonexistent/photo.jpg");
         register_thumbnail(&ghost_source, cache_dir).unwrap();
         let thumb_ghost = thumbnail_path(&ghost_source, cache_dir).unwrap();
-        std::fs::write(&thumb_ghost, b"fake thumb").unwrap();
+        write_thumbnail(&thumb_ghost, b"fake thumb").unwrap();
 
         let removed = cleanup_orphans(&cache_dir_str).unwrap();
 
         assert_eq!(removed, 1, "one orphan should be removed");
-        assert!(!thumb_ghost.exists(), "orphan thumbnail should be deleted from disk");
-        assert!(thumb_existing.exists(), "thumbnail for existing source should remain");
+        assert!(
+            !thumb_ghost.exists(),
+            "orphan thumbnail should be deleted from disk"
+        );
+        assert!(
+            thumb_existing.exists(),
+            "thumbnail for existing source should remain"
+        );
 
         let manifest = load_manifest(cache_dir).unwrap();
-        assert_eq!(manifest.len(), 1, "only the valid entry should remain in manifest");
+        assert_eq!(
+            manifest.len(),
+            1,
+            "only the valid entry should remain in manifest"
+        );
     }
 
     #[test]
@@ -537,35 +2831,1029 @@ mod tests {
 
         let removed = cleanup_orphans(&cache_dir_str).unwrap();
 
-        assert_eq!(removed, 0, "no entries should be removed for existing sources");
+        assert_eq!(
+            removed, 0,
+            "no entries should be removed for existing sources"
+        );
         let manifest = load_manifest(cache_dir).unwrap();
         assert_eq!(manifest.len(), 1, "manifest should be unchanged");
     }
 
-    // ---------------------------------------------------------------------------
-    // delete_all
-    // ---------------------------------------------------------------------------
-
     #[test]
-    fn test_delete_all_removes_cache_directory() {
+    fn test_preview_cleanup_orphans_does_not_delete() {
         let env = setup_test_env();
-        let cache_dir = env.temp_dir.path().join("thumbnails");
-        std::fs::create_dir_all(&cache_dir).unwrap();
-        std::fs::write(cache_dir.join("abc123.jpg"), b"fake thumb").unwrap();
-        std::fs::write(cache_dir.join("manifest.json"), b"{}").unwrap();
+        let cache_dir = env.temp_dir.path();
+        let cache_dir_str = cache_dir.to_str().unwrap().to_string();
 
-        delete_all(cache_dir.to_str().unwrap()).unwrap();
+        let existing = cache_dir.join("real_image.jpg");
+        std::fs::write(&existing, b"fake jpg").unwrap();
+        register_thumbnail(&existing, cache_dir).unwrap();
 
-        assert!(!cache_dir.exists(), "cache directory should be completely removed");
+        let ghost = PathBuf::from("/ghost/nonexistent/photo.jpg");
+        register_thumbnail(&ghost, cache_dir).unwrap();
+        let thumb_ghost = thumbnail_path(&ghost, cache_dir).unwrap();
+        write_thumbnail(&thumb_ghost, b"fake thumb").unwrap();
+
+        let preview = preview_cleanup_orphans(&cache_dir_str).unwrap();
+
+        assert_eq!(preview.sources, vec!["/ghost/nonexistent/photo.jpg"]);
+        assert_eq!(preview.bytes, 10);
+        assert!(thumb_ghost.exists(), "dry run must not delete thumbnails");
+        assert_eq!(load_manifest(cache_dir).unwrap().len(), 2);
+    }
+
+    // ---------------------------------------------------------------------------
+    // verify
+    // ---------------------------------------------------------------------------
+
+    /// Writes a small but valid JPEG thumbnail.
+    fn write_valid_thumbnail(path: &Path) {
+        let mut data = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut data, image::ImageFormat::Jpeg)
+            .unwrap();
+        write_thumbnail(path, &data.into_inner()).unwrap();
     }
 
     #[test]
-    fn test_delete_all_idempotent_when_dir_missing() {
+    fn test_is_valid_thumbnail() {
+        let env = setup_test_env();
+        let valid = env.temp_dir.path().join("valid.jpg");
+        write_valid_thumbnail(&valid);
+        let data = std::fs::read(&valid).unwrap();
+
+        let empty = env.temp_dir.path().join("empty.jpg");
+        std::fs::write(&empty, b"").unwrap();
+        let truncated = env.temp_dir.path().join("truncated.jpg");
+        std::fs::write(&truncated, &data[..data.len() / 2]).unwrap();
+        let garbage = env.temp_dir.path().join("garbage.jpg");
+        std::fs::write(&garbage, b"not an image").unwrap();
+
+        assert!(is_valid_thumbnail(&valid));
+        assert!(!is_valid_thumbnail(&empty));
+        assert!(!is_valid_thumbnail(&truncated));
+        assert!(!is_valid_thumbnail(&garbage));
+    }
+
+    #[test]
+    fn test_verify_clean_cache() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let source = env.temp_dir.path().join("photo.jpg");
+        std::fs::write(&source, b"photo").unwrap();
+        register_thumbnail(&source, &cache_dir).unwrap();
+        write_valid_thumbnail(&thumbnail_path(&source, &cache_dir).unwrap());
+
+        let report = verify(cache_dir.to_str().unwrap(), &[], true).unwrap();
+
+        assert!(report.is_clean(), "unexpected findings: {:?}", report);
+        assert!(!report.repaired, "nothing should be repaired");
+    }
+
+    #[test]
+    fn test_verify_missing_cache_dir() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("missing");
+
+        let report = verify(cache_dir.to_str().unwrap(), &[], true).unwrap();
+
+        assert!(report.is_clean());
+        assert!(!cache_dir.exists(), "verify must not create the cache dir");
+    }
+
+    /// Sets up a cache with one problem of every kind.
+    /// Returns (cache_dir, media_root, paths of interest).
+    fn setup_inconsistent_cache(env: &TestEnvGuard) -> (PathBuf, PathBuf, [PathBuf; 5]) {
+        let cache_dir = env.temp_dir.path().join("cache");
+        let root = env.temp_dir.path().join("media");
+        std::fs::create_dir_all(root.join("nested")).unwrap();
+
+        // Registered, but the thumbnail file is gone
+        let missing_source = root.join("missing.jpg");
+        std::fs::write(&missing_source, b"missing").unwrap();
+        register_thumbnail(&missing_source, &cache_dir).unwrap();
+
+        // Registered, thumbnail truncated to zero bytes
+        let corrupt_source = root.join("corrupt.jpg");
+        std::fs::write(&corrupt_source, b"corrupt").unwrap();
+        register_thumbnail(&corrupt_source, &cache_dir).unwrap();
+        let corrupt_thumb = thumbnail_path(&corrupt_source, &cache_dir).unwrap();
+        write_thumbnail(&corrupt_thumb, b"").unwrap();
+
+        // Thumbnail without entry, source found under the media root
+        let recoverable_source = root.join("nested").join("recoverable.jpg");
+        std::fs::write(&recoverable_source, b"recoverable").unwrap();
+        let recoverable_thumb = thumbnail_path(&recoverable_source, &cache_dir).unwrap();
+        write_valid_thumbnail(&recoverable_thumb);
+
+        // Thumbnail without entry, source unknown
+        let unknown_thumb = thumbnail_path(Path::new("/gone/unknown.jpg"), &cache_dir).unwrap();
+        write_valid_thumbnail(&unknown_thumb);
+
+        let write_test = cache_dir.join(".write_test");
+        std::fs::write(&write_test, b"").unwrap();
+
+        (
+            cache_dir,
+            root,
+            [
+                missing_source,
+                corrupt_thumb,
+                recoverable_source,
+                unknown_thumb,
+                write_test,
+            ],
+        )
+    }
+
+    #[test]
+    fn test_verify_reports_without_modifying() {
+        let env = setup_test_env();
+        let (
+            cache_dir,
+            root,
+            [missing_source, corrupt_thumb, recoverable_source, unknown_thumb, write_test],
+        ) = setup_inconsistent_cache(&env);
+        let manifest_before = load_manifest(&cache_dir).unwrap();
+
+        let report = verify(
+            cache_dir.to_str().unwrap(),
+            &[root.to_str().unwrap().to_string()],
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            report.missing,
+            vec![normalize_path(&missing_source.to_string_lossy())]
+        );
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(
+            report.recoverable,
+            vec![normalize_path(&recoverable_source.to_string_lossy())]
+        );
+        assert_eq!(
+            report.unregistered,
+            vec![normalize_path(&unknown_thumb.to_string_lossy())]
+        );
+        assert_eq!(
+            report.stray,
+            vec![normalize_path(&write_test.to_string_lossy())]
+        );
+        assert!(!report.repaired);
+
+        assert!(corrupt_thumb.exists(), "dry run must not delete files");
+        assert!(unknown_thumb.exists(), "dry run must not delete files");
+        assert!(write_test.exists(), "dry run must not delete files");
+        assert_eq!(load_manifest(&cache_dir).unwrap(), manifest_before);
+    }
+
+    #[test]
+    fn test_verify_repairs_cache() {
+        let env = setup_test_env();
+        let (
+            cache_dir,
+            root,
+            [missing_source, corrupt_thumb, recoverable_source, unknown_thumb, write_test],
+        ) = setup_inconsistent_cache(&env);
+
+        let report = verify(
+            cache_dir.to_str().unwrap(),
+            &[root.to_str().unwrap().to_string()],
+            true,
+        )
+        .unwrap();
+
+        assert!(report.repaired);
+        assert_eq!(
+            report.regenerate.len(),
+            2,
+            "missing and corrupt sources still exist"
+        );
+        assert!(
+            !corrupt_thumb.exists(),
+            "corrupt thumbnail should be deleted"
+        );
+        assert!(
+            !unknown_thumb.exists(),
+            "unattributed thumbnail should be deleted"
+        );
+        assert!(!write_test.exists(), "stray file should be deleted");
+
+        let manifest = load_manifest(&cache_dir).unwrap();
+        assert_eq!(
+            manifest.len(),
+            1,
+            "only the re-registered entry should remain"
+        );
+        assert!(manifest.contains_key(&hash_for_path(&recoverable_source)));
+        assert!(!manifest.contains_key(&hash_for_path(&missing_source)));
+
+        let second = verify(
+            cache_dir.to_str().unwrap(),
+            &[root.to_str().unwrap().to_string()],
+            false,
+        )
+        .unwrap();
+        assert!(
+            second.is_clean(),
+            "repaired cache should verify clean: {:?}",
+            second
+        );
+    }
+
+    // ---------------------------------------------------------------------------
+    // CacheLock
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_cache_lock_fails_while_held_by_another_process() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+
+        // A separate handle on the lock file behaves like another process holding it
+        let other = File::create(lock_path(cache_dir)).unwrap();
+        other.lock().unwrap();
+
+        let result = CacheLock::acquire_with_timeout(cache_dir, Duration::from_millis(100));
+        let err = result.err().expect("lock should not be acquired");
+        assert!(
+            err.contains("in use by another process"),
+            "unexpected error: {}",
+            err
+        );
+
+        other.unlock().unwrap();
+        assert!(CacheLock::acquire_with_timeout(cache_dir, Duration::from_millis(100)).is_ok());
+    }
+
+    #[test]
+    fn test_cache_lock_waits_for_release() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().to_path_buf();
+
+        let other = File::create(lock_path(&cache_dir)).unwrap();
+        other.lock().unwrap();
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(other);
+        });
+
+        let result = CacheLock::acquire_with_timeout(&cache_dir, Duration::from_secs(5));
+        releaser.join().unwrap();
+
+        assert!(result.is_ok(), "lock should be acquired once released");
+    }
+
+    #[test]
+    fn test_cache_lock_waiting_for_another_process_leaves_other_caches_usable() {
+        let env = setup_test_env();
+        let busy = env.temp_dir.path().join("busy");
+        let other_cache = env.temp_dir.path().join("other");
+        fs::create_dir_all(&busy).unwrap();
+        fs::create_dir_all(&other_cache).unwrap();
+
+        let other = File::create(lock_path(&busy)).unwrap();
+        other.lock().unwrap();
+        let waiter = thread::spawn(move || {
+            CacheLock::acquire_with_timeout(&busy, Duration::from_millis(500)).is_ok()
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        let lock = CacheLock::acquire_with_timeout(&other_cache, Duration::from_millis(100));
+        assert!(lock.is_ok());
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert!(!waiter.join().unwrap());
+    }
+
+    #[test]
+    fn test_cache_lock_does_not_create_the_directory() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("missing");
+
+        let lock = CacheLock::acquire(&cache_dir).unwrap();
+        assert!(!cache_dir.exists());
+        drop(lock);
+
+        // The lock is released even though it had no file
+        assert!(load_failures(&cache_dir).unwrap().is_empty());
+        assert_eq!(cleanup_orphans(&cache_dir.to_string_lossy()).unwrap(), 0);
+        assert!(!cache_dir.exists());
+    }
+
+    // ---------------------------------------------------------------------------
+    // failures
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_known_failure_until_file_changes() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let source = env.temp_dir.path().join("broken.jpg");
+        fs::write(&source, b"broken").unwrap();
+
+        record_failure(&source, &cache_dir, "error", "Failed to decode").unwrap();
+
+        let failures = load_failures(&cache_dir).unwrap();
+        let failure = known_failure(&failures, &source, &cache_dir).unwrap();
+        assert_eq!(failure.status, "error");
+        assert_eq!(failure.reason, "Failed to decode");
+        assert_eq!(failure.size, 6);
+
+        fs::write(&source, b"fixed content").unwrap();
+        assert!(known_failure(&failures, &source, &cache_dir).is_none());
+    }
+
+    #[test]
+    fn test_register_thumbnail_clears_failure() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let source = env.temp_dir.path().join("clip.mp4");
+        fs::write(&source, b"video").unwrap();
+        record_failure(&source, &cache_dir, "frontend-render", "No frame").unwrap();
+
+        register_thumbnail(&source, &cache_dir).unwrap();
+
+        assert!(load_failures(&cache_dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_verify_ignores_failures() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let source = env.temp_dir.path().join("broken.jpg");
+        fs::write(&source, b"broken").unwrap();
+        record_failure(&source, &cache_dir, "error", "Failed to decode").unwrap();
+
+        let report = verify(cache_dir.to_str().unwrap(), &[], false).unwrap();
+
+        assert!(
+            report.is_clean(),
+            "failures have no thumbnail by design: {:?}",
+            report
+        );
+    }
+
+    #[test]
+    fn test_clear_failures_includes_portable_caches() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let root = env.temp_dir.path().join("drive");
+        let portable = root.join(PORTABLE_CACHE_DIR);
+        fs::create_dir_all(&portable).unwrap();
+
+        let a = env.temp_dir.path().join("a.jpg");
+        let b = root.join("b.jpg");
+        let ok = root.join("ok.jpg");
+        for file in [&a, &b, &ok] {
+            fs::write(file, b"data").unwrap();
+        }
+        record_failure(&a, &cache_dir, "error", "bad").unwrap();
+        record_failure(&b, &portable, "error", "bad").unwrap();
+        register_thumbnail(&ok, &portable).unwrap();
+
+        let cleared = clear_failures(
+            cache_dir.to_str().unwrap(),
+            &[root.to_string_lossy().to_string()],
+        )
+        .unwrap();
+
+        assert_eq!(cleared, 2);
+        assert!(load_failures(&cache_dir).unwrap().is_empty());
+        assert!(load_failures(&portable).unwrap().is_empty());
+        assert_eq!(load_manifest(&portable).unwrap().len(), 1);
+    }
+
+    // ---------------------------------------------------------------------------
+    // portable caches
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_cache_dir_for_prefers_portable_cache() {
+        let env = setup_test_env();
+        let global = env.temp_dir.path().join("global");
+        let root = env.temp_dir.path().join("drive");
+        let album = root.join("2024").join("trip");
+        fs::create_dir_all(&album).unwrap();
+        let _roots = use_roots(&[&root]);
+
+        assert_eq!(cache_dir_for(&album, &global), global);
+
+        let portable =
+            enable_portable_cache(root.to_str().unwrap(), global.to_str().unwrap()).unwrap();
+        assert_eq!(PathBuf::from(&portable), root.join(PORTABLE_CACHE_DIR));
+        assert_eq!(
+            cache_dir_for(&album, &global),
+            root.join(PORTABLE_CACHE_DIR)
+        );
+        assert_eq!(
+            cache_dir_for(&album.join("img.jpg"), &global),
+            root.join(PORTABLE_CACHE_DIR)
+        );
+    }
+
+    #[test]
+    fn test_cache_dir_for_ignores_caches_outside_roots() {
+        let env = setup_test_env();
+        let global = env.temp_dir.path().join("global");
+        let root = env.temp_dir.path().join("drive");
+        let album = root.join("album");
+        // E.g. a `.mv` directory of another program in the home directory
+        fs::create_dir_all(env.temp_dir.path().join(PORTABLE_CACHE_DIR)).unwrap();
+        fs::create_dir_all(album.join(PORTABLE_CACHE_DIR)).unwrap();
+        let _roots = use_roots(&[&root]);
+
+        assert_eq!(cache_dir_for(&album.join("img.jpg"), &global), global);
+        assert_eq!(cache_dir_for(&root, &global), global);
+
+        // Nested roots use the closest portable cache
+        fs::create_dir_all(root.join(PORTABLE_CACHE_DIR)).unwrap();
+        set_roots(&[
+            root.to_string_lossy().to_string(),
+            album.to_string_lossy().to_string(),
+        ]);
+        assert_eq!(
+            cache_dir_for(&album.join("img.jpg"), &global),
+            album.join(PORTABLE_CACHE_DIR)
+        );
+        assert_eq!(
+            cache_dir_for(&root.join("img.jpg"), &global),
+            root.join(PORTABLE_CACHE_DIR)
+        );
+    }
+
+    #[test]
+    fn test_enable_portable_cache_moves_existing_thumbnails() {
+        let env = setup_test_env();
+        let global = env.temp_dir.path().join("global");
+        let root = env.temp_dir.path().join("drive");
+        let inside = root.join("album").join("img.jpg");
+        let outside = env.temp_dir.path().join("other.jpg");
+        fs::create_dir_all(inside.parent().unwrap()).unwrap();
+        for source in [&inside, &outside] {
+            fs::write(source, b"image").unwrap();
+            write_valid_thumbnail(&thumbnail_path(source, &global).unwrap());
+            register_thumbnail(source, &global).unwrap();
+        }
+        let variant = variant_path(&inside, 256, &global);
+        write_valid_thumbnail(&variant);
+        let _roots = use_roots(&[&root]);
+
+        let portable = PathBuf::from(
+            enable_portable_cache(root.to_str().unwrap(), global.to_str().unwrap()).unwrap(),
+        );
+
+        assert_eq!(portable, root.join(PORTABLE_CACHE_DIR));
+        let manifest = load_manifest(&portable).unwrap();
+        assert_eq!(manifest.len(), 1);
+        assert_eq!(manifest.values().next().unwrap().source, "album/img.jpg");
+        assert!(thumbnail_path(&inside, &portable).unwrap().is_file());
+        assert!(variant_path(&inside, 256, &portable).is_file());
+        assert!(!variant.exists());
+        assert_eq!(
+            fresh_thumbnail(&inside, &global),
+            Some(thumbnail_path(&inside, &portable).unwrap())
+        );
+
+        let manifest = load_manifest(&global).unwrap();
+        assert_eq!(manifest.len(), 1);
+        assert!(thumbnail_path(&outside, &global).unwrap().is_file());
+    }
+
+    #[test]
+    fn test_cleanup_includes_portable_caches() {
+        let env = setup_test_env();
+        let global = env.temp_dir.path().join("global");
+        let root = env.temp_dir.path().join("drive");
+        let portable = root.join(PORTABLE_CACHE_DIR);
+        let gone = root.join("album").join("gone.jpg");
+        let kept = root.join("album").join("kept.jpg");
+        fs::create_dir_all(kept.parent().unwrap()).unwrap();
+        fs::write(&kept, b"image").unwrap();
+        fs::create_dir_all(&global).unwrap();
+        for source in [&gone, &kept] {
+            write_valid_thumbnail(&thumbnail_path(source, &portable).unwrap());
+            register_thumbnail(source, &portable).unwrap();
+        }
+        let _roots = use_roots(&[&root]);
+        let global_str = global.to_str().unwrap();
+
+        assert_eq!(
+            preview_cleanup_orphans(global_str).unwrap().sources,
+            vec![normalize_path(&gone.to_string_lossy())]
+        );
+        assert_eq!(cleanup_orphans(global_str).unwrap(), 1);
+        assert_eq!(load_manifest(&portable).unwrap().len(), 1);
+
+        let album = root.join("album").to_string_lossy().to_string();
+        assert_eq!(
+            preview_cleanup_for_prefix(&album, global_str)
+                .unwrap()
+                .sources,
+            vec![normalize_path(&kept.to_string_lossy())]
+        );
+        assert_eq!(cleanup_for_prefix(&album, global_str).unwrap(), 1);
+        assert!(load_manifest(&portable).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_portable_cache_stores_relative_keys() {
+        let env = setup_test_env();
+        let root = env.temp_dir.path().join("drive");
+        let cache_dir = root.join(PORTABLE_CACHE_DIR);
+        let source = root.join("album").join("img.jpg");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, b"image").unwrap();
+
+        register_thumbnail(&source, &cache_dir).unwrap();
+
+        let manifest = load_manifest(&cache_dir).unwrap();
+        let entry = manifest.values().next().unwrap();
+        assert_eq!(entry.source, "album/img.jpg");
+        assert_eq!(
+            thumbnail_path(&source, &cache_dir).unwrap(),
+            thumbnail_path_for_hash(&hash_for_path(Path::new("album/img.jpg")), &cache_dir)
+        );
+    }
+
+    #[test]
+    fn test_portable_cache_survives_new_mount_point() {
+        let env = setup_test_env();
+        let old_root = env.temp_dir.path().join("mnt-a");
+        let source = old_root.join("album").join("img.jpg");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        fs::write(&source, b"image").unwrap();
+        let cache_dir = old_root.join(PORTABLE_CACHE_DIR);
+        let thumb = thumbnail_path(&source, &cache_dir).unwrap();
+        fs::create_dir_all(&cache_dir).unwrap();
+        write_thumbnail(&thumb, b"thumb").unwrap();
+        register_thumbnail(&source, &cache_dir).unwrap();
+
+        // Same drive mounted somewhere else
+        let new_root = env.temp_dir.path().join("mnt-b");
+        fs::rename(&old_root, &new_root).unwrap();
+        let new_source = new_root.join("album").join("img.jpg");
+        let new_cache_dir = new_root.join(PORTABLE_CACHE_DIR);
+
+        assert!(thumbnail_path(&new_source, &new_cache_dir)
+            .unwrap()
+            .exists());
+        let cache_str = new_cache_dir.to_str().unwrap();
+        assert_eq!(cleanup_orphans(cache_str).unwrap(), 0);
+        assert_eq!(
+            preview_cleanup_for_prefix(&new_root.join("album").to_string_lossy(), cache_str)
+                .unwrap()
+                .sources,
+            vec![normalize_path(&new_source.to_string_lossy())]
+        );
+    }
+
+    // ---------------------------------------------------------------------------
+    // previews
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_evict_previews_removes_least_recently_used() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let old = preview_path(Path::new("/photos/old.tif"), cache_dir);
+        let recent = preview_path(Path::new("/photos/recent.tif"), cache_dir);
+        write_thumbnail(&old, &[0u8; 600]).unwrap();
+        write_thumbnail(&recent, &[0u8; 600]).unwrap();
+        let past = std::time::SystemTime::now() - Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(&recent)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+        File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(past - Duration::from_secs(60))
+            .unwrap();
+
+        // Viewing the old preview again makes it the most recently used
+        touch(&old);
+        let evicted = evict_previews(cache_dir, 1000).unwrap();
+
+        assert_eq!(evicted, 1);
+        assert!(old.exists());
+        assert!(!recent.exists());
+        assert_eq!(evict_previews(cache_dir, 1000).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_find_thumbnail_searches_cache_dirs_in_order() {
+        let env = setup_test_env();
+        let global = env.temp_dir.path().join("global");
+        let portable = env.temp_dir.path().join("root").join(".mv");
+        let source = Path::new("/photos/a.jpg");
+        let thumb = thumbnail_path(source, &portable).unwrap();
+        let variant = variant_path(source, 256, &portable);
+        write_valid_thumbnail(&thumb);
+        write_valid_thumbnail(&variant);
+        let dirs = [global, portable];
+
+        let name = |path: &Path| path.file_name().unwrap().to_string_lossy().to_string();
+        assert_eq!(find_thumbnail(&name(&thumb), &dirs), Some(thumb.clone()));
+        assert_eq!(find_thumbnail(&name(&variant), &dirs), Some(variant));
+        assert_eq!(find_thumbnail("0123456789abcdef.jpg", &dirs), None);
+    }
+
+    #[test]
+    fn test_find_thumbnail_rejects_other_names() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().to_path_buf();
+        write_valid_thumbnail(&cache_dir.join("manifest.jpg"));
+        fs::write(manifest_path(&cache_dir), b"{}").unwrap();
+        let dirs = [cache_dir.clone()];
+
+        for name in [
+            "manifest.jpg",
+            "manifest.json",
+            "../manifest.jpg",
+            "ab/../manifest.jpg",
+            "..\\manifest.jpg",
+            ".jpg",
+        ] {
+            assert_eq!(find_thumbnail(name, &dirs), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_find_preview() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let preview = preview_path(Path::new("/photos/a.tif"), cache_dir);
+        write_valid_thumbnail(&preview);
+        let name = preview.file_name().unwrap().to_str().unwrap();
+
+        let dirs = [cache_dir.join("missing"), cache_dir.to_path_buf()];
+        assert_eq!(find_preview(name, &dirs), Some(preview.clone()));
+        // Previews are not thumbnails and vice versa
+        assert_eq!(find_thumbnail(name, &[cache_dir.to_path_buf()]), None);
+    }
+
+    #[test]
+    fn test_find_transcode() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let transcode = transcode_path(Path::new("/videos/a.mkv"), cache_dir);
+        write_thumbnail(&transcode, b"mp4").unwrap();
+        let name = transcode.file_name().unwrap().to_str().unwrap();
+
+        assert!(name.ends_with(".mp4"));
+        let dirs = [cache_dir.join("missing"), cache_dir.to_path_buf()];
+        assert_eq!(find_transcode(name, &dirs), Some(transcode.clone()));
+        assert_eq!(find_transcode("0123456789abcdef.mp4", &dirs), None);
+        assert_eq!(find_transcode("../manifest.json", &dirs), None);
+        assert_eq!(find_transcode(".mp4", &dirs), None);
+    }
+
+    #[test]
+    fn test_register_proxy_is_kept_and_cleaned_up_with_thumbnail() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let source = cache_dir.join("videos").join("clip.mp4");
+        write_thumbnail(&source, b"video").unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        register_thumbnail(&source, cache_dir).unwrap();
+        write_thumbnail(&thumbnail_path(&source, cache_dir).unwrap(), b"thumb").unwrap();
+        assert_eq!(existing_proxy(&source, cache_dir).unwrap(), None);
+
+        let proxy = proxy_path(&source, cache_dir);
+        write_thumbnail(&proxy, b"mp4").unwrap();
+        // Unregistered proxies are not reported
+        assert_eq!(existing_proxy(&source, cache_dir).unwrap(), None);
+        register_proxy(&source, cache_dir).unwrap();
+        assert_eq!(
+            existing_proxy(&source, cache_dir).unwrap(),
+            Some(proxy.clone())
+        );
+
+        // Regenerating the thumbnail keeps the proxy registered
+        register_thumbnail(&source, cache_dir).unwrap();
+        let manifest = load_manifest(cache_dir).unwrap();
+        assert!(manifest[&hash_for_source(&source, cache_dir)].proxy);
+        let data = fs::read_to_string(manifest_path(cache_dir)).unwrap();
+        assert!(data.contains("\"proxy\": true"));
+
+        let prefix = normalize_path(&cache_dir.join("videos").to_string_lossy());
+        cleanup_for_prefix(&prefix, cache_dir.to_str().unwrap()).unwrap();
+        assert!(!proxy.exists());
+    }
+
+    #[test]
+    fn test_recorded_probe_is_kept_until_the_video_changes() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let source = env.temp_dir.path().join("videos").join("clip.mp4");
+        write_thumbnail(&source, b"video").unwrap();
+        assert_eq!(recorded_probe(&source, &cache_dir).unwrap(), None);
+
+        record_probe(&source, &cache_dir, Some((1920, 1080)), Some(8_000_000)).unwrap();
+        let probe = recorded_probe(&source, &cache_dir).unwrap().unwrap();
+        assert_eq!(probe.size, Some((1920, 1080)));
+        assert_eq!(probe.bit_rate, Some(8_000_000));
+
+        // Regenerating the thumbnail keeps the probe
+        register_thumbnail(&source, &cache_dir).unwrap();
+        assert_eq!(recorded_probe(&source, &cache_dir).unwrap(), Some(probe));
+
+        fs::write(&source, b"edited video").unwrap();
+        assert_eq!(recorded_probe(&source, &cache_dir).unwrap(), None);
+    }
+
+    #[test]
+    fn test_fresh_thumbnail() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let source = env.temp_dir.path().join("photos").join("a.jpg");
+        write_thumbnail(&source, b"jpeg").unwrap();
+        assert_eq!(fresh_thumbnail(&source, &cache_dir), None);
+
+        thread::sleep(Duration::from_millis(20));
+        let thumb = thumbnail_path(&source, &cache_dir).unwrap();
+        write_thumbnail(&thumb, b"thumb").unwrap();
+        assert_eq!(fresh_thumbnail(&source, &cache_dir), Some(thumb.clone()));
+
+        // Changed sources have no fresh thumbnail
+        thread::sleep(Duration::from_millis(20));
+        fs::write(&source, b"edited").unwrap();
+        assert_eq!(fresh_thumbnail(&source, &cache_dir), None);
+    }
+
+    #[test]
+    fn test_thumbnail_key() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("cache");
+        let root = env.temp_dir.path().join("photos");
+        let source = root.join("a.jpg");
+        write_thumbnail(&source, b"jpeg").unwrap();
+
+        let key = thumbnail_key(&source, &cache_dir);
+        let thumb = thumbnail_path(&source, &cache_dir).unwrap();
+        assert_eq!(thumb.file_stem().unwrap().to_str(), Some(key.as_str()));
+
+        // Portable caches key files relative to their root
+        let _roots = use_roots(&[&root]);
+        enable_portable_cache(&root.to_string_lossy(), &cache_dir.to_string_lossy()).unwrap();
+        assert_ne!(thumbnail_key(&source, &cache_dir), key);
+        assert_eq!(
+            thumbnail_key(&source, &cache_dir),
+            hash_for_path(Path::new("a.jpg"))
+        );
+    }
+
+    #[test]
+    fn test_find_proxy() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let proxy = proxy_path(Path::new("/videos/a.mp4"), cache_dir);
+        write_thumbnail(&proxy, b"mp4").unwrap();
+        let name = proxy.file_name().unwrap().to_str().unwrap();
+
+        assert!(name.ends_with("_proxy.mp4"));
+        assert_eq!(
+            find_proxy(name, &[cache_dir.join("missing"), cache_dir.to_path_buf()]),
+            Some(proxy.clone())
+        );
+        assert_eq!(
+            find_proxy("0123456789abcdef_proxy.mp4", &[cache_dir.to_path_buf()]),
+            None
+        );
+        assert_eq!(
+            find_proxy("../manifest.json", &[cache_dir.to_path_buf()]),
+            None
+        );
+        assert_eq!(find_proxy("_proxy.mp4", &[cache_dir.to_path_buf()]), None);
+    }
+
+    #[test]
+    fn test_evict_tiles_removes_incomplete_and_least_recently_used() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        let pyramid = |name: &str, used: Option<Duration>| {
+            let dir = tile_pyramid_dir(Path::new(name), cache_dir);
+            fs::create_dir_all(dir.join("0")).unwrap();
+            fs::write(dir.join("0").join("0_0.jpg"), [0u8; 600]).unwrap();
+            if let Some(age) = used {
+                let info = dir.join("info.json");
+                fs::write(&info, b"{}").unwrap();
+                File::options()
+                    .write(true)
+                    .open(&info)
+                    .unwrap()
+                    .set_modified(std::time::SystemTime::now() - age)
+                    .unwrap();
+            }
+            dir
+        };
+        let old = pyramid("/photos/old.tif", Some(Duration::from_secs(3600)));
+        let recent = pyramid("/photos/recent.tif", Some(Duration::from_secs(60)));
+        let incomplete = pyramid("/photos/incomplete.tif", None);
+        let current = pyramid("/photos/current.tif", Some(Duration::from_secs(7200)));
+
+        let evicted = evict_tiles(cache_dir, 1500, "info.json", &current).unwrap();
+
+        assert_eq!(evicted, 2);
+        assert!(!incomplete.exists());
+        assert!(!old.exists());
+        assert!(recent.exists());
+        assert!(current.exists());
+    }
+
+    #[test]
+    fn test_previews_are_ignored_by_verify() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path();
+        write_valid_thumbnail(&preview_path(Path::new("/photos/a.tif"), cache_dir));
+
+        let report = verify(cache_dir.to_str().unwrap(), &[], false).unwrap();
+
+        assert!(report.is_clean(), "{:?}", report);
+    }
+
+    // ---------------------------------------------------------------------------
+    // move_cache
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_move_cache_copies_everything_and_removes_old_location() {
+        let env = setup_test_env();
+        let from = env.temp_dir.path().join("old");
+        let to = env.temp_dir.path().join("new");
+        register_thumbnail(&PathBuf::from("/photos/img.jpg"), &from).unwrap();
+        write_thumbnail(
+            &thumbnail_path(&PathBuf::from("/photos/img.jpg"), &from).unwrap(),
+            b"thumb",
+        )
+        .unwrap();
+        fs::create_dir_all(from.join("nested")).unwrap();
+        fs::write(from.join("nested").join("extra.bin"), b"data").unwrap();
+        let manifest = fs::read(manifest_path(&from)).unwrap();
+
+        let mut updates = Vec::new();
+        let result = move_cache(
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+            &["/photos".to_string()],
+            |p| updates.push(p.clone()),
+        )
+        .unwrap();
+
+        assert!(!from.exists(), "old cache location should be removed");
+        assert_eq!(fs::read(manifest_path(&to)).unwrap(), manifest);
+        assert!(thumbnail_path(&PathBuf::from("/photos/img.jpg"), &to)
+            .unwrap()
+            .exists());
+        assert!(to.join("nested").join("extra.bin").exists());
+        assert!(!lock_path(&to).exists(), "lock file should not be copied");
+        assert_eq!(result.copied_files, 3);
+        assert_eq!(result.copied_files, result.total_files);
+        assert_eq!(result.copied_bytes, result.total_bytes);
+        assert_eq!(updates.len(), 4, "one initial update plus one per file");
+        assert_eq!(updates.last(), Some(&result));
+    }
+
+    #[test]
+    fn test_move_cache_missing_source_creates_target() {
+        let env = setup_test_env();
+        let from = env.temp_dir.path().join("missing");
+        let to = env.temp_dir.path().join("new");
+
+        let result = move_cache(from.to_str().unwrap(), to.to_str().unwrap(), &[], |_| {});
+
+        assert_eq!(result, Ok(CacheMoveProgress::default()));
+        assert!(to.is_dir());
+    }
+
+    #[test]
+    fn test_move_cache_rejects_nested_locations() {
+        let env = setup_test_env();
+        let from = env.temp_dir.path().join("old");
+        register_thumbnail(&PathBuf::from("/photos/img.jpg"), &from).unwrap();
+
+        let inside = from.join("sub");
+        assert!(move_cache(
+            from.to_str().unwrap(),
+            inside.to_str().unwrap(),
+            &[],
+            |_| {}
+        )
+        .is_err());
+        let parent = env.temp_dir.path();
+        assert!(move_cache(
+            from.to_str().unwrap(),
+            parent.to_str().unwrap(),
+            &[],
+            |_| {}
+        )
+        .is_err());
+        assert!(move_cache(from.to_str().unwrap(), from.to_str().unwrap(), &[], |_| {}).is_err());
+        assert!(
+            manifest_path(&from).exists(),
+            "old cache should be untouched"
+        );
+    }
+
+    #[test]
+    fn test_move_cache_rejects_target_inside_media_root() {
+        let env = setup_test_env();
+        let from = env.temp_dir.path().join("old");
+        let root = env.temp_dir.path().join("photos");
+        fs::create_dir_all(&root).unwrap();
+        let roots = vec![root.to_string_lossy().to_string()];
+
+        let inside = root.join("cache");
+        assert!(move_cache(
+            from.to_str().unwrap(),
+            inside.to_str().unwrap(),
+            &roots,
+            |_| {}
+        )
+        .is_err());
+        assert!(
+            !inside.exists(),
+            "nothing should be created inside the media root"
+        );
+
+        // Sibling folder sharing the prefix is fine
+        let sibling = env.temp_dir.path().join("photos-cache");
+        assert!(move_cache(
+            from.to_str().unwrap(),
+            sibling.to_str().unwrap(),
+            &roots,
+            |_| {}
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_move_cache_rejects_non_empty_target() {
+        let env = setup_test_env();
+        let from = env.temp_dir.path().join("old");
+        let to = env.temp_dir.path().join("new");
+        register_thumbnail(&PathBuf::from("/photos/img.jpg"), &from).unwrap();
+        fs::create_dir_all(&to).unwrap();
+        fs::write(to.join("unrelated.txt"), b"keep me").unwrap();
+
+        let result = move_cache(from.to_str().unwrap(), to.to_str().unwrap(), &[], |_| {});
+
+        assert!(result.is_err());
+        assert!(to.join("unrelated.txt").exists());
+        assert!(manifest_path(&from).exists());
+    }
+
+    // ---------------------------------------------------------------------------
+    // delete_all
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_delete_all_removes_cache_directory() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("thumbnails");
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::write(cache_dir.join("abc123.jpg"), b"fake thumb").unwrap();
+        std::fs::write(cache_dir.join("manifest.json"), b"{}").unwrap();
+
+        delete_all(cache_dir.to_str().unwrap()).unwrap();
+
+        assert!(
+            !cache_dir.exists(),
+            "cache directory should be completely removed"
+        );
+    }
+
+    #[test]
+    fn test_delete_all_removes_lock_file() {
+        let env = setup_test_env();
+        let cache_dir = env.temp_dir.path().join("thumbnails");
+        register_thumbnail(&PathBuf::from("/photos/img.jpg"), &cache_dir).unwrap();
+        assert!(lock_path(&cache_dir).exists());
+
+        delete_all(cache_dir.to_str().unwrap()).unwrap();
+
+        assert!(
+            !cache_dir.exists(),
+            "cache directory should be completely removed"
+        );
+    }
+
+    #[test]
+    fn test_delete_all_idempotent_when_dir_missing() {
         let env = setup_test_env();
         let cache_dir = env.temp_dir.path().join("nonexistent");
 
         let result = delete_all(cache_dir.to_str().unwrap());
 
-        assert!(result.is_ok(), "delete_all should succeed even if directory does not exist");
+        assert!(
+            result.is_ok(),
+            "delete_all should succeed even if directory does not exist"
+        );
     }
 }
@@ -1,8 +1,20 @@
 mod cache;
 mod service;
+mod tiles;
+mod transcode;
 
-pub use cache::{cleanup_for_prefix, cleanup_orphans, delete_all};
+pub use cache::{
+    cache_dir_for, cleanup_for_prefix, cleanup_for_prefixes, cleanup_orphans, clear_failures,
+    delete_all, enable_portable_cache, find_preview, find_proxy, find_thumbnail, find_transcode,
+    fresh_thumbnail, move_cache, portable_cache_dir, preview_cleanup_for_prefix,
+    preview_cleanup_orphans, set_roots, thumbnail_key, verify, CacheMoveProgress, CleanupPreview,
+    VerifyReport, PORTABLE_CACHE_DIR,
+};
 pub use service::ThumbnailService;
+pub use tiles::{resolve_tile, tile_info, TileInfo};
+pub use transcode::{
+    generate_proxies, growing_transcode, transcode, ProxyOptions, ProxyUpdate, TranscodeProgress,
+};
 
 /// Normalizes a file path to use forward slashes.
 /// This ensures consistent paths across platforms.
@@ -1,5 +1,6 @@
 use super::cache;
 use super::normalize_path;
+use crate::media::{self, Content, MediaKind};
 use serde::Serialize;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -7,15 +8,22 @@ use tauri::{AppHandle, Emitter};
 use tokio::sync::Semaphore;
 
 const THUMBNAIL_SIZE: u32 = 512;
+/// Maximum edge length of full-screen previews.
+const PREVIEW_SIZE: u32 = 2560;
+/// Disk space previews may take up before the least recently used are evicted.
+const PREVIEW_CACHE_LIMIT: u64 = 1024 * 1024 * 1024;
 const MAX_WORKERS: usize = 4;
 
 const SUPPORTED_EXTENSIONS: &[&str] = &[
     "jpg", "jpeg", "png", "gif", "bmp", "webp", "tiff", "tif", "ico",
 ];
 
-const VIDEO_EXTENSIONS: &[&str] = &["mp4", "webm", "mkv", "avi", "mov", "wmv", "flv", "m4v"];
-
-const HEIC_EXTENSIONS: &[&str] = &["heic", "heif"];
+/// Common ffmpeg locations: PATH first, then Homebrew paths.
+pub(super) const FFMPEG_CANDIDATES: &[&str] = &[
+    "ffmpeg",
+    "/opt/homebrew/bin/ffmpeg",
+    "/usr/local/bin/ffmpeg",
+];
 
 const SUPPORTED_FORMATS: &[image::ImageFormat] = &[
     image::ImageFormat::Jpeg,
@@ -33,9 +41,43 @@ struct ThumbnailUpdate {
     path: String,
     status: String,
     thumbnail_path: Option<String>,
+    /// All sizes available for the file, so the frontend can pick the best fit.
+    variants: Vec<ThumbnailVariant>,
     session_id: u64,
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThumbnailVariant {
+    /// Maximum edge length in pixels.
+    size: u32,
+    path: String,
+}
+
+/// Why a thumbnail could not be generated.
+#[derive(Debug)]
+enum ThumbnailError {
+    /// The file cannot be decoded, which only changes when the file does.
+    Decode(String),
+    /// Reading the file or writing the cache failed, e.g. on a full disk or while
+    /// another process holds the cache lock.
+    Io(String),
+}
+
+impl From<String> for ThumbnailError {
+    fn from(message: String) -> Self {
+        ThumbnailError::Io(message)
+    }
+}
+
+impl std::fmt::Display for ThumbnailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThumbnailError::Decode(message) | ThumbnailError::Io(message) => f.write_str(message),
+        }
+    }
+}
+
 pub struct ThumbnailService;
 
 impl ThumbnailService {
@@ -58,22 +100,18 @@ impl ThumbnailService {
             .unwrap_or(false)
     }
 
-    fn is_video(path: &Path) -> bool {
-        path.extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| VIDEO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
-            .unwrap_or(false)
+    /// Returns true for videos, as classified for the listing.
+    pub(super) fn is_video(path: &Path) -> bool {
+        media::classify(path) == Some(MediaKind::Video)
     }
 
+    /// Returns true for HEIC/HEIF images, by their file type box or else their extension.
     fn is_heic(path: &Path) -> bool {
-        path.extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| HEIC_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
-            .unwrap_or(false)
+        media::detect(path) == Some(Content::Heic)
     }
 
     /// Opens an image, parses magic bytes to guess the format, and returns the reader.
-    fn get_image_reader(
+    pub(super) fn get_image_reader(
         source: &Path,
     ) -> Result<image::ImageReader<std::io::BufReader<std::fs::File>>, String> {
         image::ImageReader::open(source)
@@ -95,13 +133,18 @@ impl ThumbnailService {
     }
 
     /// Loads an image from a path, using magic bytes to correctly guess the format.
-    fn load_image(source: &Path) -> Result<image::DynamicImage, String> {
-        Self::get_image_reader(source)?.decode().map_err(|e| {
-            format!(
+    fn load_image(source: &Path) -> Result<image::DynamicImage, ThumbnailError> {
+        let reader = Self::get_image_reader(source).map_err(ThumbnailError::Io)?;
+        reader.decode().map_err(|e| {
+            let message = format!(
                 "Failed to decode image {}: {}",
                 normalize_path(&source.to_string_lossy()),
                 e
-            )
+            );
+            match e {
+                image::ImageError::IoError(_) => ThumbnailError::Io(message),
+                _ => ThumbnailError::Decode(message),
+            }
         })
     }
 
@@ -225,20 +268,13 @@ impl ThumbnailService {
             .unwrap_or_else(|| "video".to_string());
         let temp_out = std::env::temp_dir().join(format!("miru_thumb_{}.jpg", file_stem));
 
-        // Try common ffmpeg locations: PATH first, then Homebrew paths
-        let ffmpeg_candidates = [
-            "ffmpeg",
-            "/opt/homebrew/bin/ffmpeg",
-            "/usr/local/bin/ffmpeg",
-        ];
-
         let video_duration_secs: f64 = {
             // Quick duration estimate from file size heuristic; default 10s
             // We seek to min(1s, duration/2) to get a representative frame
             1.0
         };
 
-        for ffmpeg in &ffmpeg_candidates {
+        for ffmpeg in FFMPEG_CANDIDATES {
             let result = std::process::Command::new(ffmpeg)
                 .args([
                     "-ss",
@@ -328,34 +364,99 @@ impl ThumbnailService {
         None
     }
 
-    /// Generates a thumbnail for a single file.
-    /// Returns the thumbnail path on success.
-    fn generate_single(source: &Path, cache_base_dir: &Path) -> Result<String, String> {
+    /// Saves an extracted thumbnail, e.g. an embedded one or a video frame, and
+    /// registers it in the manifest.
+    fn save_extracted_thumbnail(
+        source: &Path,
+        thumb_path: &Path,
+        cache_base_dir: &Path,
+        data: &[u8],
+    ) -> Result<(), String> {
+        cache::ensure_cache_dir(cache_base_dir)?;
+        cache::write_thumbnail(thumb_path, data)
+            .map_err(|e| format!("Failed to write thumbnail file: {}", e))?;
+        cache::register_thumbnail(source, cache_base_dir)
+    }
+
+    /// Returns a valid cached thumbnail for the source, if there is one.
+    /// Falls back to the thumbnail of a moved or copied file with identical content.
+    fn cached_thumbnail(source: &Path, cache_base_dir: &Path) -> Option<PathBuf> {
+        let thumb_path = cache::thumbnail_path(source, cache_base_dir).ok()?;
+        if thumb_path.exists() {
+            return (!cache::is_stale(source, &thumb_path)).then_some(thumb_path);
+        }
+
+        cache::reuse_thumbnail_by_fingerprint(source, cache_base_dir)
+            .ok()
+            .flatten()
+            .filter(|reused| !cache::is_stale(source, reused))
+    }
+
+    /// Returns the cached thumbnails of a source ordered by size.
+    fn available_variants(source: &Path, cache_base_dir: &Path) -> Vec<ThumbnailVariant> {
+        let default = cache::thumbnail_path(source, cache_base_dir)
+            .ok()
+            .map(|path| (THUMBNAIL_SIZE, path));
+        let variants = cache::VARIANT_SIZES
+            .iter()
+            .map(|size| (*size, cache::variant_path(source, *size, cache_base_dir)));
+
+        let mut available: Vec<ThumbnailVariant> = default
+            .into_iter()
+            .chain(variants)
+            .filter(|(_, path)| path.exists())
+            .map(|(size, path)| ThumbnailVariant {
+                size,
+                path: normalize_path(&path.to_string_lossy()),
+            })
+            .collect();
+        available.sort_by_key(|v| v.size);
+        available
+    }
+
+    /// Generates a thumbnail for a single file, together with its size variants.
+    /// Returns the path of the default size thumbnail on success.
+    fn generate_single(source: &Path, cache_base_dir: &Path) -> Result<String, ThumbnailError> {
         let thumb_path = cache::thumbnail_path(source, cache_base_dir)?;
 
-        // Check if cached thumbnail is still valid
-        if thumb_path.exists() && !cache::is_stale(source, &thumb_path) {
-            return Ok(thumb_path.to_string_lossy().to_string());
+        // Check if cached thumbnail is still valid, caches of older versions lack the variants
+        if let Some(cached) = Self::cached_thumbnail(source, cache_base_dir) {
+            if cache::VARIANT_SIZES
+                .iter()
+                .all(|size| cache::variant_path(source, *size, cache_base_dir).exists())
+            {
+                return Ok(cached.to_string_lossy().to_string());
+            }
         }
 
         // Ensure cache directory exists
         cache::ensure_cache_dir(cache_base_dir)?;
 
-        // Open and resize the image, ignoring file extension and inferring from magic bytes
-        let img = Self::load_image(source)?;
+        // Open the image, ignoring file extension and inferring from magic bytes
+        let mut img = Self::load_image(source)?;
 
-        let (width, height) = (img.width(), img.height());
+        // Resize from the largest size down, so every step works on the smallest input
+        let mut sizes: Vec<u32> = cache::VARIANT_SIZES.to_vec();
+        sizes.push(THUMBNAIL_SIZE);
+        sizes.sort_unstable_by(|a, b| b.cmp(a));
 
-        let thumbnail = if width <= THUMBNAIL_SIZE && height <= THUMBNAIL_SIZE {
-            img
-        } else {
-            img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
-        };
-
-        // Save as JPEG
-        thumbnail
-            .save(&thumb_path)
-            .map_err(|e| format!("Failed to save thumbnail: {}", e))?;
+        for size in sizes {
+            if img.width() > size || img.height() > size {
+                img = img.thumbnail(size, size);
+            }
+            let path = if size == THUMBNAIL_SIZE {
+                thumb_path.clone()
+            } else {
+                cache::variant_path(source, size, cache_base_dir)
+            };
+
+            // Encode as JPEG and write it in one step, so a crash never leaves a truncated file
+            let mut jpeg = std::io::Cursor::new(Vec::new());
+            img.write_to(&mut jpeg, image::ImageFormat::Jpeg)
+                .map_err(|e| format!("Failed to save thumbnail: {}", e))?;
+            cache::write_thumbnail(&path, jpeg.get_ref())
+                .map_err(|e| format!("Failed to save thumbnail: {}", e))?;
+        }
 
         // Register in manifest for cleanup tracking
         cache::register_thumbnail(source, cache_base_dir)?;
@@ -363,6 +464,64 @@ impl ThumbnailService {
         Ok(thumb_path.to_string_lossy().to_string())
     }
 
+    /// Decodes a file for a full-screen preview.
+    /// Falls back to ffmpeg for formats the image crate cannot read (e.g. HEIC),
+    /// and to the embedded thumbnail as a last resort.
+    fn load_preview_image(source: &Path) -> Result<image::DynamicImage, String> {
+        let err = match Self::load_image(source) {
+            Ok(img) => return Ok(img),
+            Err(e) => e.to_string(),
+        };
+
+        let fallback = Self::extract_video_frame_ffmpeg(source).or_else(|| {
+            Self::is_heic(source)
+                .then(|| Self::extract_heic_thumbnail(source))
+                .flatten()
+        });
+        fallback
+            .and_then(|bytes| image::load_from_memory(&bytes).ok())
+            .ok_or(err)
+    }
+
+    /// Generates a screen-sized JPEG rendition of a file the webview cannot display.
+    /// Previews are cached with their own size limit, evicting the least recently
+    /// viewed, in the cache responsible for the file. Returns the preview path.
+    pub fn generate_preview(source: &Path, cache_base_dir: &Path) -> Result<String, String> {
+        let cache_base_dir = &cache::cache_dir_for(source, cache_base_dir);
+        let preview = cache::preview_path(source, cache_base_dir);
+        if preview.exists() && !cache::is_stale(source, &preview) {
+            cache::touch(&preview);
+            return Ok(normalize_path(&preview.to_string_lossy()));
+        }
+
+        cache::ensure_cache_dir(cache_base_dir)?;
+
+        let img = Self::load_preview_image(source)?;
+        let img = if img.width() > PREVIEW_SIZE || img.height() > PREVIEW_SIZE {
+            img.resize(
+                PREVIEW_SIZE,
+                PREVIEW_SIZE,
+                image::imageops::FilterType::Lanczos3,
+            )
+        } else {
+            img
+        };
+
+        // JPEG has no alpha channel
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgb8(img.to_rgb8())
+            .write_to(&mut jpeg, image::ImageFormat::Jpeg)
+            .map_err(|e| format!("Failed to encode preview: {}", e))?;
+        cache::write_thumbnail(&preview, jpeg.get_ref())
+            .map_err(|e| format!("Failed to save preview: {}", e))?;
+
+        if let Err(e) = cache::evict_previews(cache_base_dir, PREVIEW_CACHE_LIMIT) {
+            eprintln!("[preview] Failed to evict previews: {}", e);
+        }
+
+        Ok(normalize_path(&preview.to_string_lossy()))
+    }
+
     /// Generates thumbnails for all media files in a directory.
     /// Emits `thumbnail-update` events to the frontend as each file is processed.
     pub async fn generate_for_dir(
@@ -376,39 +535,100 @@ impl ThumbnailService {
             return Err(format!("Not a directory: {}", dir));
         }
 
-        // Read directory entries
-        let entries: Vec<_> = std::fs::read_dir(dir_path)
+        // Read directory entries, only the files the listing shows as media
+        let rules = media::IgnoreRules::for_dir(dir_path);
+        let files: Vec<PathBuf> = std::fs::read_dir(dir_path)
             .map_err(|e| format!("Failed to read directory: {}", e))?
             .filter_map(|e| e.ok())
-            .filter(|e| e.path().is_file())
+            .map(|e| e.path())
+            .filter(|path| {
+                path.is_file() && media::classify(path).is_some() && !rules.is_ignored(path, false)
+            })
             .collect();
 
+        Self::generate_for_files(
+            dir_path.to_path_buf(),
+            files,
+            session_id,
+            cache_base_dir,
+            app_handle,
+        )
+        .await
+    }
+
+    /// Generates thumbnails for the given media files, which all lie directly inside `dir`.
+    /// Emits `thumbnail-update` events to the frontend as each file is processed.
+    pub async fn generate_for_files(
+        dir: PathBuf,
+        files: Vec<PathBuf>,
+        session_id: u64,
+        cache_base_dir: String,
+        app_handle: AppHandle,
+    ) -> Result<(), String> {
+        // Collections with a portable cache keep their thumbnails on the same drive
+        let cache_base_dir = cache::cache_dir_for(&dir, Path::new(&cache_base_dir))
+            .to_string_lossy()
+            .to_string();
+
+        // Move thumbnails of older versions into the sharded layout before looking them up,
+        // and load the files that failed before so they are skipped until they change
+        let migrate_dir = PathBuf::from(&cache_base_dir);
+        let failures = tokio::task::spawn_blocking(move || {
+            let _ = cache::migrate_layout(&migrate_dir);
+            cache::load_failures(&migrate_dir).unwrap_or_default()
+        })
+        .await
+        .unwrap_or_default();
+        let failures = Arc::new(failures);
+
         let semaphore = Arc::new(Semaphore::new(MAX_WORKERS));
         let mut handles = Vec::new();
 
-        for entry in entries {
-            let path = entry.path();
+        for path in files {
             let app = app_handle.clone();
             let sem = semaphore.clone();
             let cache_base_dir_worker = cache_base_dir.clone();
+            let failures = failures.clone();
 
             let handle = tokio::spawn(async move {
                 let _permit = sem.acquire().await.unwrap();
 
                 let path_str = normalize_path(&path.to_string_lossy());
 
+                if let Some(failure) =
+                    cache::known_failure(&failures, &path, Path::new(&cache_base_dir_worker))
+                {
+                    let _ = app.emit(
+                        "thumbnail-update",
+                        ThumbnailUpdate {
+                            path: path_str,
+                            status: failure.status.clone(),
+                            thumbnail_path: None,
+                            variants: Vec::new(),
+                            session_id,
+                        },
+                    );
+                    return;
+                }
+
                 if Self::is_video(&path) {
                     let cache_path =
                         cache::thumbnail_path(&path, Path::new(&cache_base_dir_worker));
 
                     if let Ok(tp) = cache_path {
-                        if tp.exists() && !cache::is_stale(&path, &tp) {
+                        if let Some(cached) =
+                            Self::cached_thumbnail(&path, Path::new(&cache_base_dir_worker))
+                        {
                             let _ = app.emit(
                                 "thumbnail-update",
                                 ThumbnailUpdate {
                                     path: path_str,
                                     status: "ready".to_string(),
-                                    thumbnail_path: Some(normalize_path(&tp.to_string_lossy())),
+                                    thumbnail_path: Some(normalize_path(&cached.to_string_lossy())),
+                                    variants: Self::available_variants(
+                                        &path,
+                                        Path::new(&cache_base_dir_worker),
+                                    ),
                                     session_id,
                                 },
                             );
@@ -418,17 +638,6 @@ impl ThumbnailService {
                         // Try to extract embedded thumbnail from the container (e.g. iPhone Live Photo thmb track)
                         let cache_base = PathBuf::from(&cache_base_dir_worker);
 
-                        // Helper closure to save raw bytes as a thumbnail and emit ready
-                        let try_save = |thumb_bytes: Vec<u8>| -> bool {
-                            if cache::ensure_cache_dir(&cache_base).is_ok() {
-                                if std::fs::write(&tp, &thumb_bytes).is_ok() {
-                                    let _ = cache::register_thumbnail(&path, &cache_base);
-                                    return true;
-                                }
-                            }
-                            false
-                        };
-
                         // 1. Try embedded thumbnail track (e.g. QuickTime thmb for some MOV files)
                         let mut resolved_bytes: Option<Vec<u8>> =
                             Self::extract_embedded_video_thumbnail(&path);
@@ -441,28 +650,51 @@ impl ThumbnailService {
                         }
 
                         if let Some(thumb_bytes) = resolved_bytes {
-                            if try_save(thumb_bytes) {
-                                let _ = app.emit(
-                                    "thumbnail-update",
+                            // A thumbnail that cannot be saved is retried with the next
+                            // generation, it is not recorded as a failure
+                            let update = match Self::save_extracted_thumbnail(
+                                &path,
+                                &tp,
+                                &cache_base,
+                                &thumb_bytes,
+                            ) {
+                                Ok(()) => ThumbnailUpdate {
+                                    path: path_str,
+                                    status: "ready".to_string(),
+                                    thumbnail_path: Some(normalize_path(&tp.to_string_lossy())),
+                                    variants: Self::available_variants(&path, &cache_base),
+                                    session_id,
+                                },
+                                Err(err) => {
+                                    eprintln!("Thumbnail error for {}: {}", path_str, err);
                                     ThumbnailUpdate {
                                         path: path_str,
-                                        status: "ready".to_string(),
-                                        thumbnail_path: Some(normalize_path(&tp.to_string_lossy())),
+                                        status: "error".to_string(),
+                                        thumbnail_path: None,
+                                        variants: Vec::new(),
                                         session_id,
-                                    },
-                                );
-                                return;
-                            }
+                                    }
+                                }
+                            };
+                            let _ = app.emit("thumbnail-update", update);
+                            return;
                         }
                     }
 
                     // No embedded thumbnail found, tell frontend to render it
+                    let _ = cache::record_failure(
+                        &path,
+                        Path::new(&cache_base_dir_worker),
+                        "frontend-render",
+                        "No embedded thumbnail or extractable frame",
+                    );
                     let _ = app.emit(
                         "thumbnail-update",
                         ThumbnailUpdate {
                             path: path_str,
                             status: "frontend-render".to_string(),
                             thumbnail_path: None,
+                            variants: Vec::new(),
                             session_id,
                         },
                     );
@@ -475,13 +707,19 @@ impl ThumbnailService {
                         cache::thumbnail_path(&path, Path::new(&cache_base_dir_worker));
 
                     if let Ok(tp) = cache_path {
-                        if tp.exists() && !cache::is_stale(&path, &tp) {
+                        if let Some(cached) =
+                            Self::cached_thumbnail(&path, Path::new(&cache_base_dir_worker))
+                        {
                             let _ = app.emit(
                                 "thumbnail-update",
                                 ThumbnailUpdate {
                                     path: path_str,
                                     status: "ready".to_string(),
-                                    thumbnail_path: Some(normalize_path(&tp.to_string_lossy())),
+                                    thumbnail_path: Some(normalize_path(&cached.to_string_lossy())),
+                                    variants: Self::available_variants(
+                                        &path,
+                                        Path::new(&cache_base_dir_worker),
+                                    ),
                                     session_id,
                                 },
                             );
@@ -502,31 +740,51 @@ impl ThumbnailService {
                         }
 
                         if let Some(thumb_bytes) = resolved {
-                            if cache::ensure_cache_dir(&cache_base).is_ok()
-                                && std::fs::write(&tp, &thumb_bytes).is_ok()
-                            {
-                                let _ = cache::register_thumbnail(&path, &cache_base);
-                                let _ = app.emit(
-                                    "thumbnail-update",
+                            // A thumbnail that cannot be saved is retried with the next
+                            // generation, it is not recorded as a failure
+                            let update = match Self::save_extracted_thumbnail(
+                                &path,
+                                &tp,
+                                &cache_base,
+                                &thumb_bytes,
+                            ) {
+                                Ok(()) => ThumbnailUpdate {
+                                    path: path_str,
+                                    status: "ready".to_string(),
+                                    thumbnail_path: Some(normalize_path(&tp.to_string_lossy())),
+                                    variants: Self::available_variants(&path, &cache_base),
+                                    session_id,
+                                },
+                                Err(err) => {
+                                    eprintln!("Thumbnail error for {}: {}", path_str, err);
                                     ThumbnailUpdate {
                                         path: path_str,
-                                        status: "ready".to_string(),
-                                        thumbnail_path: Some(normalize_path(&tp.to_string_lossy())),
+                                        status: "error".to_string(),
+                                        thumbnail_path: None,
+                                        variants: Vec::new(),
                                         session_id,
-                                    },
-                                );
-                                return;
-                            }
+                                    }
+                                }
+                            };
+                            let _ = app.emit("thumbnail-update", update);
+                            return;
                         }
                     }
 
                     // No thumbnail could be generated
+                    let _ = cache::record_failure(
+                        &path,
+                        Path::new(&cache_base_dir_worker),
+                        "unsupported",
+                        "No embedded thumbnail or extractable frame",
+                    );
                     let _ = app.emit(
                         "thumbnail-update",
                         ThumbnailUpdate {
                             path: path_str,
                             status: "unsupported".to_string(),
                             thumbnail_path: None,
+                            variants: Vec::new(),
                             session_id,
                         },
                     );
@@ -540,6 +798,7 @@ impl ThumbnailService {
                             path: path_str,
                             status: "unsupported".to_string(),
                             thumbnail_path: None,
+                            variants: Vec::new(),
                             session_id,
                         },
                     );
@@ -547,9 +806,10 @@ impl ThumbnailService {
                 }
 
                 // Run blocking image work off the async thread
+                let cache_base = PathBuf::from(cache_base_dir_worker);
                 let result = tokio::task::spawn_blocking({
                     let path = path.clone();
-                    let cache_base_dir_owned = PathBuf::from(cache_base_dir_worker);
+                    let cache_base_dir_owned = cache_base.clone();
                     move || Self::generate_single(&path, &cache_base_dir_owned)
                 })
                 .await;
@@ -562,18 +822,25 @@ impl ThumbnailService {
                                 path: path_str,
                                 status: "ready".to_string(),
                                 thumbnail_path: Some(normalize_path(&thumb_path)),
+                                variants: Self::available_variants(&path, &cache_base),
                                 session_id,
                             },
                         );
                     }
                     Ok(Err(err)) => {
                         eprintln!("Thumbnail error for {}: {}", path_str, err);
+                        // Only files that cannot be decoded are skipped until they change,
+                        // cache errors are retried with the next generation
+                        if let ThumbnailError::Decode(reason) = &err {
+                            let _ = cache::record_failure(&path, &cache_base, "error", reason);
+                        }
                         let _ = app.emit(
                             "thumbnail-update",
                             ThumbnailUpdate {
                                 path: path_str,
                                 status: "error".to_string(),
                                 thumbnail_path: None,
+                                variants: Vec::new(),
                                 session_id,
                             },
                         );
@@ -586,6 +853,7 @@ impl ThumbnailService {
                                 path: path_str,
                                 status: "error".to_string(),
                                 thumbnail_path: None,
+                                variants: Vec::new(),
                                 session_id,
                             },
                         );
@@ -611,7 +879,7 @@ impl ThumbnailService {
         cache_base_dir: String,
     ) -> Result<String, String> {
         let source = Path::new(&source_path);
-        let cache_dir = Path::new(&cache_base_dir);
+        let cache_dir = &cache::cache_dir_for(source, Path::new(&cache_base_dir));
 
         cache::ensure_cache_dir(cache_dir)?;
 
@@ -629,7 +897,7 @@ impl ThumbnailService {
             .decode(b64_contents)
             .map_err(|e| format!("Failed to decode base64: {}", e))?;
 
-        std::fs::write(&thumb_path, image_data)
+        cache::write_thumbnail(&thumb_path, &image_data)
             .map_err(|e| format!("Failed to write thumbnail file: {}", e))?;
 
         cache::register_thumbnail(source, cache_dir)?;
@@ -838,7 +1106,9 @@ mod tests {
         assert!(!ThumbnailService::is_video(&PathBuf::from(
             "no_extension_file"
         )));
-        assert!(!ThumbnailService::is_video(&PathBuf::from(".hidden_no_ext")));
+        assert!(!ThumbnailService::is_video(&PathBuf::from(
+            ".hidden_no_ext"
+        )));
     }
 
     #[test]
@@ -865,6 +1135,46 @@ mod tests {
         assert!(!ThumbnailService::is_heic(&PathBuf::from(".hidden_no_ext")));
     }
 
+    #[test]
+    fn test_is_video_and_heic_sniff_containers() {
+        // Header-only samples whose extension, if any, does not match the container
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("fixtures/problematic-files");
+        for name in [
+            "mp4-without-extension",
+            "mkv-without-extension",
+            "avi-with-jpg-extension.jpg",
+            "wmv-with-png-extension.png",
+        ] {
+            assert!(ThumbnailService::is_video(&d.join(name)), "{}", name);
+            assert!(!ThumbnailService::is_heic(&d.join(name)), "{}", name);
+        }
+
+        let heic = d.join("heic-with-jpg-extension.jpg");
+        assert!(ThumbnailService::is_heic(&heic));
+        assert!(!ThumbnailService::is_video(&heic));
+
+        // A JPEG is neither, whatever its extension says
+        let jpeg = d.join("jpg-with-png-extension.png");
+        assert!(!ThumbnailService::is_video(&jpeg));
+        assert!(!ThumbnailService::is_heic(&jpeg));
+    }
+
+    #[test]
+    fn test_is_video_real_files() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("fixtures/file-examples.com");
+        assert!(ThumbnailService::is_video(
+            &d.join("file_example_MOV_1280_1_4MB.mov")
+        ));
+        assert!(ThumbnailService::is_video(
+            &d.join("file_example_MP4_480_1_5MG.mp4")
+        ));
+        assert!(!ThumbnailService::is_video(
+            &d.join("file_example_WEBP_250kB.webp")
+        ));
+    }
+
     // ---------------------------------------------------------------------------
     // Helpers shared by extract_embedded_video_thumbnail tests
     // ---------------------------------------------------------------------------
@@ -993,18 +1303,22 @@ mod tests {
     // ---------------------------------------------------------------------------
 
     fn find_ffmpeg() -> Option<&'static str> {
-        ["ffmpeg", "/opt/homebrew/bin/ffmpeg", "/usr/local/bin/ffmpeg"]
-            .iter()
-            .copied()
-            .find(|&candidate| {
-                std::process::Command::new(candidate)
-                    .arg("-version")
-                    .stdout(std::process::Stdio::null())
-                    .stderr(std::process::Stdio::null())
-                    .status()
-                    .map(|s| s.success())
-                    .unwrap_or(false)
-            })
+        [
+            "ffmpeg",
+            "/opt/homebrew/bin/ffmpeg",
+            "/usr/local/bin/ffmpeg",
+        ]
+        .iter()
+        .copied()
+        .find(|&candidate| {
+            std::process::Command::new(candidate)
+                .arg("-version")
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status()
+                .map(|s| s.success())
+                .unwrap_or(false)
+        })
     }
 
     /// Trims `src` to `duration_secs` seconds using `-c copy` and writes to `dest`.
@@ -1108,9 +1422,8 @@ mod tests {
 
     #[test]
     fn test_extract_video_frame_ffmpeg_nonexistent_file() {
-        let result = ThumbnailService::extract_video_frame_ffmpeg(Path::new(
-            "/nonexistent/path/video.mp4",
-        ));
+        let result =
+            ThumbnailService::extract_video_frame_ffmpeg(Path::new("/nonexistent/path/video.mp4"));
         assert!(result.is_none());
     }
 
@@ -1404,8 +1717,7 @@ mod tests {
 
     #[test]
     fn test_extract_heic_thumbnail_nonexistent_file() {
-        let result =
-            ThumbnailService::extract_heic_thumbnail(Path::new("/nonexistent/file.heic"));
+        let result = ThumbnailService::extract_heic_thumbnail(Path::new("/nonexistent/file.heic"));
         assert!(result.is_none());
     }
 
@@ -1429,8 +1741,7 @@ mod tests {
         let mut fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         fixture.push("fixtures/file-examples.com/file_example_JPG_100kB.jpg");
 
-        let cache_base_dir =
-            std::env::temp_dir().join("media_viewer_test_cache_hit");
+        let cache_base_dir = std::env::temp_dir().join("media_viewer_test_cache_hit");
         if cache_base_dir.exists() {
             let _ = std::fs::remove_dir_all(&cache_base_dir);
         }
@@ -1454,6 +1765,92 @@ mod tests {
         let _ = std::fs::remove_dir_all(&cache_base_dir);
     }
 
+    #[test]
+    fn test_generate_single_writes_size_variants() {
+        use tempfile::tempdir;
+        let cache_dir = tempdir().unwrap();
+        let mut fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        fixture.push("fixtures/file-examples.com/file_example_PNG_500kB.png");
+
+        ThumbnailService::generate_single(&fixture, cache_dir.path())
+            .expect("generate_single failed");
+
+        let variants = ThumbnailService::available_variants(&fixture, cache_dir.path());
+        let sizes: Vec<u32> = variants.iter().map(|v| v.size).collect();
+        assert_eq!(sizes, vec![256, THUMBNAIL_SIZE, 1024]);
+        for variant in &variants {
+            let img = image::open(&variant.path).expect("Failed to open variant");
+            assert!(
+                img.width() <= variant.size && img.height() <= variant.size,
+                "variant {} is {}x{}",
+                variant.size,
+                img.width(),
+                img.height()
+            );
+        }
+    }
+
+    #[test]
+    fn test_generate_single_adds_missing_variants() {
+        use tempfile::tempdir;
+        let cache_dir = tempdir().unwrap();
+        let mut fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        fixture.push("fixtures/file-examples.com/file_example_JPG_100kB.jpg");
+
+        ThumbnailService::generate_single(&fixture, cache_dir.path())
+            .expect("first generate_single failed");
+        let small = cache::variant_path(&fixture, 256, cache_dir.path());
+        std::fs::remove_file(&small).unwrap();
+
+        ThumbnailService::generate_single(&fixture, cache_dir.path())
+            .expect("second generate_single failed");
+
+        assert!(small.exists(), "missing variant should be regenerated");
+    }
+
+    // ---------------------------------------------------------------------------
+    // generate_preview
+    // ---------------------------------------------------------------------------
+
+    #[test]
+    fn test_generate_preview_writes_jpeg_and_reuses_it() {
+        use tempfile::tempdir;
+        let src_dir = tempdir().unwrap();
+        let cache_dir = tempdir().unwrap();
+
+        // A TIFF larger than the preview size, which the webview cannot display
+        let source = src_dir.path().join("scan.tiff");
+        image::DynamicImage::new_rgb8(PREVIEW_SIZE + 100, 50)
+            .save(&source)
+            .unwrap();
+
+        let first = ThumbnailService::generate_preview(&source, cache_dir.path())
+            .expect("generate_preview failed");
+        let img = image::open(&first).expect("Failed to open preview");
+        assert_eq!(img.width(), PREVIEW_SIZE);
+        assert_eq!(
+            image::ImageFormat::from_path(&first).unwrap(),
+            image::ImageFormat::Jpeg
+        );
+
+        let second = ThumbnailService::generate_preview(&source, cache_dir.path())
+            .expect("second generate_preview failed");
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_generate_preview_handles_alpha() {
+        use tempfile::tempdir;
+        let cache_dir = tempdir().unwrap();
+        let mut fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        fixture.push("fixtures/file-examples.com/file_example_favicon.ico");
+
+        let preview = ThumbnailService::generate_preview(&fixture, cache_dir.path())
+            .expect("generate_preview failed");
+
+        assert!(PathBuf::from(preview).exists());
+    }
+
     #[test]
     fn test_generate_single_regenerates_when_source_is_newer() {
         use tempfile::tempdir;
@@ -1473,8 +1870,8 @@ mod tests {
 
         // Force the thumbnail mtime into the past so the source is guaranteed to be newer.
         // Uses std::fs::File::set_modified (stable since Rust 1.75, cross-platform).
-        let old_time = std::time::SystemTime::UNIX_EPOCH
-            + std::time::Duration::from_secs(1_577_836_800); // 2020-01-01
+        let old_time =
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_577_836_800); // 2020-01-01
         std::fs::OpenOptions::new()
             .write(true)
             .open(&thumb_path)
@@ -1494,6 +1891,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_generate_single_reuses_thumbnail_of_moved_file() {
+        use tempfile::tempdir;
+        let src_dir = tempdir().unwrap();
+        let cache_dir = tempdir().unwrap();
+
+        let mut fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        fixture.push("fixtures/file-examples.com/file_example_JPG_100kB.jpg");
+        let before = src_dir.path().join("before.jpg");
+        std::fs::copy(&fixture, &before).unwrap();
+
+        let first = ThumbnailService::generate_single(&before, cache_dir.path())
+            .expect("first generate_single failed");
+        let first_mtime = std::fs::metadata(&first).unwrap().modified().unwrap();
+
+        let after = src_dir.path().join("after.jpg");
+        std::fs::rename(&before, &after).unwrap();
+
+        let second = ThumbnailService::generate_single(&after, cache_dir.path())
+            .expect("second generate_single failed");
+
+        assert_ne!(first, second, "moved file should get a new cache key");
+        assert!(
+            !PathBuf::from(&first).exists(),
+            "old thumbnail should be rekeyed"
+        );
+        assert_eq!(
+            std::fs::metadata(&second).unwrap().modified().unwrap(),
+            first_mtime,
+            "thumbnail should be reused instead of regenerated"
+        );
+    }
+
     #[test]
     fn test_generate_single_returns_err_for_unsupported_file() {
         use tempfile::tempdir;
@@ -1504,7 +1934,62 @@ mod tests {
         std::fs::write(&path, b"this is a text file, not an image").unwrap();
 
         let result = ThumbnailService::generate_single(&path, cache_dir.path());
-        assert!(result.is_err(), "expected Err for unsupported file type");
+        assert!(
+            matches!(result, Err(ThumbnailError::Decode(_))),
+            "expected a decode error for unsupported file type"
+        );
+    }
+
+    #[test]
+    fn test_generate_single_cache_errors_are_not_decode_errors() {
+        use tempfile::tempdir;
+        let tmp = tempdir().unwrap();
+        let mut fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        fixture.push("fixtures/file-examples.com/file_example_JPG_100kB.jpg");
+
+        // A file where the cache directory should be makes every cache write fail
+        let cache_base_dir = tmp.path().join("cache");
+        std::fs::write(&cache_base_dir, b"not a directory").unwrap();
+
+        let result = ThumbnailService::generate_single(&fixture, &cache_base_dir);
+        assert!(matches!(result, Err(ThumbnailError::Io(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn test_save_extracted_thumbnail() {
+        use tempfile::tempdir;
+        let tmp = tempdir().unwrap();
+        let source = tmp.path().join("clip.mov");
+        std::fs::write(&source, b"video").unwrap();
+        let cache_base_dir = tmp.path().join("cache");
+        let thumb_path = cache::thumbnail_path(&source, &cache_base_dir).unwrap();
+
+        ThumbnailService::save_extracted_thumbnail(&source, &thumb_path, &cache_base_dir, b"jpeg")
+            .unwrap();
+        assert_eq!(std::fs::read(&thumb_path).unwrap(), b"jpeg");
+        assert_eq!(
+            ThumbnailService::cached_thumbnail(&source, &cache_base_dir),
+            Some(thumb_path)
+        );
+    }
+
+    #[test]
+    fn test_save_extracted_thumbnail_reports_cache_errors() {
+        use tempfile::tempdir;
+        let tmp = tempdir().unwrap();
+        let source = tmp.path().join("clip.mov");
+        std::fs::write(&source, b"video").unwrap();
+        let cache_base_dir = tmp.path().join("cache");
+        std::fs::write(&cache_base_dir, b"not a directory").unwrap();
+        let thumb_path = cache::thumbnail_path(&source, &cache_base_dir).unwrap();
+
+        let result = ThumbnailService::save_extracted_thumbnail(
+            &source,
+            &thumb_path,
+            &cache_base_dir,
+            b"jpeg",
+        );
+        assert!(result.is_err());
     }
 
     /// #11 — The returned path string is a child of cache_base_dir.
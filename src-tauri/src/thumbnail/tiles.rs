@@ -0,0 +1,413 @@
+//! Deep-zoom tile pyramids for images too large to display in the webview at once.
+//!
+//! Pyramids follow the DeepZoom layout: the highest level holds the image at full
+//! resolution, every level below halves it, down to a single pixel at level 0.
+//! Each level is cut into JPEG tiles stored as
+//! `<cache_base_dir>/tiles/<hash>/<level>/<col>_<row>.jpg`, next to an `info.json`
+//! written last, so an incomplete pyramid is never served.
+
+use super::cache;
+use super::normalize_path;
+use super::service::ThumbnailService;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Edge length of a tile in pixels.
+pub const TILE_SIZE: u32 = 256;
+
+/// Images up to this edge length are shown as a whole, they are not tiled.
+const MIN_TILED_EDGE: u32 = 4096;
+
+/// Memory the decoder may allocate for an image being tiled. The default decoder
+/// limit of 512 MiB would refuse the very images tiling exists for, e.g. 16-bit
+/// frames of 100 MP sensors. Larger images are shown like smaller ones.
+const MAX_DECODE_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Disk space tile pyramids may take up before the least recently used are evicted.
+const TILE_CACHE_LIMIT: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Name of the file describing a complete pyramid.
+const INFO_FILE: &str = "info.json";
+
+/// Serializes pyramid generation, decoding several huge images at once exhausts memory.
+static GENERATION_LOCK: Mutex<()> = Mutex::new(());
+
+/// Pyramids handed out by [`tile_info`], by id. Only these are served by [`resolve_tile`].
+static PYRAMIDS: Mutex<Option<HashMap<String, PathBuf>>> = Mutex::new(None);
+
+/// Describes the tile pyramid of an image.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TileInfo {
+    /// Identifies the pyramid in tile routes, see [`resolve_tile`].
+    pub id: String,
+    /// Width of the full resolution image.
+    pub width: u32,
+    /// Height of the full resolution image.
+    pub height: u32,
+    pub tile_size: u32,
+    /// Level holding the full resolution image.
+    pub max_level: u32,
+}
+
+/// Returns the level holding the full resolution image, i.e. ceil(log2(longest edge)).
+pub fn max_level(width: u32, height: u32) -> u32 {
+    let edge = width.max(height).max(1);
+    u32::BITS - (edge - 1).leading_zeros()
+}
+
+/// Returns the image size at a pyramid level, halving and rounding up per level.
+pub fn level_size(width: u32, height: u32, level: u32) -> (u32, u32) {
+    let shift = max_level(width, height).saturating_sub(level);
+    let scale = |edge: u32| ((u64::from(edge) + (1u64 << shift) - 1) >> shift).max(1) as u32;
+    (scale(width), scale(height))
+}
+
+/// Returns the tile pyramid of a large image, generating it on first use in the
+/// cache responsible for the image, see [`cache::cache_dir_for`]. Returns `None` for images small enough to be displayed as a whole, and for
+/// images too large to decode within [`MAX_DECODE_BYTES`].
+pub fn tile_info(source: &Path, cache_base_dir: &Path) -> Result<Option<TileInfo>, String> {
+    let (width, height) = ThumbnailService::get_image_reader(source)?
+        .into_dimensions()
+        .map_err(|e| {
+            format!(
+                "Failed to read dimensions of {}: {}",
+                normalize_path(&source.to_string_lossy()),
+                e
+            )
+        })?;
+    if width.max(height) <= MIN_TILED_EDGE {
+        return Ok(None);
+    }
+
+    let _guard = GENERATION_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let cache_base_dir = &cache::cache_dir_for(source, cache_base_dir);
+    let dir = cache::tile_pyramid_dir(source, cache_base_dir);
+    let info_path = dir.join(INFO_FILE);
+
+    let cached = fs::read(&info_path)
+        .ok()
+        .and_then(|data| serde_json::from_slice::<TileInfo>(&data).ok())
+        .filter(|info| info.width == width && info.height == height)
+        .filter(|_| !cache::is_stale(source, &info_path));
+    let info = match cached {
+        Some(info) => {
+            cache::touch(&info_path);
+            info
+        }
+        None => {
+            cache::ensure_cache_dir(cache_base_dir)?;
+            let Some(info) = build_pyramid(source, &dir, width, height)? else {
+                return Ok(None);
+            };
+            if let Err(e) = cache::evict_tiles(cache_base_dir, TILE_CACHE_LIMIT, INFO_FILE, &dir) {
+                eprintln!("[tiles] Failed to evict tile pyramids: {}", e);
+            }
+            info
+        }
+    };
+
+    PYRAMIDS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(info.id.clone(), dir);
+    Ok(Some(info))
+}
+
+/// Decodes the source and writes all levels of its pyramid into `dir`.
+/// Returns `None` if decoding the source would exceed [`MAX_DECODE_BYTES`].
+fn build_pyramid(
+    source: &Path,
+    dir: &Path,
+    width: u32,
+    height: u32,
+) -> Result<Option<TileInfo>, String> {
+    if dir.exists() {
+        fs::remove_dir_all(dir).map_err(|e| format!("Failed to clear tile dir: {}", e))?;
+    }
+
+    let mut reader = ThumbnailService::get_image_reader(source)?;
+    let mut limits = image::Limits::default();
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    reader.limits(limits);
+    let img = match reader.decode() {
+        Ok(img) => img,
+        Err(image::ImageError::Limits(_)) => return Ok(None),
+        Err(e) => {
+            return Err(format!(
+                "Failed to decode image {}: {}",
+                normalize_path(&source.to_string_lossy()),
+                e
+            ))
+        }
+    };
+
+    // Levels are kept in the decoded color type and only tiles are converted, so
+    // no full resolution copy is made next to the decoded image
+    let mut level_img = img;
+    let max_level = max_level(width, height);
+    for level in (0..=max_level).rev() {
+        let (level_width, level_height) = level_size(width, height, level);
+        if (level_img.width(), level_img.height()) != (level_width, level_height) {
+            level_img = level_img.resize_exact(
+                level_width,
+                level_height,
+                image::imageops::FilterType::Triangle,
+            );
+        }
+
+        let level_dir = dir.join(level.to_string());
+        fs::create_dir_all(&level_dir).map_err(|e| format!("Failed to create tile dir: {}", e))?;
+        for row in 0..level_height.div_ceil(TILE_SIZE) {
+            for col in 0..level_width.div_ceil(TILE_SIZE) {
+                let (x, y) = (col * TILE_SIZE, row * TILE_SIZE);
+                // JPEG has no alpha channel
+                let tile = level_img
+                    .crop_imm(
+                        x,
+                        y,
+                        TILE_SIZE.min(level_width - x),
+                        TILE_SIZE.min(level_height - y),
+                    )
+                    .into_rgb8();
+
+                let mut jpeg = std::io::Cursor::new(Vec::new());
+                tile.write_to(&mut jpeg, image::ImageFormat::Jpeg)
+                    .map_err(|e| format!("Failed to encode tile: {}", e))?;
+                fs::write(
+                    level_dir.join(format!("{}_{}.jpg", col, row)),
+                    jpeg.get_ref(),
+                )
+                .map_err(|e| format!("Failed to save tile: {}", e))?;
+            }
+        }
+    }
+
+    let info = TileInfo {
+        id: dir
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        width,
+        height,
+        tile_size: TILE_SIZE,
+        max_level,
+    };
+    let json =
+        serde_json::to_vec(&info).map_err(|e| format!("Failed to serialize tile info: {}", e))?;
+    cache::write_atomic(&dir.join(INFO_FILE), &json)
+        .map_err(|e| format!("Failed to save tile info: {}", e))?;
+    Ok(Some(info))
+}
+
+/// Resolves a tile route of the form `<id>/<level>/<col>_<row>.jpg` to the tile file.
+/// Only pyramids handed out by [`tile_info`] are resolved, and the file name is built
+/// from the parsed numbers, so routes cannot escape the pyramid directory.
+pub fn resolve_tile(route: &str) -> Option<PathBuf> {
+    let mut parts = route.split('/');
+    let id = parts.next()?;
+    let level: u32 = parts.next()?.parse().ok()?;
+    let (col, row) = parts.next()?.strip_suffix(".jpg")?.split_once('_')?;
+    let (col, row): (u32, u32) = (col.parse().ok()?, row.parse().ok()?);
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let dir = PYRAMIDS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()?
+        .get(id)?
+        .clone();
+    let tile = dir
+        .join(level.to_string())
+        .join(format!("{}_{}.jpg", col, row));
+    tile.is_file().then_some(tile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_test_image(dir: &Path, name: &str, width: u32, height: u32) -> PathBuf {
+        let path = dir.join(name);
+        image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        })
+        .save(&path)
+        .unwrap();
+        path
+    }
+
+    // ---------------------------------------------------------------
+    // level math
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_max_level() {
+        assert_eq!(max_level(1, 1), 0);
+        assert_eq!(max_level(2, 1), 1);
+        assert_eq!(max_level(4096, 3000), 12);
+        assert_eq!(max_level(3000, 4097), 13);
+    }
+
+    #[test]
+    fn test_level_size_rounds_up() {
+        assert_eq!(level_size(5000, 3001, 13), (5000, 3001));
+        assert_eq!(level_size(5000, 3001, 12), (2500, 1501));
+        assert_eq!(level_size(5000, 3001, 11), (1250, 751));
+        assert_eq!(level_size(5000, 3001, 0), (1, 1));
+    }
+
+    // ---------------------------------------------------------------
+    // tile_info
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_tile_info_skips_small_images() {
+        let temp = TempDir::new().unwrap();
+        let source = write_test_image(temp.path(), "small.png", 640, 480);
+        let cache_dir = temp.path().join("cache");
+
+        assert_eq!(tile_info(&source, &cache_dir).unwrap(), None);
+        assert!(!cache::tile_pyramid_dir(&source, &cache_dir).exists());
+    }
+
+    #[test]
+    fn test_tile_info_skips_images_beyond_memory_limits() {
+        let temp = TempDir::new().unwrap();
+        let cache_dir = temp.path().join("cache");
+        // Only the header of a 24-bit BMP of 40000 × 40000 pixels, 4.8 GB decoded
+        let source = temp.path().join("huge.bmp");
+        let mut header = b"BM".to_vec();
+        for value in [54u32, 0, 54, 40, 40_000, 40_000] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&24u16.to_le_bytes());
+        header.extend_from_slice(&[0; 24]);
+        fs::write(&source, header).unwrap();
+
+        assert_eq!(tile_info(&source, &cache_dir).unwrap(), None);
+        assert!(!cache::tile_pyramid_dir(&source, &cache_dir).exists());
+    }
+
+    #[test]
+    fn test_tile_info_builds_pyramid() {
+        let temp = TempDir::new().unwrap();
+        let source = write_test_image(temp.path(), "large.png", 4200, 300);
+        let cache_dir = temp.path().join("cache");
+
+        let info = tile_info(&source, &cache_dir).unwrap().unwrap();
+        assert_eq!((info.width, info.height), (4200, 300));
+        assert_eq!(info.tile_size, TILE_SIZE);
+        assert_eq!(info.max_level, 13);
+
+        let dir = cache::tile_pyramid_dir(&source, &cache_dir);
+        assert!(dir.join(INFO_FILE).exists());
+        // Full resolution: 17 columns, 2 rows, the last ones cropped
+        let last = image::open(dir.join("13/16_1.jpg")).unwrap();
+        assert_eq!((last.width(), last.height()), (4200 - 16 * 256, 300 - 256));
+        assert!(!dir.join("13/17_0.jpg").exists());
+        // Single tile levels
+        let top = image::open(dir.join("0/0_0.jpg")).unwrap();
+        assert_eq!((top.width(), top.height()), (1, 1));
+        let level8 = image::open(dir.join("8/0_0.jpg")).unwrap();
+        assert_eq!((level8.width(), level8.height()), (132, 10));
+    }
+
+    #[test]
+    fn test_tile_info_tiles_images_beyond_100_megapixels() {
+        let temp = TempDir::new().unwrap();
+        // A binary graymap of 11648 × 8736 pixels, PNM is written without compression
+        let source = temp.path().join("gfx.pgm");
+        let mut pgm = b"P5 11648 8736 255\n".to_vec();
+        pgm.resize(pgm.len() + 11_648 * 8_736, 90);
+        fs::write(&source, pgm).unwrap();
+        let cache_dir = temp.path().join("cache");
+
+        let info = tile_info(&source, &cache_dir).unwrap().unwrap();
+        assert_eq!((info.width, info.height), (11_648, 8_736));
+        assert_eq!(info.max_level, 14);
+        let dir = cache::tile_pyramid_dir(&source, &cache_dir);
+        assert!(dir.join("14/45_34.jpg").exists());
+        assert!(dir.join("0/0_0.jpg").exists());
+    }
+
+    #[test]
+    fn test_tile_info_reuses_pyramid() {
+        let temp = TempDir::new().unwrap();
+        let source = write_test_image(temp.path(), "large.png", 4200, 300);
+        let cache_dir = temp.path().join("cache");
+
+        let first = tile_info(&source, &cache_dir).unwrap().unwrap();
+        let marker = cache::tile_pyramid_dir(&source, &cache_dir).join("13/0_0.jpg");
+        fs::write(&marker, b"kept").unwrap();
+
+        let second = tile_info(&source, &cache_dir).unwrap().unwrap();
+        assert_eq!(first, second);
+        assert_eq!(fs::read(&marker).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn test_tile_info_rebuilds_incomplete_pyramid() {
+        let temp = TempDir::new().unwrap();
+        let source = write_test_image(temp.path(), "large.png", 4200, 300);
+        let cache_dir = temp.path().join("cache");
+
+        tile_info(&source, &cache_dir).unwrap().unwrap();
+        let dir = cache::tile_pyramid_dir(&source, &cache_dir);
+        fs::remove_file(dir.join(INFO_FILE)).unwrap();
+        fs::write(dir.join("13/0_0.jpg"), b"partial").unwrap();
+
+        tile_info(&source, &cache_dir).unwrap().unwrap();
+        assert!(dir.join(INFO_FILE).exists());
+        assert!(image::open(dir.join("13/0_0.jpg")).is_ok());
+    }
+
+    // ---------------------------------------------------------------
+    // resolve_tile
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_resolve_tile() {
+        let temp = TempDir::new().unwrap();
+        let source = write_test_image(temp.path(), "large.png", 4200, 300);
+        let cache_dir = temp.path().join("cache");
+        let info = tile_info(&source, &cache_dir).unwrap().unwrap();
+        let dir = cache::tile_pyramid_dir(&source, &cache_dir);
+
+        assert_eq!(
+            resolve_tile(&format!("{}/13/16_1.jpg", info.id)),
+            Some(dir.join("13").join("16_1.jpg"))
+        );
+        // Outside the pyramid
+        assert_eq!(resolve_tile(&format!("{}/13/17_0.jpg", info.id)), None);
+        assert_eq!(resolve_tile(&format!("{}/14/0_0.jpg", info.id)), None);
+    }
+
+    #[test]
+    fn test_resolve_tile_rejects_invalid_routes() {
+        let temp = TempDir::new().unwrap();
+        let source = write_test_image(temp.path(), "large.png", 4200, 300);
+        let cache_dir = temp.path().join("cache");
+        let info = tile_info(&source, &cache_dir).unwrap().unwrap();
+
+        for route in [
+            "0000000000000000/0/0_0.jpg".to_string(),
+            format!("{}/../../0_0.jpg", info.id),
+            format!("{}/0/..%2F0_0.jpg", info.id),
+            format!("{}/0/0_0.png", info.id),
+            format!("{}/0/0_0.jpg/extra", info.id),
+            format!("{}/-1/0_0.jpg", info.id),
+            format!("{}/{}", info.id, INFO_FILE),
+        ] {
+            assert_eq!(resolve_tile(&route), None, "{}", route);
+        }
+    }
+}
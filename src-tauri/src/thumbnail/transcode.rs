@@ -0,0 +1,891 @@
+//! Browser-playable transcodes of videos the webview cannot play (e.g. MKV, AVI, WMV, FLV).
+//!
+//! Videos are converted on demand with ffmpeg into an MP4 with H.264 video and AAC audio,
+//! streams already using these codecs are only remuxed. The MP4 is fragmented, so the
+//! `mv` protocol serves it while it is written, see [`growing_transcode`]. Finished
+//! transcodes are cached with their own size limit and seeked through range requests.
+//!
+//! Videos above a configurable resolution or bitrate can also get a 720p H.264 proxy,
+//! generated in the background by [`generate_proxies`] and kept next to the thumbnails.
+
+use super::cache;
+use super::normalize_path;
+use super::service::{ThumbnailService, FFMPEG_CANDIDATES};
+use crate::media::IgnoreRules;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+/// Disk space transcodes may take up before the least recently played are evicted.
+const TRANSCODE_CACHE_LIMIT: u64 = 10 * 1024 * 1024 * 1024;
+
+/// Height of the shorter edge of proxies.
+const PROXY_EDGE: u32 = 720;
+
+/// Serializes transcoding, a single ffmpeg run already keeps all cores busy.
+/// Proxies have their own lock, so the viewer never waits for background proxy jobs.
+static TRANSCODE_LOCK: Mutex<()> = Mutex::new(());
+static PROXY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Video of the most recent [`transcode`] call. Transcodes of other videos stop, so
+/// the viewer never waits for a video it has left.
+static REQUESTED: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Output paths of the transcodes being written, see [`growing_transcode`].
+static GROWING: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Session of the most recent [`generate_proxies`] call, older jobs stop when it changes.
+static PROXY_SESSION: AtomicU64 = AtomicU64::new(0);
+
+/// Progress of [`transcode`], emitted to the frontend.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TranscodeProgress {
+    pub path: String,
+    /// Fraction of the video converted so far, from 0 to 1.
+    pub progress: f64,
+}
+
+/// Which videos get a proxy, configured in the settings.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyOptions {
+    /// Videos whose shorter edge has at least this many pixels get a proxy.
+    pub min_resolution: u32,
+    /// Videos above this bitrate in megabits per second get a proxy.
+    pub min_bitrate_mbps: u32,
+}
+
+/// A proxy that became available, emitted to the frontend.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyUpdate {
+    pub path: String,
+    pub proxy_path: String,
+    pub session_id: u64,
+}
+
+#[derive(Deserialize)]
+struct Probe {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+    bit_rate: Option<String>,
+}
+
+/// How the streams of a video are converted.
+#[derive(Clone, Debug, PartialEq)]
+struct Plan {
+    /// The video stream is H.264 already and only remuxed.
+    copy_video: bool,
+    /// The audio stream is AAC already, or there is none.
+    copy_audio: bool,
+    /// Duration in seconds, used for progress reporting.
+    duration: Option<f64>,
+    /// Dimensions of the video stream, if known.
+    size: Option<(u32, u32)>,
+    /// Overall bitrate in bits per second, if known.
+    bit_rate: Option<u64>,
+    /// The video is scaled down to a proxy, which requires re-encoding.
+    proxy: bool,
+}
+
+/// Returns the first ffmpeg candidate that can be executed.
+fn find_ffmpeg() -> Option<&'static str> {
+    FFMPEG_CANDIDATES.iter().copied().find(|ffmpeg| {
+        Command::new(ffmpeg)
+            .arg("-version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|s| s.success())
+    })
+}
+
+/// Returns the ffprobe binary installed next to an ffmpeg binary.
+fn ffprobe_for(ffmpeg: &str) -> String {
+    match ffmpeg.strip_suffix("ffmpeg") {
+        Some(dir) => format!("{}ffprobe", dir),
+        None => "ffprobe".to_string(),
+    }
+}
+
+/// Decides how to convert a video from the JSON output of ffprobe.
+fn parse_probe(json: &[u8]) -> Result<Plan, String> {
+    let probe: Probe = serde_json::from_slice(json)
+        .map_err(|e| format!("Failed to parse ffprobe output: {}", e))?;
+    let stream = |kind: &str| {
+        probe
+            .streams
+            .iter()
+            .find(|s| s.codec_type.as_deref() == Some(kind))
+    };
+    let codec = |s: &ProbeStream| s.codec_name.as_deref().unwrap_or_default().to_string();
+
+    let video = stream("video").ok_or("No video stream found")?;
+    let format = probe.format.as_ref();
+    Ok(Plan {
+        copy_video: codec(video) == "h264",
+        copy_audio: stream("audio").is_none_or(|audio| codec(audio) == "aac"),
+        duration: format
+            .and_then(|f| f.duration.as_deref())
+            .and_then(|d| d.parse::<f64>().ok())
+            .filter(|d| *d > 0.0),
+        size: video.width.zip(video.height),
+        bit_rate: format
+            .and_then(|f| f.bit_rate.as_deref())
+            .and_then(|b| b.parse::<u64>().ok()),
+        proxy: false,
+    })
+}
+
+/// Inspects the streams of a video with ffprobe.
+fn probe(ffprobe: &str, source: &Path) -> Result<Plan, String> {
+    let output = Command::new(ffprobe)
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration,bit_rate:stream=codec_type,codec_name,width,height",
+            "-of",
+            "json",
+        ])
+        .arg(source)
+        .output()
+        .map_err(|e| format!("Failed to run ffprobe: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "ffprobe failed for {}: {}",
+            normalize_path(&source.to_string_lossy()),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_probe(&output.stdout)
+}
+
+/// Builds the ffmpeg arguments converting `source` into an MP4 at `output`, reporting
+/// progress on stdout. Transcodes are fragmented so they play while being written,
+/// proxies are only played once finished and get their index up front instead.
+fn ffmpeg_args(source: &Path, output: &Path, plan: &Plan) -> Vec<String> {
+    let mut args: Vec<String> = ["-v", "error", "-nostdin", "-i"].map(String::from).to_vec();
+    args.push(source.to_string_lossy().to_string());
+    args.extend(["-map", "0:v:0", "-map", "0:a:0?", "-sn"].map(String::from));
+
+    if plan.copy_video && !plan.proxy {
+        args.extend(["-c:v", "copy"].map(String::from));
+    } else {
+        if plan.proxy {
+            // Scale the shorter edge down to the proxy size, keeping the aspect ratio
+            args.push("-vf".to_string());
+            args.push(format!(
+                "scale='if(gt(iw,ih),-2,min({0},iw))':'if(gt(iw,ih),min({0},ih),-2)'",
+                PROXY_EDGE
+            ));
+        }
+        args.extend(
+            [
+                "-c:v", "libx264", "-preset", "veryfast", "-crf", "23", "-pix_fmt", "yuv420p",
+            ]
+            .map(String::from),
+        );
+    }
+    if plan.copy_audio {
+        args.extend(["-c:a", "copy"].map(String::from));
+    } else {
+        args.extend(["-c:a", "aac", "-b:a", "160k"].map(String::from));
+    }
+
+    args.push("-movflags".to_string());
+    args.push(if plan.proxy {
+        "+faststart".to_string()
+    } else {
+        "frag_keyframe+empty_moov+default_base_moof".to_string()
+    });
+    args.extend(["-f", "mp4", "-progress", "pipe:1", "-y"].map(String::from));
+    args.push(output.to_string_lossy().to_string());
+    args
+}
+
+/// Parses a line of ffmpeg's `-progress` output into the converted fraction.
+fn parse_progress(line: &str, duration: Option<f64>) -> Option<f64> {
+    // `out_time_ms` is in microseconds as well, despite its name
+    let micros = line
+        .strip_prefix("out_time_us=")
+        .or_else(|| line.strip_prefix("out_time_ms="))?
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+    Some((micros / 1_000_000.0 / duration?).clamp(0.0, 1.0))
+}
+
+/// Runs ffmpeg into `partial`, passing each line of its progress output to `on_line`.
+/// Kills ffmpeg once `cancelled` returns true. Returns ffmpeg's error output on failure.
+fn run_ffmpeg(
+    ffmpeg: &str,
+    source: &Path,
+    partial: &Path,
+    plan: &Plan,
+    on_line: &mut impl FnMut(&str),
+    cancelled: &impl Fn() -> bool,
+) -> Result<(), String> {
+    let mut child = Command::new(ffmpeg)
+        .args(ffmpeg_args(source, partial, plan))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            on_line(&line);
+            if cancelled() {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err("Cancelled".to_string());
+            }
+        }
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+/// Marks the transcode written to an output path as growing while alive.
+struct Growing(PathBuf);
+
+impl Growing {
+    fn new(output: &Path) -> Self {
+        GROWING
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(HashSet::new)
+            .insert(output.to_path_buf());
+        Self(output.to_path_buf())
+    }
+}
+
+impl Drop for Growing {
+    fn drop(&mut self) {
+        if let Some(growing) = GROWING.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            growing.remove(&self.0);
+        }
+    }
+}
+
+fn is_growing(output: &Path) -> bool {
+    GROWING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .is_some_and(|growing| growing.contains(output))
+}
+
+/// Returns the partial file of a transcode still being written, given the file name
+/// of the finished transcode. Its bytes don't change once written, and it is renamed
+/// to the finished transcode when complete.
+pub fn growing_transcode(name: &str, cache_dirs: &[PathBuf]) -> Option<PathBuf> {
+    GROWING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()?
+        .iter()
+        .find(|output| {
+            cache_dirs.iter().any(|dir| output.starts_with(dir))
+                && output.file_name().is_some_and(|n| n == name)
+        })
+        .map(|output| output.with_extension("mp4.part"))
+}
+
+fn is_cached(source: &Path, output: &Path) -> bool {
+    output.exists() && !cache::is_stale(source, output)
+}
+
+fn is_requested(source: &Path) -> bool {
+    REQUESTED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_deref()
+        == Some(source)
+}
+
+/// Converts a video into a browser-playable MP4, or returns the cached transcode.
+/// Returns the transcode path as soon as ffmpeg wrote the first bytes, or at once if
+/// the video is being transcoded already, while the rest is written in the background,
+/// see [`growing_transcode`]. Progress is reported as a fraction from 0 to 1.
+/// A running transcode of another video is cancelled.
+/// Transcodes are stored in the cache responsible for the video, see [`cache::cache_dir_for`].
+pub fn transcode(
+    source: &Path,
+    cache_base_dir: &Path,
+    on_progress: impl FnMut(f64) + Send + 'static,
+) -> Result<String, String> {
+    *REQUESTED.lock().unwrap_or_else(|e| e.into_inner()) = Some(source.to_path_buf());
+    let cache_base_dir = &cache::cache_dir_for(source, cache_base_dir);
+    let output = cache::transcode_path(source, cache_base_dir);
+    let path = normalize_path(&output.to_string_lossy());
+    if is_cached(source, &output) {
+        cache::touch(&output);
+        return Ok(path);
+    }
+    if is_growing(&output) {
+        return Ok(path);
+    }
+
+    let ffmpeg = find_ffmpeg().ok_or("ffmpeg is required to play this video")?;
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let (source, cache_base_dir) = (source.to_path_buf(), cache_base_dir.to_path_buf());
+    thread::spawn(move || {
+        let result = write_transcode(
+            ffmpeg,
+            &source,
+            &cache_base_dir,
+            &output,
+            on_progress,
+            &ready_tx,
+        );
+        // Nobody waits for the result once the transcode started playing
+        if let Err(mpsc::SendError(Err(e))) = ready_tx.send(result) {
+            eprintln!("[transcode] {}", e);
+        }
+    });
+    ready_rx
+        .recv()
+        .map_err(|e| format!("Failed to transcode video: {}", e))??;
+    Ok(path)
+}
+
+/// Writes the transcode of `source` to `output`, sending on `ready` once its first
+/// bytes were written.
+fn write_transcode(
+    ffmpeg: &str,
+    source: &Path,
+    cache_base_dir: &Path,
+    output: &Path,
+    mut on_progress: impl FnMut(f64),
+    ready: &Sender<Result<(), String>>,
+) -> Result<(), String> {
+    let _guard = TRANSCODE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    // Another request may have transcoded the video while this one was waiting
+    if is_cached(source, output) {
+        cache::touch(output);
+        return Ok(());
+    }
+    let cancelled = || !is_requested(source);
+    let cancelled_error = || {
+        format!(
+            "Transcoding {} was cancelled for another video",
+            normalize_path(&source.to_string_lossy())
+        )
+    };
+    if cancelled() {
+        return Err(cancelled_error());
+    }
+
+    let mut plan = probe(&ffprobe_for(ffmpeg), source)?;
+    cache::ensure_cache_dir(cache_base_dir)?;
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create transcode dir: {}", e))?;
+    }
+    let partial = output.with_extension("mp4.part");
+
+    // A partial file left by an earlier run must not be served as this one
+    let _ = fs::remove_file(&partial);
+    let _growing = Growing::new(output);
+    let started = Cell::new(false);
+    let duration = plan.duration;
+    let mut on_line = |line: &str| {
+        if let Some(progress) = parse_progress(line, duration) {
+            on_progress(progress);
+        }
+        if !started.get() && fs::metadata(&partial).is_ok_and(|m| m.len() > 0) {
+            started.set(true);
+            let _ = ready.send(Ok(()));
+        }
+    };
+    let mut result = run_ffmpeg(ffmpeg, source, &partial, &plan, &mut on_line, &cancelled);
+    if result.is_err() && plan.copy_video && !started.get() && !cancelled() {
+        // Some containers store H.264 in ways MP4 cannot hold, re-encode instead
+        plan.copy_video = false;
+        result = run_ffmpeg(ffmpeg, source, &partial, &plan, &mut on_line, &cancelled);
+    }
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial);
+        if cancelled() {
+            return Err(cancelled_error());
+        }
+        return Err(format!(
+            "ffmpeg failed to transcode {}: {}",
+            normalize_path(&source.to_string_lossy()),
+            e
+        ));
+    }
+
+    fs::rename(&partial, output).map_err(|e| format!("Failed to save transcode: {}", e))?;
+    on_progress(1.0);
+
+    if let Err(e) = cache::evict_transcodes(cache_base_dir, TRANSCODE_CACHE_LIMIT) {
+        eprintln!("[transcode] Failed to evict transcodes: {}", e);
+    }
+    Ok(())
+}
+
+/// Returns true if a video of the given dimensions and bitrate warrants a proxy.
+fn needs_proxy(size: Option<(u32, u32)>, bit_rate: Option<u64>, options: &ProxyOptions) -> bool {
+    let min_bit_rate = u64::from(options.min_bitrate_mbps) * 1_000_000;
+    size.is_some_and(|(w, h)| w.min(h) >= options.min_resolution)
+        || bit_rate.is_some_and(|b| b > min_bit_rate)
+}
+
+/// Generates the 720p proxy of a video, or returns the existing one.
+/// Returns None for videos below the thresholds of `options`. Videos are probed once
+/// per content, later calls go by the properties recorded in the cache.
+fn generate_proxy(
+    ffmpeg: &str,
+    source: &Path,
+    cache_base_dir: &Path,
+    options: &ProxyOptions,
+) -> Result<Option<String>, String> {
+    if let Some(proxy) = cache::existing_proxy(source, cache_base_dir)? {
+        return Ok(Some(normalize_path(&proxy.to_string_lossy())));
+    }
+
+    let recorded = cache::recorded_probe(source, cache_base_dir)?;
+    if recorded
+        .as_ref()
+        .is_some_and(|probe| !needs_proxy(probe.size, probe.bit_rate, options))
+    {
+        return Ok(None);
+    }
+
+    let mut plan = probe(&ffprobe_for(ffmpeg), source)?;
+    if recorded.is_none() {
+        cache::record_probe(source, cache_base_dir, plan.size, plan.bit_rate)?;
+    }
+    if !needs_proxy(plan.size, plan.bit_rate, options) {
+        return Ok(None);
+    }
+    plan.proxy = true;
+
+    let _guard = PROXY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let output = cache::proxy_path(source, cache_base_dir);
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create shard directory: {}", e))?;
+    }
+    let partial = output.with_extension("mp4.part");
+    if let Err(e) = run_ffmpeg(ffmpeg, source, &partial, &plan, &mut |_| {}, &|| false) {
+        let _ = fs::remove_file(&partial);
+        return Err(format!(
+            "ffmpeg failed to create a proxy of {}: {}",
+            normalize_path(&source.to_string_lossy()),
+            e
+        ));
+    }
+    fs::rename(&partial, &output).map_err(|e| format!("Failed to save proxy: {}", e))?;
+    cache::register_proxy(source, cache_base_dir)?;
+
+    Ok(Some(normalize_path(&output.to_string_lossy())))
+}
+
+/// Generates proxies for the videos in a directory one at a time, reporting each
+/// available proxy. Stops early once a newer session starts.
+pub fn generate_proxies(
+    dir: &Path,
+    session_id: u64,
+    cache_base_dir: &Path,
+    options: &ProxyOptions,
+    mut on_update: impl FnMut(ProxyUpdate),
+) -> Result<(), String> {
+    PROXY_SESSION.store(session_id, Ordering::SeqCst);
+    if !dir.is_dir() {
+        return Err(format!("Not a directory: {}", dir.display()));
+    }
+    let ffmpeg = find_ffmpeg().ok_or("ffmpeg is required to generate proxies")?;
+    let cache_base_dir = cache::cache_dir_for(dir, cache_base_dir);
+    cache::ensure_cache_dir(&cache_base_dir)?;
+
+    let rules = IgnoreRules::for_dir(dir);
+    let mut videos: Vec<_> = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read directory: {}", e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && ThumbnailService::is_video(p) && !rules.is_ignored(p, false))
+        .collect();
+    videos.sort();
+
+    for video in videos {
+        if PROXY_SESSION.load(Ordering::SeqCst) != session_id {
+            break;
+        }
+        match generate_proxy(ffmpeg, &video, &cache_base_dir, options) {
+            Ok(Some(proxy_path)) => on_update(ProxyUpdate {
+                path: normalize_path(&video.to_string_lossy()),
+                proxy_path,
+                session_id,
+            }),
+            Ok(None) => {}
+            Err(e) => eprintln!("[proxy] {}", e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    /// Waits for the background part of [`transcode`] to finish.
+    fn wait_until_written(output: &Path) {
+        for _ in 0..600 {
+            if !is_growing(output) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("transcode did not finish");
+    }
+
+    fn plan(copy_video: bool, copy_audio: bool) -> Plan {
+        Plan {
+            copy_video,
+            copy_audio,
+            duration: Some(10.0),
+            size: None,
+            bit_rate: None,
+            proxy: false,
+        }
+    }
+
+    // ---------------------------------------------------------------
+    // parse_probe
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_parse_probe_remuxes_h264_and_aac() {
+        let json = br#"{
+            "streams": [
+                {"codec_name": "h264", "codec_type": "video", "width": 3840, "height": 2160},
+                {"codec_name": "aac", "codec_type": "audio"}
+            ],
+            "format": {"duration": "12.480000", "bit_rate": "45000000"}
+        }"#;
+        assert_eq!(
+            parse_probe(json).unwrap(),
+            Plan {
+                copy_video: true,
+                copy_audio: true,
+                duration: Some(12.48),
+                size: Some((3840, 2160)),
+                bit_rate: Some(45_000_000),
+                proxy: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_probe_reencodes_other_codecs() {
+        let json = br#"{
+            "streams": [
+                {"codec_name": "mpeg4", "codec_type": "video"},
+                {"codec_name": "mp3", "codec_type": "audio"},
+                {"codec_name": "subrip", "codec_type": "subtitle"}
+            ],
+            "format": {"duration": "N/A"}
+        }"#;
+        assert_eq!(
+            parse_probe(json).unwrap(),
+            Plan {
+                copy_video: false,
+                copy_audio: false,
+                duration: None,
+                size: None,
+                bit_rate: None,
+                proxy: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_probe_without_audio() {
+        let json = br#"{"streams": [{"codec_name": "hevc", "codec_type": "video"}]}"#;
+        let plan = parse_probe(json).unwrap();
+        assert!(!plan.copy_video);
+        assert!(plan.copy_audio);
+    }
+
+    #[test]
+    fn test_parse_probe_without_video() {
+        let json = br#"{"streams": [{"codec_name": "mp3", "codec_type": "audio"}]}"#;
+        assert!(parse_probe(json).is_err());
+        assert!(parse_probe(b"not json").is_err());
+    }
+
+    // ---------------------------------------------------------------
+    // ffmpeg arguments and progress
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_ffprobe_for() {
+        assert_eq!(ffprobe_for("ffmpeg"), "ffprobe");
+        assert_eq!(
+            ffprobe_for("/opt/homebrew/bin/ffmpeg"),
+            "/opt/homebrew/bin/ffprobe"
+        );
+    }
+
+    #[test]
+    fn test_ffmpeg_args() {
+        let source = Path::new("/videos/clip.mkv");
+        let output = Path::new("/cache/clip.mp4.part");
+
+        let remux = ffmpeg_args(source, output, &plan(true, true));
+        assert!(remux.windows(2).any(|w| w == ["-c:v", "copy"]));
+        assert!(remux.windows(2).any(|w| w == ["-c:a", "copy"]));
+        assert!(remux.windows(2).any(|w| w == ["-f", "mp4"]));
+        assert!(remux
+            .windows(2)
+            .any(|w| w == ["-movflags", "frag_keyframe+empty_moov+default_base_moof"]));
+        assert_eq!(remux.last().unwrap(), "/cache/clip.mp4.part");
+
+        let encode = ffmpeg_args(source, output, &plan(false, false));
+        assert!(encode.windows(2).any(|w| w == ["-c:v", "libx264"]));
+        assert!(encode.windows(2).any(|w| w == ["-c:a", "aac"]));
+        assert!(!encode.contains(&"-vf".to_string()));
+
+        // Proxies are always re-encoded and scaled
+        let proxy = ffmpeg_args(
+            source,
+            output,
+            &Plan {
+                proxy: true,
+                ..plan(true, true)
+            },
+        );
+        assert!(proxy.windows(2).any(|w| w == ["-c:v", "libx264"]));
+        let filter = proxy.iter().position(|a| a == "-vf").unwrap();
+        assert!(proxy[filter + 1].starts_with("scale="));
+        assert!(proxy[filter + 1].contains("min(720,ih)"));
+        assert!(proxy.windows(2).any(|w| w == ["-movflags", "+faststart"]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_ffmpeg_stops_when_cancelled() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::Instant;
+        let temp = TempDir::new().unwrap();
+        // Reports progress forever, like ffmpeg on a long video
+        let ffmpeg = temp.path().join("ffmpeg");
+        fs::write(
+            &ffmpeg,
+            "#!/bin/sh\nwhile :; do echo out_time_us=1000000; sleep 0.05; done\n",
+        )
+        .unwrap();
+        fs::set_permissions(&ffmpeg, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let lines = Cell::new(0);
+        let started = Instant::now();
+        let result = run_ffmpeg(
+            &ffmpeg.to_string_lossy(),
+            Path::new("clip.mkv"),
+            &temp.path().join("clip.mp4.part"),
+            &plan(false, false),
+            &mut |_| lines.set(lines.get() + 1),
+            &|| lines.get() >= 3,
+        );
+        assert!(result.is_err());
+        assert_eq!(lines.get(), 3);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    // ---------------------------------------------------------------
+    // growing_transcode
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_growing_transcode() {
+        let temp = TempDir::new().unwrap();
+        let cache_dir = temp.path().join("cache");
+        let output = cache::transcode_path(&temp.path().join("clip.mkv"), &cache_dir);
+        let name = output.file_name().unwrap().to_string_lossy().to_string();
+        let dirs = [temp.path().join("other"), cache_dir];
+        assert_eq!(growing_transcode(&name, &dirs), None);
+
+        let growing = Growing::new(&output);
+        assert_eq!(
+            growing_transcode(&name, &dirs),
+            Some(output.with_extension("mp4.part"))
+        );
+        assert_eq!(growing_transcode(&name, &dirs[..1]), None);
+        assert_eq!(growing_transcode("0000000000000000.mp4", &dirs), None);
+
+        drop(growing);
+        assert_eq!(growing_transcode(&name, &dirs), None);
+    }
+
+    // ---------------------------------------------------------------
+    // needs_proxy
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_needs_proxy() {
+        let options = ProxyOptions {
+            min_resolution: 2160,
+            min_bitrate_mbps: 50,
+        };
+        assert!(needs_proxy(Some((3840, 2160)), None, &options));
+        // The shorter edge counts, portrait videos are treated alike
+        assert!(needs_proxy(Some((2160, 3840)), None, &options));
+        assert!(!needs_proxy(Some((1920, 1080)), Some(20_000_000), &options));
+        assert!(needs_proxy(Some((1920, 1080)), Some(80_000_000), &options));
+        assert!(!needs_proxy(None, None, &options));
+    }
+
+    #[test]
+    fn test_parse_progress() {
+        assert_eq!(parse_progress("out_time_us=5000000", Some(10.0)), Some(0.5));
+        assert_eq!(
+            parse_progress("out_time_ms=2500000", Some(10.0)),
+            Some(0.25)
+        );
+        assert_eq!(
+            parse_progress("out_time_us=20000000", Some(10.0)),
+            Some(1.0)
+        );
+        assert_eq!(parse_progress("out_time_us=5000000", None), None);
+        assert_eq!(parse_progress("frame=120", Some(10.0)), None);
+        assert_eq!(parse_progress("out_time_us=N/A", Some(10.0)), None);
+    }
+
+    // ---------------------------------------------------------------
+    // transcode (requires ffmpeg)
+    // ---------------------------------------------------------------
+
+    #[test]
+    fn test_transcode_mov_fixture() {
+        if find_ffmpeg().is_none() {
+            return;
+        }
+        let mut fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        fixture.push("fixtures/file-examples.com/file_example_MOV_1280_1_4MB.mov");
+        let temp = TempDir::new().unwrap();
+        let source = temp.path().join("clip.mov");
+        fs::copy(&fixture, &source).unwrap();
+        let cache_dir = temp.path().join("cache");
+
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let reported = progress.clone();
+        let output = transcode(&source, &cache_dir, move |p| {
+            reported.lock().unwrap().push(p)
+        })
+        .unwrap();
+
+        assert_eq!(
+            output,
+            normalize_path(&cache::transcode_path(&source, &cache_dir).to_string_lossy())
+        );
+        wait_until_written(Path::new(&output));
+        assert_eq!(progress.lock().unwrap().last(), Some(&1.0));
+        let data = fs::read(&output).unwrap();
+        assert_eq!(&data[4..8], b"ftyp");
+        assert!(!Path::new(&output).with_extension("mp4.part").exists());
+
+        // The second call is served from the cache
+        let called = Arc::new(Mutex::new(false));
+        let flag = called.clone();
+        assert_eq!(
+            transcode(&source, &cache_dir, move |_| *flag.lock().unwrap() = true).unwrap(),
+            output
+        );
+        assert!(!*called.lock().unwrap());
+    }
+
+    #[test]
+    fn test_generate_proxies_mov_fixture() {
+        if find_ffmpeg().is_none() {
+            return;
+        }
+        let mut fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        fixture.push("fixtures/file-examples.com/file_example_MOV_1280_1_4MB.mov");
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("videos");
+        fs::create_dir(&dir).unwrap();
+        let source = dir.join("clip.mov");
+        fs::copy(&fixture, &source).unwrap();
+        fs::write(dir.join("notes.txt"), b"not a video").unwrap();
+        let cache_dir = temp.path().join("cache");
+
+        // Below the thresholds nothing is generated
+        let small = ProxyOptions {
+            min_resolution: 2160,
+            min_bitrate_mbps: 1000,
+        };
+        let mut updates = Vec::new();
+        generate_proxies(&dir, 1, &cache_dir, &small, |u| updates.push(u)).unwrap();
+        assert!(updates.is_empty());
+        assert!(!cache::proxy_path(&source, &cache_dir).exists());
+        let probe = cache::recorded_probe(&source, &cache_dir).unwrap().unwrap();
+        assert_eq!(probe.size, Some((1280, 720)));
+
+        let options = ProxyOptions {
+            min_resolution: 720,
+            min_bitrate_mbps: 1000,
+        };
+        generate_proxies(&dir, 2, &cache_dir, &options, |u| updates.push(u)).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].path, normalize_path(&source.to_string_lossy()));
+        assert_eq!(updates[0].session_id, 2);
+        let proxy = cache::proxy_path(&source, &cache_dir);
+        assert_eq!(
+            updates[0].proxy_path,
+            normalize_path(&proxy.to_string_lossy())
+        );
+        assert_eq!(&fs::read(&proxy).unwrap()[4..8], b"ftyp");
+        assert!(cache::existing_proxy(&source, &cache_dir)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_transcode_invalid_file() {
+        if find_ffmpeg().is_none() {
+            return;
+        }
+        let temp = TempDir::new().unwrap();
+        let source = temp.path().join("broken.mkv");
+        fs::write(&source, b"not a video").unwrap();
+        let cache_dir = temp.path().join("cache");
+
+        assert!(transcode(&source, &cache_dir, |_| {}).is_err());
+        assert!(!cache::transcode_path(&source, &cache_dir).exists());
+    }
+}
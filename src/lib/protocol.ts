@@ -0,0 +1,39 @@
+import { convertFileSrc } from "@tauri-apps/api/core";
+
+// URLs of the `mv` protocol served by the backend (src-tauri/src/protocol.rs).
+// Only cache entries and files inside the configured roots resolve, see `set_media_scope`.
+const SCHEME = "mv";
+
+function fileName(path: string): string {
+    return path.replace(/\\/g, "/").split("/").pop() ?? "";
+}
+
+// Thumbnail or variant, from the cache path reported by the backend
+export function thumbnailUrl(thumbnailPath: string): string {
+    return convertFileSrc(`thumb/${fileName(thumbnailPath)}`, SCHEME);
+}
+
+// Full-screen preview, from the path returned by `generate_preview`
+export function previewUrl(previewPath: string): string {
+    return convertFileSrc(`preview/${fileName(previewPath)}`, SCHEME);
+}
+
+// Browser-playable transcode, from the path returned by `transcode_video`
+export function transcodeUrl(transcodePath: string): string {
+    return convertFileSrc(`transcode/${fileName(transcodePath)}`, SCHEME);
+}
+
+// Low-resolution proxy of a large video, from the path reported by `generate_proxies`
+export function proxyUrl(proxyPath: string): string {
+    return convertFileSrc(`proxy/${fileName(proxyPath)}`, SCHEME);
+}
+
+// Deep-zoom tile of a pyramid returned by `tile_info`
+export function tileUrl(id: string, level: number, col: number, row: number): string {
+    return convertFileSrc(`tiles/${id}/${level}/${col}_${row}.jpg`, SCHEME);
+}
+
+// Original media file, with range support for video playback
+export function mediaUrl(path: string): string {
+    return convertFileSrc(`media/${path}`, SCHEME);
+}
@@ -1,9 +1,24 @@
 import { load } from "@tauri-apps/plugin-store";
+import { invoke } from "@tauri-apps/api/core";
 import { appLocalDataDir, join } from '@tauri-apps/api/path';
 
 const DEFAULT_THUMBNAIL_SIZE = 128;
 const DEFAULT_SIDEBAR_WIDTH = 256;
+const DEFAULT_PROXY_MIN_RESOLUTION = 2160;
+const DEFAULT_PROXY_MIN_BITRATE_MBPS = 50;
 const STORE_NAME = "settings.json";
+// Junk that NAS and photo tools leave inside archives
+const DEFAULT_IGNORE_PATTERNS = [
+    "@eaDir/",
+    ".thumbnails/",
+    "*.lrdata/",
+    "node_modules/",
+    "Thumbs.db",
+    ".DS_Store",
+];
+
+export type SortBy = "name" | "date";
+export type GroupBy = "none" | "day" | "month";
 
 const storeOptions = {
     defaults: {
@@ -12,6 +27,12 @@ const storeOptions = {
         rootPaths: [] as string[],
         treeNavModifier: "Alt",
         cleanupCacheOnRootRemove: true,
+        proxyEnabled: false,
+        proxyMinResolution: DEFAULT_PROXY_MIN_RESOLUTION,
+        proxyMinBitrateMbps: DEFAULT_PROXY_MIN_BITRATE_MBPS,
+        sortBy: "name" as SortBy,
+        groupBy: "none" as GroupBy,
+        ignorePatterns: DEFAULT_IGNORE_PATTERNS,
     },
     autoSave: true as const,
     overrideDefaults: false,
@@ -24,6 +45,12 @@ class SettingsStore {
     cacheBaseDir = $state<string | null>(null);
     treeNavModifier = $state("Alt");
     cleanupCacheOnRootRemove = $state(true);
+    proxyEnabled = $state(false);
+    proxyMinResolution = $state(DEFAULT_PROXY_MIN_RESOLUTION);
+    proxyMinBitrateMbps = $state(DEFAULT_PROXY_MIN_BITRATE_MBPS);
+    sortBy = $state<SortBy>("name");
+    groupBy = $state<GroupBy>("none");
+    ignorePatterns = $state<string[]>(DEFAULT_IGNORE_PATTERNS);
     ready = $state(false);
 
     private store: any = null;
@@ -76,6 +103,39 @@ class SettingsStore {
                 this.cleanupCacheOnRootRemove = savedCleanupCache;
             }
 
+            const savedProxyEnabled = await this.store.get("proxyEnabled") as boolean | null | undefined;
+            if (savedProxyEnabled !== null && savedProxyEnabled !== undefined) {
+                this.proxyEnabled = savedProxyEnabled;
+            }
+
+            const savedProxyResolution = await this.store.get("proxyMinResolution") as number | null | undefined;
+            if (savedProxyResolution !== null && savedProxyResolution !== undefined) {
+                this.proxyMinResolution = savedProxyResolution;
+            }
+
+            const savedProxyBitrate = await this.store.get("proxyMinBitrateMbps") as number | null | undefined;
+            if (savedProxyBitrate !== null && savedProxyBitrate !== undefined) {
+                this.proxyMinBitrateMbps = savedProxyBitrate;
+            }
+
+            const savedSortBy = await this.store.get("sortBy") as SortBy | null | undefined;
+            if (savedSortBy) {
+                this.sortBy = savedSortBy;
+            }
+
+            const savedGroupBy = await this.store.get("groupBy") as GroupBy | null | undefined;
+            if (savedGroupBy) {
+                this.groupBy = savedGroupBy;
+            }
+
+            const savedIgnorePatterns = await this.store.get("ignorePatterns") as string[] | null | undefined;
+            if (savedIgnorePatterns) {
+                this.ignorePatterns = savedIgnorePatterns;
+            }
+
+            await this.syncMediaScope();
+            await this.syncIgnorePatterns();
+            this.syncCatalog();
         } catch (error) {
             console.error("Failed to load settings:", error);
         } finally {
@@ -83,6 +143,37 @@ class SettingsStore {
         }
     }
 
+    // The mv protocol only serves the cache and files inside the root folders
+    private async syncMediaScope() {
+        if (!this.cacheBaseDir) return;
+        try {
+            await invoke("set_media_scope", {
+                cacheBaseDir: this.cacheBaseDir,
+                roots: this.rootPaths,
+            });
+        } catch (error) {
+            console.error("Failed to update media scope:", error);
+        }
+    }
+
+    // Listings, the catalog and the watcher skip files matching these patterns
+    private async syncIgnorePatterns() {
+        try {
+            await invoke("set_ignore_patterns", { patterns: this.ignorePatterns });
+        } catch (error) {
+            console.error("Failed to update ignore patterns:", error);
+        }
+    }
+
+    // Brings the catalog up to date in the background, unchanged files are not read again
+    private syncCatalog() {
+        if (!this.cacheBaseDir) return;
+        invoke("rescan_catalog", {
+            roots: this.rootPaths,
+            cacheBaseDir: this.cacheBaseDir,
+        }).catch((error) => console.error("Failed to rescan catalog:", error));
+    }
+
     // Debounced save for rapidly changing values like slider
     async saveSize(size: number) {
         this.thumbnailSize = size;
@@ -98,17 +189,22 @@ class SettingsStore {
         if (!this.rootPaths.includes(path)) {
             this.rootPaths = [...this.rootPaths, path];
             await this.saveNow("rootPaths", this.rootPaths);
+            await this.syncMediaScope();
+            this.syncCatalog();
         }
     }
 
     async removeRootPath(path: string) {
         this.rootPaths = this.rootPaths.filter(p => p !== path);
         await this.saveNow("rootPaths", this.rootPaths);
+        await this.syncMediaScope();
+        this.syncCatalog();
     }
 
     async setCacheBaseDir(path: string) {
         this.cacheBaseDir = path;
         await this.saveNow("cacheBaseDir", path);
+        await this.syncMediaScope();
     }
 
     async setTreeNavModifier(modifier: string) {
@@ -121,6 +217,38 @@ class SettingsStore {
         await this.saveNow("cleanupCacheOnRootRemove", value);
     }
 
+    async setProxyEnabled(value: boolean) {
+        this.proxyEnabled = value;
+        await this.saveNow("proxyEnabled", value);
+    }
+
+    async setProxyMinResolution(value: number) {
+        this.proxyMinResolution = value;
+        await this.saveNow("proxyMinResolution", value);
+    }
+
+    async setProxyMinBitrateMbps(value: number) {
+        this.proxyMinBitrateMbps = value;
+        await this.saveNow("proxyMinBitrateMbps", value);
+    }
+
+    async setSortBy(value: SortBy) {
+        this.sortBy = value;
+        await this.saveNow("sortBy", value);
+    }
+
+    async setGroupBy(value: GroupBy) {
+        this.groupBy = value;
+        await this.saveNow("groupBy", value);
+    }
+
+    async setIgnorePatterns(patterns: string[]) {
+        this.ignorePatterns = patterns;
+        await this.saveNow("ignorePatterns", patterns);
+        await this.syncIgnorePatterns();
+        this.syncCatalog();
+    }
+
     private debouncedSave(key: string, value: any) {
         if (!this.ready || !this.store) return;
 